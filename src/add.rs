@@ -0,0 +1,204 @@
+//! The `add` wizard, which registers a new disk in the config file.
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::config::{self, Config};
+use crate::{blkid, output, prompt, sysfs};
+
+/// Devices that can't hold a disk we'd want to manage.
+const IGNORED_PREFIXES: &[&str] = &["loop", "ram", "zram", "sr", "dm-"];
+
+struct Candidate {
+	dev_path: PathBuf,
+	size: u64,
+	probe: blkid::Probe,
+}
+
+fn candidates() -> Result<Vec<Candidate>> {
+	sysfs::all_devices()?
+		.into_iter()
+		.filter(|name| {
+			!IGNORED_PREFIXES
+				.iter()
+				.any(|prefix| name.starts_with(prefix))
+		})
+		.map(|name| {
+			let dev_path = Path::new("/dev").join(&name);
+			Ok(Candidate {
+				size: sysfs::size_bytes(&name)?,
+				probe: blkid::probe(&dev_path)?,
+				dev_path,
+			})
+		})
+		.collect()
+}
+
+fn configured_as<'a>(existing: &'a Config, uuid: &str) -> Option<&'a str> {
+	existing
+		.disks
+		.iter()
+		.find(|disk| disk.uuid == uuid || disk.luks_uuid.as_deref() == Some(uuid))
+		.map(config::Disk::as_repr)
+}
+
+fn pick_candidate(existing: &Config) -> Result<Candidate> {
+	let mut candidates = candidates()?;
+	ensure!(!candidates.is_empty(), "no block devices found");
+
+	let mut table = output::Table::new(&["#", "DEVICE", "SIZE", "CONTENT", "LABEL", "UUID", "NOTE"]);
+	for (idx, candidate) in candidates.iter().enumerate() {
+		let probe = &candidate.probe;
+		let note = match probe
+			.uuid
+			.as_deref()
+			.and_then(|uuid| configured_as(existing, uuid))
+		{
+			Some(name) => format!("already configured as {name}"),
+			None => String::new(),
+		};
+		table.row(vec![
+			((idx + 1).to_string(), None),
+			(candidate.dev_path.display().to_string(), None),
+			(output::format_size(candidate.size), None),
+			(probe.content_type.clone().unwrap_or_default(), None),
+			(probe.label.clone().unwrap_or_default(), None),
+			(probe.uuid.clone().unwrap_or_default(), None),
+			(note, None),
+		]);
+	}
+	table.print();
+
+	let response = prompt::ask("which device? (number)")?;
+	let idx: usize = response
+		.parse()
+		.ok()
+		.filter(|idx| (1..=candidates.len()).contains(idx))
+		.with_context(|| format!("{response:?} is not one of the listed numbers"))?;
+	Ok(candidates.swap_remove(idx - 1))
+}
+
+/// Open a LUKS container just long enough to probe the filesystem inside it.
+fn probe_inside_luks(luks_uuid: &str, disk_name: &str) -> Result<blkid::Probe> {
+	eprintln!("the device is encrypted. unlock it so the filesystem inside can be probed.");
+	let dev_path = crate::dev_path_for_uuid(luks_uuid)?;
+	crate::open_encrypted(
+		&dev_path,
+		luks_uuid,
+		disk_name,
+		&crate::unlock::Key::Prompt,
+		true,
+		None,
+	)
+	.context("opening encrypted device")?;
+	let opened_path =
+		Path::new("/dev/mapper").join(crate::opened_name_for_encrypted(luks_uuid, disk_name));
+	let probe = blkid::probe(&opened_path);
+	crate::close_encrypted(luks_uuid, disk_name).context("closing encrypted device")?;
+	probe
+}
+
+/// The raw config file and the config parsed from it, which is empty if there is no config file yet.
+pub fn load_existing() -> Result<(String, Config)> {
+	let raw = config::load_raw()?;
+	let existing = if raw.is_empty() {
+		Config::default()
+	} else {
+		config::parse(&raw).context("the existing config is invalid; fix it before adding disks")?
+	};
+	Ok((raw, existing))
+}
+
+/// Ask for a name, suggesting `default_name`, and a shortcut.
+pub fn ask_name_and_shortcut(default_name: Option<&str>) -> Result<(String, String)> {
+	let name = prompt::ask_with_default("name for the disk?", default_name)?;
+	config::validate_name(&name).context("invalid name")?;
+	let shortcut = prompt::ask("shortcut for the disk?")?;
+	config::validate_name(&shortcut).context("invalid shortcut")?;
+	Ok((name, shortcut))
+}
+
+pub struct NewDisk<'a> {
+	pub name: &'a str,
+	pub shortcut: &'a str,
+	pub uuid: &'a str,
+	pub luks_uuid: Option<&'a str>,
+	pub filesystem: &'a str,
+}
+
+/// Append a `[[disk]]` entry to `raw`, the existing config file, and save it.
+pub fn append_disk(raw: &str, disk: &NewDisk<'_>) -> Result<()> {
+	let NewDisk {
+		name,
+		shortcut,
+		uuid,
+		luks_uuid,
+		filesystem,
+	} = disk;
+
+	let mut entry = String::new();
+	// Infallible: writing to a String.
+	let _ = writeln!(entry, "\n[[disk]]");
+	let _ = writeln!(entry, "name = {name:?}");
+	let _ = writeln!(entry, "shortcut = {shortcut:?}");
+	let _ = writeln!(entry, "uuid = {uuid:?}");
+	if let Some(luks_uuid) = luks_uuid {
+		let _ = writeln!(entry, "luks_uuid = {luks_uuid:?}");
+	}
+	let _ = writeln!(entry, "filesystem = {filesystem:?}");
+
+	eprintln!("adding this entry to {}:{entry}", config::CONFIG_PATH);
+	let header = if raw.is_empty() {
+		format!("version = {}\n", config::CURRENT_VERSION)
+	} else {
+		String::new()
+	};
+	config::save_raw(&format!("{header}{raw}{entry}"))?;
+	eprintln!("added {name}. mount it with `d m {shortcut}`.");
+	Ok(())
+}
+
+pub fn run() -> Result<()> {
+	let (raw, existing) = load_existing()?;
+
+	let candidate = pick_candidate(&existing)?;
+	let Some(outer_uuid) = candidate.probe.uuid.clone() else {
+		bail!(
+			"{} has no recognizable content or UUID",
+			candidate.dev_path.display()
+		);
+	};
+	if let Some(name) = configured_as(&existing, &outer_uuid) {
+		bail!(
+			"{} is already configured as {name}",
+			candidate.dev_path.display()
+		);
+	}
+
+	let (name, shortcut) = ask_name_and_shortcut(candidate.probe.label.as_deref())?;
+
+	let (inner, luks_uuid) = if candidate.probe.is_luks() {
+		(probe_inside_luks(&outer_uuid, &name)?, Some(outer_uuid))
+	} else {
+		(candidate.probe, None)
+	};
+	let Some(uuid) = inner.uuid else {
+		bail!("no filesystem UUID found; is there a filesystem on the device?");
+	};
+	let Some(filesystem) = inner.content_type else {
+		bail!("no filesystem found on the device");
+	};
+
+	append_disk(
+		&raw,
+		&NewDisk {
+			name: &name,
+			shortcut: &shortcut,
+			uuid: &uuid,
+			luks_uuid: luks_uuid.as_deref(),
+			filesystem: &filesystem,
+		},
+	)
+}
@@ -0,0 +1,158 @@
+//! Asking for passphrases with a program, such as pinentry or zenity, when there is no terminal to prompt on.
+
+use std::io::{BufRead as _, BufReader, Write as _};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::privilege::CommandExt as _;
+
+/// Escape a string for an Assuan command, as pinentry expects.
+fn assuan_escape(text: &str) -> String {
+	text
+		.replace('%', "%25")
+		.replace('\n', "%0A")
+		.replace('\r', "%0D")
+}
+
+fn assuan_unescape(text: &str) -> String {
+	let mut bytes = Vec::with_capacity(text.len());
+	let mut rest = text.as_bytes();
+	while let Some((&byte, after)) = rest.split_first() {
+		let decoded = (byte == b'%')
+			.then(|| after.get(..2))
+			.flatten()
+			.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+		if let Some(decoded) = decoded {
+			bytes.push(decoded);
+			rest = &after[2..];
+		} else {
+			bytes.push(byte);
+			rest = after;
+		}
+	}
+	String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Ask with pinentry, which speaks the Assuan protocol on its stdin and stdout.
+fn pinentry(command: &str, prompt: &str) -> Result<String> {
+	let mut words = command.split_whitespace();
+	let program = words.next().unwrap_or_default();
+	let mut child = Command::new(program)
+		.args(words)
+		.as_invoking_user()
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.spawn()
+		.with_context(|| format!("running {program}"))?;
+	let mut input = child.stdin.take().context("no stdin for pinentry")?;
+	let mut output = BufReader::new(child.stdout.take().context("no stdout for pinentry")?);
+
+	let mut read_reply = || -> Result<(Option<String>, String)> {
+		let mut data = None;
+		loop {
+			let mut line = String::new();
+			ensure!(
+				output.read_line(&mut line)? > 0,
+				"pinentry exited unexpectedly"
+			);
+			let line = line.trim_end_matches('\n');
+			if let Some(rest) = line.strip_prefix("D ") {
+				data = Some(assuan_unescape(rest));
+			} else if line == "OK" || line.starts_with("OK ") || line.starts_with("ERR ") {
+				return Ok((data, line.to_owned()));
+			}
+		}
+	};
+
+	let (_, greeting) = read_reply()?;
+	ensure!(greeting.starts_with("OK"), "pinentry said {greeting:?}");
+	let mut pin = None;
+	for command in [
+		format!("SETDESC {}", assuan_escape(prompt)),
+		"SETPROMPT Passphrase:".to_owned(),
+		"GETPIN".to_owned(),
+	] {
+		writeln!(input, "{command}").context("writing to pinentry")?;
+		let (data, status) = read_reply()?;
+		if status.starts_with("ERR ") {
+			let _ = writeln!(input, "BYE");
+			let _ = child.wait();
+			bail!("pinentry failed or was cancelled: {status}");
+		}
+		pin = data;
+	}
+	let _ = writeln!(input, "BYE");
+	drop(input);
+	let _ = child.wait();
+	pin.context("pinentry gave no passphrase")
+}
+
+/// Ask for a passphrase with `command`, as the invoking user.
+///
+/// If the command is a pinentry program (with arguments separated by spaces), it is spoken to over the Assuan protocol. Otherwise, it is run with `sh -c` with the prompt in `$D_PROMPT`, and the first line of its output is the passphrase.
+pub fn ask(command: &str, prompt: &str) -> Result<String> {
+	let program = command.split_whitespace().next().unwrap_or_default();
+	let is_pinentry = program
+		.rsplit('/')
+		.next()
+		.is_some_and(|name| name.starts_with("pinentry"));
+	if is_pinentry {
+		return pinentry(command, prompt);
+	}
+
+	let output = Command::new("sh")
+		.arg("-c")
+		.arg(command)
+		.env("D_PROMPT", prompt)
+		.as_invoking_user()
+		.stdin(Stdio::null())
+		.stderr(Stdio::inherit())
+		.output()
+		.with_context(|| format!("running {command:?}"))?;
+	// Dialog programs exit unsuccessfully when they are cancelled.
+	ensure!(
+		output.status.success(),
+		"{command:?} exited with status {:?}, so the prompt may have been cancelled",
+		output.status.code()
+	);
+	let stdout = String::from_utf8(output.stdout).context("output is not UTF-8")?;
+	Ok(stdout.lines().next().unwrap_or_default().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn escape() {
+		assert_eq!(assuan_escape(""), "");
+		assert_eq!(assuan_escape("passphrase for a:"), "passphrase for a:");
+		assert_eq!(assuan_escape("100%"), "100%25");
+		assert_eq!(assuan_escape("a\nb\r\n"), "a%0Ab%0D%0A");
+		assert_eq!(assuan_escape("%0A"), "%250A");
+	}
+
+	#[test]
+	fn unescape() {
+		assert_eq!(assuan_unescape(""), "");
+		assert_eq!(assuan_unescape("hunter2"), "hunter2");
+		assert_eq!(assuan_unescape("a%25b%0a%0D"), "a%b\n\r");
+		assert_eq!(assuan_unescape("%c3%A9"), "é");
+	}
+
+	#[test]
+	fn unescape_malformed() {
+		for text in ["%", "a%", "%4", "%zz", "%%41", "100% sure"] {
+			let expected = text.replace("%41", "A");
+			assert_eq!(assuan_unescape(text), expected, "{text}");
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		for text in ["", "%", "%25", "a b\nc\r", "é%0A%"] {
+			assert_eq!(assuan_unescape(&assuan_escape(text)), text);
+		}
+	}
+}
@@ -0,0 +1,55 @@
+//! Locking encrypted disks that were left open with nothing mounted, such as after something else unmounted the filesystem or a teardown failed halfway.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::{config, output, state, DiskState};
+
+/// How often to look for idle mappings.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Close the encryption of disks that have been open without being mounted for `timeout`, forever. Run by `d helper`.
+pub fn run(timeout: Duration) {
+	let mut idle_since: HashMap<String, Instant> = HashMap::new();
+	loop {
+		std::thread::sleep(CHECK_INTERVAL);
+		// Reloaded each time, so that disks added to the config are watched too.
+		let config = match config::load() {
+			Ok(config) => config,
+			Err(error) => {
+				output::warning(format_args!(
+					"auto-lock failed to load the config: {error:#}"
+				));
+				continue;
+			}
+		};
+		for disk in config.disks.iter().filter(|disk| disk.is_encrypted()) {
+			// Mappings that were open before `d` mounted their disk belong to whatever opened them. If that can't be told, they are left alone too.
+			let is_external = !config.close_external_mappings
+				&& state::has_external_mapping(disk.as_repr()).unwrap_or(true);
+			if is_external || !matches!(crate::disk_state(disk), Ok(DiskState::Open)) {
+				idle_since.remove(&disk.name);
+				continue;
+			}
+			let since = idle_since
+				.entry(disk.name.clone())
+				.or_insert_with(Instant::now);
+			if since.elapsed() < timeout {
+				continue;
+			}
+			// If closing fails, such as because something is stacked on the mapping, wait for another timeout before retrying.
+			*since = Instant::now();
+			match crate::lock(disk) {
+				Ok(()) => eprintln!(
+					"locked {} after it was open without being mounted for {}s.",
+					disk.as_repr(),
+					timeout.as_secs()
+				),
+				Err(error) => output::warning(format_args!(
+					"failed to lock idle disk {}: {error:#}",
+					disk.as_repr()
+				)),
+			}
+		}
+	}
+}
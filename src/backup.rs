@@ -0,0 +1,70 @@
+//! Backing up a disk with borg or restic, as configured per disk.
+
+use std::process::Command;
+
+use anyhow::{ensure, Context as _, Result};
+
+use crate::config::Backup;
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tool {
+	Borg,
+	Restic,
+}
+
+impl Tool {
+	fn program(self) -> &'static str {
+		match self {
+			Self::Borg => "borg",
+			Self::Restic => "restic",
+		}
+	}
+}
+
+/// Back up the disk mounted at `mount_path`.
+///
+/// The tool runs inside the mount path with relative paths, so that the backup's paths are the same wherever the disk was mounted.
+pub fn run(disk_name: &str, mount_path: &str, backup: &Backup) -> Result<()> {
+	let paths: &[String] = if backup.paths.is_empty() {
+		&[".".to_owned()]
+	} else {
+		&backup.paths
+	};
+
+	let mut command = Command::new(backup.tool.program());
+	match backup.tool {
+		Tool::Borg => {
+			// borg expands the placeholders itself.
+			command.arg("create").args(&backup.options).arg(format!(
+				"{}::{{hostname}}-{disk_name}-{{now}}",
+				backup.repository
+			));
+		}
+		Tool::Restic => {
+			command
+				.arg("--repo")
+				.arg(&backup.repository)
+				.arg("backup")
+				.args(["--tag", &format!("d-{disk_name}")])
+				.args(&backup.options);
+		}
+	}
+	command
+		.arg("--")
+		.args(paths)
+		.current_dir(mount_path)
+		.envs(&backup.env);
+
+	eprintln!("backing up {disk_name} with {}.", backup.tool.program());
+	let status = command
+		.status()
+		.with_context(|| format!("running {}", backup.tool.program()))?;
+	ensure!(
+		status.success(),
+		"{} exited with status {:?}",
+		backup.tool.program(),
+		status.code()
+	);
+	Ok(())
+}
@@ -0,0 +1,135 @@
+//! Operating on several disks at once.
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+
+use anyhow::{bail, Result};
+
+use crate::config::{Config, Disk};
+use crate::{output, progress, MountOptions, MountReturn};
+
+enum Outcome {
+	Mounted(String),
+	AlreadyMounted(String),
+	Failed(anyhow::Error),
+	/// Not attempted because a dependency failed or was skipped.
+	Skipped(String),
+}
+
+impl Outcome {
+	fn is_success(&self) -> bool {
+		matches!(self, Self::Mounted(..) | Self::AlreadyMounted(..))
+	}
+
+	fn describe(&self) -> (String, Option<output::Color>) {
+		match self {
+			Self::Mounted(mount_path) => (
+				format!("mounted at {mount_path}"),
+				Some(output::Color::Green),
+			),
+			Self::AlreadyMounted(mount_path) => (
+				format!("already mounted at {mount_path}"),
+				Some(output::Color::Green),
+			),
+			Self::Failed(error) => (format!("failed: {error:#}"), Some(output::Color::Red)),
+			Self::Skipped(dependency) => (
+				format!("skipped since {dependency} was not mounted"),
+				Some(output::Color::Yellow),
+			),
+		}
+	}
+}
+
+/// Mount `disks` in parallel, starting each one once its dependencies are mounted.
+///
+/// `disks` must include the dependencies of every disk in it, as returned by `Config::resolve_targets`.
+pub fn mount_all(config: &Config, disks: &[&Disk], options: MountOptions) -> Result<()> {
+	progress::set_enabled(false);
+
+	let mut outcomes: HashMap<&str, Outcome> = HashMap::new();
+	let mut pending: Vec<&Disk> = disks.to_vec();
+
+	std::thread::scope(|scope| -> Result<()> {
+		let (sender, receiver) = mpsc::channel();
+		let mut running = 0;
+
+		loop {
+			let mut idx = 0;
+			while idx < pending.len() {
+				let disk = pending[idx];
+				let dependencies = config.dependencies(disk)?;
+
+				let unsatisfied = dependencies.iter().find(|dependency| {
+					outcomes
+						.get(dependency.as_repr())
+						.is_some_and(|outcome| !outcome.is_success())
+				});
+				if let Some(unsatisfied) = unsatisfied {
+					outcomes.insert(disk.as_repr(), Outcome::Skipped(unsatisfied.name.clone()));
+					pending.remove(idx);
+					continue;
+				}
+
+				let is_ready = dependencies
+					.iter()
+					.all(|dependency| outcomes.contains_key(dependency.as_repr()));
+				if is_ready {
+					let sender = sender.clone();
+					scope.spawn(move || {
+						let outcome = match crate::do_mount(config, disk, options) {
+							Ok(MountReturn {
+								mount_path,
+								was_already_mounted: false,
+							}) => Outcome::Mounted(mount_path),
+							Ok(MountReturn {
+								mount_path,
+								was_already_mounted: true,
+							}) => Outcome::AlreadyMounted(mount_path),
+							Err(error) => Outcome::Failed(error),
+						};
+						let _ = sender.send((disk.as_repr(), outcome));
+					});
+					running += 1;
+					pending.remove(idx);
+					continue;
+				}
+
+				idx += 1;
+			}
+
+			if running == 0 {
+				break;
+			}
+			let (name, outcome) = receiver.recv().expect("a mount thread is still running");
+			running -= 1;
+			outcomes.insert(name, outcome);
+		}
+
+		Ok(())
+	})?;
+
+	// Anything still pending depends on a disk that was never attempted, which shouldn't happen if `disks` is complete.
+	for disk in pending {
+		outcomes.insert(disk.as_repr(), Outcome::Skipped("a dependency".to_owned()));
+	}
+
+	let mut table = output::Table::new(&["DISK", "RESULT"]);
+	let mut num_failed = 0;
+	for disk in disks {
+		let outcome = &outcomes[disk.as_repr()];
+		if !outcome.is_success() {
+			num_failed += 1;
+		}
+		let (description, color) = outcome.describe();
+		table.row(vec![
+			(disk.as_repr().to_owned(), None),
+			(description, color),
+		]);
+	}
+	table.print();
+
+	if num_failed > 0 {
+		bail!("{num_failed} of {} disks were not mounted", disks.len());
+	}
+	Ok(())
+}
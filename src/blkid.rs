@@ -0,0 +1,52 @@
+//! Probing devices for filesystems and other content with `blkid`.
+
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+
+#[derive(Debug, Clone, Default)]
+pub struct Probe {
+	/// The kind of content, such as `ext4` or `crypto_LUKS`.
+	pub content_type: Option<String>,
+	/// The kind of partition table, such as `gpt` or `dos`, for whole disks.
+	pub partition_table: Option<String>,
+	pub uuid: Option<String>,
+	pub label: Option<String>,
+}
+
+impl Probe {
+	pub fn is_luks(&self) -> bool {
+		self.content_type.as_deref() == Some("crypto_LUKS")
+	}
+}
+
+/// Devices without any recognized content produce an empty probe rather than an error.
+pub fn probe(dev_path: &Path) -> Result<Probe> {
+	let output = crate::caps::tool("blkid")
+		.args(["--output", "export"])
+		.arg(dev_path)
+		.output()
+		.context("running blkid")?;
+
+	let mut probe = Probe::default();
+	// blkid exits with status 2 if it finds nothing, which isn't an error for us.
+	if !output.status.success() {
+		return Ok(probe);
+	}
+
+	for line in String::from_utf8_lossy(&output.stdout).lines() {
+		let Some((key, value)) = line.split_once('=') else {
+			continue;
+		};
+		let value = Some(value.to_owned());
+		match key {
+			"TYPE" => probe.content_type = value,
+			"PTTYPE" => probe.partition_table = value,
+			"UUID" => probe.uuid = value,
+			"LABEL" => probe.label = value,
+			_ => {}
+		}
+	}
+
+	Ok(probe)
+}
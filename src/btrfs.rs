@@ -0,0 +1,270 @@
+//! btrfs-specific mount options and maintenance: scrubbing, the per-device error counters, and finding the members of multi-device filesystems.
+
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::io::AsRawFd as _;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::{caps, output, progress, stats};
+
+/// How often to poll a running scrub.
+const SCRUB_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn btrfs_output(args: &[&str]) -> Result<String> {
+	let output = caps::tool("btrfs")
+		.args(args)
+		.output()
+		.context("running btrfs")?;
+	ensure!(
+		output.status.success(),
+		"`btrfs {}` exited with status {:?}: {}",
+		args.join(" "),
+		output.status.code(),
+		String::from_utf8_lossy(&output.stderr).trim()
+	);
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The value of a `Key: value` line in the output of `btrfs scrub status`.
+fn status_field<'a>(status: &'a str, key: &str) -> Option<&'a str> {
+	status.lines().find_map(|line| {
+		let (line_key, value) = line.split_once(':')?;
+		(line_key.trim() == key).then(|| value.trim())
+	})
+}
+
+fn scrub_status(mount_path: &str) -> Result<String> {
+	btrfs_output(&["scrub", "status", mount_path])
+}
+
+fn is_scrub_running(status: &str) -> bool {
+	status_field(status, "Status") == Some("running")
+}
+
+/// Start a scrub of the filesystem mounted at `mount_path`, or attach to one that is already running, and wait for it to finish.
+pub fn scrub(disk_name: &str, mount_path: &str) -> Result<()> {
+	if is_scrub_running(&scrub_status(mount_path)?) {
+		eprintln!("a scrub is already running on {disk_name}, waiting for it.");
+	} else {
+		btrfs_output(&["scrub", "start", mount_path]).context("starting scrub")?;
+		eprintln!("started scrubbing {disk_name}. this reads every block, so it can take hours.");
+	}
+
+	let status = progress::with_spinner("scrubbing", || -> Result<String> {
+		loop {
+			let status = scrub_status(mount_path)?;
+			if !is_scrub_running(&status) {
+				return Ok(status);
+			}
+			std::thread::sleep(SCRUB_POLL_INTERVAL);
+		}
+	})?;
+	eprint!("{status}");
+
+	match status_field(&status, "Status") {
+		Some("finished") => {}
+		Some(other) => bail!("scrub did not finish (status: {other})"),
+		None => bail!("could not find the scrub status in the output of `btrfs scrub status`"),
+	}
+	if let Err(error) = stats::record_scrub(disk_name) {
+		output::warning(format_args!("failed to record scrub: {error:#}"));
+	}
+	let summary = status_field(&status, "Error summary").unwrap_or_default();
+	ensure!(
+		summary == "no errors found",
+		"scrub found errors: {summary}"
+	);
+	Ok(())
+}
+
+pub struct DeviceStat {
+	pub device: String,
+	pub counter: String,
+	pub value: u64,
+}
+
+/// The accumulated error counters of each device in the filesystem mounted at `mount_path`.
+pub fn device_stats(mount_path: &str) -> Result<Vec<DeviceStat>> {
+	// Lines look like `[/dev/mapper/foo].write_io_errs    0`.
+	btrfs_output(&["device", "stats", mount_path])?
+		.lines()
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| {
+			let parse = || {
+				let (name, value) = line.split_once(char::is_whitespace)?;
+				let (device, counter) = name.strip_prefix('[')?.split_once("].")?;
+				Some(DeviceStat {
+					device: device.to_owned(),
+					counter: counter.to_owned(),
+					value: value.trim().parse().ok()?,
+				})
+			};
+			parse().with_context(|| format!("unexpected line from `btrfs device stats`: {line:?}"))
+		})
+		.collect()
+}
+
+/// See `include/uapi/linux/btrfs.h`.
+const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+
+#[repr(C)]
+struct VolArgs {
+	fd: i64,
+	name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+// Registers the device with the kernel and returns whether any device of its filesystem has not been registered.
+nix::ioctl_read!(btrfs_devices_ready, BTRFS_IOCTL_MAGIC, 39, VolArgs);
+
+/// Register every btrfs device with the kernel, in case udev hasn't, and describe the members of the filesystem on `dev_path` that are missing.
+pub fn missing_members(dev_path: &Path) -> Result<Option<String>> {
+	btrfs_output(&["device", "scan"]).context("scanning for btrfs devices")?;
+
+	let path = dev_path.as_os_str().as_bytes();
+	ensure!(path.len() <= BTRFS_PATH_NAME_MAX, "device path is too long");
+	let mut args = VolArgs {
+		fd: 0,
+		name: [0; BTRFS_PATH_NAME_MAX + 1],
+	};
+	args.name[..path.len()].copy_from_slice(path);
+	let control = std::fs::File::open("/dev/btrfs-control").context("opening /dev/btrfs-control")?;
+	// SAFETY: the argument is a `struct btrfs_ioctl_vol_args` with a NUL-terminated path.
+	let missing = unsafe { btrfs_devices_ready(control.as_raw_fd(), std::ptr::addr_of_mut!(args)) }
+		.context("checking whether all btrfs devices are present")?;
+	if missing == 0 {
+		return Ok(None);
+	}
+
+	// Lines look like `Total devices 3 FS bytes used 1.00GiB` and `devid    1 size 10.00GiB used 2.00GiB path /dev/sdb`.
+	let show = btrfs_output(&["filesystem", "show", &dev_path.to_string_lossy()])?;
+	let total = show.lines().find_map(|line| {
+		line
+			.trim()
+			.strip_prefix("Total devices ")?
+			.split_whitespace()
+			.next()?
+			.parse::<usize>()
+			.ok()
+	});
+	let present: Vec<&str> = show
+		.lines()
+		.filter(|line| line.trim_start().starts_with("devid") && !line.contains("MISSING"))
+		.filter_map(|line| line.split_once(" path ").map(|(_, path)| path.trim()))
+		.collect();
+	Ok(Some(match total {
+		Some(total) => format!(
+			"{} of {total} devices are missing. present: {}",
+			total.saturating_sub(present.len()),
+			present.join(", ")
+		),
+		None => format!("some devices are missing. present: {}", present.join(", ")),
+	}))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+	Zstd,
+	Lzo,
+	Zlib,
+}
+
+impl Algorithm {
+	fn as_repr(self) -> &'static str {
+		match self {
+			Self::Zstd => "zstd",
+			Self::Lzo => "lzo",
+			Self::Zlib => "zlib",
+		}
+	}
+
+	/// The levels that the kernel accepts, or `None` if the algorithm has no levels.
+	fn levels(self) -> Option<std::ops::RangeInclusive<u32>> {
+		match self {
+			Self::Zstd => Some(1..=15),
+			Self::Lzo => None,
+			Self::Zlib => Some(1..=9),
+		}
+	}
+}
+
+/// Compression of newly written data. Existing data stays as it is until it is rewritten or defragmented.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Compression {
+	pub algorithm: Algorithm,
+	/// Higher levels compress better but more slowly. Defaults to the kernel's default for the algorithm.
+	#[serde(default)]
+	pub level: Option<u32>,
+}
+
+/// Options left unset keep the kernel's defaults.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tuning {
+	#[serde(default)]
+	pub compress: Option<Compression>,
+	/// Use the free space tree (`space_cache=v2`), which is faster on large filesystems and the default since Linux 5.15.
+	#[serde(default)]
+	pub space_cache_v2: bool,
+	/// Defragment files in the background when small random writes are detected.
+	#[serde(default)]
+	pub autodefrag: Option<bool>,
+	/// Override whether btrfs treats the drive as solid-state, which it otherwise guesses from the rotational flag.
+	#[serde(default)]
+	pub ssd: Option<bool>,
+}
+
+impl Tuning {
+	pub fn validate(&self, filesystem: &str) -> Result<()> {
+		ensure!(
+			filesystem == "btrfs",
+			"btrfs options only apply to btrfs disks"
+		);
+		if let Some(compression) = &self.compress {
+			match (compression.algorithm.levels(), compression.level) {
+				(_, None) => {}
+				(None, Some(_)) => bail!(
+					"{} compression has no levels",
+					compression.algorithm.as_repr()
+				),
+				(Some(levels), Some(level)) => ensure!(
+					levels.contains(&level),
+					"{} compression levels are {} to {}",
+					compression.algorithm.as_repr(),
+					levels.start(),
+					levels.end()
+				),
+			}
+		}
+		Ok(())
+	}
+
+	/// The mount options for these settings, to add to the others.
+	pub fn mount_options(&self) -> Vec<String> {
+		let mut options = Vec::new();
+		if let Some(compression) = &self.compress {
+			options.push(match compression.level {
+				Some(level) => format!("compress={}:{level}", compression.algorithm.as_repr()),
+				None => format!("compress={}", compression.algorithm.as_repr()),
+			});
+		}
+		if self.space_cache_v2 {
+			options.push("space_cache=v2".to_owned());
+		}
+		match self.autodefrag {
+			Some(true) => options.push("autodefrag".to_owned()),
+			Some(false) => options.push("noautodefrag".to_owned()),
+			None => {}
+		}
+		match self.ssd {
+			Some(true) => options.push("ssd".to_owned()),
+			Some(false) => options.push("nossd".to_owned()),
+			None => {}
+		}
+		options
+	}
+}
@@ -0,0 +1,47 @@
+//! Canary files, whose checksum is verified after mounting to catch mounting the wrong disk or silent corruption.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::config::Canary;
+
+/// The SHA-256 checksum of a file, in lowercase hex.
+fn sha256(path: &Path) -> Result<String> {
+	let output = Command::new("sha256sum")
+		.arg("--")
+		.arg(path)
+		.output()
+		.context("running sha256sum")?;
+	ensure!(
+		output.status.success(),
+		"sha256sum failed: {}",
+		String::from_utf8_lossy(&output.stderr).trim()
+	);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	let checksum = stdout
+		.split_whitespace()
+		.next()
+		.context("sha256sum printed nothing")?;
+	Ok(checksum.to_ascii_lowercase())
+}
+
+/// Check that the canary file exists under `mount_path` and has the configured checksum.
+pub fn verify(mount_path: &str, canary: &Canary) -> Result<()> {
+	let path = Path::new(mount_path).join(&canary.path);
+	if !path.is_file() {
+		bail!(
+			"canary file {} is missing. is this the right disk?",
+			path.display()
+		);
+	}
+	let actual = sha256(&path).context("checksumming canary file")?;
+	ensure!(
+		actual.eq_ignore_ascii_case(&canary.sha256),
+		"canary file {} has checksum {actual}, but {} was expected. this may be the wrong disk, or the file may have been modified or corrupted",
+		path.display(),
+		canary.sha256
+	);
+	Ok(())
+}
@@ -0,0 +1,145 @@
+//! Running with just the capabilities needed to mount and open encryption, given to the binary as file capabilities, instead of as full root:
+//!
+//! ```sh
+//! setcap cap_sys_admin,cap_dac_override+ep "$(which d)"
+//! ```
+
+use std::ffi::OsStr;
+use std::os::unix::process::CommandExt as _;
+use std::process::Command;
+use std::ptr::addr_of_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context as _, Result};
+use nix::errno::Errno;
+use nix::libc;
+
+/// See `capabilities(7)`.
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_SYS_ADMIN: u32 = 21;
+/// Both capabilities are in the first of the two words of each set.
+const REQUIRED: u32 = 1 << CAP_DAC_OVERRIDE | 1 << CAP_SYS_ADMIN;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct Header {
+	version: u32,
+	pid: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Data {
+	effective: u32,
+	permitted: u32,
+	inheritable: u32,
+}
+
+fn header() -> Header {
+	Header {
+		version: LINUX_CAPABILITY_VERSION_3,
+		// This thread.
+		pid: 0,
+	}
+}
+
+fn get() -> Result<[Data; 2]> {
+	let mut header = header();
+	let mut data = [Data::default(); 2];
+	// SAFETY: version 3 of the interface reads into two `Data`s.
+	let ret = unsafe { libc::syscall(libc::SYS_capget, addr_of_mut!(header), data.as_mut_ptr()) };
+	Errno::result(ret).context("getting capabilities")?;
+	Ok(data)
+}
+
+fn set(mut data: [Data; 2]) -> Result<()> {
+	let mut header = header();
+	// SAFETY: version 3 of the interface reads from two `Data`s.
+	let ret = unsafe { libc::syscall(libc::SYS_capset, addr_of_mut!(header), data.as_mut_ptr()) };
+	Errno::result(ret).context("setting capabilities")?;
+	Ok(())
+}
+
+/// Whether this process can mount and open encryption without being root.
+pub fn has_required() -> bool {
+	get().is_ok_and(|data| data[0].effective & REQUIRED == REQUIRED)
+}
+
+/// Whether `tool` should pass our capabilities on, because we have them as file capabilities rather than by being root.
+static PASS_TO_TOOLS: AtomicBool = AtomicBool::new(false);
+
+/// If running with file capabilities, make them inheritable, so that `tool` can pass them to the programs that need them, like `mount` and `cryptsetup`.
+///
+/// File capabilities are not inherited across `exec` by themselves. Root doesn't need this.
+pub fn prepare_for_tools() -> Result<()> {
+	if nix::unistd::Uid::effective().is_root() || !has_required() {
+		return Ok(());
+	}
+
+	let mut data = get()?;
+	data[0].inheritable |= REQUIRED;
+	set(data)?;
+	PASS_TO_TOOLS.store(true, Ordering::Relaxed);
+	Ok(())
+}
+
+/// Raise our capabilities as ambient capabilities, so that the program about to be run gets them.
+///
+/// This only makes syscalls, so it is safe to use between `fork` and `exec`.
+fn raise_ambient() -> std::io::Result<()> {
+	for cap in [CAP_DAC_OVERRIDE, CAP_SYS_ADMIN] {
+		// SAFETY: `prctl` with these options takes no pointers.
+		let ret = unsafe {
+			libc::prctl(
+				libc::PR_CAP_AMBIENT,
+				libc::PR_CAP_AMBIENT_RAISE,
+				libc::c_ulong::from(cap),
+				0,
+				0,
+			)
+		};
+		Errno::result(ret).map_err(std::io::Error::from)?;
+	}
+	Ok(())
+}
+
+/// Where tools are looked up when they get our capabilities, instead of the invoking user's `PATH`.
+const TRUSTED_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// The only variables that tools with our capabilities get from the invoking user. Others, such as `LD_PRELOAD`, could make them run the user's code.
+const PASSED_VARS: &[&str] = &["LANG", "LC_ALL", "LC_CTYPE", "LC_MESSAGES", "TERM"];
+
+/// A command for a program that needs our capabilities to work on devices and filesystems, such as `cryptsetup` or `fsck`. Other programs, such as the user's shell, never get them.
+///
+/// When it does get them, the program is looked up in a fixed `PATH` and runs with almost none of the invoking user's environment.
+pub fn tool(program: impl AsRef<OsStr>) -> Command {
+	let mut command = Command::new(program);
+	if PASS_TO_TOOLS.load(Ordering::Relaxed) {
+		command.env_clear().env("PATH", TRUSTED_PATH);
+		for var in PASSED_VARS {
+			if let Some(value) = std::env::var_os(var) {
+				command.env(var, value);
+			}
+		}
+		// SAFETY: `raise_ambient` only makes syscalls.
+		unsafe { command.pre_exec(raise_ambient) };
+	}
+	command
+}
+
+/// Drop all ambient capabilities, for a process about to run something as the invoking user.
+///
+/// This only makes a syscall, so it is safe to use between `fork` and `exec`.
+pub fn clear_ambient() -> std::io::Result<()> {
+	// SAFETY: `prctl` with these options takes no pointers.
+	let ret = unsafe {
+		libc::prctl(
+			libc::PR_CAP_AMBIENT,
+			libc::PR_CAP_AMBIENT_CLEAR_ALL,
+			0,
+			0,
+			0,
+		)
+	};
+	Errno::result(ret).map(drop).map_err(std::io::Error::from)
+}
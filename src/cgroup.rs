@@ -0,0 +1,117 @@
+//! Control groups for `c` sessions, for finding everything that was started in a session and limiting how much of the disk's bandwidth it can use.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::os::unix::io::AsRawFd as _;
+use std::os::unix::process::CommandExt as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+use nix::libc;
+use nix::sys::stat::{major, minor};
+
+use crate::sysfs;
+
+const ROOT: &str = "/sys/fs/cgroup";
+/// Our cgroups are kept together under this one, which never has processes of its own, as cgroup v2 requires for controllers to be enabled in it.
+const PARENT: &str = "d";
+
+/// Enable the I/O controller for the children of `dir`.
+fn enable_io_controller(dir: &Path) -> Result<()> {
+	std::fs::write(dir.join("cgroup.subtree_control"), "+io")
+		.with_context(|| format!("enabling the I/O controller in {}", dir.display()))
+}
+
+/// Remove the cgroups of sessions whose `d` has exited, which are left behind when processes outlive their session.
+fn remove_stale(parent: &Path) {
+	let Ok(entries) = std::fs::read_dir(parent) else {
+		return;
+	};
+	for entry in entries.flatten() {
+		let name = entry.file_name();
+		let owner = name
+			.to_str()
+			.and_then(|name| name.rsplit_once('-'))
+			.and_then(|(_, pid)| pid.parse::<u32>().ok());
+		let is_stale = owner.is_some_and(|pid| !Path::new("/proc").join(pid.to_string()).exists());
+		// Removing a cgroup that still has processes fails, so those are left alone.
+		if is_stale && entry.path().is_dir() {
+			let _ = std::fs::remove_dir(entry.path());
+		}
+	}
+}
+
+/// A cgroup that is removed when dropped, if its processes have all exited.
+pub struct Scope {
+	path: PathBuf,
+	/// Opened while we are still root, so that processes can move themselves in after dropping privileges.
+	procs: File,
+}
+
+impl Scope {
+	/// Create a cgroup for the session of `disk_name` run by this process.
+	pub fn create(disk_name: &str) -> Result<Self> {
+		let parent = Path::new(ROOT).join(PARENT);
+		std::fs::create_dir_all(&parent).context("creating parent cgroup")?;
+		remove_stale(&parent);
+		let path = parent.join(format!("{disk_name}-{}", std::process::id()));
+		std::fs::create_dir(&path).context("creating cgroup")?;
+		let procs = match OpenOptions::new()
+			.write(true)
+			.open(path.join("cgroup.procs"))
+		{
+			Ok(procs) => procs,
+			Err(error) => {
+				let _ = std::fs::remove_dir(&path);
+				return Err(error).context("opening cgroup.procs");
+			}
+		};
+		Ok(Self { path, procs })
+	}
+
+	/// Limit reads and writes to the block device `kernel_name` to `bytes_per_sec` each.
+	pub fn limit_io(&self, kernel_name: &str, bytes_per_sec: u64) -> Result<()> {
+		enable_io_controller(Path::new(ROOT))?;
+		enable_io_controller(&Path::new(ROOT).join(PARENT))?;
+		let device = sysfs::device_number(kernel_name)?;
+		let limit = format!(
+			"{}:{} rbps={bytes_per_sec} wbps={bytes_per_sec}",
+			major(device),
+			minor(device)
+		);
+		std::fs::write(self.path.join("io.max"), limit).context("setting I/O limit")
+	}
+
+	/// Make `command` start in this cgroup, so that everything it runs is in it too.
+	pub fn attach(&self, command: &mut Command) {
+		let fd = self.procs.as_raw_fd();
+		let join = move || {
+			// Writing 0 moves the writing process. If this fails, `add` moves the process from the outside after it starts.
+			// SAFETY: `fd` stays open until the scope is dropped, which is after spawning.
+			unsafe { libc::write(fd, b"0".as_ptr().cast(), 1) };
+			Ok(())
+		};
+		// SAFETY: `join` only makes a syscall.
+		unsafe { command.pre_exec(join) };
+	}
+
+	/// The processes in the cgroup, which are those started in the session that are still running.
+	pub fn processes(&self) -> Result<Vec<i32>> {
+		let raw = std::fs::read_to_string(self.path.join("cgroup.procs"))
+			.context("listing processes in cgroup")?;
+		Ok(raw.lines().filter_map(|line| line.parse().ok()).collect())
+	}
+
+	pub fn add(&self, pid: u32) -> Result<()> {
+		(&self.procs)
+			.write_all(pid.to_string().as_bytes())
+			.context("moving process into cgroup")
+	}
+}
+
+impl Drop for Scope {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir(&self.path);
+	}
+}
@@ -0,0 +1,152 @@
+//! Recovering from crashes and killed sessions, which can leave mappings open and directories behind.
+
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+
+use crate::config::{self, Config};
+use crate::{mountinfo, output, state, sysfs};
+
+/// Length of a hyphenated UUID, which is the prefix of the mapping names that `d` uses.
+const UUID_LEN: usize = 36;
+
+/// Whether a device-mapper name looks like one from `opened_name_for_encrypted`.
+fn is_d_mapping_name(name: &str) -> bool {
+	let Some((uuid, rest)) = name.split_at_checked(UUID_LEN) else {
+		return false;
+	};
+	rest
+		.strip_prefix('-')
+		.is_some_and(|disk_name| config::validate_name(disk_name).is_ok())
+		&& uuid.split('-').map(str::len).eq([8, 4, 4, 4, 12])
+		&& config::validate_uuid(uuid).is_ok()
+}
+
+/// Open mappings named like `d`'s with nothing mounted on or stacked on top of them.
+fn orphaned_mappings(mounts: &[mountinfo::Entry]) -> Result<Vec<String>> {
+	let mut orphaned = Vec::new();
+	for device in sysfs::all_devices()? {
+		let Some(dm_name) = sysfs::dm_name(&device) else {
+			continue;
+		};
+		if !is_d_mapping_name(&dm_name) || !sysfs::holders(&device)?.is_empty() {
+			continue;
+		}
+		let number = sysfs::device_number(&device)?;
+		if mounts.iter().all(|entry| entry.device != number) {
+			orphaned.push(dm_name);
+		}
+	}
+	Ok(orphaned)
+}
+
+/// Empty directories at the mount paths of configured disks and composites that aren't mounted.
+///
+/// Paths nested inside composites are skipped, since they are on the composite's first disk.
+fn stale_mount_dirs(config: &Config, mounts: &[mountinfo::Entry]) -> Result<Vec<String>> {
+	let names = config
+		.disks
+		.iter()
+		.filter(|disk| config.composite_membership(disk).is_none())
+		.map(|disk| disk.name.as_str())
+		.chain(
+			config
+				.composites
+				.iter()
+				.map(|composite| composite.name.as_str()),
+		);
+
+	let mut stale = Vec::new();
+	for name in names {
+		let mount_path = crate::mount_path_for_name(name);
+		let is_mounted = mounts
+			.iter()
+			.any(|entry| entry.mount_point == Path::new(&mount_path));
+		if is_mounted {
+			continue;
+		}
+		let is_empty_dir = match std::fs::read_dir(&mount_path) {
+			Ok(mut entries) => entries.next().is_none(),
+			Err(error) if error.kind() == std::io::ErrorKind::NotFound => false,
+			Err(error) => return Err(error).with_context(|| format!("listing {mount_path}")),
+		};
+		if is_empty_dir {
+			stale.push(mount_path);
+		}
+	}
+	Ok(stale)
+}
+
+/// Close orphaned mappings, remove stale mount directories, and forget about disks that are no longer mounted.
+///
+/// With `dry_run`, only report what would be done.
+pub fn run(config: &Config, dry_run: bool) -> Result<()> {
+	let verb = if dry_run { "would " } else { "" };
+	let mut num_actions = 0;
+
+	let mounts = mountinfo::read()?;
+	let known = state::load()?;
+	for mapping_name in orphaned_mappings(&mounts)? {
+		// Mappings that were open before `d` mounted their disk belong to whatever opened them.
+		let disk_name = &mapping_name[UUID_LEN + 1..];
+		if !config.close_external_mappings
+			&& known
+				.get(disk_name)
+				.is_some_and(|entry| entry.external_mapping == Some(true))
+		{
+			continue;
+		}
+		eprintln!("{verb}close orphaned mapping {mapping_name}.");
+		num_actions += 1;
+		if !dry_run {
+			if let Err(error) =
+				crate::close_mapping(&mapping_name).and_then(|()| state::clear_external_mapping(disk_name))
+			{
+				output::warning(format_args!("failed to close {mapping_name}: {error:#}"));
+			}
+		}
+	}
+
+	for mount_path in stale_mount_dirs(config, &mounts)? {
+		eprintln!("{verb}remove empty mount directory {mount_path}.");
+		num_actions += 1;
+		if !dry_run {
+			if let Err(error) = std::fs::remove_dir(&mount_path) {
+				output::warning(format_args!("failed to remove {mount_path}: {error}"));
+			}
+		}
+	}
+
+	// Sessions of dead processes are pruned whenever the state is loaded, so only mounts need checking.
+	let mut stale_entries = Vec::new();
+	for (disk_name, entry) in state::load()? {
+		if !entry.managed {
+			continue;
+		}
+		let is_mounted = match config.disk(&disk_name) {
+			Ok(disk) => !crate::mount_points(disk)?.is_empty(),
+			Err(_) => false,
+		};
+		if !is_mounted {
+			stale_entries.push(disk_name);
+		}
+	}
+	for disk_name in &stale_entries {
+		eprintln!("{verb}forget that d mounted {disk_name}, since it is no longer mounted.");
+		num_actions += 1;
+	}
+	if !dry_run && !stale_entries.is_empty() {
+		state::update(|state| {
+			for disk_name in &stale_entries {
+				if let Some(entry) = state.get_mut(disk_name) {
+					entry.managed = false;
+				}
+			}
+		})?;
+	}
+
+	if num_actions == 0 {
+		eprintln!("nothing to clean up.");
+	}
+	Ok(())
+}
@@ -0,0 +1,197 @@
+//! Copying whole disks to image files or other devices, for full backups before risky operations.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read as _, Seek as _, SeekFrom, Write as _};
+use std::os::unix::fs::{FileTypeExt as _, OpenOptionsExt as _};
+use std::os::unix::io::AsRawFd as _;
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use anyhow::{ensure, Context as _, Result};
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use nix::libc;
+
+use crate::{output, progress, prompt};
+
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// Checksums data as it is copied, with `sha256sum`, so that the disk only has to be read once.
+struct Hasher {
+	child: Child,
+	stdin: ChildStdin,
+}
+
+impl Hasher {
+	fn new() -> Result<Self> {
+		let mut child = Command::new("sha256sum")
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()
+			.context("running sha256sum")?;
+		let stdin = child.stdin.take().expect("stdin is piped");
+		Ok(Self { child, stdin })
+	}
+
+	fn update(&mut self, data: &[u8]) -> Result<()> {
+		self.stdin.write_all(data).context("writing to sha256sum")
+	}
+
+	fn finish(self) -> Result<String> {
+		drop(self.stdin);
+		let output = self
+			.child
+			.wait_with_output()
+			.context("waiting for sha256sum")?;
+		ensure!(
+			output.status.success(),
+			"sha256sum exited with status {:?}",
+			output.status.code()
+		);
+		let checksum = String::from_utf8_lossy(&output.stdout)
+			.split_whitespace()
+			.next()
+			.context("sha256sum printed nothing")?
+			.to_owned();
+		Ok(checksum)
+	}
+}
+
+/// The size of a file or block device, leaving it positioned at the start.
+fn size_of(file: &mut File) -> Result<u64> {
+	let size = file.seek(SeekFrom::End(0)).context("getting size")?;
+	file.rewind().context("seeking to start")?;
+	Ok(size)
+}
+
+/// The checksum of the first `size` bytes of `file`.
+fn checksum(file: &mut File, size: u64, message: &str) -> Result<String> {
+	let mut hasher = Hasher::new()?;
+	let mut buffer = vec![0; CHUNK_SIZE];
+	let mut done = 0;
+	let mut progress = progress::Transfer::new(message, size);
+	while done < size {
+		let wanted = usize::try_from(size - done).map_or(CHUNK_SIZE, |left| left.min(CHUNK_SIZE));
+		let len = file.read(&mut buffer[..wanted]).context("reading")?;
+		ensure!(len > 0, "ended early, after {done} bytes");
+		hasher.update(&buffer[..len])?;
+		done += len as u64;
+		progress.update(done);
+	}
+	progress.finish();
+	hasher.finish()
+}
+
+/// Open the target for writing, after making sure that the user wants to overwrite it if it exists. Returns whether it is a block device.
+fn open_target(target: &Path, source: &Path) -> Result<(File, bool)> {
+	let is_device = match std::fs::metadata(target) {
+		Ok(metadata) if metadata.file_type().is_block_device() => {
+			ensure!(
+				std::fs::canonicalize(target).context("resolving target")? != source,
+				"the target is the disk itself"
+			);
+			output::warning(format_args!(
+				"cloning overwrites everything on {}.",
+				target.display()
+			));
+			prompt::confirm_typed(&target.display().to_string())?;
+			true
+		}
+		Ok(metadata) => {
+			ensure!(
+				metadata.is_file(),
+				"{} is neither a file nor a block device",
+				target.display()
+			);
+			prompt::confirm_risky(&format!("overwrite {}", target.display()))?;
+			false
+		}
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => false,
+		Err(error) => return Err(error).context("checking target"),
+	};
+	let file = if is_device {
+		// Opening a block device exclusively fails if it is mounted or otherwise in use.
+		OpenOptions::new()
+			.write(true)
+			.custom_flags(libc::O_EXCL)
+			.open(target)
+	} else {
+		File::create(target)
+	}
+	.with_context(|| format!("opening {}", target.display()))?;
+	Ok((file, is_device))
+}
+
+/// Copy all of `source`, a block device, to `target`, an image file or another block device, and print its checksum.
+///
+/// Runs of zeros are left as holes in image files. With `verify`, the copy is read back from the target and compared.
+pub fn run(source: &Path, target: &Path, verify: bool) -> Result<()> {
+	let mut source_file =
+		File::open(source).with_context(|| format!("opening {}", source.display()))?;
+	let size = size_of(&mut source_file)?;
+	let (mut target_file, is_device) = open_target(target, source)?;
+	if is_device {
+		let target_size = size_of(&mut target_file)?;
+		ensure!(
+			target_size >= size,
+			"{} ({}) is smaller than the disk ({})",
+			target.display(),
+			output::format_size(target_size),
+			output::format_size(size)
+		);
+	}
+
+	let mut hasher = Hasher::new()?;
+	let mut buffer = vec![0; CHUNK_SIZE];
+	let mut done = 0;
+	let mut progress = progress::Transfer::new("cloning", size);
+	while done < size {
+		let len = source_file.read(&mut buffer).context("reading disk")?;
+		ensure!(len > 0, "the disk ended early, after {done} bytes");
+		let chunk = &buffer[..len];
+		hasher.update(chunk)?;
+		// A device may have old data where the disk has zeros, but a new image file reads as zeros wherever nothing was written.
+		if !is_device && chunk.iter().all(|&byte| byte == 0) {
+			target_file
+				.seek(SeekFrom::Current(
+					i64::try_from(len).expect("chunks are small"),
+				))
+				.context("skipping zeros in image")?;
+		} else {
+			target_file.write_all(chunk).context("writing copy")?;
+		}
+		done += len as u64;
+		progress.update(done);
+	}
+	progress.finish();
+	if !is_device {
+		// Zeros at the end were skipped, so the file may not have reached its full size yet.
+		target_file.set_len(size).context("extending image")?;
+	}
+	target_file.sync_all().context("flushing copy")?;
+	let source_checksum = hasher.finish()?;
+	eprintln!(
+		"cloned {} to {}. its SHA-256 checksum is {source_checksum}.",
+		output::format_size(size),
+		target.display()
+	);
+
+	if verify {
+		// Drop the copy from the page cache, so that it is actually read back from the target.
+		posix_fadvise(
+			target_file.as_raw_fd(),
+			0,
+			0,
+			PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+		)
+		.context("dropping cached copy")?;
+		let mut target_file =
+			File::open(target).with_context(|| format!("opening {}", target.display()))?;
+		let target_checksum = checksum(&mut target_file, size, "verifying")?;
+		ensure!(
+			target_checksum == source_checksum,
+			"the copy does not match the disk! its checksum is {target_checksum}"
+		);
+		eprintln!("verified the copy.");
+	}
+	Ok(())
+}
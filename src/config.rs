@@ -0,0 +1,1958 @@
+//! The configuration file, which describes the disks that `d` manages.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+
+use crate::privilege::Elevator;
+use crate::secret::{self, Secret};
+
+pub const CONFIG_DIR: &str = "/etc/d";
+pub const CONFIG_PATH: &str = "/etc/d/config.toml";
+
+/// The version of the config format that this version of `d` understands.
+///
+/// Files written before the `version` field existed are version 1.
+pub const CURRENT_VERSION: u32 = 1;
+
+/// Each migration upgrades a config from version `index + 1` to the next version, operating on the raw TOML so that it can restructure anything.
+const MIGRATIONS: &[fn(&mut toml::value::Table) -> Result<()>] = &[];
+
+const _: () = assert!(
+	MIGRATIONS.len() + 1 == CURRENT_VERSION as usize,
+	"there must be a migration for every version"
+);
+
+fn default_version() -> u32 {
+	CURRENT_VERSION
+}
+
+fn default_filesystem() -> String {
+	"ext4".to_owned()
+}
+
+fn default_min_free_percent() -> f64 {
+	5.0
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+	/// Handled by `migrate` before deserializing, but declared so that it isn't an unknown field.
+	#[allow(dead_code)]
+	#[serde(default = "default_version")]
+	pub version: u32,
+	/// Handled by `add_includes` before deserializing, but declared so that it isn't an unknown field.
+	#[allow(dead_code)]
+	#[serde(default)]
+	pub include: Vec<String>,
+	#[serde(default)]
+	pub secrets: secret::Settings,
+	/// How to become root when run by a normal user, if `d` is not installed setuid.
+	#[serde(default)]
+	pub elevate_with: Elevator,
+	/// A program to ask for passphrases with when there is no terminal, such as `pinentry-gnome3`. See `crate::askpass`.
+	#[serde(default)]
+	pub askpass: Option<String>,
+	/// Users who may mount and unmount disks through `d helper` without being root.
+	#[serde(default)]
+	pub helper_users: Vec<String>,
+	/// Where `d helper` serves Prometheus metrics, such as `127.0.0.1:9583`.
+	#[serde(default)]
+	pub metrics_address: Option<String>,
+	/// Whether `d helper` offers the `org.mattfbacon.d` service on the system bus.
+	#[serde(default)]
+	pub dbus_service: bool,
+	/// Have `d helper` lock encrypted disks that have been open without being mounted for this long.
+	#[serde(default)]
+	pub lock_idle_after_minutes: Option<u32>,
+	/// After a `c` session, ask whether to unmount, and unmount if there is no answer within this many seconds.
+	#[serde(default)]
+	pub ask_before_unmount_seconds: Option<u32>,
+	/// How long to wait for udev to create a device's by-UUID symlink, such as right after it is attached or unlocked. 0 doesn't wait.
+	#[serde(default)]
+	pub resolution_timeout_seconds: Option<u32>,
+	/// What to do when a disk is busy while unmounting.
+	#[serde(default)]
+	pub unmount: crate::unmount::Policy,
+	/// Close LUKS mappings when unmounting even if they were already open when `d` mounted the disk.
+	#[serde(default)]
+	pub close_external_mappings: bool,
+	/// How to keep shells from saving history in sessions on encrypted disks, keyed by the file name of the shell's program. These replace the built-in profiles for fish, zsh, and bash.
+	#[serde(default, rename = "shell")]
+	pub shells: BTreeMap<String, crate::shell::Profile>,
+	/// Named sets of disks that can be operated on together, by name or shortcut.
+	#[serde(default)]
+	pub groups: BTreeMap<String, Vec<String>>,
+	#[serde(default, rename = "disk")]
+	pub disks: Vec<Disk>,
+	#[serde(default, rename = "composite")]
+	pub composites: Vec<Composite>,
+	#[serde(default, rename = "fuse")]
+	pub fuse_mounts: Vec<Fuse>,
+	#[serde(default, rename = "swap")]
+	pub swaps: Vec<Swap>,
+	/// Which disks belong to each machine, keyed by hostname. See `Config::select_profile`.
+	#[serde(default, rename = "profile")]
+	pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			version: CURRENT_VERSION,
+			include: Vec::new(),
+			secrets: secret::Settings::default(),
+			elevate_with: Elevator::default(),
+			askpass: None,
+			helper_users: Vec::new(),
+			metrics_address: None,
+			dbus_service: false,
+			lock_idle_after_minutes: None,
+			ask_before_unmount_seconds: None,
+			resolution_timeout_seconds: None,
+			unmount: crate::unmount::Policy::default(),
+			close_external_mappings: false,
+			shells: BTreeMap::new(),
+			groups: BTreeMap::new(),
+			disks: Vec::new(),
+			composites: Vec::new(),
+			fuse_mounts: Vec::new(),
+			swaps: Vec::new(),
+			profiles: BTreeMap::new(),
+		}
+	}
+}
+
+/// Disks with this tag are untrusted, as if they had `trust = "untrusted"`.
+pub const UNTRUSTED_TAG: &str = "untrusted";
+
+/// How much to trust what is on a disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trust {
+	#[default]
+	Trusted,
+	/// Possibly malicious, such as a drive that someone else handed over. It is mounted with `noexec` and `nosymfollow`, and `c` sessions on it are sandboxed.
+	Untrusted,
+}
+
+/// Whether the shells of `c` sessions on a disk are kept from saving history.
+///
+/// ```toml
+/// private_session = true
+/// private_session = "auto"
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum PrivateSession {
+	Explicit(bool),
+	/// Private if the disk is encrypted, or for FUSE filesystems, if it is gocryptfs.
+	Auto(Auto),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Auto {
+	Auto,
+}
+
+impl Default for PrivateSession {
+	fn default() -> Self {
+		Self::Auto(Auto::Auto)
+	}
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)] // Independent settings.
+pub struct Disk {
+	pub name: String,
+	pub shortcut: String,
+	/// What the disk is, for people, such as "photo backups in the blue enclosure".
+	#[serde(default)]
+	pub description: Option<String>,
+	/// Labels such as `backup` or `external`, for operating on all disks with a tag at once.
+	#[serde(default)]
+	pub tags: Vec<String>,
+	#[serde(default)]
+	pub trust: Trust,
+	/// UUID of the filesystem.
+	pub uuid: String,
+	/// UUID of the LUKS container, if the disk is encrypted.
+	#[serde(default)]
+	pub luks_uuid: Option<String>,
+	/// Find the attached device by the UUID of its partition instead of by the UUID of its content.
+	#[serde(default)]
+	pub partuuid: Option<String>,
+	/// Find the attached device at this stable path, such as `/dev/disk/by-id/...`, instead of by the UUID of its content.
+	#[serde(default)]
+	pub device: Option<String>,
+	/// Find the attached device as a partition of a physical drive, which stays the same when the partition is reformatted.
+	#[serde(default)]
+	pub drive: Option<Drive>,
+	/// Verify the disk with dm-verity, which makes it read-only.
+	#[serde(default)]
+	pub verity: Option<Verity>,
+	#[serde(default = "default_filesystem")]
+	pub filesystem: String,
+	/// Filesystems to try in order if mounting as `filesystem` fails with EINVAL, such as after reformatting the disk.
+	#[serde(default)]
+	pub fallback_filesystems: Vec<String>,
+	/// Used to unlock the LUKS container instead of prompting. Only encrypted passphrases are allowed, since the config is readable by everyone.
+	#[serde(default)]
+	pub passphrase: Option<Secret>,
+	/// The path of a key file used to unlock the LUKS container instead of prompting.
+	#[serde(default)]
+	pub keyfile: Option<Secret>,
+	/// A shell command, run as the invoking user, whose first line of output is the passphrase. For example, `pass show disks/foo`.
+	#[serde(default)]
+	pub passphrase_command: Option<String>,
+	/// Look up the passphrase in the desktop keyring (Secret Service) before prompting.
+	#[serde(default)]
+	pub keyring: bool,
+	/// Warn when less than this percentage of the filesystem is free, after mounting and during `c` sessions. Set to 0 to disable.
+	#[serde(default = "default_min_free_percent")]
+	pub min_free_percent: f64,
+	/// Mount read-only if less than `min_free_percent` is free.
+	#[serde(default)]
+	pub read_only_when_low: bool,
+	/// During `c` sessions, remount read-only if the kernel reports errors on the disk.
+	#[serde(default)]
+	pub read_only_on_errors: bool,
+	/// systemd units to start after mounting the disk and stop before unmounting it, such as services that serve files from it.
+	#[serde(default)]
+	pub units: Vec<String>,
+	/// Shell commands run as the invoking user in the mount path when a `c` session starts, after mounting and before the shell, unless an earlier session already ran them and they haven't been torn down.
+	#[serde(default)]
+	pub session_setup: Vec<String>,
+	/// Shell commands run like `session_setup` when a `c` session ends, before unmounting, to undo what it did.
+	#[serde(default)]
+	pub session_teardown: Vec<String>,
+	/// During `c` sessions, sync the filesystem this often, so that losing power or the connection loses less.
+	#[serde(default)]
+	pub sync_every_minutes: Option<u32>,
+	/// During `c` sessions, limit the subshell's reads and writes to the disk to this many MiB per second each, so that big copies don't starve the rest of the system.
+	#[serde(default)]
+	pub io_limit_mib_per_sec: Option<u32>,
+	/// Check the filesystem before mounting if it has been mounted this many times since the last check.
+	#[serde(default)]
+	pub check_every_mounts: Option<u64>,
+	/// Check the filesystem before mounting if it has been this many days since the last check.
+	#[serde(default)]
+	pub check_every_days: Option<u64>,
+	/// Disks, by name or shortcut, that must be mounted before this one and unmounted after it.
+	#[serde(default)]
+	pub depends_on: Vec<String>,
+	/// Whether the shells of `c` sessions on the disk are kept from saving history.
+	#[serde(default)]
+	pub private_session: PrivateSession,
+	/// For encrypted disks, what to do if the system hibernates while the disk is open.
+	#[serde(default)]
+	pub on_hibernate: crate::hibernate::Policy,
+	/// Spin down the drive right after unmounting, if it is rotational.
+	#[serde(default)]
+	pub spin_down: bool,
+	/// When mounting a rotational drive, set its standby timer so that it spins down after being idle this long.
+	#[serde(default)]
+	pub standby_after_minutes: Option<u32>,
+	/// A file whose checksum is verified after mounting.
+	#[serde(default)]
+	pub canary: Option<Canary>,
+	/// How `d backup` backs up the disk.
+	#[serde(default)]
+	pub backup: Option<Backup>,
+	/// Run the backup whenever the disk is unmounted by `d u` or at the end of a `c` session.
+	#[serde(default)]
+	pub backup_on_unmount: bool,
+	/// Directories that `d sync` copies to or from the disk.
+	#[serde(default)]
+	pub rsync: Vec<RsyncJob>,
+	/// When to take btrfs snapshots of the disk and how many to keep.
+	#[serde(default)]
+	pub snapshots: Option<Snapshots>,
+	/// Which quotas to enable when mounting, on ext4 and xfs.
+	#[serde(default)]
+	pub quota: Vec<crate::quota::Kind>,
+	/// Mount options for ext4, such as how often to commit the journal.
+	#[serde(default)]
+	pub ext4: Option<crate::ext4::Tuning>,
+	/// Mount options for btrfs, such as compression.
+	#[serde(default)]
+	pub btrfs: Option<crate::btrfs::Tuning>,
+	/// The SELinux context of everything on the disk, overriding the labels stored on it. Only used if SELinux is enabled.
+	#[serde(default)]
+	pub context: Option<String>,
+	/// The SELinux context of the filesystem itself. Only used if SELinux is enabled.
+	#[serde(default)]
+	pub fscontext: Option<String>,
+}
+
+/// A partition of a physical drive identified by its serial number or World Wide Name, as `lsblk -o NAME,SERIAL,WWN` shows.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Drive {
+	#[serde(default)]
+	pub serial: Option<String>,
+	#[serde(default)]
+	pub wwn: Option<String>,
+	/// The partition number, starting at 1.
+	pub partition: u32,
+}
+
+/// The hash device and root hash of a disk verified with dm-verity. See `crate::verity`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Verity {
+	/// UUID of the device with the hash tree, as printed by `veritysetup format`.
+	pub hash_uuid: String,
+	/// The root hash printed by `veritysetup format`, in hex.
+	pub root_hash: String,
+}
+
+/// A file on a disk with a known checksum. See `crate::canary`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Canary {
+	/// Relative to the root of the disk.
+	pub path: String,
+	/// The SHA-256 checksum of the file, in hex, as printed by `sha256sum`.
+	pub sha256: String,
+}
+
+/// Several disks mounted together under one mount path, such as one disk at `/mnt/media` and another at `/mnt/media/archive`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Composite {
+	pub name: String,
+	pub shortcut: String,
+	/// The first member is mounted at the composite's mount path, and the rest at their paths within it.
+	pub members: Vec<CompositeMember>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompositeMember {
+	/// The name or shortcut of the disk.
+	pub disk: String,
+	/// Relative to the composite's mount path. Empty for the first member.
+	#[serde(default)]
+	pub path: String,
+}
+
+/// A filesystem mounted with a FUSE program by the invoking user, without any privileges. See `crate::fuse`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fuse {
+	pub name: String,
+	pub shortcut: String,
+	pub kind: crate::fuse::Kind,
+	/// What to mount, as the program expects it: `host:path` for sshfs, `remote:path` for rclone, or the encrypted directory for gocryptfs.
+	pub source: String,
+	/// Extra arguments for the program, such as `["-o", "reconnect"]`.
+	#[serde(default)]
+	pub options: Vec<String>,
+	/// A `host:port` to connect to before mounting, to fail quickly if the server is unreachable. sshfs servers are checked without it, using the SSH config.
+	#[serde(default)]
+	pub check_address: Option<String>,
+	/// Whether the shells of `c` sessions on the filesystem are kept from saving history.
+	#[serde(default)]
+	pub private_session: PrivateSession,
+}
+
+impl Fuse {
+	pub fn has_private_sessions(&self) -> bool {
+		match self.private_session {
+			PrivateSession::Explicit(private) => private,
+			PrivateSession::Auto(Auto::Auto) => matches!(self.kind, crate::fuse::Kind::Gocryptfs),
+		}
+	}
+}
+
+/// A swap device, activated by `d m` and deactivated by `d u`. See `crate::swap`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Swap {
+	pub name: String,
+	pub shortcut: String,
+	/// A stable path to the device, such as `/dev/disk/by-partuuid/...`, since encrypted swap has no UUID of its own.
+	pub device: String,
+	/// Encrypt the swap with a new random key each time it is activated, so that nothing swapped out survives deactivation.
+	#[serde(default)]
+	pub encrypt: bool,
+	/// The priority passed to `swapon`. Higher priority swap is used first.
+	#[serde(default)]
+	pub priority: Option<i32>,
+}
+
+/// The disks of one machine, so that a single config file can be shared between machines.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+	/// Disks, composites, FUSE filesystems, and swap devices, by name or shortcut. Dependencies are included automatically.
+	pub disks: Vec<String>,
+}
+
+/// The profile chosen with `--profile`, which overrides the one matching the hostname.
+static PROFILE_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+pub fn set_profile(name: String) {
+	// Only set once, from the command line.
+	let _ = PROFILE_OVERRIDE.set(name);
+}
+
+/// A backup of a disk with borg or restic. See `crate::backup`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+	pub tool: crate::backup::Tool,
+	/// The repository to back up to, as the tool expects it.
+	pub repository: String,
+	/// What to back up, relative to the root of the disk. Everything by default.
+	#[serde(default)]
+	pub paths: Vec<String>,
+	/// Extra arguments for `borg create` or `restic backup`, such as `["--exclude", "cache"]`.
+	#[serde(default)]
+	pub options: Vec<String>,
+	/// Environment variables for the tool, such as `BORG_PASSCOMMAND` or `RESTIC_PASSWORD_FILE`.
+	#[serde(default)]
+	pub env: BTreeMap<String, String>,
+	/// Mount the disk read-only for the backup, if `d backup` mounts it.
+	#[serde(default)]
+	pub read_only: bool,
+}
+
+/// A directory copied to or from a disk with rsync by `d sync`. See `crate::rsync`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RsyncJob {
+	/// Selects the job on the command line.
+	pub name: String,
+	/// The absolute path of the directory on this computer.
+	pub local: String,
+	/// The directory on the disk, relative to its root. The root itself by default.
+	#[serde(default)]
+	pub path: Option<String>,
+	#[serde(default)]
+	pub direction: crate::rsync::Direction,
+	/// Extra arguments for rsync, such as `["--delete"]`.
+	#[serde(default)]
+	pub options: Vec<String>,
+}
+
+/// When to take snapshots of a btrfs disk, and which to keep when pruning. See `crate::snapshot`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshots {
+	/// Take a snapshot right after mounting.
+	#[serde(default)]
+	pub on_mount: bool,
+	/// Take a snapshot right before unmounting with `d u` or at the end of a `c` session.
+	#[serde(default)]
+	pub on_unmount: bool,
+	/// Keep this many of the most recent snapshots.
+	#[serde(default)]
+	pub keep_last: Option<u32>,
+	/// Keep the most recent snapshot of each of this many days.
+	#[serde(default)]
+	pub keep_daily: Option<u32>,
+	/// Keep the most recent snapshot of each of this many weeks.
+	#[serde(default)]
+	pub keep_weekly: Option<u32>,
+}
+
+impl Snapshots {
+	/// Whether any snapshots should be pruned. Without a retention policy, all of them are kept.
+	pub fn has_retention(&self) -> bool {
+		self.keep_last.is_some() || self.keep_daily.is_some() || self.keep_weekly.is_some()
+	}
+}
+
+impl Composite {
+	fn validate(&self) -> Result<()> {
+		validate_name(&self.name).context("invalid name")?;
+		validate_name(&self.shortcut).context("invalid shortcut")?;
+		let Some((root, rest)) = self.members.split_first() else {
+			bail!("must have at least one member");
+		};
+		ensure!(
+			root.path.is_empty(),
+			"the first member ({}) must not have a path, since it is mounted at the root",
+			root.disk
+		);
+		let mut paths = HashSet::new();
+		for member in rest {
+			ensure!(
+				is_simple_relative_path(&member.path),
+				"the path of {} ({:?}) must be a non-empty relative path without `.` or `..`",
+				member.disk,
+				member.path
+			);
+			ensure!(
+				paths.insert(Path::new(&member.path)),
+				"duplicate path {:?}",
+				member.path
+			);
+		}
+		Ok(())
+	}
+}
+
+pub enum Mountable<'a> {
+	Plain {
+		uuid: &'a str,
+	},
+	Encrypted {
+		outer_uuid: &'a str,
+		inner_uuid: &'a str,
+	},
+	/// The filesystem is on the data device, and is mounted from the verity mapping on top of it.
+	Verity {
+		data_uuid: &'a str,
+		hash_uuid: &'a str,
+		root_hash: &'a str,
+	},
+}
+
+impl Disk {
+	pub fn as_repr(&self) -> &str {
+		&self.name
+	}
+
+	pub fn shortcut(&self) -> &str {
+		&self.shortcut
+	}
+
+	pub fn inner_filesystem(&self) -> &str {
+		&self.filesystem
+	}
+
+	pub fn to_mountable(&self) -> Mountable<'_> {
+		match (&self.luks_uuid, &self.verity) {
+			(Some(luks_uuid), _) => Mountable::Encrypted {
+				outer_uuid: luks_uuid,
+				inner_uuid: &self.uuid,
+			},
+			(None, Some(verity)) => Mountable::Verity {
+				data_uuid: &self.uuid,
+				hash_uuid: &verity.hash_uuid,
+				root_hash: &verity.root_hash,
+			},
+			(None, None) => Mountable::Plain { uuid: &self.uuid },
+		}
+	}
+
+	pub fn is_untrusted(&self) -> bool {
+		self.trust == Trust::Untrusted || self.tags.iter().any(|tag| tag == UNTRUSTED_TAG)
+	}
+
+	pub fn has_private_sessions(&self) -> bool {
+		match self.private_session {
+			PrivateSession::Explicit(private) => private,
+			PrivateSession::Auto(Auto::Auto) => self.is_encrypted(),
+		}
+	}
+
+	pub fn is_encrypted(&self) -> bool {
+		match self.to_mountable() {
+			Mountable::Plain { .. } | Mountable::Verity { .. } => false,
+			Mountable::Encrypted { .. } => true,
+		}
+	}
+
+	/// Check how the disk's devices are found.
+	fn validate_devices(&self) -> Result<()> {
+		validate_uuid(&self.uuid).context("invalid uuid")?;
+		if let Some(luks_uuid) = &self.luks_uuid {
+			validate_uuid(luks_uuid).context("invalid luks_uuid")?;
+		}
+		if let Some(partuuid) = &self.partuuid {
+			validate_uuid(partuuid).context("invalid partuuid")?;
+		}
+		ensure!(
+			usize::from(self.partuuid.is_some())
+				+ usize::from(self.device.is_some())
+				+ usize::from(self.drive.is_some())
+				<= 1,
+			"at most one of partuuid, device, and drive can be set"
+		);
+		if let Some(drive) = &self.drive {
+			ensure!(
+				drive.serial.is_some() != drive.wwn.is_some(),
+				"the drive must have exactly one of serial and wwn"
+			);
+			ensure!(drive.partition >= 1, "drive partition numbers start at 1");
+		}
+		if let Some(device) = &self.device {
+			ensure!(
+				device.starts_with("/dev/"),
+				"device ({device:?}) must be a path in /dev"
+			);
+		}
+		if let Some(verity) = &self.verity {
+			ensure!(
+				self.luks_uuid.is_none(),
+				"verity can't be combined with luks_uuid"
+			);
+			validate_uuid(&verity.hash_uuid).context("invalid verity hash_uuid")?;
+			ensure!(
+				!verity.root_hash.is_empty() && verity.root_hash.chars().all(|ch| ch.is_ascii_hexdigit()),
+				"the verity root_hash must be in hex, as printed by `veritysetup format`"
+			);
+			ensure!(
+				self.snapshots.is_none()
+					&& self.quota.is_empty()
+					&& !self.read_only_when_low
+					&& !self.read_only_on_errors,
+				"snapshots, quota, read_only_when_low, and read_only_on_errors don't apply to verity disks, which are always read-only"
+			);
+		}
+		Ok(())
+	}
+
+	/// Options that only some filesystems support.
+	fn validate_filesystem_features(&self) -> Result<()> {
+		ensure!(
+			self.quota.is_empty() || matches!(self.filesystem.as_str(), "ext4" | "xfs"),
+			"quotas are only supported on ext4 and xfs"
+		);
+		ensure!(
+			self.snapshots.is_none() || self.filesystem == "btrfs",
+			"snapshots are only supported for btrfs"
+		);
+		if let Some(tuning) = &self.ext4 {
+			tuning
+				.validate(&self.filesystem)
+				.context("invalid ext4 options")?;
+		}
+		if let Some(tuning) = &self.btrfs {
+			tuning
+				.validate(&self.filesystem)
+				.context("invalid btrfs options")?;
+		}
+		Ok(())
+	}
+
+	#[allow(clippy::too_many_lines)] // A flat list of independent checks.
+	fn validate(&self) -> Result<()> {
+		validate_name(&self.name).context("invalid name")?;
+		validate_name(&self.shortcut).context("invalid shortcut")?;
+		for tag in &self.tags {
+			validate_name(tag).with_context(|| format!("invalid tag {tag:?}"))?;
+		}
+		self.validate_devices()?;
+		let num_keys = usize::from(self.passphrase.is_some())
+			+ usize::from(self.keyfile.is_some())
+			+ usize::from(self.passphrase_command.is_some());
+		ensure!(
+			(num_keys == 0 && !self.keyring) || self.luks_uuid.is_some(),
+			"passphrase, keyfile, passphrase_command, and keyring can only be used with encrypted disks (those with luks_uuid)"
+		);
+		ensure!(
+			(0.0..=100.0).contains(&self.min_free_percent),
+			"min_free_percent must be between 0 and 100"
+		);
+		ensure!(
+			num_keys <= 1,
+			"at most one of passphrase, keyfile, and passphrase_command can be set"
+		);
+		// The config is readable by every user.
+		ensure!(
+			!matches!(self.passphrase, Some(Secret::Plain(_))),
+			"a passphrase in plain text would be readable by every user. encrypt it with age or gpg, or put it in a key file that only root can read and set keyfile to its path"
+		);
+		if let Some(canary) = &self.canary {
+			ensure!(
+				is_simple_relative_path(&canary.path),
+				"the canary path ({:?}) must be a non-empty relative path without `.` or `..`",
+				canary.path
+			);
+			ensure!(
+				canary.sha256.len() == 64 && canary.sha256.chars().all(|ch| ch.is_ascii_hexdigit()),
+				"the canary checksum must be a SHA-256 checksum in hex, as printed by `sha256sum`"
+			);
+		}
+		if let Some(backup) = &self.backup {
+			for path in &backup.paths {
+				ensure!(
+					is_simple_relative_path(path),
+					"the backup path {path:?} must be a non-empty relative path without `.` or `..`"
+				);
+			}
+		}
+		for (i, job) in self.rsync.iter().enumerate() {
+			validate_name(&job.name).with_context(|| format!("invalid rsync job name {:?}", job.name))?;
+			ensure!(
+				self.rsync[..i].iter().all(|other| other.name != job.name),
+				"there are several rsync jobs named {:?}",
+				job.name
+			);
+			ensure!(
+				Path::new(&job.local).is_absolute(),
+				"the local path of rsync job {:?} must be absolute",
+				job.name
+			);
+			if let Some(path) = &job.path {
+				ensure!(
+					is_simple_relative_path(path),
+					"the path of rsync job {:?} must be a non-empty relative path without `.` or `..`",
+					job.name
+				);
+			}
+		}
+		ensure!(
+			!self.backup_on_unmount || self.backup.is_some(),
+			"backup_on_unmount requires a backup to be configured"
+		);
+		for context in [&self.context, &self.fscontext].into_iter().flatten() {
+			ensure!(
+				context.split(':').count() >= 3 && !context.contains(['"', ' ']),
+				"{context:?} is not a valid SELinux context, such as `system_u:object_r:removable_t:s0`"
+			);
+		}
+		for filesystem in &self.fallback_filesystems {
+			ensure!(
+				*filesystem != self.filesystem
+					&& !filesystem.is_empty()
+					&& !filesystem.contains(['/', ',']),
+				"invalid fallback filesystem {filesystem:?}"
+			);
+		}
+		self.validate_filesystem_features()?;
+		for unit in &self.units {
+			// Unit names always have a type suffix, such as `.service`.
+			ensure!(
+				unit.contains('.') && !unit.contains('/'),
+				"invalid unit name {unit:?}"
+			);
+		}
+		ensure!(
+			self.sync_every_minutes != Some(0),
+			"sync_every_minutes must be at least 1"
+		);
+		ensure!(
+			self.io_limit_mib_per_sec != Some(0),
+			"io_limit_mib_per_sec must be at least 1"
+		);
+		if let Some(minutes) = self.standby_after_minutes {
+			ensure!(
+				(1..=crate::power::MAX_STANDBY_MINUTES).contains(&minutes),
+				"standby_after_minutes must be between 1 and {}",
+				crate::power::MAX_STANDBY_MINUTES
+			);
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown disk {target:?}. {hint}")]
+pub struct UnknownDisk {
+	target: String,
+	/// A suggestion if the target looks like a typo, followed by the configured disks.
+	hint: String,
+}
+
+/// The number of single-character insertions, deletions, and substitutions needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut previous: Vec<usize> = (0..=b.len()).collect();
+	for (i, a_ch) in a.chars().enumerate() {
+		let mut current = vec![i + 1];
+		for (j, &b_ch) in b.iter().enumerate() {
+			let substitution = previous[j] + usize::from(a_ch != b_ch);
+			current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+		}
+		previous = current;
+	}
+	previous[b.len()]
+}
+
+/// A target that refers to every configured disk.
+pub const ALL_TARGET: &str = "all";
+
+impl Config {
+	/// Find a disk by its shortcut or its name.
+	pub fn disk(&self, name_or_shortcut: &str) -> Result<&Disk, UnknownDisk> {
+		self
+			.disks
+			.iter()
+			.find(|disk| disk.shortcut == name_or_shortcut)
+			.or_else(|| self.disks.iter().find(|disk| disk.name == name_or_shortcut))
+			.ok_or_else(|| self.unknown_disk(name_or_shortcut))
+	}
+
+	fn unknown_disk(&self, target: &str) -> UnknownDisk {
+		let describe = |disk: &Disk| format!("`{}` ({})", disk.shortcut, disk.name);
+
+		let closest = self
+			.disks
+			.iter()
+			.map(|disk| {
+				let distance = edit_distance(target, &disk.shortcut).min(edit_distance(target, &disk.name));
+				(distance, disk)
+			})
+			.min_by_key(|&(distance, _)| distance);
+		// Allow more typos in longer targets, but not so many that anything matches.
+		let max_distance = (target.chars().count() / 3).max(1);
+		let mut hint = match closest {
+			Some((distance, disk)) if distance <= max_distance => {
+				format!("did you mean {}? ", describe(disk))
+			}
+			_ => String::new(),
+		};
+
+		if self.disks.is_empty() {
+			hint.push_str("no disks are configured; add one with `d add`.");
+		} else {
+			let known: Vec<String> = self.disks.iter().map(describe).collect();
+			hint = format!("{hint}configured disks: {}.", known.join(", "));
+		}
+
+		UnknownDisk {
+			target: target.to_owned(),
+			hint,
+		}
+	}
+
+	/// Find a composite by its shortcut or its name.
+	fn composite(&self, name_or_shortcut: &str) -> Option<&Composite> {
+		self
+			.composites
+			.iter()
+			.find(|composite| composite.shortcut == name_or_shortcut)
+			.or_else(|| {
+				self
+					.composites
+					.iter()
+					.find(|composite| composite.name == name_or_shortcut)
+			})
+	}
+
+	/// Find a FUSE filesystem by its shortcut or its name.
+	pub fn fuse(&self, name_or_shortcut: &str) -> Option<&Fuse> {
+		self
+			.fuse_mounts
+			.iter()
+			.find(|fuse| fuse.shortcut == name_or_shortcut)
+			.or_else(|| {
+				self
+					.fuse_mounts
+					.iter()
+					.find(|fuse| fuse.name == name_or_shortcut)
+			})
+	}
+
+	/// Find a swap device by its shortcut or its name.
+	pub fn swap(&self, name_or_shortcut: &str) -> Option<&Swap> {
+		self
+			.swaps
+			.iter()
+			.find(|swap| swap.shortcut == name_or_shortcut)
+			.or_else(|| self.swaps.iter().find(|swap| swap.name == name_or_shortcut))
+	}
+
+	/// The names of the disks with a tag.
+	pub fn tagged(&self, tag: &str) -> Result<Vec<String>> {
+		let names: Vec<String> = self
+			.disks
+			.iter()
+			.filter(|disk| disk.tags.iter().any(|candidate| candidate == tag))
+			.map(|disk| disk.name.clone())
+			.collect();
+		ensure!(!names.is_empty(), "no disks are tagged {tag:?}");
+		Ok(names)
+	}
+
+	/// Separate targets that name FUSE filesystems, or groups' FUSE members, from the rest, which are left for `resolve_targets`.
+	pub fn split_fuse_targets(&self, targets: &[String]) -> (Vec<&Fuse>, Vec<String>) {
+		let mut fuse_mounts = Vec::new();
+		let mut rest = Vec::new();
+		for target in targets {
+			if let Some(fuse) = self.fuse(target) {
+				fuse_mounts.push(fuse);
+				continue;
+			}
+			if let Some(members) = self.groups.get(target) {
+				let fuse_members: Vec<&Fuse> = members
+					.iter()
+					.filter_map(|member| self.fuse(member))
+					.collect();
+				// Groups of only FUSE filesystems leave nothing for `resolve_targets`.
+				let has_disks = fuse_members.len() < members.len();
+				fuse_mounts.extend(fuse_members);
+				if !has_disks {
+					continue;
+				}
+			}
+			rest.push(target.clone());
+		}
+		(fuse_mounts, rest)
+	}
+
+	/// Separate targets that name swap devices from the rest.
+	pub fn split_swap_targets(&self, targets: &[String]) -> (Vec<&Swap>, Vec<String>) {
+		let mut swaps = Vec::new();
+		let mut rest = Vec::new();
+		for target in targets {
+			match self.swap(target) {
+				Some(swap) => swaps.push(swap),
+				None => rest.push(target.clone()),
+			}
+		}
+		(swaps, rest)
+	}
+
+	/// The composite that a disk is part of, along with its membership.
+	pub fn composite_membership(&self, disk: &Disk) -> Option<(&Composite, &CompositeMember)> {
+		self.composites.iter().find_map(|composite| {
+			let member = composite.members.iter().find(|member| {
+				self
+					.disk(&member.disk)
+					.is_ok_and(|candidate| candidate.name == disk.name)
+			})?;
+			Some((composite, member))
+		})
+	}
+
+	/// Disks in a composite are mounted inside it, and other disks at their own mount path.
+	pub fn mount_path(&self, disk: &Disk) -> String {
+		match self.composite_membership(disk) {
+			Some((composite, member)) if !member.path.is_empty() => format!(
+				"{}/{}",
+				crate::mount_path_for_name(&composite.name),
+				member.path
+			),
+			Some((composite, _)) => crate::mount_path_for_name(&composite.name),
+			None => crate::mount_path_for_name(disk.as_repr()),
+		}
+	}
+
+	/// The member of a disk's composite whose mount path most closely encloses the disk's.
+	fn enclosing_member(&self, disk: &Disk) -> Result<Option<&Disk>> {
+		let Some((composite, member)) = self.composite_membership(disk) else {
+			return Ok(None);
+		};
+		let enclosing = composite
+			.members
+			.iter()
+			.filter(|candidate| {
+				candidate.path != member.path && Path::new(&member.path).starts_with(&candidate.path)
+			})
+			.max_by_key(|candidate| Path::new(&candidate.path).components().count());
+		match enclosing {
+			Some(enclosing) => Ok(Some(self.disk(&enclosing.disk)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// The disks that a disk directly depends on, including the disk that its mount path is inside of.
+	pub fn dependencies<'a>(&'a self, disk: &'a Disk) -> Result<Vec<&'a Disk>> {
+		let mut dependencies = disk
+			.depends_on
+			.iter()
+			.map(|dependency| self.disk(dependency))
+			.collect::<Result<Vec<_>, _>>()?;
+		dependencies.extend(self.enclosing_member(disk)?);
+		Ok(dependencies)
+	}
+
+	/// Depth-first search adding `disk` to `ordered` after its dependencies.
+	fn visit_with_dependencies<'a>(
+		&'a self,
+		disk: &'a Disk,
+		ordered: &mut Vec<&'a Disk>,
+		in_progress: &mut Vec<&'a str>,
+	) -> Result<()> {
+		if ordered.iter().any(|existing| existing.name == disk.name) {
+			return Ok(());
+		}
+		if in_progress.contains(&disk.as_repr()) {
+			bail!(
+				"dependency cycle: {} -> {}",
+				in_progress.join(" -> "),
+				disk.name
+			);
+		}
+
+		in_progress.push(disk.as_repr());
+		for dependency in self.dependencies(disk)? {
+			self.visit_with_dependencies(dependency, ordered, in_progress)?;
+		}
+		in_progress.pop();
+
+		ordered.push(disk);
+		Ok(())
+	}
+
+	/// Resolve disk names and shortcuts, group names, composite names and shortcuts, and `all` into disks, including their dependencies.
+	///
+	/// Dependencies come before the disks that depend on them.
+	pub fn resolve_targets(&self, targets: &[String]) -> Result<Vec<&Disk>> {
+		let mut requested = Vec::new();
+		for target in targets {
+			if target == ALL_TARGET {
+				requested.extend(&self.disks);
+			} else if let Some(members) = self.groups.get(target) {
+				// FUSE members are taken out by `split_fuse_targets`.
+				for member in members.iter().filter(|member| self.fuse(member).is_none()) {
+					requested.push(self.disk(member)?);
+				}
+			} else if let Some(composite) = self.composite(target) {
+				for member in &composite.members {
+					requested.push(self.disk(&member.disk)?);
+				}
+			} else {
+				requested.push(self.disk(target)?);
+			}
+		}
+
+		let mut ordered = Vec::new();
+		for disk in requested {
+			self.visit_with_dependencies(disk, &mut ordered, &mut Vec::new())?;
+		}
+		Ok(ordered)
+	}
+
+	/// `locate` describes where the disk at an index is defined, for error messages.
+	#[allow(clippy::too_many_lines)] // Independent checks, one section of the config after another.
+	fn validate(&self, locate: impl Fn(usize) -> String) -> Result<()> {
+		ensure!(
+			self.lock_idle_after_minutes != Some(0),
+			"lock_idle_after_minutes must be at least 1"
+		);
+		ensure!(
+			self.ask_before_unmount_seconds != Some(0),
+			"ask_before_unmount_seconds must be at least 1"
+		);
+		let mut names = HashSet::new();
+		let mut shortcuts = HashSet::new();
+		for (index, disk) in self.disks.iter().enumerate() {
+			let location = || format!("in disk {:?} ({})", disk.name, locate(index));
+			disk.validate().with_context(location)?;
+			ensure!(
+				disk.name != ALL_TARGET && disk.shortcut != ALL_TARGET,
+				"`{ALL_TARGET}` can't be used as a disk name or shortcut ({})",
+				locate(index)
+			);
+			ensure!(
+				names.insert(&disk.name),
+				"duplicate disk name {:?} ({})",
+				disk.name,
+				locate(index)
+			);
+			ensure!(
+				shortcuts.insert(&disk.shortcut),
+				"duplicate shortcut {:?} ({})",
+				disk.shortcut,
+				locate(index)
+			);
+		}
+
+		let mut composite_members = HashSet::new();
+		for composite in &self.composites {
+			let context = || format!("in composite {:?}", composite.name);
+			composite.validate().with_context(context)?;
+			for name in [&composite.name, &composite.shortcut] {
+				ensure!(
+					name != ALL_TARGET && self.disk(name).is_err() && !self.groups.contains_key(name),
+					"composite name or shortcut {name:?} conflicts with a disk, a group, or `{ALL_TARGET}`"
+				);
+			}
+			ensure!(
+				names.insert(&composite.name) && shortcuts.insert(&composite.shortcut),
+				"duplicate composite name or shortcut {:?}",
+				composite.name
+			);
+			for member in &composite.members {
+				let disk = self.disk(&member.disk).with_context(context)?;
+				ensure!(
+					composite_members.insert(&disk.name),
+					"disk {:?} is in more than one composite",
+					disk.name
+				);
+			}
+		}
+
+		for (index, disk) in self.disks.iter().enumerate() {
+			self
+				.visit_with_dependencies(disk, &mut Vec::new(), &mut Vec::new())
+				.with_context(|| {
+					format!(
+						"in the dependencies of disk {:?} ({})",
+						disk.name,
+						locate(index)
+					)
+				})?;
+		}
+
+		for fuse in &self.fuse_mounts {
+			self.validate_other_target(
+				"FUSE filesystem",
+				&fuse.name,
+				&fuse.shortcut,
+				&mut names,
+				&mut shortcuts,
+			)?;
+			if let Some(address) = &fuse.check_address {
+				ensure!(
+					crate::fuse::parse_address(address).is_some(),
+					"check_address of FUSE filesystem {:?} must be like host:port",
+					fuse.name
+				);
+			}
+		}
+		for swap in &self.swaps {
+			self.validate_other_target(
+				"swap",
+				&swap.name,
+				&swap.shortcut,
+				&mut names,
+				&mut shortcuts,
+			)?;
+			ensure!(
+				Path::new(&swap.device).is_absolute(),
+				"the swap device {:?} must be an absolute path",
+				swap.device
+			);
+		}
+
+		self.validate_profiles()?;
+
+		for (group, members) in &self.groups {
+			validate_name(group).with_context(|| format!("invalid group name {group:?}"))?;
+			ensure!(
+				group != ALL_TARGET && self.disk(group).is_err(),
+				"group name {group:?} conflicts with a disk or `{ALL_TARGET}`"
+			);
+			for member in members.iter().filter(|member| self.fuse(member).is_none()) {
+				self
+					.disk(member)
+					.with_context(|| format!("in group {group:?}"))?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Check the name and shortcut of a FUSE filesystem or swap device, which can be used as targets alongside disks.
+	fn validate_other_target<'a>(
+		&self,
+		kind: &str,
+		name: &'a String,
+		shortcut: &'a String,
+		names: &mut HashSet<&'a String>,
+		shortcuts: &mut HashSet<&'a String>,
+	) -> Result<()> {
+		let context = || format!("in {kind} {name:?}");
+		validate_name(name)
+			.context("invalid name")
+			.with_context(context)?;
+		validate_name(shortcut)
+			.context("invalid shortcut")
+			.with_context(context)?;
+		for candidate in [name, shortcut] {
+			ensure!(
+				candidate != ALL_TARGET
+					&& self.disk(candidate).is_err()
+					&& self.composite(candidate).is_none()
+					&& !self.groups.contains_key(candidate),
+				"{kind} name or shortcut {candidate:?} conflicts with a disk, a composite, a group, or `{ALL_TARGET}`"
+			);
+		}
+		ensure!(
+			names.insert(name) && shortcuts.insert(shortcut),
+			"duplicate {kind} name or shortcut {name:?}"
+		);
+		Ok(())
+	}
+
+	fn validate_profiles(&self) -> Result<()> {
+		for (profile, entries) in &self.profiles {
+			for entry in &entries.disks {
+				ensure!(
+					self.disk(entry).is_ok()
+						|| self.composite(entry).is_some()
+						|| self.fuse(entry).is_some()
+						|| self.swap(entry).is_some(),
+					"unknown disk, composite, FUSE filesystem, or swap {entry:?} in profile {profile:?}"
+				);
+			}
+		}
+		Ok(())
+	}
+
+	/// The profile to use: the one chosen with `--profile`, or else the one named after this machine's hostname, if any.
+	fn active_profile(&self) -> Result<Option<&str>> {
+		if let Some(name) = PROFILE_OVERRIDE.get() {
+			let (name, _) = self.profiles.get_key_value(name).with_context(|| {
+				let known = self.profiles.keys().map(String::as_str).collect::<Vec<_>>();
+				format!("unknown profile {name:?}. profiles: {}", known.join(", "))
+			})?;
+			return Ok(Some(name));
+		}
+		let hostname = nix::unistd::gethostname().context("getting hostname")?;
+		let hostname = hostname.to_string_lossy();
+		Ok(
+			self
+				.profiles
+				.get_key_value(&*hostname)
+				.map(|(name, _)| name.as_str()),
+		)
+	}
+
+	/// Keep only the disks, composites, FUSE filesystems, and swap devices in the active profile, along with everything they need.
+	///
+	/// Without a matching profile, everything is kept.
+	fn select_profile(mut self) -> Result<Self> {
+		let Some(profile) = self.active_profile()? else {
+			return Ok(self);
+		};
+		let (fuse_mounts, targets) = self.split_fuse_targets(&self.profiles[profile].disks);
+		let fuse_names: HashSet<String> = fuse_mounts.iter().map(|fuse| fuse.name.clone()).collect();
+		let (swaps, mut targets) = self.split_swap_targets(&targets);
+		let swap_names: HashSet<String> = swaps.iter().map(|swap| swap.name.clone()).collect();
+
+		// Composites are mounted as a whole, so keeping one member keeps the others, which can bring in more dependencies.
+		let kept = loop {
+			let kept: HashSet<String> = self
+				.resolve_targets(&targets)?
+				.iter()
+				.map(|disk| disk.name.clone())
+				.collect();
+			let partial: Vec<String> = self
+				.composites
+				.iter()
+				.filter(|composite| {
+					let is_member_kept = |member: &CompositeMember| {
+						self
+							.disk(&member.disk)
+							.is_ok_and(|disk| kept.contains(&disk.name))
+					};
+					composite.members.iter().any(is_member_kept)
+						&& !composite.members.iter().all(is_member_kept)
+				})
+				.map(|composite| composite.name.clone())
+				.collect();
+			if partial.is_empty() {
+				break kept;
+			}
+			targets.extend(partial);
+		};
+
+		let is_kept = |name: &String| self.disk(name).is_ok_and(|disk| kept.contains(&disk.name));
+		let groups = self
+			.groups
+			.iter()
+			.map(|(group, members)| {
+				let members: Vec<String> = members
+					.iter()
+					.filter(|member| is_kept(member))
+					.cloned()
+					.collect();
+				(group.clone(), members)
+			})
+			.filter(|(_, members)| !members.is_empty())
+			.collect();
+		// Composites are now either wholly kept or not at all, so checking the first member is enough.
+		let composites: HashSet<String> = self
+			.composites
+			.iter()
+			.filter(|composite| is_kept(&composite.members[0].disk))
+			.map(|composite| composite.name.clone())
+			.collect();
+
+		self.groups = groups;
+		self
+			.composites
+			.retain(|composite| composites.contains(&composite.name));
+		self.disks.retain(|disk| kept.contains(&disk.name));
+		self
+			.fuse_mounts
+			.retain(|fuse| fuse_names.contains(&fuse.name));
+		self.swaps.retain(|swap| swap_names.contains(&swap.name));
+		Ok(self)
+	}
+}
+
+/// Whether `path` is relative and only goes down, so that it stays inside whatever it is joined to.
+fn is_simple_relative_path(path: &str) -> bool {
+	!path.is_empty()
+		&& Path::new(path)
+			.components()
+			.all(|component| matches!(component, Component::Normal(..)))
+}
+
+/// Names end up in paths and device-mapper names, so keep them simple.
+pub fn validate_name(name: &str) -> Result<()> {
+	ensure!(!name.is_empty(), "must not be empty");
+	if let Some(bad) = name
+		.chars()
+		.find(|&ch| !(ch.is_ascii_alphanumeric() || ch == '-' || ch == '_'))
+	{
+		bail!("{name:?} contains {bad:?}; only ASCII letters, digits, '-', and '_' are allowed");
+	}
+	Ok(())
+}
+
+/// Accepts the usual hyphenated UUIDs as well as the shorter forms some filesystems use, such as `1234-ABCD` for FAT.
+pub fn validate_uuid(uuid: &str) -> Result<()> {
+	ensure!(!uuid.is_empty(), "must not be empty");
+	let is_valid = uuid
+		.split('-')
+		.all(|group| !group.is_empty() && group.chars().all(|ch| ch.is_ascii_hexdigit()));
+	ensure!(is_valid, "{uuid:?} is not a valid UUID");
+	Ok(())
+}
+
+/// Upgrade a config to the current version in place, returning the version it was originally.
+fn migrate(value: &mut toml::Value) -> Result<u32> {
+	let table = value.as_table_mut().context("config must be a table")?;
+	let version = match table.get("version") {
+		None => 1,
+		Some(version) => version
+			.as_integer()
+			.and_then(|version| u32::try_from(version).ok())
+			.filter(|&version| version >= 1)
+			.context("version must be a positive integer")?,
+	};
+	ensure!(
+		version <= CURRENT_VERSION,
+		"config is version {version}, but this version of d only supports up to version {CURRENT_VERSION}. upgrade d"
+	);
+
+	for (from, migration) in (version..).zip(&MIGRATIONS[(version - 1) as usize..]) {
+		migration(table)
+			.with_context(|| format!("migrating config from version {from} to {}", from + 1))?;
+	}
+	table.insert("version".to_owned(), i64::from(CURRENT_VERSION).into());
+
+	Ok(version)
+}
+
+/// Sections that included files can add to. Arrays of tables are appended to, and tables are merged.
+const INCLUDABLE: &[&str] = &["disk", "composite", "fuse", "swap", "groups", "profile"];
+
+/// Whether `name` matches `pattern`, in which `*` matches any run of characters and `?` matches any one character.
+fn wildcard_matches(pattern: &[char], name: &[char]) -> bool {
+	match pattern.split_first() {
+		None => name.is_empty(),
+		Some(('*', rest)) => (0..=name.len()).any(|skip| wildcard_matches(rest, &name[skip..])),
+		Some((&expected, rest)) => name.split_first().is_some_and(|(&actual, name)| {
+			(expected == '?' || expected == actual) && wildcard_matches(rest, name)
+		}),
+	}
+}
+
+/// The files matching an include pattern, which is relative to the config directory unless absolute.
+///
+/// Wildcards are only supported in the file name, and files are sorted so that they are included in a predictable order.
+fn expand_include(pattern: &str) -> Result<Vec<PathBuf>> {
+	let path = Path::new(CONFIG_DIR).join(pattern);
+	let file_pattern = path
+		.file_name()
+		.and_then(|name| name.to_str())
+		.context("must end in a file name")?;
+	if !file_pattern.contains(['*', '?']) {
+		return Ok(vec![path]);
+	}
+	let directory = path.parent().context("must be in a directory")?;
+	ensure!(
+		!directory.to_string_lossy().contains(['*', '?']),
+		"wildcards are only supported in the file name"
+	);
+
+	let file_pattern: Vec<char> = file_pattern.chars().collect();
+	let entries = match std::fs::read_dir(directory) {
+		Ok(entries) => entries,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(error) => return Err(error).with_context(|| format!("reading {}", directory.display())),
+	};
+	let mut paths = Vec::new();
+	for entry in entries {
+		let entry = entry.with_context(|| format!("reading {}", directory.display()))?;
+		let name: Vec<char> = entry.file_name().to_string_lossy().chars().collect();
+		// Like shells, don't match hidden files such as editor backups with wildcards.
+		if name.first() != Some(&'.') && wildcard_matches(&file_pattern, &name) {
+			paths.push(entry.path());
+		}
+	}
+	paths.sort();
+	Ok(paths)
+}
+
+/// Merge the files listed in `include` into the config, returning whether there were any.
+fn add_includes(table: &mut toml::value::Table) -> Result<bool> {
+	let Some(patterns) = table.remove("include") else {
+		return Ok(false);
+	};
+	let patterns: Vec<String> = patterns
+		.try_into()
+		.context("include must be an array of paths")?;
+
+	for pattern in &patterns {
+		for path in expand_include(pattern).with_context(|| format!("in include {pattern:?}"))? {
+			let context = || format!("in included file {}", path.display());
+			let raw = std::fs::read_to_string(&path)
+				.context("reading file")
+				.with_context(context)?;
+			let included: toml::value::Table = toml::from_str(&raw)
+				.context("parsing")
+				.with_context(context)?;
+			for (key, value) in included {
+				ensure!(
+					INCLUDABLE.contains(&key.as_str()),
+					"{key:?} can't be set in included files ({}); only {} can",
+					path.display(),
+					INCLUDABLE.join(", ")
+				);
+				match (table.get_mut(&key), value) {
+					(None, value) => {
+						table.insert(key, value);
+					}
+					(Some(toml::Value::Array(existing)), toml::Value::Array(added)) => existing.extend(added),
+					(Some(toml::Value::Table(existing)), toml::Value::Table(added)) => {
+						for (name, value) in added {
+							ensure!(
+								!existing.contains_key(&name),
+								"{key}.{name} is defined more than once ({})",
+								path.display()
+							);
+							existing.insert(name, value);
+						}
+					}
+					_ => bail!(
+						"{key:?} has a different type than in the main config ({})",
+						path.display()
+					),
+				}
+			}
+		}
+	}
+	Ok(!patterns.is_empty())
+}
+
+/// Replace `${NAME}` in every string with the value of the environment variable `NAME`, returning whether anything was replaced.
+///
+/// `$${` is a literal `${`.
+fn interpolate(value: &mut toml::Value) -> Result<bool> {
+	match value {
+		toml::Value::String(string) => {
+			if !string.contains("${") {
+				return Ok(false);
+			}
+			*string = interpolate_string(string)?;
+			Ok(true)
+		}
+		toml::Value::Array(array) => array
+			.iter_mut()
+			.try_fold(false, |changed, value| Ok(interpolate(value)? || changed)),
+		toml::Value::Table(table) => table
+			.iter_mut()
+			.map(|(_, value)| value)
+			.try_fold(false, |changed, value| Ok(interpolate(value)? || changed)),
+		_ => Ok(false),
+	}
+}
+
+fn interpolate_string(string: &str) -> Result<String> {
+	// In secure-execution mode (setuid or file capabilities), the environment belongs to the user who ran us, who could use it to change which files are read or which commands are run.
+	// SAFETY: `getauxval` has no preconditions.
+	let is_secure = unsafe { nix::libc::getauxval(nix::libc::AT_SECURE) } != 0;
+
+	let mut result = String::new();
+	let mut rest = string;
+	while let Some(start) = rest.find("${") {
+		if rest[..start].ends_with('$') {
+			result.push_str(&rest[..start - 1]);
+			result.push_str("${");
+			rest = &rest[start + 2..];
+			continue;
+		}
+		result.push_str(&rest[..start]);
+		let end = rest[start..]
+			.find('}')
+			.with_context(|| format!("unterminated ${{ in {string:?}"))?;
+		let name = &rest[start + 2..start + end];
+		ensure!(
+			!is_secure,
+			"environment variables can't be used in the config when d is installed setuid or with capabilities"
+		);
+		let value =
+			std::env::var(name).with_context(|| format!("environment variable {name:?} is not set"))?;
+		result.push_str(&value);
+		rest = &rest[start + end + 1..];
+	}
+	result.push_str(rest);
+	Ok(result)
+}
+
+struct Parsed {
+	config: Config,
+	original_version: u32,
+	/// The migrated config, if it was migrated.
+	migrated: Option<toml::Value>,
+}
+
+fn parse_and_migrate(raw: &str) -> Result<Parsed> {
+	let mut value: toml::Value = toml::from_str(raw).context("parsing config")?;
+	let original_version = migrate(&mut value)?;
+	let migrated = (original_version != CURRENT_VERSION).then(|| value.clone());
+
+	let table = value.as_table_mut().context("config must be a table")?;
+	let has_includes = add_includes(table)?;
+	let interpolated = interpolate(&mut value).context("substituting environment variables")?;
+
+	let config: Config = if migrated.is_none() && !has_includes && !interpolated {
+		// Deserializing from the raw text rather than the value keeps the line and column in errors.
+		toml::from_str(raw).context("parsing config")?
+	} else {
+		value.try_into().context("parsing config")?
+	};
+
+	let sections = disk_sections(raw);
+	let locate = |index: usize| match sections.get(index) {
+		Some(section) => format!("line {}", raw[..section.start].lines().count() + 1),
+		None => "an included file".to_owned(),
+	};
+	config.validate(locate).context("validating config")?;
+
+	Ok(Parsed {
+		config,
+		original_version,
+		migrated,
+	})
+}
+
+/// Parse, migrate, and validate a config. Errors include the line at which the problem occurs.
+pub fn parse(raw: &str) -> Result<Config> {
+	parse_and_migrate(raw).map(|parsed| parsed.config)
+}
+
+/// Validate the config file, optionally rewriting it in the current format if it is outdated.
+pub fn check(upgrade: bool) -> Result<()> {
+	let raw = load_raw()?;
+	let Parsed {
+		config,
+		original_version,
+		migrated,
+	} = parse_and_migrate(&raw)?;
+
+	eprintln!(
+		"config is valid. {} disks, version {original_version}.",
+		config.disks.len()
+	);
+
+	if let Some(migrated) = migrated {
+		if upgrade {
+			let upgraded = toml::to_string_pretty(&migrated).context("serializing migrated config")?;
+			save_raw(&upgraded)?;
+			eprintln!(
+				"upgraded config to version {CURRENT_VERSION}. note that comments were not preserved."
+			);
+		} else {
+			eprintln!(
+				"the current version is {CURRENT_VERSION}. run `d config check --upgrade` to rewrite the config in the current format."
+			);
+		}
+	}
+
+	Ok(())
+}
+
+pub fn load() -> Result<Config> {
+	let raw = match std::fs::read_to_string(CONFIG_PATH) {
+		Ok(raw) => raw,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+			return Err(anyhow!(
+				"config file {CONFIG_PATH} does not exist. add a disk with `d add`, or see d.example.toml"
+			));
+		}
+		Err(error) => return Err(error).context("reading config file"),
+	};
+	let parsed = parse_and_migrate(&raw)?;
+	if parsed.original_version < CURRENT_VERSION {
+		crate::output::warning(format_args!(
+			"config is in an old format (version {}). run `d config check --upgrade` to update it.",
+			parsed.original_version
+		));
+	}
+	parsed.config.select_profile()
+}
+
+/// Atomically replace the config file with `raw`, which must be valid.
+///
+/// The file is readable by everyone, which is why validation rejects passphrases in plain text.
+pub fn save_raw(raw: &str) -> Result<()> {
+	parse(raw).context("refusing to save invalid config")?;
+
+	std::fs::create_dir_all(CONFIG_DIR).context("creating config directory")?;
+	let temp_path = format!("{CONFIG_PATH}.tmp");
+	std::fs::write(&temp_path, raw).context("writing new config file")?;
+	std::fs::rename(temp_path, CONFIG_PATH).context("replacing config file")
+}
+
+/// The raw contents of the config file, or an empty string if it doesn't exist yet.
+pub fn load_raw() -> Result<String> {
+	match std::fs::read_to_string(CONFIG_PATH) {
+		Ok(raw) => Ok(raw),
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+		Err(error) => Err(error).context("reading config file"),
+	}
+}
+
+/// The byte ranges of the `[[disk]]` sections in the raw config, in the same order as `Config::disks`.
+///
+/// Editing the raw text rather than re-serializing the parsed config preserves comments and formatting.
+fn disk_sections(raw: &str) -> Vec<Range<usize>> {
+	let mut sections = Vec::new();
+	let mut current_start = None;
+	let mut offset = 0;
+	for line in raw.split_inclusive('\n') {
+		let trimmed = line.trim();
+		if trimmed.starts_with('[') {
+			if let Some(start) = current_start.take() {
+				sections.push(start..offset);
+			}
+			if trimmed == "[[disk]]" {
+				current_start = Some(offset);
+			}
+		}
+		offset += line.len();
+	}
+	if let Some(start) = current_start {
+		sections.push(start..raw.len());
+	}
+	sections
+}
+
+/// The section of the disk named `name`. The raw config is parsed again, since the loaded config may only contain the disks in the active profile.
+fn disk_section(raw: &str, name: &str) -> Result<Range<usize>> {
+	let index = parse(raw)?
+		.disks
+		.iter()
+		.position(|disk| disk.name == name)
+		.context("could not find the disk in the config file")?;
+	disk_sections(raw)
+		.into_iter()
+		.nth(index)
+		.context("the disk is defined in an included file; edit it there")
+}
+
+/// Remove the disk named `name` from the raw config.
+pub fn remove_disk(raw: &str, name: &str) -> Result<String> {
+	let section = disk_section(raw, name)?;
+	let mut edited = raw.to_owned();
+	edited.replace_range(section, "");
+	Ok(edited)
+}
+
+/// Replace the line setting `key` in the section of the disk named `name`.
+fn replace_disk_field(raw: &str, name: &str, key: &str, value: &str) -> Result<String> {
+	let section = disk_section(raw, name)?;
+	let mut offset = section.start;
+	for line in raw[section.clone()].split_inclusive('\n') {
+		let is_key_line = line
+			.trim_start()
+			.strip_prefix(key)
+			.is_some_and(|rest| rest.trim_start().starts_with('='));
+		if is_key_line {
+			let line_end = offset + line.trim_end_matches('\n').len();
+			let mut edited = raw.to_owned();
+			edited.replace_range(offset..line_end, &format!("{key} = {value:?}"));
+			return Ok(edited);
+		}
+		offset += line.len();
+	}
+	bail!("could not find the disk's {key} in its section of the config file")
+}
+
+/// Rename the disk named `name` in the raw config.
+pub fn rename_disk(raw: &str, name: &str, new_name: &str) -> Result<String> {
+	replace_disk_field(raw, name, "name", new_name)
+}
+
+/// Change the `device` of the disk named `name` in the raw config.
+pub fn set_disk_device(raw: &str, name: &str, device: &str) -> Result<String> {
+	replace_disk_field(raw, name, "device", device)
+}
+
+/// Read the config that the user saved at `path`, making sure that it is their own file, since they control the path and could have replaced it with a link to something that only root can read.
+///
+/// Editors often save by replacing the file rather than writing to it, so the file that was created for editing can't be read instead.
+fn read_edited(path: &Path, uid: nix::unistd::Uid) -> Result<String> {
+	use std::io::Read as _;
+	use std::os::unix::fs::{MetadataExt as _, OpenOptionsExt as _};
+
+	let mut file = std::fs::OpenOptions::new()
+		.read(true)
+		// Not blocking in case it was replaced with a FIFO, which is refused below.
+		.custom_flags(nix::libc::O_NOFOLLOW | nix::libc::O_NONBLOCK)
+		.open(path)
+		.context("opening edited config")?;
+	let metadata = file.metadata().context("checking edited config")?;
+	ensure!(
+		metadata.file_type().is_file() && metadata.uid() == uid.as_raw() && metadata.nlink() == 1,
+		"{} was replaced with something other than a file of your own; not saving",
+		path.display()
+	);
+	let mut edited = String::new();
+	file
+		.read_to_string(&mut edited)
+		.context("reading edited config")?;
+	Ok(edited)
+}
+
+/// Let the user edit the config file with their editor, only installing the result if it is valid.
+///
+/// The editor is run as the real user, not root, on a temporary copy of the config.
+pub fn edit() -> Result<()> {
+	use std::io::Write as _;
+	use std::os::unix::io::FromRawFd as _;
+	use std::os::unix::process::CommandExt as _;
+
+	let original = load_raw()?;
+	let (uid, gid) = crate::privilege::invoking_user();
+	let (fd, temp_path) =
+		nix::unistd::mkstemp("/tmp/d-config-XXXXXX").context("creating temporary file")?;
+	// SAFETY: the file was just created, and is now owned by the `File`.
+	let mut temp_file = unsafe { std::fs::File::from_raw_fd(fd) };
+	// Written through the descriptor and only then given to the user, so that they can't redirect the write by replacing the path.
+	temp_file
+		.write_all(original.as_bytes())
+		.context("writing temporary file")?;
+	nix::unistd::fchown(fd, Some(uid), Some(gid)).context("chowning temporary file")?;
+	drop(temp_file);
+
+	let editor = std::env::var("VISUAL")
+		.or_else(|_| std::env::var("EDITOR"))
+		.unwrap_or_else(|_| "vi".to_owned());
+
+	let result = loop {
+		let status = std::process::Command::new("sh")
+			.arg("-c")
+			.arg(format!("{editor} \"$1\""))
+			.arg("sh")
+			.arg(&temp_path)
+			.uid(uid.as_raw())
+			.gid(gid.as_raw())
+			.status()
+			.context("running editor")?;
+		if !status.success() {
+			break Err(anyhow!(
+				"editor exited with status {:?}; not saving",
+				status.code()
+			));
+		}
+
+		let edited = match read_edited(&temp_path, uid) {
+			Ok(edited) => edited,
+			Err(error) => break Err(error),
+		};
+		if edited == original {
+			eprintln!("no changes.");
+			break Ok(());
+		}
+		match parse(&edited) {
+			Ok(_) => break save_raw(&edited),
+			Err(error) => {
+				crate::output::error(&error);
+				let response = crate::prompt::ask("edit again? (otherwise changes are discarded) [Y/n]")?;
+				if response.eq_ignore_ascii_case("n") {
+					break Err(anyhow!("config is invalid; discarded changes"));
+				}
+			}
+		}
+	};
+
+	let _ = std::fs::remove_file(&temp_path);
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn migrated(raw: &str) -> Result<(u32, toml::Value)> {
+		let mut value: toml::Value = toml::from_str(raw).unwrap();
+		let version = migrate(&mut value)?;
+		Ok((version, value))
+	}
+
+	#[test]
+	fn migrate_unversioned() {
+		let (version, value) = migrated("").unwrap();
+		assert_eq!(version, 1);
+		assert_eq!(
+			value.get("version").and_then(toml::Value::as_integer),
+			Some(i64::from(CURRENT_VERSION))
+		);
+	}
+
+	#[test]
+	fn migrate_current() {
+		let raw = format!("version = {CURRENT_VERSION}\n[[disk]]\nname = \"a\"\n");
+		let (version, value) = migrated(&raw).unwrap();
+		assert_eq!(version, CURRENT_VERSION);
+		assert!(value.get("disk").is_some());
+	}
+
+	#[test]
+	fn migrate_invalid_version() {
+		for raw in [
+			"version = 0",
+			"version = -1",
+			"version = \"1\"",
+			"version = 1.0",
+		] {
+			assert!(migrated(raw).is_err(), "{raw}");
+		}
+	}
+
+	#[test]
+	fn migrate_newer_version() {
+		let raw = format!("version = {}", CURRENT_VERSION + 1);
+		assert!(migrated(&raw).is_err());
+	}
+
+	#[test]
+	fn migrate_not_a_table() {
+		let mut value = toml::Value::Integer(1);
+		assert!(migrate(&mut value).is_err());
+	}
+
+	#[test]
+	fn edit_distance_empty() {
+		assert_eq!(edit_distance("", ""), 0);
+		assert_eq!(edit_distance("", "abc"), 3);
+		assert_eq!(edit_distance("abc", ""), 3);
+	}
+
+	#[test]
+	fn edit_distance_edits() {
+		assert_eq!(edit_distance("sivydatni", "sivydatni"), 0);
+		assert_eq!(edit_distance("sivydatni", "sivdatni"), 1);
+		assert_eq!(edit_distance("sivydatni", "sivydatnii"), 1);
+		assert_eq!(edit_distance("sivydatni", "sivydatmi"), 1);
+		assert_eq!(edit_distance("kitten", "sitting"), 3);
+		assert_eq!(edit_distance("ab", "ba"), 2);
+	}
+
+	#[test]
+	fn edit_distance_counts_characters() {
+		assert_eq!(edit_distance("é", "e"), 1);
+		assert_eq!(edit_distance("日本", "日"), 1);
+	}
+
+	fn matches(pattern: &str, name: &str) -> bool {
+		let pattern: Vec<char> = pattern.chars().collect();
+		let name: Vec<char> = name.chars().collect();
+		wildcard_matches(&pattern, &name)
+	}
+
+	#[test]
+	fn wildcard_literal() {
+		assert!(matches("", ""));
+		assert!(!matches("", "a"));
+		assert!(!matches("a", ""));
+		assert!(matches("disks.toml", "disks.toml"));
+		assert!(!matches("disks.toml", "disks.tom"));
+	}
+
+	#[test]
+	fn wildcard_star() {
+		assert!(matches("*", ""));
+		assert!(matches("*", "anything"));
+		assert!(matches("*.toml", ".toml"));
+		assert!(matches("*.toml", "disks.toml"));
+		assert!(!matches("*.toml", "disks.toml.bak"));
+		assert!(matches("a*b*c", "abc"));
+		assert!(matches("a*b*c", "axxbyyc"));
+		assert!(!matches("a*b*c", "axxcyyb"));
+		assert!(matches("**", "a"));
+	}
+
+	#[test]
+	fn wildcard_question_mark() {
+		assert!(matches("?", "a"));
+		assert!(!matches("?", ""));
+		assert!(!matches("?", "ab"));
+		assert!(matches("disk-?.toml", "disk-1.toml"));
+		assert!(matches("?*", "a"));
+		assert!(!matches("?*", ""));
+	}
+
+	fn with_var<T>(name: &str, value: &str, f: impl FnOnce() -> T) -> T {
+		std::env::set_var(name, value);
+		let ret = f();
+		std::env::remove_var(name);
+		ret
+	}
+
+	#[test]
+	fn interpolate_nothing() {
+		assert_eq!(interpolate_string("").unwrap(), "");
+		assert_eq!(interpolate_string("no variables").unwrap(), "no variables");
+		assert_eq!(
+			interpolate_string("$HOME and {braces}").unwrap(),
+			"$HOME and {braces}"
+		);
+	}
+
+	#[test]
+	fn interpolate_variables() {
+		with_var("D_TEST_INTERPOLATE_A", "value", || {
+			assert_eq!(
+				interpolate_string("${D_TEST_INTERPOLATE_A}").unwrap(),
+				"value"
+			);
+			assert_eq!(
+				interpolate_string("a/${D_TEST_INTERPOLATE_A}/${D_TEST_INTERPOLATE_A}b").unwrap(),
+				"a/value/valueb"
+			);
+		});
+		with_var("D_TEST_INTERPOLATE_EMPTY", "", || {
+			assert_eq!(
+				interpolate_string("[${D_TEST_INTERPOLATE_EMPTY}]").unwrap(),
+				"[]"
+			);
+		});
+	}
+
+	#[test]
+	fn interpolate_escape() {
+		assert_eq!(interpolate_string("$${NAME}").unwrap(), "${NAME}");
+		assert_eq!(interpolate_string("a$${").unwrap(), "a${");
+		// The value isn't interpolated again.
+		with_var("D_TEST_INTERPOLATE_NESTED", "$${X}", || {
+			assert_eq!(
+				interpolate_string("${D_TEST_INTERPOLATE_NESTED}").unwrap(),
+				"$${X}"
+			);
+		});
+	}
+
+	#[test]
+	fn interpolate_errors() {
+		assert!(interpolate_string("${D_TEST_INTERPOLATE_UNSET}").is_err());
+		assert!(interpolate_string("${unterminated").is_err());
+	}
+
+	#[test]
+	fn interpolate_values() {
+		let mut value: toml::Value = toml::from_str("a = 1\nb = [\"x\"]\n[c]\nd = \"y\"").unwrap();
+		assert!(!interpolate(&mut value).unwrap());
+
+		with_var("D_TEST_INTERPOLATE_B", "z", || {
+			let mut value: toml::Value =
+				toml::from_str("a = [\"${D_TEST_INTERPOLATE_B}\"]\n[c]\nd = \"${D_TEST_INTERPOLATE_B}\"")
+					.unwrap();
+			assert!(interpolate(&mut value).unwrap());
+			assert_eq!(value["a"][0].as_str(), Some("z"));
+			assert_eq!(value["c"]["d"].as_str(), Some("z"));
+		});
+	}
+}
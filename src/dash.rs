@@ -0,0 +1,215 @@
+//! `d dash`, a live overview of every configured disk that refreshes every second.
+
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context as _, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, Termios};
+
+use crate::config::{Config, Disk};
+use crate::{fsck, iostat, metrics, output, space, state, stats, sysfs, DiskState};
+
+const REFRESH_INTERVAL_MS: i32 = 1000;
+/// smartctl is slow, so temperatures are read less often.
+const TEMPERATURE_INTERVAL: Duration = Duration::from_mins(1);
+const KEY_CTRL_C: u8 = 0x03;
+const KEY_ESCAPE: u8 = 0x1b;
+
+/// Reads keys one at a time without echoing them, and hides the cursor, until dropped.
+struct RawMode {
+	original: Termios,
+}
+
+impl RawMode {
+	fn enable() -> Result<Self> {
+		let original = tcgetattr(0).context("getting terminal attributes")?;
+		let mut raw = original.clone();
+		// Ctrl-C is read as a key rather than killing `d`, so that the terminal is always restored.
+		raw
+			.local_flags
+			.remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG);
+		tcsetattr(0, SetArg::TCSAFLUSH, &raw).context("setting terminal attributes")?;
+		print!("\x1b[?25l");
+		Ok(Self { original })
+	}
+}
+
+impl Drop for RawMode {
+	fn drop(&mut self) {
+		print!("\x1b[?25h");
+		let _ = std::io::stdout().flush();
+		let _ = tcsetattr(0, SetArg::TCSAFLUSH, &self.original);
+	}
+}
+
+struct Dashboard<'a> {
+	config: &'a Config,
+	previous_io: HashMap<String, iostat::Counters>,
+	previous_time: Instant,
+	temperatures: HashMap<String, f64>,
+	temperatures_read: Option<Instant>,
+}
+
+impl<'a> Dashboard<'a> {
+	fn new(config: &'a Config) -> Self {
+		Self {
+			config,
+			previous_io: HashMap::new(),
+			previous_time: Instant::now(),
+			temperatures: HashMap::new(),
+			temperatures_read: None,
+		}
+	}
+
+	fn read_temperatures(&mut self) {
+		if self
+			.temperatures_read
+			.is_some_and(|read| read.elapsed() < TEMPERATURE_INTERVAL)
+		{
+			return;
+		}
+		self.temperatures_read = Some(Instant::now());
+		self.temperatures = self
+			.config
+			.disks
+			.iter()
+			.filter_map(|disk| {
+				let path = metrics::whole_disk_path(disk)?;
+				Some((disk.name.clone(), metrics::temperature(&path)?))
+			})
+			.collect();
+	}
+
+	/// Things that need attention, such as a filesystem check or a disk left open.
+	fn pending(disk: &Disk, current: DiskState, all_stats: &stats::Stats) -> Vec<String> {
+		let mut pending = Vec::new();
+		if current == DiskState::Open {
+			pending.push("open but not mounted".to_owned());
+		}
+		let usage = all_stats.get(disk.as_repr()).copied().unwrap_or_default();
+		if let Some(reason) = fsck::due_reason(disk, &usage) {
+			pending.push(format!("check due ({reason})"));
+		}
+		let sessions = state::session_count(disk.as_repr()).unwrap_or(0);
+		if sessions > 0 {
+			let plural = if sessions == 1 { "" } else { "s" };
+			pending.push(format!("{sessions} session{plural}"));
+		}
+		pending
+	}
+
+	fn draw(&mut self) -> Result<()> {
+		let io = iostat::read()?;
+		let elapsed = self.previous_time.elapsed().as_secs_f64().max(0.001);
+		self.read_temperatures();
+		let all_stats = stats::load()?;
+
+		let mut table =
+			output::Table::new(&["NAME", "STATE", "FREE", "READ", "WRITE", "TEMP", "PENDING"]);
+		for disk in &self.config.disks {
+			let Ok(current) = crate::disk_state(disk) else {
+				table.row(vec![
+					(disk.as_repr().to_owned(), None),
+					("error".to_owned(), Some(output::Color::Red)),
+				]);
+				continue;
+			};
+			let free = crate::mount_points(disk)
+				.ok()
+				.and_then(|points| points.into_iter().next())
+				.and_then(|point| space::usage(&point.to_string_lossy()).ok())
+				.map(|usage| {
+					let is_low = usage.available_percent() < disk.min_free_percent;
+					(
+						format!(
+							"{} ({:.0}%)",
+							output::format_size(usage.available),
+							usage.available_percent()
+						),
+						is_low.then_some(output::Color::Red),
+					)
+				})
+				.unwrap_or_default();
+			let (read, write) = crate::outer_dev_path(disk)
+				.ok()
+				.filter(|_| current != DiskState::Absent)
+				.and_then(|dev_path| sysfs::kernel_name(&dev_path))
+				.and_then(|kernel_name| {
+					let counters = io.get(&kernel_name)?;
+					let previous = self.previous_io.get(&kernel_name)?;
+					#[allow(
+						clippy::cast_possible_truncation,
+						clippy::cast_precision_loss,
+						clippy::cast_sign_loss
+					)] // Only used for display.
+					let rate = |bytes: u64| {
+						let rate = bytes as f64 / elapsed;
+						format!("{}/s", output::format_size(rate as u64))
+					};
+					Some((
+						rate(counters.bytes_read().saturating_sub(previous.bytes_read())),
+						rate(
+							counters
+								.bytes_written()
+								.saturating_sub(previous.bytes_written()),
+						),
+					))
+				})
+				.unwrap_or_default();
+			let temperature = self
+				.temperatures
+				.get(&disk.name)
+				.map(|temperature| format!("{temperature:.0}°C"))
+				.unwrap_or_default();
+			table.row(vec![
+				(disk.as_repr().to_owned(), None),
+				(current.as_repr().to_owned(), current.color()),
+				free,
+				(read, None),
+				(write, None),
+				(temperature, None),
+				(
+					Self::pending(disk, current, &all_stats).join(", "),
+					Some(output::Color::Yellow),
+				),
+			]);
+		}
+		self.previous_io = io;
+		self.previous_time = Instant::now();
+
+		// Move to the top left and clear the screen.
+		print!("\x1b[H\x1b[2J");
+		println!(
+			"d dash: {} disks. press q to quit.\n",
+			self.config.disks.len()
+		);
+		table.print();
+		std::io::stdout().flush().context("writing to terminal")
+	}
+}
+
+pub fn run(config: &Config) -> Result<()> {
+	ensure!(
+		crate::prompt::stdin_is_tty() && nix::unistd::isatty(1) == Ok(true),
+		"d dash needs a terminal"
+	);
+	let _raw_mode = RawMode::enable()?;
+	let mut dashboard = Dashboard::new(config);
+	loop {
+		dashboard.draw()?;
+		let mut fds = [PollFd::new(0, PollFlags::POLLIN)];
+		if poll(&mut fds, REFRESH_INTERVAL_MS).context("waiting for input")? == 0 {
+			continue;
+		}
+		// Read directly rather than through `Stdin`, whose buffer would hide keys from `poll`.
+		let mut key = [0];
+		let len = nix::unistd::read(0, &mut key).context("reading key")?;
+		if len == 0 || matches!(key[0], b'q' | KEY_CTRL_C | KEY_ESCAPE) {
+			break;
+		}
+	}
+	print!("\x1b[H\x1b[2J");
+	Ok(())
+}
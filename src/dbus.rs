@@ -0,0 +1,755 @@
+//! Just enough of the D-Bus protocol to offer a service on the system bus and call systemd: authentication, and messages whose bodies contain strings, object paths, unsigned integers, and arrays of strings.
+//!
+//! See <https://dbus.freedesktop.org/doc/dbus-specification.html>. Only little-endian messages are understood, which is what every common platform sends, and others are skipped.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{Read as _, Write as _};
+use std::os::unix::net::UnixStream;
+use std::time::Instant;
+
+use anyhow::{bail, ensure, Context as _, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+
+const SYSTEM_BUS_PATH: &str = "/run/dbus/system_bus_socket";
+const PROTOCOL_VERSION: u8 = 1;
+/// The length of the fixed part of the header, before the array of header fields.
+const FIXED_HEADER_LEN: usize = 16;
+/// The longest message that is buffered. Longer ones are discarded as they arrive, since nothing this module handles comes close, and the specification allows up to 128 MiB.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+pub const BUS_NAME: &str = "org.freedesktop.DBus";
+const BUS_PATH: &str = "/org/freedesktop/DBus";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+	MethodCall = 1,
+	MethodReturn = 2,
+	Error = 3,
+	Signal = 4,
+}
+
+impl Kind {
+	fn from_byte(byte: u8) -> Option<Self> {
+		Some(match byte {
+			1 => Self::MethodCall,
+			2 => Self::MethodReturn,
+			3 => Self::Error,
+			4 => Self::Signal,
+			_ => return None,
+		})
+	}
+}
+
+/// The flag for method calls that don't want a reply.
+pub const NO_REPLY_EXPECTED: u8 = 0x1;
+
+mod field {
+	pub const PATH: u8 = 1;
+	pub const INTERFACE: u8 = 2;
+	pub const MEMBER: u8 = 3;
+	pub const ERROR_NAME: u8 = 4;
+	pub const REPLY_SERIAL: u8 = 5;
+	pub const DESTINATION: u8 = 6;
+	pub const SENDER: u8 = 7;
+	pub const SIGNATURE: u8 = 8;
+}
+
+/// Marshals values, keeping track of alignment. Offsets are relative to the start of the message or body, which are both 8-aligned.
+#[derive(Debug, Default)]
+pub struct Writer {
+	buf: Vec<u8>,
+}
+
+impl Writer {
+	fn align(&mut self, alignment: usize) {
+		self
+			.buf
+			.resize(self.buf.len().next_multiple_of(alignment), 0);
+	}
+
+	fn byte(&mut self, value: u8) {
+		self.buf.push(value);
+	}
+
+	pub fn u32(&mut self, value: u32) {
+		self.align(4);
+		self.buf.extend(value.to_le_bytes());
+	}
+
+	pub fn string(&mut self, value: &str) {
+		self.u32(u32::try_from(value.len()).expect("string is too long for D-Bus"));
+		self.buf.extend(value.as_bytes());
+		self.buf.push(0);
+	}
+
+	fn signature(&mut self, value: &str) {
+		self.byte(u8::try_from(value.len()).expect("signature is too long for D-Bus"));
+		self.buf.extend(value.as_bytes());
+		self.buf.push(0);
+	}
+
+	/// Write an array whose elements have `alignment`, using `f` to write them.
+	fn array(&mut self, alignment: usize, f: impl FnOnce(&mut Self)) {
+		self.u32(0);
+		let len_pos = self.buf.len() - 4;
+		// The padding before the first element is not part of the length.
+		self.align(alignment);
+		let start = self.buf.len();
+		f(self);
+		let len = u32::try_from(self.buf.len() - start).expect("array is too long for D-Bus");
+		self.buf[len_pos..len_pos + 4].copy_from_slice(&len.to_le_bytes());
+	}
+
+	pub fn strings(&mut self, values: &[&str]) {
+		self.array(4, |writer| {
+			for value in values {
+				writer.string(value);
+			}
+		});
+	}
+}
+
+/// Unmarshals values in the order they were written.
+struct Reader<'a> {
+	buf: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn align(&mut self, alignment: usize) {
+		self.pos = self.pos.next_multiple_of(alignment);
+	}
+
+	fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+		let end = self.pos.checked_add(len).context("length is too large")?;
+		let bytes = self
+			.buf
+			.get(self.pos..end)
+			.context("message is truncated")?;
+		self.pos = end;
+		Ok(bytes)
+	}
+
+	fn byte(&mut self) -> Result<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		self.align(4);
+		let bytes = self.take(4)?;
+		Ok(u32::from_le_bytes(bytes.try_into().expect("took 4 bytes")))
+	}
+
+	fn string(&mut self) -> Result<String> {
+		let len = self.u32()? as usize;
+		let bytes = self.take(len + 1)?;
+		String::from_utf8(bytes[..len].to_vec()).context("string is not UTF-8")
+	}
+
+	fn signature(&mut self) -> Result<String> {
+		let len = usize::from(self.byte()?);
+		let bytes = self.take(len + 1)?;
+		String::from_utf8(bytes[..len].to_vec()).context("signature is not UTF-8")
+	}
+}
+
+/// An argument of a message. Object paths are read as strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+	U32(u32),
+	String(String),
+}
+
+#[derive(Debug, Default)]
+pub struct Message {
+	pub kind: Option<Kind>,
+	pub flags: u8,
+	pub serial: u32,
+	pub path: Option<String>,
+	pub interface: Option<String>,
+	pub member: Option<String>,
+	pub error_name: Option<String>,
+	pub reply_serial: Option<u32>,
+	pub destination: Option<String>,
+	pub sender: Option<String>,
+	pub signature: String,
+	pub body: Vec<u8>,
+}
+
+impl Message {
+	pub fn method_call(destination: &str, path: &str, interface: &str, member: &str) -> Self {
+		Self {
+			kind: Some(Kind::MethodCall),
+			path: Some(path.to_owned()),
+			interface: Some(interface.to_owned()),
+			member: Some(member.to_owned()),
+			destination: Some(destination.to_owned()),
+			..Self::default()
+		}
+	}
+
+	pub fn signal(path: &str, interface: &str, member: &str) -> Self {
+		Self {
+			kind: Some(Kind::Signal),
+			path: Some(path.to_owned()),
+			interface: Some(interface.to_owned()),
+			member: Some(member.to_owned()),
+			..Self::default()
+		}
+	}
+
+	/// A reply to this method call.
+	pub fn method_return(&self) -> Self {
+		Self {
+			kind: Some(Kind::MethodReturn),
+			reply_serial: Some(self.serial),
+			destination: self.sender.clone(),
+			..Self::default()
+		}
+	}
+
+	/// An error reply to this method call.
+	pub fn error(&self, name: &str, text: &str) -> Self {
+		let mut error = Self {
+			kind: Some(Kind::Error),
+			error_name: Some(name.to_owned()),
+			reply_serial: Some(self.serial),
+			destination: self.sender.clone(),
+			..Self::default()
+		};
+		error.set_body("s", |writer| writer.string(text));
+		error
+	}
+
+	pub fn set_body(&mut self, signature: &str, f: impl FnOnce(&mut Writer)) {
+		let mut writer = Writer::default();
+		f(&mut writer);
+		signature.clone_into(&mut self.signature);
+		self.body = writer.buf;
+	}
+
+	/// The arguments of a message whose body consists only of strings and object paths.
+	pub fn string_args(&self) -> Result<Vec<String>> {
+		ensure!(
+			self.signature.chars().all(|ch| matches!(ch, 's' | 'o')),
+			"expected only string arguments, got signature {:?}",
+			self.signature
+		);
+		let mut reader = Reader {
+			buf: &self.body,
+			pos: 0,
+		};
+		self.signature.chars().map(|_| reader.string()).collect()
+	}
+
+	/// The arguments of a message whose body consists only of strings, object paths, and `u32`s.
+	pub fn args(&self) -> Result<Vec<Value>> {
+		let mut reader = Reader {
+			buf: &self.body,
+			pos: 0,
+		};
+		self
+			.signature
+			.chars()
+			.map(|ch| match ch {
+				's' | 'o' => reader.string().map(Value::String),
+				'u' => reader.u32().map(Value::U32),
+				other => bail!("unexpected argument type {other:?}"),
+			})
+			.collect()
+	}
+
+	/// The argument of a message whose body is a single `u32`.
+	pub fn u32_arg(&self) -> Result<u32> {
+		ensure!(
+			self.signature == "u",
+			"expected a u32, got signature {:?}",
+			self.signature
+		);
+		Reader {
+			buf: &self.body,
+			pos: 0,
+		}
+		.u32()
+	}
+
+	fn encode(&self, serial: u32) -> Vec<u8> {
+		let mut writer = Writer::default();
+		writer.byte(b'l');
+		writer.byte(self.kind.expect("outgoing messages have a kind") as u8);
+		writer.byte(self.flags);
+		writer.byte(PROTOCOL_VERSION);
+		writer.u32(u32::try_from(self.body.len()).expect("body is too long for D-Bus"));
+		writer.u32(serial);
+
+		let string_fields = [
+			(field::INTERFACE, &self.interface),
+			(field::MEMBER, &self.member),
+			(field::ERROR_NAME, &self.error_name),
+			(field::DESTINATION, &self.destination),
+		];
+		writer.array(8, |writer| {
+			let mut field = |code: u8, signature: &str, write: &dyn Fn(&mut Writer)| {
+				writer.align(8);
+				writer.byte(code);
+				writer.signature(signature);
+				write(writer);
+			};
+			if let Some(path) = &self.path {
+				field(field::PATH, "o", &|writer| writer.string(path));
+			}
+			for (code, value) in string_fields {
+				if let Some(value) = value {
+					field(code, "s", &|writer| writer.string(value));
+				}
+			}
+			if let Some(reply_serial) = self.reply_serial {
+				field(field::REPLY_SERIAL, "u", &|writer| writer.u32(reply_serial));
+			}
+			if !self.signature.is_empty() {
+				field(field::SIGNATURE, "g", &|writer| {
+					writer.signature(&self.signature);
+				});
+			}
+		});
+		writer.align(8);
+		writer.buf.extend(&self.body);
+		writer.buf
+	}
+
+	/// The total length of the message that starts `buf`, if enough of it is there to tell.
+	fn encoded_len(buf: &[u8]) -> Option<usize> {
+		let fixed = buf.get(..FIXED_HEADER_LEN)?;
+		// Big-endian messages can't be decoded, but their length is still needed to skip them.
+		let u32_at = |pos: usize| {
+			let bytes = fixed[pos..pos + 4].try_into().ok()?;
+			let value = if fixed[0] == b'B' {
+				u32::from_be_bytes(bytes)
+			} else {
+				u32::from_le_bytes(bytes)
+			};
+			Some(value as usize)
+		};
+		let body_len = u32_at(4)?;
+		let fields_len = u32_at(12)?;
+		Some((FIXED_HEADER_LEN + fields_len).next_multiple_of(8) + body_len)
+	}
+
+	fn decode(buf: &[u8]) -> Result<Self> {
+		ensure!(buf[0] == b'l', "only little-endian messages are supported");
+		let mut reader = Reader { buf, pos: 1 };
+		let mut message = Self {
+			kind: Kind::from_byte(reader.byte()?),
+			flags: reader.byte()?,
+			..Self::default()
+		};
+		let _version = reader.byte()?;
+		let body_len = reader.u32()? as usize;
+		message.serial = reader.u32()?;
+
+		let fields_end = reader.u32()? as usize + reader.pos;
+		while reader.pos < fields_end {
+			reader.align(8);
+			let code = reader.byte()?;
+			match reader.signature()?.as_str() {
+				"s" | "o" => {
+					let value = Some(reader.string()?);
+					match code {
+						field::PATH => message.path = value,
+						field::INTERFACE => message.interface = value,
+						field::MEMBER => message.member = value,
+						field::ERROR_NAME => message.error_name = value,
+						field::DESTINATION => message.destination = value,
+						field::SENDER => message.sender = value,
+						_ => {}
+					}
+				}
+				"u" => {
+					let value = reader.u32()?;
+					if code == field::REPLY_SERIAL {
+						message.reply_serial = Some(value);
+					}
+				}
+				"g" => {
+					let value = reader.signature()?;
+					if code == field::SIGNATURE {
+						message.signature = value;
+					}
+				}
+				other => bail!("unexpected header field signature {other:?}"),
+			}
+		}
+		reader.align(8);
+		message.body = reader.take(body_len)?.to_vec();
+		Ok(message)
+	}
+}
+
+pub struct Connection {
+	stream: UnixStream,
+	/// Received bytes that don't make up a whole message yet.
+	buf: Vec<u8>,
+	/// Messages received while waiting for a reply, to be handled afterward.
+	pending: VecDeque<Message>,
+	/// How much of a message that is too long to buffer has yet to arrive and be discarded.
+	discarding: usize,
+	last_serial: u32,
+}
+
+impl Connection {
+	/// Connect and authenticate to the system bus, and register with it.
+	pub fn system() -> Result<Self> {
+		let mut stream =
+			UnixStream::connect(SYSTEM_BUS_PATH).context("connecting to the system bus")?;
+		let uid = nix::unistd::Uid::effective().to_string();
+		let hex_uid = uid.bytes().fold(String::new(), |mut hex, byte| {
+			let _ = write!(hex, "{byte:02x}");
+			hex
+		});
+		// The protocol starts with a nul byte, for passing credentials on some systems.
+		write!(stream, "\0AUTH EXTERNAL {hex_uid}\r\n").context("authenticating")?;
+		let mut response = Vec::new();
+		while !response.ends_with(b"\r\n") {
+			let mut byte = [0];
+			stream.read_exact(&mut byte).context("authenticating")?;
+			response.push(byte[0]);
+		}
+		ensure!(
+			response.starts_with(b"OK "),
+			"the bus rejected authentication: {}",
+			String::from_utf8_lossy(&response).trim()
+		);
+		stream.write_all(b"BEGIN\r\n").context("authenticating")?;
+
+		let mut connection = Self {
+			stream,
+			buf: Vec::new(),
+			pending: VecDeque::new(),
+			discarding: 0,
+			last_serial: 0,
+		};
+		connection
+			.call(&Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "Hello"))
+			.context("registering with the bus")?;
+		Ok(connection)
+	}
+
+	pub fn send(&mut self, message: &Message) -> Result<u32> {
+		self.last_serial += 1;
+		self
+			.stream
+			.write_all(&message.encode(self.last_serial))
+			.context("sending message")?;
+		Ok(self.last_serial)
+	}
+
+	/// Call a method and wait for its reply. Other messages that arrive meanwhile are kept for `next_message_before`.
+	pub fn call(&mut self, message: &Message) -> Result<Message> {
+		let serial = self.send(message)?;
+		loop {
+			let reply = self.read_message()?;
+			if reply.reply_serial != Some(serial) {
+				self.pending.push_back(reply);
+				continue;
+			}
+			if reply.kind == Some(Kind::Error) {
+				let text = reply
+					.string_args()
+					.ok()
+					.and_then(|args| args.into_iter().next());
+				bail!(
+					"{}: {}",
+					reply.error_name.unwrap_or_default(),
+					text.unwrap_or_default()
+				);
+			}
+			return Ok(reply);
+		}
+	}
+
+	/// Ask the bus for a well-known name, failing if something else already has it.
+	pub fn request_name(&mut self, name: &str) -> Result<()> {
+		/// From the specification.
+		const DO_NOT_QUEUE: u32 = 0x4;
+		const PRIMARY_OWNER: u32 = 1;
+
+		let mut call = Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "RequestName");
+		call.set_body("su", |writer| {
+			writer.string(name);
+			writer.u32(DO_NOT_QUEUE);
+		});
+		let result = self.call(&call)?.u32_arg()?;
+		ensure!(
+			result == PRIMARY_OWNER,
+			"{name} is already taken on the bus"
+		);
+		Ok(())
+	}
+
+	/// Receive the signals that match `rule`, such as `type='signal',member='JobRemoved'`.
+	pub fn add_match(&mut self, rule: &str) -> Result<()> {
+		let mut call = Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "AddMatch");
+		call.set_body("s", |writer| writer.string(rule));
+		self.call(&call).context("subscribing to signals")?;
+		Ok(())
+	}
+
+	/// The next message, whether it was received earlier or has yet to arrive, or `None` if none arrives by `deadline`.
+	pub fn next_message_before(&mut self, deadline: Instant) -> Result<Option<Message>> {
+		loop {
+			if let Some(message) = self.buffered_message() {
+				return Ok(Some(message));
+			}
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			if remaining.is_zero() {
+				return Ok(None);
+			}
+			let timeout_ms = i32::try_from(remaining.as_millis()).unwrap_or(i32::MAX);
+			let mut fds = [PollFd::new(self.as_raw_fd(), PollFlags::POLLIN)];
+			poll(&mut fds, timeout_ms).context("waiting for the bus")?;
+			if fds[0].revents().is_some_and(|events| !events.is_empty()) {
+				self.receive()?;
+			}
+		}
+	}
+
+	/// The user ID of the process behind a connection, such as the sender of a method call.
+	pub fn unix_user(&mut self, bus_name: &str) -> Result<u32> {
+		let mut call = Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "GetConnectionUnixUser");
+		call.set_body("s", |writer| writer.string(bus_name));
+		self.call(&call)?.u32_arg()
+	}
+
+	/// A message that was already received, if there is one.
+	pub fn buffered_message(&mut self) -> Option<Message> {
+		self.pending.pop_front().or_else(|| self.take_message())
+	}
+
+	/// The first whole message in the buffer, if there is one.
+	///
+	/// Messages that are too long or can't be decoded are skipped, since any client on the bus can send them, and they shouldn't break the connection.
+	fn take_message(&mut self) -> Option<Message> {
+		loop {
+			let len = Message::encoded_len(&self.buf)?;
+			if len > MAX_MESSAGE_LEN {
+				eprintln!("skipping a message of {len} bytes, which is too long.");
+				self.discarding = len;
+				self.discard_buffered();
+				continue;
+			}
+			if self.buf.len() < len {
+				return None;
+			}
+			let message = Message::decode(&self.buf[..len]);
+			self.buf.drain(..len);
+			match message {
+				Ok(message) => return Some(message),
+				Err(error) => eprintln!("skipping a message that couldn't be decoded: {error:#}"),
+			}
+		}
+	}
+
+	fn discard_buffered(&mut self) {
+		let len = self.discarding.min(self.buf.len());
+		self.buf.drain(..len);
+		self.discarding -= len;
+	}
+
+	/// Receive whatever is available, blocking until something is.
+	pub fn receive(&mut self) -> Result<()> {
+		let mut chunk = [0; 4096];
+		let len = self
+			.stream
+			.read(&mut chunk)
+			.context("receiving from the bus")?;
+		ensure!(len > 0, "the bus closed the connection");
+		self.buf.extend(&chunk[..len]);
+		self.discard_buffered();
+		Ok(())
+	}
+
+	fn read_message(&mut self) -> Result<Message> {
+		loop {
+			if let Some(message) = self.take_message() {
+				return Ok(message);
+			}
+			self.receive()?;
+		}
+	}
+
+	pub fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+		use std::os::unix::io::AsRawFd as _;
+		self.stream.as_raw_fd()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn connection(buf: Vec<u8>) -> Connection {
+		let (stream, _) = UnixStream::pair().unwrap();
+		Connection {
+			stream,
+			buf,
+			pending: VecDeque::new(),
+			discarding: 0,
+			last_serial: 0,
+		}
+	}
+
+	fn call() -> Message {
+		let mut call = Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "RequestName");
+		call.set_body("su", |writer| {
+			writer.string("org.example");
+			writer.u32(4);
+		});
+		call
+	}
+
+	/// The same message, in big-endian byte order as far as `encoded_len` is concerned.
+	fn to_big_endian(mut encoded: Vec<u8>) -> Vec<u8> {
+		encoded[0] = b'B';
+		for pos in [4, 8, 12] {
+			encoded[pos..pos + 4].reverse();
+		}
+		encoded
+	}
+
+	#[test]
+	fn round_trip() {
+		let encoded = call().encode(7);
+		assert_eq!(Message::encoded_len(&encoded), Some(encoded.len()));
+		let decoded = Message::decode(&encoded).unwrap();
+		assert_eq!(decoded.kind, Some(Kind::MethodCall));
+		assert_eq!(decoded.serial, 7);
+		assert_eq!(decoded.path.as_deref(), Some(BUS_PATH));
+		assert_eq!(decoded.interface.as_deref(), Some(BUS_NAME));
+		assert_eq!(decoded.member.as_deref(), Some("RequestName"));
+		assert_eq!(decoded.destination.as_deref(), Some(BUS_NAME));
+		assert_eq!(
+			decoded.args().unwrap(),
+			[Value::String("org.example".to_owned()), Value::U32(4)]
+		);
+	}
+
+	#[test]
+	fn round_trip_error() {
+		let mut call = call();
+		call.serial = 3;
+		call.sender = Some(":1.5".to_owned());
+		let encoded = call.error("org.example.Error", "no").encode(9);
+		let decoded = Message::decode(&encoded).unwrap();
+		assert_eq!(decoded.kind, Some(Kind::Error));
+		assert_eq!(decoded.reply_serial, Some(3));
+		assert_eq!(decoded.destination.as_deref(), Some(":1.5"));
+		assert_eq!(decoded.error_name.as_deref(), Some("org.example.Error"));
+		assert_eq!(decoded.string_args().unwrap(), ["no"]);
+	}
+
+	#[test]
+	fn round_trip_empty_body() {
+		let encoded = Message::signal(BUS_PATH, BUS_NAME, "Ping").encode(1);
+		let decoded = Message::decode(&encoded).unwrap();
+		assert_eq!(decoded.signature, "");
+		assert!(decoded.body.is_empty());
+		assert_eq!(decoded.args().unwrap(), []);
+	}
+
+	#[test]
+	fn truncated() {
+		let encoded = call().encode(1);
+		assert_eq!(Message::encoded_len(&encoded[..FIXED_HEADER_LEN - 1]), None);
+		assert!(Message::decode(&encoded[..encoded.len() - 1]).is_err());
+		assert!(Message::decode(&encoded[..FIXED_HEADER_LEN]).is_err());
+	}
+
+	#[test]
+	fn truncated_body_args() {
+		let mut message = call();
+		message.body.truncate(6);
+		assert!(message.args().is_err());
+	}
+
+	#[test]
+	fn oversized_string_length() {
+		let mut message = call();
+		message.body[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+		assert!(message.string_args().is_err());
+	}
+
+	#[test]
+	fn unsupported_header_field() {
+		let mut encoded = call().encode(1);
+		// The signature of the first header field, the path.
+		assert_eq!(&encoded[17..19], [1, b'o']);
+		encoded[18] = b'v';
+		assert!(Message::decode(&encoded).is_err());
+
+		encoded.extend(call().encode(2));
+		let message = connection(encoded).take_message().unwrap();
+		assert_eq!(message.serial, 2);
+	}
+
+	#[test]
+	fn skips_big_endian() {
+		let mut buf = to_big_endian(call().encode(1));
+		assert_eq!(Message::encoded_len(&buf), Some(buf.len()));
+		assert!(Message::decode(&buf).is_err());
+
+		buf.extend(call().encode(2));
+		let mut connection = connection(buf);
+		assert_eq!(connection.take_message().unwrap().serial, 2);
+		assert!(connection.take_message().is_none());
+	}
+
+	#[test]
+	fn waits_for_whole_message() {
+		let encoded = call().encode(1);
+		let mut connection = connection(encoded[..encoded.len() - 1].to_vec());
+		assert!(connection.take_message().is_none());
+		connection.buf.push(*encoded.last().unwrap());
+		assert_eq!(connection.take_message().unwrap().serial, 1);
+	}
+
+	#[test]
+	fn discards_too_long() {
+		let mut header = Writer::default();
+		header.byte(b'l');
+		header.byte(Kind::Signal as u8);
+		header.byte(0);
+		header.byte(PROTOCOL_VERSION);
+		header.u32(u32::try_from(MAX_MESSAGE_LEN).unwrap());
+		header.u32(1);
+		header.u32(0);
+		let mut buf = header.buf;
+		buf.extend([0; 100]);
+
+		let mut connection = connection(buf);
+		assert!(connection.take_message().is_none());
+		assert!(connection.buf.is_empty());
+		assert_eq!(connection.discarding, MAX_MESSAGE_LEN - 100);
+
+		// The rest of it arrives, followed by a message that fits.
+		connection.buf.extend(vec![0; MAX_MESSAGE_LEN - 100]);
+		connection.buf.extend(call().encode(2));
+		connection.discard_buffered();
+		assert_eq!(connection.discarding, 0);
+		assert_eq!(connection.take_message().unwrap().serial, 2);
+	}
+
+	#[test]
+	fn longest_allowed() {
+		let mut message = Message::signal(BUS_PATH, BUS_NAME, "Big");
+		let header_len = message.encode(1).len();
+		message.body = vec![0; MAX_MESSAGE_LEN - header_len];
+		let encoded = message.encode(1);
+		assert_eq!(encoded.len(), MAX_MESSAGE_LEN);
+		let mut connection = connection(encoded);
+		assert_eq!(
+			connection.take_message().unwrap().body.len(),
+			MAX_MESSAGE_LEN - header_len
+		);
+	}
+}
@@ -0,0 +1,73 @@
+//! Noticing when a disk's RAID array is missing a member, and only mounting it degraded when asked to.
+
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+
+use crate::config::Disk;
+use crate::{btrfs, output, prompt, sysfs};
+
+/// The md arrays under a device, directly or through mappings such as LUKS, that are missing members.
+fn degraded_md_arrays(kernel_name: &str, found: &mut Vec<String>) -> Result<()> {
+	if let Some((missing, present)) = sysfs::md_degraded(kernel_name)? {
+		found.push(format!(
+			"the md array {kernel_name} is missing {missing} device(s). present: {}",
+			present.join(", ")
+		));
+	}
+	for slave in sysfs::slaves(kernel_name)? {
+		degraded_md_arrays(&slave, found)?;
+	}
+	Ok(())
+}
+
+/// Make sure that mounting degraded is what the user wants, with `--degraded` or by asking.
+fn confirm(disk_name: &str, problems: &[String], allowed: bool) -> Result<()> {
+	for problem in problems {
+		output::warning(format_args!("{problem}"));
+	}
+	if allowed {
+		eprintln!("mounting {disk_name} degraded, as requested.");
+		return Ok(());
+	}
+	prompt::confirm_risky(&format!("mount {disk_name} degraded")).with_context(|| {
+		format!(
+			"{disk_name} is degraded. attach the missing devices, or pass --degraded to mount it anyway"
+		)
+	})
+}
+
+/// Check the arrays that `dev_path` is on, and the members of its filesystem if it is btrfs.
+///
+/// Returns whether the filesystem has to be mounted with the `degraded` option.
+pub fn check(disk: &Disk, dev_path: &Path, allowed: bool) -> Result<bool> {
+	let mut problems = Vec::new();
+	if let Some(kernel_name) =
+		sysfs::kernel_name(&std::fs::canonicalize(dev_path).context("resolving device path")?)
+	{
+		degraded_md_arrays(&kernel_name, &mut problems)?;
+	}
+
+	// Otherwise the mount fails with just EINVAL and a kernel message about the first missing device.
+	let mut btrfs_degraded = false;
+	if disk.inner_filesystem() == "btrfs" {
+		match btrfs::missing_members(dev_path) {
+			Ok(None) => {}
+			Ok(Some(missing)) => {
+				problems.push(format!(
+					"{} is a multi-device btrfs filesystem, and {missing}",
+					disk.as_repr()
+				));
+				btrfs_degraded = true;
+			}
+			Err(error) => output::warning(format_args!(
+				"failed to check for missing btrfs devices: {error:#}"
+			)),
+		}
+	}
+
+	if !problems.is_empty() {
+		confirm(disk.as_repr(), &problems, allowed)?;
+	}
+	Ok(btrfs_degraded)
+}
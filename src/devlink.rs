@@ -0,0 +1,90 @@
+//! Resolving the symlinks that udev makes for devices, such as in `/dev/disk/by-uuid`, which appear a moment after the device itself.
+
+use std::os::unix::io::AsRawFd as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+
+use crate::sysfs;
+
+/// How long to wait for udev by default, which is plenty for it to probe a device or a new mapping.
+pub const DEFAULT_TIMEOUT_SECONDS: u32 = 5;
+
+/// Set from `resolution_timeout_seconds` in the config.
+static TIMEOUT_SECONDS: AtomicU32 = AtomicU32::new(DEFAULT_TIMEOUT_SECONDS);
+
+pub fn set_timeout(seconds: u32) {
+	TIMEOUT_SECONDS.store(seconds, Ordering::Relaxed);
+}
+
+/// Wait until `link` exists or the timeout is over, whichever is first.
+fn wait_for(link: &Path, timeout: Duration) -> Result<()> {
+	let Some(dir) = link.parent() else {
+		return Ok(());
+	};
+	let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)
+		.context("initializing inotify")?;
+	// The directory only exists once udev has made a link in it, in which case there is nothing to watch yet.
+	if inotify
+		.add_watch(dir, AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+		.is_err()
+	{
+		return Ok(());
+	}
+
+	// Checked after adding the watch, so that a link made in between isn't missed.
+	let deadline = Instant::now() + timeout;
+	while !link.exists() {
+		let remaining = deadline.saturating_duration_since(Instant::now());
+		if remaining.is_zero() {
+			break;
+		}
+		let timeout_ms = i32::try_from(remaining.as_millis()).unwrap_or(i32::MAX);
+		let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+		poll(&mut fds, timeout_ms).context("waiting for udev")?;
+		// Only used to know when to check again.
+		let _ = inotify.read_events();
+	}
+	Ok(())
+}
+
+fn timeout() -> Duration {
+	Duration::from_secs(TIMEOUT_SECONDS.load(Ordering::Relaxed).into())
+}
+
+/// The device that `link` points to, waiting a bit for udev to make it if it doesn't exist yet, such as right after the device was attached or a mapping was opened.
+pub fn resolve(link: &Path) -> Result<PathBuf> {
+	let timeout = timeout();
+	if !link.exists() && !timeout.is_zero() {
+		wait_for(link, timeout)?;
+	}
+	std::fs::canonicalize(link).with_context(|| format!("resolving {}", link.display()))
+}
+
+/// Like `resolve`, for the link to the content of a mapping that was just opened.
+///
+/// udev occasionally misses the content of a new device-mapper device, since it is probed before the mapping is ready, in which case the link never appears. If it hasn't appeared by the timeout, udev is asked to probe the mapping again.
+pub fn resolve_in_mapping(mapping_path: &Path, link: &Path) -> Result<PathBuf> {
+	let timeout = timeout();
+	if !link.exists() && !timeout.is_zero() {
+		wait_for(link, timeout)?;
+		if !link.exists() {
+			eprintln!(
+				"udev hasn't found the content of {} yet, asking it to look again.",
+				mapping_path.display()
+			);
+			let kernel_name = std::fs::canonicalize(mapping_path)
+				.ok()
+				.as_deref()
+				.and_then(sysfs::kernel_name)
+				.with_context(|| format!("finding the device of {}", mapping_path.display()))?;
+			sysfs::trigger_change(&kernel_name)?;
+			wait_for(link, timeout)?;
+		}
+	}
+	std::fs::canonicalize(link).with_context(|| format!("resolving {}", link.display()))
+}
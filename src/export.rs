@@ -0,0 +1,155 @@
+//! Exporting disk definitions in other formats, for moving disks to boot-time mounting.
+
+use std::str::FromStr;
+
+use anyhow::Result;
+
+use crate::config::{Config, Disk};
+use crate::secret::Secret;
+use crate::{btrfs, ext4};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+	Fstab,
+	Crypttab,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown export format {0:?}. valid formats are fstab, crypttab.")]
+pub struct UnknownFormat(String);
+
+impl FromStr for Format {
+	type Err = UnknownFormat;
+
+	fn from_str(s: &str) -> Result<Self, UnknownFormat> {
+		Ok(match s {
+			"fstab" => Self::Fstab,
+			"crypttab" => Self::Crypttab,
+			_ => return Err(UnknownFormat(s.to_owned())),
+		})
+	}
+}
+
+/// `noauto,nofail` so that a missing disk doesn't hold up booting, plus the options that `d` mounts with.
+fn fstab_options(disk: &Disk) -> String {
+	let mut options = vec!["noauto", "nofail", "noatime", "nosuid", "nodev"];
+	if disk.is_untrusted() {
+		options.extend(["noexec", "nosymfollow"]);
+	}
+	if disk.verity.is_some() {
+		options.push("ro");
+	} else if disk.inner_filesystem().starts_with("ext") {
+		options.push("discard");
+	}
+	let tuning = disk.ext4.as_ref();
+	if disk.inner_filesystem() == "ext4" && !tuning.is_some_and(ext4::Tuning::conflicts_with_delalloc)
+	{
+		options.push("delalloc");
+	}
+	let mut options: Vec<String> = options.into_iter().map(str::to_owned).collect();
+	options.extend(tuning.map(ext4::Tuning::mount_options).unwrap_or_default());
+	options.extend(
+		disk
+			.btrfs
+			.as_ref()
+			.map(btrfs::Tuning::mount_options)
+			.unwrap_or_default(),
+	);
+	options.join(",")
+}
+
+/// Only ext filesystems are checked at boot by `fsck`; the others check themselves. Verity disks can't be repaired anyway.
+fn fstab_pass(disk: &Disk) -> u8 {
+	if disk.inner_filesystem().starts_with("ext") && disk.verity.is_none() {
+		2
+	} else {
+		0
+	}
+}
+
+/// How fstab and crypttab should find the attached device, whose UUID is `uuid`, the same way `d` does if they can.
+fn outer_device_spec(disk: &Disk, uuid: &str) -> String {
+	if let Some(partuuid) = &disk.partuuid {
+		format!("PARTUUID={partuuid}")
+	} else if let Some(device) = &disk.device {
+		device.clone()
+	} else {
+		format!("UUID={uuid}")
+	}
+}
+
+fn fstab_line(config: &Config, disk: &Disk) -> String {
+	// The verity mapping has the same filesystem UUID as the data device under it.
+	let device = if disk.verity.is_some() {
+		crate::verity::mapping_path(disk.as_repr())
+			.display()
+			.to_string()
+	} else if disk.is_encrypted() {
+		format!("UUID={}", disk.uuid)
+	} else {
+		outer_device_spec(disk, &disk.uuid)
+	};
+	format!(
+		"{device}\t{}\t{}\t{}\t0\t{}",
+		config.mount_path(disk),
+		disk.inner_filesystem(),
+		fstab_options(disk),
+		fstab_pass(disk),
+	)
+}
+
+fn crypttab_line(disk: &Disk, luks_uuid: &str) -> String {
+	// Encrypted key files can't be used at boot, so fall back to prompting.
+	let keyfile = match &disk.keyfile {
+		Some(Secret::Plain(path)) => path.as_str(),
+		_ => "none",
+	};
+	format!(
+		"{}\t{}\t{keyfile}\tluks,noauto,nofail,discard",
+		disk.as_repr(),
+		outer_device_spec(disk, luks_uuid),
+	)
+}
+
+/// Print entries for the disks in `targets`, or every disk if there are none, in `format`. Disks that have no entry in the format are noted in comments.
+pub fn run(config: &Config, targets: &[String], format: Format) -> Result<()> {
+	let disks = if targets.is_empty() {
+		config.disks.iter().collect()
+	} else {
+		config.resolve_targets(targets)?
+	};
+	for disk in disks {
+		println!("# {} ({}), exported by d", disk.as_repr(), disk.shortcut());
+		match format {
+			Format::Fstab => {
+				if disk.is_encrypted() {
+					println!(
+						"# needs the mapping from `d export crypttab` to be opened first, such as by systemd-cryptsetup."
+					);
+				}
+				if disk.verity.is_some() {
+					println!(
+						"# needs the verity mapping to be opened first, such as by systemd-veritysetup."
+					);
+				}
+				if disk.drive.is_some() && !disk.is_encrypted() {
+					println!("# fstab can't find disks by their drive, so this uses the UUID instead.");
+				}
+				println!("{}", fstab_line(config, disk));
+			}
+			Format::Crypttab => match &disk.luks_uuid {
+				Some(luks_uuid) => {
+					if matches!(disk.keyfile, Some(Secret::Encrypted(..))) {
+						println!("# the key file is encrypted in d's config, so this prompts for the passphrase instead.");
+					}
+					if disk.drive.is_some() {
+						println!("# crypttab can't find disks by their drive, so this uses the UUID instead.");
+					}
+					println!("{}", crypttab_line(disk, luks_uuid));
+				}
+				None => println!("# not encrypted, so it needs no crypttab entry."),
+			},
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,79 @@
+//! Tuning ext4 through its mount options.
+
+use anyhow::{ensure, Result};
+
+/// How ext4 journals data, as opposed to metadata, which is always journaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataMode {
+	/// Data is written out before the metadata that refers to it is committed. The default.
+	Ordered,
+	/// Data may be written after its metadata, so files can have stale contents after a crash, but writes are faster.
+	Writeback,
+	/// Data goes through the journal too, which is the safest and the slowest.
+	Journal,
+}
+
+impl DataMode {
+	fn as_repr(self) -> &'static str {
+		match self {
+			Self::Ordered => "ordered",
+			Self::Writeback => "writeback",
+			Self::Journal => "journal",
+		}
+	}
+}
+
+/// Options left unset keep the kernel's defaults.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tuning {
+	/// How often to commit the journal, in seconds. The kernel's default is 5.
+	#[serde(default)]
+	pub commit_seconds: Option<u32>,
+	#[serde(default)]
+	pub data: Option<DataMode>,
+	/// Whether to flush the drive's write cache when committing. Turning this off is only safe if the drive's cache survives losing power.
+	#[serde(default)]
+	pub barrier: Option<bool>,
+}
+
+impl Tuning {
+	pub fn validate(&self, filesystem: &str) -> Result<()> {
+		ensure!(
+			filesystem == "ext4",
+			"ext4 options only apply to ext4 disks"
+		);
+		ensure!(
+			self.commit_seconds != Some(0),
+			"commit_seconds must be at least 1"
+		);
+		ensure!(
+			!(self.data == Some(DataMode::Journal) && self.barrier == Some(false)),
+			"data = \"journal\" is for safety, which barrier = false gives up"
+		);
+		Ok(())
+	}
+
+	/// Whether the options are incompatible with delayed allocation, which ext4 refuses to combine with journaling data.
+	pub fn conflicts_with_delalloc(&self) -> bool {
+		self.data == Some(DataMode::Journal)
+	}
+
+	/// The mount options for these settings, to add to the others.
+	pub fn mount_options(&self) -> Vec<String> {
+		let mut options = Vec::new();
+		if let Some(seconds) = self.commit_seconds {
+			options.push(format!("commit={seconds}"));
+		}
+		if let Some(data) = self.data {
+			options.push(format!("data={}", data.as_repr()));
+		}
+		match self.barrier {
+			Some(true) => options.push("barrier".to_owned()),
+			Some(false) => options.push("nobarrier".to_owned()),
+			None => {}
+		}
+		options
+	}
+}
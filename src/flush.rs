@@ -0,0 +1,44 @@
+//! Periodic syncing during `c` sessions, so that losing power or the cable only loses recent writes.
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use nix::errno::Errno;
+use nix::libc;
+
+use crate::output;
+
+/// How often to check whether the session is over.
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Write out everything cached for the filesystem at `mount_path`.
+pub fn sync(mount_path: &str) -> Result<()> {
+	let dir = File::open(mount_path).context("opening mount path")?;
+	// SAFETY: the file descriptor is valid until `dir` is dropped.
+	let ret = unsafe { libc::syncfs(dir.as_raw_fd()) };
+	Errno::result(ret).context("syncing filesystem")?;
+	Ok(())
+}
+
+/// Sync the filesystem every `interval` until `stop` is set.
+pub fn watch(mount_path: &str, disk_name: &str, interval: Duration, stop: &AtomicBool) {
+	let mut last_sync = Instant::now();
+	while !stop.load(Ordering::Relaxed) {
+		std::thread::sleep(STOP_POLL_INTERVAL);
+		if last_sync.elapsed() < interval {
+			continue;
+		}
+		last_sync = Instant::now();
+		// Nothing can be written to a read-only filesystem, and syncing it after errors would only repeat them.
+		if crate::watchdog::is_read_only(mount_path) {
+			continue;
+		}
+		if let Err(error) = sync(mount_path) {
+			output::warning(format_args!("failed to sync {disk_name}: {error:#}"));
+			return;
+		}
+	}
+}
@@ -0,0 +1,182 @@
+//! The `format` wizard, which turns a blank device into a disk managed by `d`.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::add::{self, NewDisk};
+use crate::{blkid, caps, mountinfo, output, prompt, swap, sysfs};
+
+const FILESYSTEMS: &[&str] = &["ext4", "btrfs", "xfs"];
+const KDFS: &[&str] = &["argon2id", "argon2i", "pbkdf2"];
+
+/// Refuse to overwrite a device that is mounted, used as swap, or has anything stacked on it, including through its partitions.
+pub fn ensure_not_in_use(kernel_name: &str) -> Result<()> {
+	check_not_in_use(kernel_name, &mountinfo::read()?, &swap::active()?)
+}
+
+fn check_not_in_use(
+	kernel_name: &str,
+	mounts: &[mountinfo::Entry],
+	swaps: &[swap::Entry],
+) -> Result<()> {
+	let holders = sysfs::holders(kernel_name)?;
+	ensure!(
+		holders.is_empty(),
+		"{kernel_name} is in use by {}",
+		holders.join(", ")
+	);
+	let device = sysfs::device_number(kernel_name)?;
+	let dev_path = Path::new("/dev").join(kernel_name);
+	if let Some(entry) = mounts
+		.iter()
+		.find(|entry| entry.device == device || entry.is_from(&dev_path))
+	{
+		bail!(
+			"{kernel_name} is mounted at {}",
+			entry.mount_point.display()
+		);
+	}
+	let is_swap = swaps.iter().any(|entry| {
+		std::fs::canonicalize(&entry.device).is_ok_and(|swap_device| swap_device == dev_path)
+	});
+	ensure!(!is_swap, "{kernel_name} is in use as swap");
+	for partition in sysfs::all_devices()? {
+		if sysfs::parent_disk(&partition)?.as_deref() == Some(kernel_name) {
+			check_not_in_use(&partition, mounts, swaps)?;
+		}
+	}
+	Ok(())
+}
+
+fn run_command(command: &mut Command) -> Result<()> {
+	let program = command.get_program().to_string_lossy().into_owned();
+	let status = command
+		.status()
+		.with_context(|| format!("running {program}"))?;
+	ensure!(
+		status.success(),
+		"{program} exited with status {:?}",
+		status.code()
+	);
+	Ok(())
+}
+
+fn ask_choice(question: &str, choices: &[&str]) -> Result<String> {
+	let response = prompt::ask_with_default(
+		&format!("{question} ({})", choices.join(", ")),
+		Some(choices[0]),
+	)?;
+	ensure!(
+		choices.contains(&response.as_str()),
+		"{response:?} is not one of {}",
+		choices.join(", ")
+	);
+	Ok(response)
+}
+
+/// Create a LUKS2 container on `dev_path`, letting cryptsetup prompt for the new passphrase.
+fn create_luks(dev_path: &Path, name: &str) -> Result<String> {
+	let kdf = ask_choice("key derivation function?", KDFS)?;
+	let iter_time =
+		prompt::ask_with_default("milliseconds to spend deriving the key?", Some("2000"))?;
+	let mut command = caps::tool("cryptsetup");
+	command
+		.args([
+			"luksFormat",
+			"--type",
+			"luks2",
+			"--batch-mode",
+			"--verify-passphrase",
+		])
+		.args(["--pbkdf", &kdf, "--iter-time", &iter_time, "--label", name]);
+	if kdf != "pbkdf2" {
+		let memory = prompt::ask_with_default("memory for the KDF, in KiB?", Some("1048576"))?;
+		command.args(["--pbkdf-memory", &memory]);
+	}
+	command.arg(dev_path);
+	run_command(&mut command).context("creating LUKS container")?;
+
+	blkid::probe(dev_path)?
+		.uuid
+		.context("no UUID on the new LUKS container")
+}
+
+fn make_filesystem(dev_path: &Path, filesystem: &str, label: &str) -> Result<String> {
+	output::warning(format_args!(
+		"creating {filesystem} on {}.",
+		dev_path.display()
+	));
+	run_command(
+		caps::tool("mkfs")
+			.args(["-t", filesystem, "-L", label])
+			.arg(dev_path),
+	)
+	.context("creating filesystem")?;
+
+	blkid::probe(dev_path)?
+		.uuid
+		.context("no UUID on the new filesystem")
+}
+
+pub fn run(dev_path: &Path) -> Result<()> {
+	let (raw, _) = add::load_existing()?;
+
+	let dev_path =
+		std::fs::canonicalize(dev_path).with_context(|| format!("resolving {}", dev_path.display()))?;
+	let kernel_name = sysfs::kernel_name(&dev_path)
+		.filter(|name| Path::new("/sys/class/block").join(name).exists())
+		.with_context(|| format!("{} is not a block device", dev_path.display()))?;
+	ensure_not_in_use(&kernel_name)?;
+
+	let probe = blkid::probe(&dev_path)?;
+	let size = output::format_size(sysfs::size_bytes(&kernel_name)?);
+	output::warning(format_args!(
+		"EVERYTHING on {} ({size}) will be destroyed. it currently contains: {}",
+		dev_path.display(),
+		probe
+			.content_type
+			.as_deref()
+			.unwrap_or("nothing recognizable")
+	));
+
+	let (name, shortcut) = add::ask_name_and_shortcut(probe.label.as_deref())?;
+	let encrypt = prompt::confirm("encrypt the disk with LUKS2?", true)?;
+	let filesystem = ask_choice("filesystem?", FILESYSTEMS)?;
+
+	eprintln!("this is your last chance to back out.");
+	prompt::confirm_typed(&dev_path.to_string_lossy())?;
+
+	let (uuid, luks_uuid) = if encrypt {
+		let luks_uuid = create_luks(&dev_path, &name)?;
+		eprintln!("unlock the new container so the filesystem can be created inside it.");
+		crate::open_encrypted(
+			&dev_path,
+			&luks_uuid,
+			&name,
+			&crate::unlock::Key::Prompt,
+			false,
+			None,
+		)
+		.context("opening new LUKS container")?;
+		let opened_path =
+			Path::new("/dev/mapper").join(crate::opened_name_for_encrypted(&luks_uuid, &name));
+		let uuid = make_filesystem(&opened_path, &filesystem, &name);
+		crate::close_encrypted(&luks_uuid, &name).context("closing new LUKS container")?;
+		(uuid?, Some(luks_uuid))
+	} else {
+		(make_filesystem(&dev_path, &filesystem, &name)?, None)
+	};
+
+	add::append_disk(
+		&raw,
+		&NewDisk {
+			name: &name,
+			shortcut: &shortcut,
+			uuid: &uuid,
+			luks_uuid: luks_uuid.as_deref(),
+			filesystem: &filesystem,
+		},
+	)
+}
@@ -0,0 +1,85 @@
+//! Periodic filesystem checks, like the mount-count and interval settings of `tune2fs` but managed by `d`.
+
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+
+use crate::config::Disk;
+use crate::stats::{self, DiskStats};
+use crate::{output, progress};
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+/// `fsck` exit status bits. See `fsck(8)`.
+const FSCK_ERRORS_CORRECTED: i32 = 1;
+const FSCK_REBOOT_REQUIRED: i32 = 2;
+
+/// Why a check is due, if it is.
+pub fn due_reason(disk: &Disk, usage: &DiskStats) -> Option<String> {
+	if let Some(every_mounts) = disk.check_every_mounts {
+		if usage.mounts_since_check >= every_mounts {
+			return Some(format!(
+				"it has been mounted {} times since the last check",
+				usage.mounts_since_check
+			));
+		}
+	}
+
+	if let Some(every_days) = disk.check_every_days {
+		let days_since = match usage.last_checked {
+			Some(last_checked) => stats::now().saturating_sub(last_checked) / SECONDS_PER_DAY,
+			None => return Some("it has never been checked by d".to_owned()),
+		};
+		if days_since >= every_days {
+			return Some(format!("it was last checked {days_since} days ago"));
+		}
+	}
+
+	None
+}
+
+/// Check and automatically repair ("preen") the unmounted filesystem on `dev_path`.
+pub fn run(dev_path: &Path, filesystem: &str) -> Result<()> {
+	let output = progress::with_spinner("checking filesystem", || {
+		crate::caps::tool("fsck")
+			.arg("-p")
+			.arg("-t")
+			.arg(filesystem)
+			.arg(dev_path)
+			.output()
+	})
+	.context("running fsck")?;
+
+	let code = output.status.code().unwrap_or(-1);
+	let is_ok = code & !(FSCK_ERRORS_CORRECTED | FSCK_REBOOT_REQUIRED) == 0;
+	if code != 0 {
+		eprint!("{}", String::from_utf8_lossy(&output.stdout));
+		eprint!("{}", String::from_utf8_lossy(&output.stderr));
+	}
+	if !is_ok {
+		bail!("fsck found errors it could not fix (exit status {code}). run fsck manually");
+	}
+	if code & FSCK_ERRORS_CORRECTED != 0 {
+		output::warning("fsck corrected errors in the filesystem.");
+	}
+	Ok(())
+}
+
+/// Run a check if one is due, unless skipped.
+pub fn check_if_due(disk: &Disk, dev_path: &Path, skip: bool) -> Result<()> {
+	let usage = stats::load()?
+		.get(disk.as_repr())
+		.copied()
+		.unwrap_or_default();
+	let Some(reason) = due_reason(disk, &usage) else {
+		return Ok(());
+	};
+	if skip {
+		eprintln!("skipping filesystem check, which is due because {reason}.");
+		return Ok(());
+	}
+
+	eprintln!("checking filesystem because {reason}. pass --skip-check to skip this.");
+	run(dev_path, disk.inner_filesystem())?;
+	stats::record_check(disk.as_repr())
+}
@@ -0,0 +1,215 @@
+//! Filesystems mounted with FUSE programs like sshfs, which need no privileges at all.
+//!
+//! These are mounted by and for the invoking user, in `~/mnt` rather than `/mnt`, so they work the same whether or not `d` is running as root.
+
+use std::net::{TcpStream, ToSocketAddrs as _};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{ensure, Context as _, Result};
+use nix::unistd::User;
+
+use crate::config::Fuse;
+use crate::mountinfo;
+use crate::privilege::{self, CommandExt as _};
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+	Sshfs,
+	Rclone,
+	Gocryptfs,
+}
+
+impl Kind {
+	/// Whether the filesystem is on a server, as opposed to a local directory.
+	pub fn is_remote(self) -> bool {
+		match self {
+			Self::Sshfs | Self::Rclone => true,
+			Self::Gocryptfs => false,
+		}
+	}
+
+	fn program(self) -> &'static str {
+		match self {
+			Self::Sshfs => "sshfs",
+			Self::Rclone => "rclone",
+			Self::Gocryptfs => "gocryptfs",
+		}
+	}
+}
+
+/// `~/mnt/<name>` in the invoking user's home directory.
+pub fn mount_path(fuse: &Fuse) -> Result<PathBuf> {
+	let (uid, _) = privilege::invoking_user();
+	let user = User::from_uid(uid)
+		.context("looking up the invoking user")?
+		.context("the invoking user does not exist")?;
+	Ok(user.dir.join("mnt").join(&fuse.name))
+}
+
+pub fn is_mounted(mount_path: &Path) -> Result<bool> {
+	Ok(mountinfo::find_by_mount_point(mount_path)?.is_some())
+}
+
+fn run_as_user(command: &mut Command) -> Result<()> {
+	let program = command.get_program().to_string_lossy().into_owned();
+	let status = command
+		.as_invoking_user()
+		.status()
+		.with_context(|| format!("running {program}"))?;
+	ensure!(
+		status.success(),
+		"{program} exited with status {:?}",
+		status.code()
+	);
+	Ok(())
+}
+
+/// How long to wait for a server to accept a connection, after which it is considered unreachable rather than letting the mount hang for minutes.
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Split a `host:port` address, where IPv6 hosts are in brackets.
+pub fn parse_address(address: &str) -> Option<(&str, u16)> {
+	let (host, port) = address.rsplit_once(':')?;
+	let host = host
+		.strip_prefix('[')
+		.and_then(|host| host.strip_suffix(']'))
+		.unwrap_or(host);
+	Some((host, port.parse().ok()?)).filter(|_| !host.is_empty())
+}
+
+/// The port given in sshfs's options, as `-p <port>` or `-o port=<port>`.
+fn ssh_port_option(options: &[String]) -> Option<&str> {
+	options.windows(2).find_map(|pair| match pair[0].as_str() {
+		"-p" => Some(pair[1].as_str()),
+		"-o" => pair[1].strip_prefix("port="),
+		_ => None,
+	})
+}
+
+/// Where sshfs will connect, as resolved by `ssh -G` from the user's SSH config, so that host aliases and ports set there are followed.
+///
+/// `None` if the connection goes through a proxy, in which case the server can't be reached directly anyway.
+fn ssh_address(fuse: &Fuse) -> Result<Option<(String, u16)>> {
+	// The source is `[user@]host:[path]`, where the host may be an IPv6 address in brackets.
+	let destination = match fuse.source.split_once("]:") {
+		Some((destination, _)) => format!("{destination}]"),
+		None => fuse
+			.source
+			.split_once(':')
+			.map_or(fuse.source.as_str(), |(destination, _)| destination)
+			.to_owned(),
+	};
+	let mut command = Command::new("ssh");
+	command.arg("-G");
+	if let Some(port) = ssh_port_option(&fuse.options) {
+		command.args(["-p", port]);
+	}
+	let output = command
+		.arg(destination.replace(['[', ']'], ""))
+		.as_invoking_user()
+		.output()
+		.context("running ssh -G")?;
+	ensure!(
+		output.status.success(),
+		"ssh -G exited with status {:?}",
+		output.status.code()
+	);
+
+	let (mut hostname, mut port) = (None, None);
+	for line in String::from_utf8_lossy(&output.stdout).lines() {
+		match line.split_once(' ') {
+			Some(("hostname", value)) => hostname = Some(value.to_owned()),
+			Some(("port", value)) => port = value.parse().ok(),
+			Some(("proxyjump" | "proxycommand", value)) if value != "none" => return Ok(None),
+			_ => {}
+		}
+	}
+	Ok(hostname.zip(port))
+}
+
+/// The server of a FUSE filesystem couldn't be reached, so mounting it would have hung.
+#[derive(Debug, thiserror::Error)]
+#[error("host unreachable: {host} port {port}: {reason}")]
+pub struct Unreachable {
+	host: String,
+	port: u16,
+	reason: String,
+}
+
+/// Connect to `host` on `port`, to fail quickly if it can't be reached.
+fn check_reachable(host: &str, port: u16) -> Result<(), Unreachable> {
+	let unreachable = |reason: String| Unreachable {
+		host: host.to_owned(),
+		port,
+		reason,
+	};
+	// Without a network, even resolving the name fails.
+	let addresses = (host, port)
+		.to_socket_addrs()
+		.map_err(|error| unreachable(format!("resolving failed: {error}")))?;
+	let mut last_error = None;
+	for address in addresses {
+		match TcpStream::connect_timeout(&address, REACHABILITY_TIMEOUT) {
+			Ok(_) => return Ok(()),
+			Err(error) => last_error = Some(error),
+		}
+	}
+	Err(unreachable(last_error.map_or_else(
+		|| "it has no addresses".to_owned(),
+		|error| error.to_string(),
+	)))
+}
+
+/// Check that the server behind `fuse` can be reached, if it has one that is known.
+fn check_server(fuse: &Fuse) -> Result<()> {
+	let address = if let Some(address) = &fuse.check_address {
+		parse_address(address).map(|(host, port)| (host.to_owned(), port))
+	} else if matches!(fuse.kind, Kind::Sshfs) {
+		ssh_address(fuse)?
+	} else {
+		None
+	};
+	match address {
+		Some((host, port)) => Ok(check_reachable(&host, port)?),
+		None => Ok(()),
+	}
+}
+
+/// Mount the filesystem at its mount path, returning whether it was already mounted.
+pub fn mount(fuse: &Fuse, mount_path: &Path) -> Result<bool> {
+	if is_mounted(mount_path)? {
+		return Ok(true);
+	}
+	check_server(fuse)?;
+
+	// Create the directory as the user so that it is owned by them even when running as root.
+	run_as_user(Command::new("mkdir").arg("-p").arg(mount_path)).context("creating mount path")?;
+
+	let mut command = Command::new(fuse.kind.program());
+	match fuse.kind {
+		Kind::Sshfs | Kind::Gocryptfs => {}
+		// Without this, rclone stays in the foreground until unmounted.
+		Kind::Rclone => {
+			command.args(["mount", "--daemon"]);
+		}
+	}
+	// gocryptfs requires options to come before the positional arguments, and the others accept them there too.
+	command
+		.args(&fuse.options)
+		.arg(&fuse.source)
+		.arg(mount_path);
+	run_as_user(&mut command)?;
+	Ok(false)
+}
+
+/// Unmount the filesystem, returning whether it was mounted.
+pub fn unmount(mount_path: &Path) -> Result<bool> {
+	if !is_mounted(mount_path)? {
+		return Ok(false);
+	}
+	run_as_user(Command::new("fusermount").arg("-u").arg(mount_path))?;
+	Ok(true)
+}
@@ -0,0 +1,406 @@
+//! A privileged helper that mounts and unmounts disks on behalf of unprivileged users, so that `d` itself doesn't need to run as root for everyday use.
+//!
+//! The client sends one request per connection to a Unix socket, as TOML, and reads one response. Disks are referred to only by name, and the helper uses its own copy of the config, so clients can't make it mount anything that isn't configured.
+
+use std::io::{Read as _, Write as _};
+use std::os::unix::fs::PermissionsExt as _;
+use std::os::unix::io::{AsRawFd as _, FromRawFd as _};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context as _, Result};
+use nix::sys::socket::getsockopt;
+use nix::sys::socket::sockopt::PeerCredentials;
+
+use crate::config::{self, Config, Disk};
+use crate::{state, unlock, MountOptions, MountReturn, Wait};
+
+pub const SOCKET_PATH: &str = "/run/d/helper.sock";
+/// The first file descriptor passed by systemd socket activation. See `sd_listen_fds(3)`.
+const LISTEN_FDS_START: i32 = 3;
+/// Far longer than any request, which holds little more than a disk name and maybe a passphrase.
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+/// How long a client may take to send its request or read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+enum Request {
+	Mount {
+		disk: String,
+		/// If not given, the disk must have a key file that the helper can use.
+		passphrase: Option<String>,
+		force_shadow: bool,
+		skip_check: bool,
+		forensic: bool,
+		degraded: bool,
+		allow_exec: bool,
+	},
+	Unmount {
+		disk: String,
+	},
+	/// Sessions are registered for the connecting process.
+	BeginSession {
+		disk: String,
+	},
+	EndSession {
+		disk: String,
+	},
+	/// Record that the disk was left mounted after a session, for `d finish`.
+	DeferUnmount {
+		disk: String,
+	},
+	SetSessionSetUp {
+		disk: String,
+		set_up: bool,
+	},
+}
+
+impl Request {
+	/// The action and disk, for the log. Not `Debug`, which would include the passphrase.
+	fn describe(&self) -> String {
+		let (action, disk) = match self {
+			Self::Mount { disk, .. } => ("mount", disk),
+			Self::Unmount { disk } => ("unmount", disk),
+			Self::BeginSession { disk } => ("begin session on", disk),
+			Self::EndSession { disk } => ("end session on", disk),
+			Self::DeferUnmount { disk } => ("defer unmount of", disk),
+			Self::SetSessionSetUp { disk, .. } => ("record session setup of", disk),
+		};
+		format!("{action} {disk}")
+	}
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Response {
+	error: Option<String>,
+	mount_path: Option<String>,
+	#[serde(default)]
+	was_already_mounted: bool,
+	/// For `EndSession`, how many other sessions are still using the disk.
+	#[serde(default)]
+	remaining_sessions: usize,
+	/// For `SetSessionSetUp`, whether the disk's session setup was in effect before.
+	#[serde(default)]
+	was_set_up: bool,
+}
+
+/// Whether a helper is listening, so that an unprivileged client can use it instead of becoming root.
+pub fn available() -> bool {
+	Path::new(SOCKET_PATH).exists()
+}
+
+fn send(request: &Request) -> Result<Response> {
+	let mut stream = UnixStream::connect(SOCKET_PATH).context("connecting to the helper")?;
+	let raw = toml::to_string(request).context("serializing request")?;
+	stream
+		.write_all(raw.as_bytes())
+		.context("sending request to the helper")?;
+	stream
+		.shutdown(std::net::Shutdown::Write)
+		.context("sending request to the helper")?;
+	let mut raw = String::new();
+	stream
+		.read_to_string(&mut raw)
+		.context("reading response from the helper")?;
+	let response: Response = toml::from_str(&raw).context("parsing response from the helper")?;
+	if let Some(error) = response.error {
+		bail!("the helper failed: {error}");
+	}
+	Ok(response)
+}
+
+/// Mount a disk through the helper. The key is obtained here, as the user, so that prompts and the user's own secrets work.
+pub fn mount(config: &Config, disk: &Disk, options: MountOptions) -> Result<MountReturn> {
+	let passphrase = if disk.is_encrypted() && !crate::device_present(&disk.uuid)? {
+		match unlock::key_for(config, disk)? {
+			unlock::Key::Passphrase { passphrase, .. } => Some(passphrase),
+			// The key file is read by the helper, which can also read files that the user can't.
+			unlock::Key::File(..) => None,
+			unlock::Key::Prompt => {
+				let prompt = format!("passphrase for {}: ", disk.as_repr());
+				Some(
+					crate::passphrase::ask(&prompt, config.askpass.as_deref())?.ok_or_else(|| {
+						crate::prompt::NeedsInteraction(format!(
+						"{} needs a passphrase, but there is no terminal to prompt on or d is non-interactive",
+						disk.as_repr()
+					))
+					})?,
+				)
+			}
+		}
+	} else {
+		None
+	};
+
+	let response = send(&Request::Mount {
+		disk: disk.name.clone(),
+		passphrase,
+		force_shadow: options.force_shadow,
+		skip_check: options.skip_check,
+		forensic: options.forensic,
+		degraded: options.degraded,
+		allow_exec: options.allow_exec,
+	})?;
+	Ok(MountReturn {
+		mount_path: response
+			.mount_path
+			.context("the helper did not say where it mounted the disk")?,
+		was_already_mounted: response.was_already_mounted,
+	})
+}
+
+pub fn unmount(disk: &Disk) -> Result<()> {
+	send(&Request::Unmount {
+		disk: disk.name.clone(),
+	})
+	.map(drop)
+}
+
+pub fn begin_session(disk_name: &str) -> Result<()> {
+	send(&Request::BeginSession {
+		disk: disk_name.to_owned(),
+	})
+	.map(drop)
+}
+
+/// Returns how many other sessions are still using the disk.
+pub fn end_session(disk_name: &str) -> Result<usize> {
+	send(&Request::EndSession {
+		disk: disk_name.to_owned(),
+	})
+	.map(|response| response.remaining_sessions)
+}
+
+pub fn defer_unmount(disk_name: &str) -> Result<()> {
+	send(&Request::DeferUnmount {
+		disk: disk_name.to_owned(),
+	})
+	.map(drop)
+}
+
+/// Returns whether the disk's session setup was in effect before.
+pub fn set_session_set_up(disk_name: &str, set_up: bool) -> Result<bool> {
+	send(&Request::SetSessionSetUp {
+		disk: disk_name.to_owned(),
+		set_up,
+	})
+	.map(|response| response.was_set_up)
+}
+
+/// Mount a disk for a client of the helper or the D-Bus service, who gave `passphrase`. Without one, only a key file from the config can be used, since there is no terminal to prompt on.
+pub fn mount_for_client(
+	config: &Config,
+	disk_name: &str,
+	passphrase: Option<String>,
+	source: &'static str,
+	options: MountOptions,
+) -> Result<MountReturn> {
+	let disk = config.disk(disk_name)?;
+	let get_key = || match passphrase {
+		Some(passphrase) => Ok(unlock::Key::Passphrase { passphrase, source }),
+		None => match unlock::key_for(config, disk)? {
+			key @ unlock::Key::File(..) => Ok(key),
+			_ => bail!("no passphrase was given, and the disk has no key file"),
+		},
+	};
+	crate::mount_with_key(config, disk, options, get_key)
+}
+
+/// Unmount a disk for a client of the helper or the D-Bus service.
+pub fn unmount_for_client(config: &Config, disk_name: &str) -> Result<()> {
+	let disk = config.disk(disk_name)?;
+	let sessions = state::session_count(disk.as_repr())?;
+	ensure!(
+		sessions == 0,
+		"{} is in use by {sessions} `d c` session(s)",
+		disk.as_repr()
+	);
+	// Only the configured policy applies, since flags like `--terminate` would let users kill others' processes.
+	crate::unmount_when_done(config, disk, config.unmount)
+}
+
+/// Whether the user with this ID is in `helper_users`.
+pub fn is_allowed(config: &Config, uid: u32) -> Result<bool> {
+	let user =
+		nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid)).context("looking up user")?;
+	Ok(user.is_some_and(|user| config.helper_users.contains(&user.name)))
+}
+
+fn handle(request: Request, peer_pid: i32) -> Result<Response> {
+	// Load the config for every request so that edits take effect without restarting the helper.
+	let config = config::load()?;
+	crate::devlink::set_timeout(
+		config
+			.resolution_timeout_seconds
+			.unwrap_or(crate::devlink::DEFAULT_TIMEOUT_SECONDS),
+	);
+	let mut response = Response::default();
+	match request {
+		Request::Mount {
+			disk,
+			passphrase,
+			force_shadow,
+			skip_check,
+			forensic,
+			degraded,
+			allow_exec,
+		} => {
+			let options = MountOptions {
+				force_shadow,
+				skip_check,
+				forensic,
+				read_only: false,
+				transient: false,
+				degraded,
+				allow_exec,
+				wait: Wait::No,
+			};
+			let MountReturn {
+				mount_path,
+				was_already_mounted,
+			} = mount_for_client(&config, &disk, passphrase, "the client", options)?;
+			response.mount_path = Some(mount_path);
+			response.was_already_mounted = was_already_mounted;
+		}
+		Request::Unmount { disk } => unmount_for_client(&config, &disk)?,
+		Request::BeginSession { disk } => {
+			state::begin_session(config.disk(&disk)?.as_repr(), peer_pid)?;
+		}
+		Request::EndSession { disk } => {
+			response.remaining_sessions = state::end_session(config.disk(&disk)?.as_repr(), peer_pid)?;
+		}
+		Request::DeferUnmount { disk } => {
+			state::defer_unmount(config.disk(&disk)?.as_repr())?;
+		}
+		Request::SetSessionSetUp { disk, set_up } => {
+			response.was_set_up = state::set_session_set_up(config.disk(&disk)?.as_repr(), set_up)?;
+		}
+	}
+	Ok(response)
+}
+
+/// Anyone can connect, so a client must not be able to tie up a thread or memory for long. The user is checked before anything is read.
+fn serve_connection(mut stream: UnixStream, allowed_users: &[String]) -> Result<()> {
+	stream
+		.set_read_timeout(Some(REQUEST_TIMEOUT))
+		.and_then(|()| stream.set_write_timeout(Some(REQUEST_TIMEOUT)))
+		.context("setting timeouts")?;
+	let credentials =
+		getsockopt(stream.as_raw_fd(), PeerCredentials).context("getting peer credentials")?;
+	let user = nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(credentials.uid()))
+		.context("looking up peer user")?
+		.context("peer user does not exist")?;
+
+	let response = if allowed_users.contains(&user.name) {
+		let mut raw = String::new();
+		(&stream)
+			.take(MAX_REQUEST_LEN + 1)
+			.read_to_string(&mut raw)
+			.context("reading request")?;
+		let result = check_request_len(&raw)
+			.and_then(|()| toml::from_str::<Request>(&raw).context("parsing request"))
+			.and_then(|request| {
+				eprintln!(
+					"request from {} (pid {}): {}",
+					user.name,
+					credentials.pid(),
+					request.describe(),
+				);
+				handle(request, credentials.pid())
+			});
+		result.unwrap_or_else(|error| Response {
+			error: Some(format!("{error:#}")),
+			..Response::default()
+		})
+	} else {
+		eprintln!(
+			"refusing request from {}, who is not in helper_users.",
+			user.name
+		);
+		Response {
+			error: Some(format!("{} is not allowed to use the helper", user.name)),
+			..Response::default()
+		}
+	};
+
+	let raw = toml::to_string(&response).context("serializing response")?;
+	stream.write_all(raw.as_bytes()).context("sending response")
+}
+
+/// Requests are read up to one byte past the limit, so that one that is too long fails here rather than being parsed in part.
+fn check_request_len(raw: &str) -> Result<()> {
+	ensure!(
+		raw.len() as u64 <= MAX_REQUEST_LEN,
+		"the request is longer than {MAX_REQUEST_LEN} bytes"
+	);
+	Ok(())
+}
+
+/// Use the socket from systemd socket activation if there is one, or create it.
+fn listener() -> Result<UnixListener> {
+	let is_activated = std::env::var("LISTEN_PID").ok() == Some(std::process::id().to_string())
+		&& std::env::var("LISTEN_FDS").ok().as_deref() == Some("1");
+	if is_activated {
+		// SAFETY: systemd passes the listening socket as this file descriptor, and nothing else uses it.
+		return Ok(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) });
+	}
+
+	std::fs::create_dir_all(
+		Path::new(SOCKET_PATH)
+			.parent()
+			.expect("socket path has a parent"),
+	)
+	.context("creating socket directory")?;
+	let _ = std::fs::remove_file(SOCKET_PATH);
+	let listener = UnixListener::bind(SOCKET_PATH).context("binding socket")?;
+	// Access is controlled by `helper_users`, which is checked against the peer's credentials.
+	std::fs::set_permissions(SOCKET_PATH, std::fs::Permissions::from_mode(0o666))
+		.context("setting socket permissions")?;
+	Ok(listener)
+}
+
+/// Serve requests forever, one thread per connection.
+pub fn serve() -> Result<()> {
+	crate::prompt::set_askpass_allowed(false);
+	let listener = listener()?;
+	let config = config::load()?;
+	if let Some(address) = config.metrics_address {
+		std::thread::spawn(move || {
+			if let Err(error) = crate::metrics::serve(&address) {
+				crate::output::warning(format_args!("metrics stopped: {error:#}"));
+			}
+		});
+	}
+	if config.dbus_service {
+		std::thread::spawn(|| {
+			if let Err(error) = crate::service::run() {
+				crate::output::warning(format_args!("D-Bus service stopped: {error:#}"));
+			}
+		});
+	}
+	if let Some(minutes) = config.lock_idle_after_minutes {
+		std::thread::spawn(move || crate::autolock::run(Duration::from_secs(u64::from(minutes) * 60)));
+	}
+	eprintln!("helper listening on {SOCKET_PATH}.");
+	for stream in listener.incoming() {
+		let stream = match stream {
+			Ok(stream) => stream,
+			Err(error) => {
+				crate::output::warning(format_args!("failed to accept connection: {error}"));
+				continue;
+			}
+		};
+		std::thread::spawn(move || {
+			let allowed_users = config::load()
+				.map(|config| config.helper_users)
+				.unwrap_or_default();
+			if let Err(error) = serve_connection(stream, &allowed_users) {
+				crate::output::warning(format_args!("failed to serve request: {error:#}"));
+			}
+		});
+	}
+	Ok(())
+}
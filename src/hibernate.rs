@@ -0,0 +1,63 @@
+//! Keeping encryption keys out of hibernation images, from a systemd-sleep hook.
+//!
+//! The hook is used rather than a logind inhibitor because it is told whether the system is suspending or hibernating, and only hibernation writes memory to disk.
+
+use anyhow::Result;
+
+use crate::config::Config;
+use crate::{output, DiskState};
+
+/// What to do with an encrypted disk that is open when the system hibernates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+	/// Log a warning, since the key and cached data end up in the hibernation image.
+	#[default]
+	Warn,
+	/// Unmount the disk and close its encryption first.
+	Unmount,
+	Ignore,
+}
+
+/// The operations that systemd-sleep passes to hooks that write memory to disk.
+fn is_hibernation(operation: &str) -> bool {
+	matches!(
+		operation,
+		"hibernate" | "hybrid-sleep" | "suspend-then-hibernate"
+	)
+}
+
+/// Handle a call from systemd-sleep, which runs hooks with `pre` or `post` and the operation. See `systemd-sleep(8)`.
+pub fn run_hook(config: &Config, phase: &str, operation: &str) -> Result<()> {
+	if phase != "pre" || !is_hibernation(operation) {
+		return Ok(());
+	}
+	for disk in config.disks.iter().filter(|disk| disk.is_encrypted()) {
+		let state = crate::disk_state(disk)?;
+		if !matches!(state, DiskState::Mounted | DiskState::Open) {
+			continue;
+		}
+		let disk_name = disk.as_repr();
+		match disk.on_hibernate {
+			Policy::Ignore => {}
+			Policy::Warn => output::warning(format_args!(
+				"{disk_name} is {} while hibernating, so its encryption key will be in the hibernation image.",
+				state.as_repr()
+			)),
+			Policy::Unmount => {
+				let res = if state == DiskState::Open {
+					crate::lock(disk)
+				} else {
+					crate::unmount_when_done(config, disk, config.unmount)
+				};
+				match res {
+					Ok(()) => eprintln!("unmounted {disk_name} before hibernating."),
+					Err(error) => output::warning(format_args!(
+						"failed to unmount {disk_name} before hibernating, so its encryption key will be in the hibernation image: {error:#}"
+					)),
+				}
+			}
+		}
+	}
+	Ok(())
+}
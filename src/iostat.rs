@@ -0,0 +1,134 @@
+//! I/O statistics of block devices from `/proc/diskstats`, and how much data is still waiting to be written.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use nix::sys::stat::{major, minor};
+
+use crate::{output, sysfs};
+
+/// `/proc/diskstats` always counts in 512-byte sectors, regardless of the device's actual sector size.
+const SECTOR_SIZE: u64 = 512;
+/// How often to recompute the write rate shown while flushing, so that it doesn't jump around.
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+	sectors_read: u64,
+	sectors_written: u64,
+}
+
+impl Counters {
+	pub fn bytes_read(self) -> u64 {
+		self.sectors_read * SECTOR_SIZE
+	}
+
+	pub fn bytes_written(self) -> u64 {
+		self.sectors_written * SECTOR_SIZE
+	}
+}
+
+/// The counters of every block device since boot, keyed by kernel name.
+pub fn read() -> Result<HashMap<String, Counters>> {
+	let raw = std::fs::read_to_string("/proc/diskstats").context("reading /proc/diskstats")?;
+	Ok(
+		raw
+			.lines()
+			.filter_map(|line| {
+				let fields: Vec<&str> = line.split_whitespace().collect();
+				let counters = Counters {
+					sectors_read: fields.get(5)?.parse().ok()?,
+					sectors_written: fields.get(9)?.parse().ok()?,
+				};
+				Some(((*fields.get(2)?).to_owned(), counters))
+			})
+			.collect(),
+	)
+}
+
+/// The sum of the fields named `keys` in a file of lines like `Dirty:  1234 kB`, in bytes.
+fn sum_kilobyte_fields(path: &str, keys: &[&str]) -> Option<u64> {
+	let raw = std::fs::read_to_string(path).ok()?;
+	let mut sum = 0;
+	for key in keys {
+		let kilobytes: u64 = raw.lines().find_map(|line| {
+			let rest = line.strip_prefix(key)?.strip_prefix(':')?;
+			rest.split_whitespace().next()?.parse().ok()
+		})?;
+		sum += kilobytes * 1024;
+	}
+	Some(sum)
+}
+
+/// Bytes of dirty and in-flight pages on the device, and whether the count is for the device alone.
+///
+/// The count for a single device is only available if debugfs is mounted; otherwise, the count for the whole system is returned.
+pub fn unwritten_bytes(kernel_name: &str) -> Option<(u64, bool)> {
+	if let Ok(number) = sysfs::device_number(kernel_name) {
+		let path = format!(
+			"/sys/kernel/debug/bdi/{}:{}/stats",
+			major(number),
+			minor(number)
+		);
+		if let Some(bytes) = sum_kilobyte_fields(&path, &["BdiWriteback", "BdiReclaimable"]) {
+			return Some((bytes, true));
+		}
+	}
+	sum_kilobyte_fields("/proc/meminfo", &["Dirty", "Writeback"]).map(|bytes| (bytes, false))
+}
+
+/// Describe how much is left to write to the device and how fast it is being written, such as `1.2G left, writing 45.0M/s`.
+pub fn describe_unwritten(kernel_name: &str, rate: Option<u64>) -> Option<String> {
+	let (bytes, is_per_device) = unwritten_bytes(kernel_name)?;
+	let scope = if is_per_device { "" } else { " system-wide" };
+	let mut description = format!("{}{scope} left", output::format_size(bytes));
+	if let Some(rate) = rate {
+		let _ = write!(description, ", writing {}/s", output::format_size(rate));
+	}
+	Some(description)
+}
+
+/// Tracks the write rate of a device between calls to `describe`, for showing progress while flushing.
+pub struct FlushProgress {
+	kernel_name: String,
+	previous: Option<(Instant, u64)>,
+	rate: Option<u64>,
+}
+
+impl FlushProgress {
+	pub fn new(kernel_name: String) -> Self {
+		Self {
+			kernel_name,
+			previous: None,
+			rate: None,
+		}
+	}
+
+	pub fn describe(&mut self) -> Option<String> {
+		let is_due = self
+			.previous
+			.is_none_or(|(time, _)| time.elapsed() >= RATE_INTERVAL);
+		if is_due {
+			let written = read()
+				.ok()?
+				.get(&self.kernel_name)
+				.copied()
+				.unwrap_or_default()
+				.bytes_written();
+			if let Some((time, previous_written)) = self.previous {
+				#[allow(
+					clippy::cast_possible_truncation,
+					clippy::cast_precision_loss,
+					clippy::cast_sign_loss
+				)] // Only used for display.
+				let rate =
+					(written.saturating_sub(previous_written) as f64 / time.elapsed().as_secs_f64()) as u64;
+				self.rate = Some(rate);
+			}
+			self.previous = Some((Instant::now(), written));
+		}
+		describe_unwritten(&self.kernel_name, self.rate)
+	}
+}
@@ -0,0 +1,66 @@
+//! Reading the kernel log from `/dev/kmsg`, which is often the only place that says why something like a mount failed.
+//!
+//! See `Documentation/ABI/testing/dev-kmsg` in the kernel for the format.
+
+use std::fs::{File, OpenOptions};
+use std::io::Read as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::os::unix::io::AsRawFd as _;
+
+use nix::errno::Errno;
+use nix::unistd::{lseek, Whence};
+
+const KMSG_PATH: &str = "/dev/kmsg";
+/// Every record fits in this, since the kernel limits them to 8 KiB including the prefix.
+const MAX_RECORD_LEN: usize = 8192;
+
+pub struct Cursor {
+	file: File,
+}
+
+impl Cursor {
+	/// Start at the end of the log, so that only messages logged from now on are read.
+	///
+	/// Returns `None` if the log can't be read, such as without `CAP_SYSLOG` when `kernel.dmesg_restrict` is set.
+	pub fn at_end() -> Option<Self> {
+		let cursor = Self::at_start()?;
+		lseek(cursor.file.as_raw_fd(), 0, Whence::SeekEnd).ok()?;
+		Some(cursor)
+	}
+
+	/// Start at the oldest message that the kernel still has. See `at_end`.
+	pub fn at_start() -> Option<Self> {
+		let file = OpenOptions::new()
+			.read(true)
+			.custom_flags(nix::libc::O_NONBLOCK)
+			.open(KMSG_PATH)
+			.ok()?;
+		Some(Self { file })
+	}
+
+	/// The messages logged since the cursor was created or last read.
+	pub fn read_new(&mut self) -> Vec<String> {
+		let mut messages = Vec::new();
+		let mut buf = vec![0; MAX_RECORD_LEN];
+		loop {
+			// Each read returns exactly one record.
+			match self.file.read(&mut buf) {
+				Ok(0) => break,
+				Ok(len) => messages.extend(parse_record(&buf[..len])),
+				// Some records were overwritten before we read them; the next read continues after them.
+				Err(error) if error.raw_os_error() == Some(Errno::EPIPE as i32) => {}
+				// `EAGAIN` once there are no more records.
+				Err(_) => break,
+			}
+		}
+		messages
+	}
+}
+
+/// Extract the message from a record like `6,1234,5678,-;EXT4-fs (dm-0): mounted filesystem`.
+fn parse_record(record: &[u8]) -> Option<String> {
+	let record = String::from_utf8_lossy(record);
+	let (_prefix, rest) = record.split_once(';')?;
+	// Any further lines are `KEY=value` metadata.
+	Some(rest.lines().next()?.to_owned())
+}
@@ -0,0 +1,95 @@
+//! Showing and changing filesystem labels.
+
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::os::unix::io::AsRawFd as _;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::caps;
+
+/// See `include/uapi/linux/fs.h`.
+const FSLABEL_MAX: usize = 256;
+
+nix::ioctl_read!(fs_ioc_getfslabel, 0x94, 49, [u8; FSLABEL_MAX]);
+nix::ioctl_write_ptr!(fs_ioc_setfslabel, 0x94, 50, [u8; FSLABEL_MAX]);
+
+/// The label of the filesystem mounted at `mount_path`, for filesystems that support the generic label ioctls (ext4, btrfs, and xfs).
+pub fn get_mounted(mount_path: &Path) -> Result<String> {
+	let dir = std::fs::File::open(mount_path).context("opening mount path")?;
+	let mut raw = [0; FSLABEL_MAX];
+	// SAFETY: FS_IOC_GETFSLABEL writes at most `FSLABEL_MAX` bytes, including the NUL terminator.
+	unsafe { fs_ioc_getfslabel(dir.as_raw_fd(), std::ptr::addr_of_mut!(raw)) }
+		.context("getting filesystem label")?;
+	let len = raw
+		.iter()
+		.position(|&byte| byte == 0)
+		.unwrap_or(FSLABEL_MAX);
+	Ok(String::from_utf8_lossy(&raw[..len]).into_owned())
+}
+
+/// Change the label of the filesystem mounted at `mount_path`. The filesystem checks the length itself, since each has its own limit.
+pub fn set_mounted(mount_path: &Path, label: &str) -> Result<()> {
+	ensure!(label.len() < FSLABEL_MAX, "the label is too long");
+	let dir = std::fs::File::open(mount_path).context("opening mount path")?;
+	let mut raw = [0; FSLABEL_MAX];
+	raw[..label.len()].copy_from_slice(label.as_bytes());
+	// SAFETY: FS_IOC_SETFSLABEL reads a NUL-terminated label of at most `FSLABEL_MAX` bytes.
+	unsafe { fs_ioc_setfslabel(dir.as_raw_fd(), std::ptr::addr_of!(raw)) }
+		.context("setting filesystem label")?;
+	Ok(())
+}
+
+/// Change the label of the unmounted `filesystem` on `dev_path` with the filesystem's own tool.
+pub fn set_unmounted(dev_path: &Path, filesystem: &str, label: &str) -> Result<()> {
+	let dev_path = dev_path.as_os_str();
+	let label = OsStr::new(label);
+	let (program, args): (&str, Vec<&OsStr>) = match filesystem {
+		"ext2" | "ext3" | "ext4" => ("e2label", vec![dev_path, label]),
+		"btrfs" => (
+			"btrfs",
+			vec!["filesystem".as_ref(), "label".as_ref(), dev_path, label],
+		),
+		// xfs_admin clears the label when given `--`.
+		"xfs" => (
+			"xfs_admin",
+			vec![
+				"-L".as_ref(),
+				if label.is_empty() {
+					"--".as_ref()
+				} else {
+					label
+				},
+				dev_path,
+			],
+		),
+		"vfat" => ("fatlabel", vec![dev_path, label]),
+		"exfat" => ("exfatlabel", vec![dev_path, label]),
+		"ntfs" | "ntfs3" => ("ntfslabel", vec![dev_path, label]),
+		_ => bail!("changing the label of {filesystem} filesystems is not supported"),
+	};
+	let status = caps::tool(program)
+		.args(args)
+		.status()
+		.with_context(|| format!("running {program}"))?;
+	ensure!(
+		status.success(),
+		"{program} exited with status {:?}",
+		status.code()
+	);
+	Ok(())
+}
+
+/// The path of the symlink that udev makes for a filesystem with this label, escaped the way udev does.
+pub fn by_label_path(label: &str) -> String {
+	let mut path = "/dev/disk/by-label/".to_owned();
+	for ch in label.chars() {
+		if ch.is_ascii_alphanumeric() || "#+-.:=@_".contains(ch) || !ch.is_ascii() {
+			path.push(ch);
+		} else {
+			let _ = write!(path, "\\x{:02x}", u32::from(ch));
+		}
+	}
+	path
+}
@@ -17,329 +17,3626 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::let_underscore_drop)]
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, ensure, Context as _, Result};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+
+use crate::config::{Config, Disk, Mountable};
+
+mod add;
+mod askpass;
+mod autolock;
+mod backup;
+mod batch;
+mod blkid;
+mod btrfs;
+mod canary;
+mod caps;
+mod cgroup;
+mod cleanup;
+mod clone;
+mod config;
+mod dash;
+mod dbus;
+mod degraded;
+mod devlink;
+mod export;
+mod ext4;
+mod flush;
+mod format;
+mod fsck;
+mod fuse;
+mod helper;
+mod hibernate;
+mod iostat;
+mod kmsg;
+mod label;
+mod manifest;
+mod metrics;
+mod mountinfo;
+mod output;
+mod passphrase;
+mod power;
+mod privilege;
+mod progress;
+mod prompt;
+mod quota;
+mod remote;
+mod resize;
+mod rsync;
+mod sandbox;
+mod secret;
+mod selinux;
+mod service;
+mod shell;
+mod snapshot;
+mod space;
+mod state;
+mod stats;
+mod statusbar;
+mod swap;
+mod sysfs;
+mod systemd;
+mod tmux;
+mod unlock;
+mod unmount;
+mod verity;
+mod watch;
+mod watchdog;
+mod wipe;
 
 /// Manage disk mounting
 #[derive(Debug, argh::FromArgs)]
 struct Args {
-	#[argh(positional)]
+	/// run on another machine over SSH, using the `d` installed there
+	#[argh(option)]
+	host: Option<String>,
+
+	/// use this profile from the config instead of the one named after this machine's hostname
+	#[argh(option)]
+	profile: Option<String>,
+
+	/// go ahead with risky operations, such as wiping disks or mounting them degraded, without asking for confirmation
+	#[argh(switch)]
+	yes: bool,
+
+	/// never prompt or wait for a person, failing instead. this is the default when stderr is not a terminal
+	#[argh(switch)]
+	non_interactive: bool,
+
+	#[argh(subcommand)]
 	action: Action,
+}
+
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum Action {
+	Mount(MountArgs),
+	Unmount(UnmountArgs),
+	Cd(CdArgs),
+	Eject(EjectArgs),
+	Lock(LockArgs),
+	Pending(PendingArgs),
+	Finish(FinishArgs),
+	List(ListArgs),
+	Watch(WatchArgs),
+	Info(InfoArgs),
+	Tree(TreeArgs),
+	Dash(DashArgs),
+	Add(AddArgs),
+	Remove(RemoveArgs),
+	Rename(RenameArgs),
+	Label(LabelArgs),
+	Config(ConfigArgs),
+	Scrub(ScrubArgs),
+	Backup(BackupArgs),
+	Sync(SyncArgs),
+	Manifest(ManifestArgs),
+	Verify(VerifyArgs),
+	Snapshots(SnapshotsArgs),
+	Quota(QuotaArgs),
+	Resize(ResizeArgs),
+	Format(FormatArgs),
+	Wipe(WipeArgs),
+	Clone(CloneArgs),
+	Cleanup(CleanupArgs),
+	Export(ExportArgs),
+	Helper(HelperArgs),
+	SleepHook(SleepHookArgs),
+}
+
+/// Mount disks. Groups, composites, and `all` can be given too, and disks are mounted in parallel where their dependencies allow.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "m")]
+#[allow(clippy::struct_excessive_bools)] // Independent switches.
+struct MountArgs {
+	#[argh(positional)]
+	disks: Vec<String>,
+
+	/// also mount every disk with this tag. can be given more than once
+	#[argh(option)]
+	tag: Vec<String>,
+
+	/// mount even if the mount path contains files, which will be hidden until the disk is unmounted
+	#[argh(switch)]
+	force_shadow: bool,
+
+	/// don't run a filesystem check, even if one is due
+	#[argh(switch)]
+	skip_check: bool,
+
+	/// if the device is not attached, wait for it to appear instead of failing
+	#[argh(switch)]
+	wait: bool,
+
+	/// with --wait, give up after this many seconds
+	#[argh(option)]
+	wait_timeout: Option<u64>,
+
+	/// open encrypted disks read-only and mount them without replaying the journal, so that nothing on them is modified
+	#[argh(switch)]
+	forensic: bool,
+
+	/// also mount this snapshot of the disk (or `latest`) read-only at /mnt/<disk>-snap
+	#[argh(option)]
+	snapshot: Option<String>,
+
+	/// mount even if a RAID array that the disk is on is missing devices, without asking
+	#[argh(switch)]
+	degraded: bool,
+
+	/// mount an untrusted disk without noexec and nosymfollow, so that programs on it can be run
+	#[argh(switch)]
+	allow_exec: bool,
+
+	/// skip FUSE filesystems on servers, such as sshfs and rclone, for when there is no network
+	#[argh(switch)]
+	local_only: bool,
+}
+
+/// Unmount disks. Groups, composites, and `all` can be given too, and disks are unmounted before their dependencies.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "u")]
+struct UnmountArgs {
+	#[argh(positional)]
+	disks: Vec<String>,
+
+	/// also unmount every disk with this tag. can be given more than once
+	#[argh(option)]
+	tag: Vec<String>,
+
+	/// unmount every disk that d mounted, most recent first, continuing past failures
+	#[argh(switch)]
+	all: bool,
+
+	/// if a disk is busy, send SIGTERM to the processes using it and try again
+	#[argh(switch)]
+	terminate: bool,
+
+	/// if a disk is still busy, detach it so that it is unmounted once nothing uses it
+	#[argh(switch)]
+	lazy: bool,
+}
+
+/// Unmount a disk, close its encryption, and power off its drive, saying when it is safe to unplug.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "e")]
+struct EjectArgs {
+	#[argh(positional)]
+	disk: String,
+
+	/// if the disk is busy, send SIGTERM to the processes using it and try again
+	#[argh(switch)]
+	terminate: bool,
+}
+
+/// Close the encryption of a disk that is open but not mounted, such as after a failed unmount.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "lock")]
+struct LockArgs {
+	#[argh(positional)]
+	disk: String,
+}
+
+/// Show the disks that `d c` sessions left mounted, to be unmounted later by `d finish`.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "pending")]
+struct PendingArgs {}
+
+/// Unmount, and close the encryption of, every disk that `d c` sessions left mounted.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "finish")]
+struct FinishArgs {}
+
+/// Mount a disk, enter a subshell inside it, and unmount it when the subshell exits.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "c")]
+#[allow(clippy::struct_excessive_bools)] // Independent switches.
+struct CdArgs {
+	#[argh(positional)]
+	disk: String,
+
+	/// mount even if the mount path contains files, which will be hidden until the disk is unmounted
+	#[argh(switch)]
+	force_shadow: bool,
+
+	/// don't run a filesystem check, even if one is due
+	#[argh(switch)]
+	skip_check: bool,
+
+	/// if the device is not attached, wait for it to appear instead of failing
+	#[argh(switch)]
+	wait: bool,
+
+	/// with --wait, give up after this many seconds
+	#[argh(option)]
+	wait_timeout: Option<u64>,
+
+	/// mount even if a RAID array that the disk is on is missing devices, without asking
+	#[argh(switch)]
+	degraded: bool,
+
+	/// run the subshell as this user instead of the one who ran d (root only)
+	#[argh(option)]
+	user: Option<String>,
+
+	/// mount an untrusted disk without noexec and nosymfollow, so that programs on it can be run
+	#[argh(switch)]
+	allow_exec: bool,
+
+	/// run the shell in a tmux session named after the disk, creating it or attaching to it. the disk stays mounted after detaching, until the tmux session ends
+	#[argh(switch)]
+	tmux: bool,
+
+	/// limit reads and writes to the disk from the subshell to this many MiB per second each, overriding the disk's `io_limit_mib_per_sec`
+	#[argh(option)]
+	io_limit: Option<u32>,
+
+	/// leave the disk mounted when the subshell exits
+	#[argh(switch)]
+	keep: bool,
+}
+
+/// List all disks and their current state, and the `d c` sessions that are open on them.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "list")]
+struct ListArgs {
+	/// also show usage statistics
+	#[argh(switch, short = 'v')]
+	verbose: bool,
+
+	/// only show disks with this tag
+	#[argh(option)]
+	tag: Option<String>,
+
+	/// how to order the disks: "config" (the default) or "recent" (most recently used first)
+	#[argh(option, default = "ListSort::Config")]
+	sort: ListSort,
+
+	/// print one line of JSON for a status bar instead of a table: "waybar" or "i3blocks"
+	#[argh(option)]
+	format: Option<statusbar::Format>,
+}
+
+/// Print the state of every disk, and then each change as it happens, until interrupted.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "watch")]
+struct WatchArgs {
+	/// print each change as a line of JSON, with the disk, its new state, and its previous state (null at first)
+	#[argh(switch)]
+	json: bool,
+}
+
+/// Show everything known about how a disk is resolved and mounted, for debugging.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "info")]
+struct InfoArgs {
+	#[argh(positional)]
+	disk: String,
+}
+
+/// Show the usage and limits of each user, group, or project on a mounted disk with quotas.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "quota")]
+struct QuotaArgs {
+	#[argh(positional)]
+	disk: String,
+}
+
+/// Back up a disk with its configured borg or restic command, mounting it first and unmounting it afterward if needed.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "backup")]
+struct BackupArgs {
+	#[argh(positional)]
+	disk: String,
+}
+
+/// List the snapshots of a mounted btrfs disk, or take, prune, or mount them.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "snapshots")]
+struct SnapshotsArgs {
+	#[argh(positional)]
+	disk: String,
+
+	/// take a snapshot now
+	#[argh(switch)]
+	take: bool,
+
+	/// delete the snapshots that the configured retention doesn't keep
+	#[argh(switch)]
+	prune: bool,
+
+	/// with --prune, only show what would be deleted
+	#[argh(switch)]
+	dry_run: bool,
+
+	/// mount this snapshot (or `latest`) read-only at /mnt/<disk>-snap
+	#[argh(option)]
+	mount: Option<String>,
+}
+
+/// Scrub a mounted btrfs disk, verifying the checksum of every block, and wait for it to finish.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "scrub")]
+struct ScrubArgs {
+	#[argh(positional)]
+	disk: String,
+}
+
+/// Grow a mounted disk's LUKS mapping and filesystem to fill its partition, after enlarging the partition.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "resize")]
+struct ResizeArgs {
+	#[argh(positional)]
+	disk: String,
+
+	/// only print the sizes and the steps that would be run
+	#[argh(switch, short = 'n')]
+	dry_run: bool,
+}
+
+/// Close orphaned mappings, remove stale mount directories, and clear stale runtime state, such as after a crash.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "cleanup")]
+struct CleanupArgs {
+	/// only report what would be cleaned up
+	#[argh(switch, short = 'n')]
+	dry_run: bool,
+}
+
+/// Print fstab or crypttab entries for disks, for moving them to mounting at boot. Groups, composites, and `all` can be given too, and the default is all disks.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "export")]
+struct ExportArgs {
+	/// the format to print: fstab or crypttab
+	#[argh(positional)]
+	format: export::Format,
+
+	#[argh(positional)]
+	disks: Vec<String>,
+}
+
+/// Run the privileged helper, which mounts and unmounts disks for the users in `helper_users`. Meant to be started by systemd.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "helper")]
+struct HelperArgs {}
+
+/// Handle encrypted disks before the system hibernates, according to their `on_hibernate`. Called by systemd-sleep through `systemd/d-sleep-hook`.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "sleep-hook")]
+struct SleepHookArgs {
+	/// the phase, pre or post
+	#[argh(positional)]
+	phase: String,
+
+	/// such as `suspend` or `hibernate`
+	#[argh(positional)]
+	operation: String,
+}
+
+/// Show each disk's stack of devices, from the physical device up to the mount point.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "tree")]
+struct TreeArgs {}
+
+/// Interactively register a new disk in the config file.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "add")]
+struct AddArgs {}
+
+/// Create a filesystem, optionally inside a new LUKS2 container, on a blank device and add it to the config. This destroys everything on the device.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "format")]
+struct FormatArgs {
+	/// the device to format, such as /dev/sdb1
+	#[argh(positional)]
+	device: PathBuf,
+}
+
+/// Show a live overview of every disk: its state, free space, I/O rates, temperature (as root), and anything that needs attention. Press q to quit.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "dash")]
+struct DashArgs {}
+
+/// Remove a disk from the config file. The disk must not be mounted or open.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "remove")]
+struct RemoveArgs {
+	#[argh(positional)]
+	disk: String,
+}
+
+/// Irreversibly erase a disk and remove it from the config file. The disk must not be mounted or open.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "wipe")]
+struct WipeArgs {
+	#[argh(positional)]
+	disk: String,
+
+	/// how to erase the disk: "discard" (for SSDs), "ata" (ATA Secure Erase), or "crypto" (destroy the LUKS keyslots)
+	#[argh(option)]
+	method: wipe::Method,
+
+	/// the name of the disk, to confirm without typing it, even for the whole drive with --method ata. --yes is not enough
+	#[argh(option)]
+	confirm: Option<String>,
+}
+
+/// Show the label of a disk's filesystem, or change it. Disks located by a `/dev/disk/by-label` device are updated in the config file to match.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "label")]
+struct LabelArgs {
+	#[argh(positional)]
+	disk: String,
+
+	#[argh(positional)]
+	new_label: Option<String>,
+}
+
+/// Copy a whole disk, byte for byte, to an image file or another device, such as before a risky operation. The disk must not be mounted or open.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "clone")]
+struct CloneArgs {
+	#[argh(positional)]
+	disk: String,
+
+	/// the image file or device to copy to
+	#[argh(positional)]
+	target: PathBuf,
+
+	/// read the copy back afterwards and check that it matches
+	#[argh(switch)]
+	verify: bool,
+}
+
+/// Copy directories to or from a disk with rsync, as configured in its `rsync` jobs, mounting it if needed and unmounting it again afterward.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "sync")]
+struct SyncArgs {
+	#[argh(positional)]
+	disk: String,
+
+	/// the job to run. all of the disk's jobs by default
+	#[argh(positional)]
+	job: Option<String>,
+
+	/// only list what would be transferred
+	#[argh(switch)]
+	dry_run: bool,
+}
+
+/// Record the checksum of every file on a disk, for `d verify` to compare against later. The disk is mounted read-only if it isn't mounted already.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "manifest")]
+struct ManifestArgs {
+	#[argh(positional)]
+	disk: String,
+}
+
+/// Compare the files on a disk against its manifest, listing added (+), removed (-), modified (~), corrupted (!), and unreadable (?) files. Fails if any are corrupted or unreadable.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "verify")]
+struct VerifyArgs {
+	#[argh(positional)]
+	disk: String,
+}
+
+/// Rename a disk in the config file. The disk must not be mounted or open.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "rename")]
+struct RenameArgs {
+	#[argh(positional)]
+	disk: String,
+
+	#[argh(positional)]
+	new_name: String,
+}
+
+/// Manage the config file.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "config")]
+struct ConfigArgs {
+	#[argh(subcommand)]
+	action: ConfigAction,
+}
+
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+enum ConfigAction {
+	Edit(ConfigEditArgs),
+	Check(ConfigCheckArgs),
+}
+
+/// Validate the config file without doing anything else.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "check")]
+struct ConfigCheckArgs {
+	/// if the config is in an old format, rewrite it in the current format
+	#[argh(switch)]
+	upgrade: bool,
+}
+
+/// Edit the config file with $VISUAL or $EDITOR, validating it before saving.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "edit")]
+struct ConfigEditArgs {}
+
+#[derive(Debug, Clone, Copy)]
+enum ListSort {
+	Config,
+	Recent,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown sort order {0:?}. valid orders are config, recent.")]
+struct UnknownListSort(String);
+
+impl FromStr for ListSort {
+	type Err = UnknownListSort;
+
+	fn from_str(s: &str) -> Result<Self, UnknownListSort> {
+		Ok(match s {
+			"config" => Self::Config,
+			"recent" => Self::Recent,
+			_ => return Err(UnknownListSort(s.to_owned())),
+		})
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiskState {
+	/// The device is not attached.
+	Absent,
+	/// The device is attached, but not mounted (and locked, if encrypted).
+	Unmounted,
+	/// The encrypted device is unlocked, but its filesystem is not mounted.
+	Open,
+	Mounted,
+}
+
+impl DiskState {
+	fn as_repr(self) -> &'static str {
+		match self {
+			Self::Absent => "absent",
+			Self::Unmounted => "unmounted",
+			Self::Open => "open",
+			Self::Mounted => "mounted",
+		}
+	}
+
+	fn color(self) -> Option<output::Color> {
+		match self {
+			Self::Absent | Self::Unmounted => None,
+			Self::Open => Some(output::Color::Yellow),
+			Self::Mounted => Some(output::Color::Green),
+		}
+	}
+}
+
+fn by_uuid_path(uuid: &str) -> String {
+	format!("/dev/disk/by-uuid/{uuid}")
+}
+
+fn device_present(uuid: &str) -> Result<bool> {
+	Path::try_exists(by_uuid_path(uuid).as_ref()).context("checking for by-UUID symlink")
+}
+
+/// How often to check whether a device has been attached.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+fn wait_for_device(disk: &Disk, timeout: Option<Duration>) -> Result<()> {
+	if outer_device_present(disk)? {
+		return Ok(());
+	}
+	let disk_name = disk.as_repr();
+	eprintln!("waiting for {disk_name} to be attached.");
+	let start = Instant::now();
+	progress::with_spinner("waiting for device", || {
+		while !outer_device_present(disk)? {
+			if let Some(timeout) = timeout {
+				ensure!(
+					start.elapsed() < timeout,
+					"{disk_name} was not attached within {}s",
+					timeout.as_secs()
+				);
+			}
+			std::thread::sleep(WAIT_POLL_INTERVAL);
+		}
+		Ok(())
+	})
+}
+
+/// Everywhere the filesystem on `dev_path` is mounted.
+fn mount_points_of(dev_path: &Path) -> Result<Vec<PathBuf>> {
+	let device = std::fs::canonicalize(dev_path).context("resolving device path")?;
+	Ok(
+		mountinfo::read()?
+			.into_iter()
+			.filter(|entry| entry.is_from(&device))
+			.map(|entry| entry.mount_point)
+			.collect(),
+	)
+}
+
+/// Everywhere a disk's filesystem is mounted, including places other than its mount path if something else mounted it.
+fn mount_points(disk: &Disk) -> Result<Vec<PathBuf>> {
+	// The data device has the same filesystem UUID as the verity mapping, so only the mapping is reliable.
+	if disk.verity.is_some() {
+		if !verity::is_open(disk.as_repr()) {
+			return Ok(Vec::new());
+		}
+		return mount_points_of(&verity::mapping_path(disk.as_repr()));
+	}
+	if disk.is_encrypted() {
+		if !device_present(&disk.uuid)? {
+			return Ok(Vec::new());
+		}
+		return mount_points_of(&dev_path_for_uuid(&disk.uuid)?);
+	}
+	if !outer_device_present(disk)? {
+		return Ok(Vec::new());
+	}
+	mount_points_of(&outer_dev_path(disk)?)
+}
+
+/// The name of the mapping that a present LUKS container is open as, which is not `d`'s name for it if something else opened it.
+fn open_mapping_name(disk: &Disk) -> Result<Option<String>> {
+	let dev_path = outer_dev_path(disk)?;
+	let Some(kernel_name) = sysfs::kernel_name(&dev_path) else {
+		return Ok(None);
+	};
+	Ok(
+		sysfs::holders(&kernel_name)?
+			.iter()
+			.find_map(|holder| sysfs::dm_name(holder)),
+	)
+}
+
+fn disk_state(disk: &Disk) -> Result<DiskState> {
+	Ok(match disk.to_mountable() {
+		Mountable::Plain { .. } => {
+			if !outer_device_present(disk)? {
+				DiskState::Absent
+			} else if !mount_points(disk)?.is_empty() {
+				DiskState::Mounted
+			} else {
+				DiskState::Unmounted
+			}
+		}
+		Mountable::Encrypted { .. } => {
+			if !outer_device_present(disk)? {
+				DiskState::Absent
+			} else if open_mapping_name(disk)?.is_none() {
+				DiskState::Unmounted
+			} else if !mount_points(disk)?.is_empty() {
+				DiskState::Mounted
+			} else {
+				DiskState::Open
+			}
+		}
+		Mountable::Verity { .. } => {
+			if verity::is_open(disk.as_repr()) {
+				if mount_points(disk)?.is_empty() {
+					DiskState::Open
+				} else {
+					DiskState::Mounted
+				}
+			} else if outer_device_present(disk)? {
+				DiskState::Unmounted
+			} else {
+				DiskState::Absent
+			}
+		}
+	})
+}
+
+/// Whether a disk was mounted or opened by something other than `d`. `d u` tears such disks down all the same.
+fn is_external(disk: &Disk, current: DiskState) -> Result<bool> {
+	Ok(matches!(current, DiskState::Open | DiskState::Mounted) && !state::is_managed(disk.as_repr())?)
+}
+
+/// Waits for the by-UUID symlink if it doesn't exist yet; see `devlink::resolve`.
+fn dev_path_for_uuid(uuid: &str) -> Result<PathBuf> {
+	devlink::resolve(by_uuid_path(uuid).as_ref())
+		.context("getting canonical device for by-UUID symlink")
+}
+
+/// The first bytes of a LUKS header, in both versions of the format.
+const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+
+fn has_luks_magic(dev_path: &Path) -> Result<bool> {
+	use std::io::Read as _;
+
+	let mut magic = [0; LUKS_MAGIC.len()];
+	let mut device = std::fs::File::open(dev_path).context("opening device")?;
+	match device.read_exact(&mut magic) {
+		Ok(()) => Ok(&magic == LUKS_MAGIC),
+		Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+		Err(error) => Err(error).context("reading device"),
+	}
+}
+
+/// Check that the device found for `uuid` holds what the disk says it does: a LUKS header if `luks`, or a filesystem otherwise.
+///
+/// This gives a clear error when a UUID resolves to something unexpected, rather than leaving `cryptsetup` or `mount` to fail confusingly.
+fn check_device(dev_path: &Path, uuid: &str, luks: bool) -> Result<()> {
+	use std::os::unix::fs::FileTypeExt as _;
+
+	let metadata = std::fs::metadata(dev_path)
+		.with_context(|| format!("reading metadata of {}", dev_path.display()))?;
+	ensure!(
+		metadata.file_type().is_block_device(),
+		"device for UUID {uuid} ({}) is not a block device",
+		dev_path.display()
+	);
+
+	if luks {
+		ensure!(
+			has_luks_magic(dev_path)?,
+			"device for UUID {uuid} ({}) is not LUKS",
+			dev_path.display()
+		);
+		return Ok(());
+	}
+
+	let probe = blkid::probe(dev_path)?;
+	match probe.content_type.as_deref() {
+		None => bail!(
+			"device for UUID {uuid} ({}) has no recognizable filesystem",
+			dev_path.display()
+		),
+		Some(_) if probe.is_luks() => bail!(
+			"device for UUID {uuid} ({}) is LUKS, but the disk is not configured as encrypted",
+			dev_path.display()
+		),
+		Some("swap") => bail!(
+			"device for UUID {uuid} ({}) is swap, not a filesystem",
+			dev_path.display()
+		),
+		Some(_) => Ok(()),
+	}
+}
+
+/// Where a disk is mounted, unless it is part of a composite. See `Config::mount_path`.
+fn mount_path_for_name(name: &str) -> String {
+	format!("/mnt/{name}")
+}
+
+/// The UUID of the device that is actually attached: the LUKS container for encrypted disks, or the filesystem otherwise.
+fn outer_uuid(disk: &Disk) -> &str {
+	match disk.to_mountable() {
+		Mountable::Plain { uuid } => uuid,
+		Mountable::Encrypted { outer_uuid, .. } => outer_uuid,
+		Mountable::Verity { data_uuid, .. } => data_uuid,
+	}
+}
+
+/// Where to find the device that is actually attached, which is by its UUID unless the disk says otherwise.
+///
+/// `None` if the disk is found by its drive and no such drive is attached.
+fn outer_device_link(disk: &Disk) -> Result<Option<String>> {
+	Ok(if let Some(partuuid) = &disk.partuuid {
+		Some(format!("/dev/disk/by-partuuid/{partuuid}"))
+	} else if let Some(device) = &disk.device {
+		Some(device.clone())
+	} else if let Some(drive) = &disk.drive {
+		drive_partition(drive)?.map(|name| format!("/dev/{name}"))
+	} else {
+		Some(by_uuid_path(outer_uuid(disk)))
+	})
+}
+
+/// Walk the whole disks in sysfs to find the kernel name of the partition.
+fn drive_partition(drive: &config::Drive) -> Result<Option<String>> {
+	// Both the attribute and the config may have a type prefix such as `naa.` or `0x`.
+	fn normalize_wwn(wwn: &str) -> String {
+		let wwn = wwn.rsplit_once('.').map_or(wwn, |(_, rest)| rest);
+		wwn.trim_start_matches("0x").to_ascii_lowercase()
+	}
+
+	for name in sysfs::all_devices()? {
+		let matches = match (&drive.serial, &drive.wwn) {
+			(Some(serial), _) => {
+				sysfs::serial(&name).is_some_and(|actual| actual.eq_ignore_ascii_case(serial))
+			}
+			(None, Some(wwn)) => {
+				sysfs::wwn(&name).is_some_and(|actual| normalize_wwn(&actual) == normalize_wwn(wwn))
+			}
+			(None, None) => false,
+		};
+		if matches && sysfs::parent_disk(&name)?.is_none() {
+			return sysfs::partition(&name, drive.partition);
+		}
+	}
+	Ok(None)
+}
+
+fn outer_device_present(disk: &Disk) -> Result<bool> {
+	match outer_device_link(disk)? {
+		Some(link) => Path::try_exists(link.as_ref()).context("checking for device symlink"),
+		None => Ok(false),
+	}
+}
+
+fn outer_dev_path(disk: &Disk) -> Result<PathBuf> {
+	let link = outer_device_link(disk)?.context("the drive is not attached")?;
+	std::fs::canonicalize(link).context("getting canonical device for symlink")
+}
+
+fn opened_name_for_encrypted(uuid: &str, disk_name: &str) -> String {
+	format!("{uuid}-{disk_name}")
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(clippy::struct_excessive_bools)] // Independent options.
+struct MountOptions {
+	force_shadow: bool,
+	skip_check: bool,
+	/// The LUKS mapping is opened read-only, so the filesystem can't be remounted read-write either.
+	forensic: bool,
+	/// Mount the filesystem read-only from the start, without checking it, turning on quotas, or anything else that writes to the disk.
+	read_only: bool,
+	/// The disk is only mounted for as long as a command like `d backup` needs it, so its units aren't started and no snapshot is taken.
+	transient: bool,
+	/// Mount even if the disk's RAID array is missing devices, without asking.
+	degraded: bool,
+	/// Mount untrusted disks without `noexec` and `nosymfollow`.
+	allow_exec: bool,
+	wait: Wait,
+}
+
+impl MountOptions {
+	/// Forensic mounts are read-only too.
+	fn is_read_only(self) -> bool {
+		self.forensic || self.read_only
+	}
+
+	/// Whether the disk is being mounted to be used, so that a snapshot should be taken and its units started.
+	fn is_for_use(self) -> bool {
+		!self.is_read_only() && !self.transient
+	}
+}
+
+/// How to run a `c` session, once the disk is mounted.
+#[derive(Debug, Clone, Copy, Default)]
+struct SessionOptions {
+	tmux: bool,
+	/// In MiB per second.
+	io_limit: Option<u32>,
+	/// Leave the disk mounted when the session ends.
+	keep: bool,
+}
+
+/// Whether to wait for a device that is not attached yet.
+#[derive(Debug, Clone, Copy, Default)]
+enum Wait {
+	#[default]
+	No,
+	Indefinitely,
+	AtMost(Duration),
+}
+
+impl Wait {
+	fn from_args(wait: bool, wait_timeout: Option<u64>) -> Result<Self> {
+		Ok(match (wait, wait_timeout) {
+			(false, None) => Self::No,
+			(false, Some(_)) => bail!("--wait-timeout requires --wait"),
+			(true, None) => Self::Indefinitely,
+			(true, Some(seconds)) => Self::AtMost(Duration::from_secs(seconds)),
+		})
+	}
+}
+
+impl MountArgs {
+	fn options(&self) -> Result<MountOptions> {
+		Ok(MountOptions {
+			force_shadow: self.force_shadow,
+			skip_check: self.skip_check,
+			forensic: self.forensic,
+			read_only: false,
+			transient: false,
+			degraded: self.degraded,
+			allow_exec: self.allow_exec,
+			wait: Wait::from_args(self.wait, self.wait_timeout)?,
+		})
+	}
+}
+
+impl UnmountArgs {
+	/// The configured policy, with the steps enabled by flags added.
+	fn policy(&self, config: &Config) -> unmount::Policy {
+		unmount::Policy {
+			terminate_blockers: config.unmount.terminate_blockers || self.terminate,
+			lazy: config.unmount.lazy || self.lazy,
+			..config.unmount
+		}
+	}
+}
+
+impl EjectArgs {
+	/// The configured policy, with the steps enabled by flags added, but never lazy, since a detached filesystem still uses the drive.
+	fn policy(&self, config: &Config) -> unmount::Policy {
+		unmount::Policy {
+			terminate_blockers: config.unmount.terminate_blockers || self.terminate,
+			lazy: false,
+			..config.unmount
+		}
+	}
+}
+
+impl CdArgs {
+	fn options(&self) -> Result<MountOptions> {
+		Ok(MountOptions {
+			force_shadow: self.force_shadow,
+			skip_check: self.skip_check,
+			forensic: false,
+			read_only: false,
+			transient: false,
+			degraded: self.degraded,
+			allow_exec: self.allow_exec,
+			wait: Wait::from_args(self.wait, self.wait_timeout)?,
+		})
+	}
+}
+
+struct MountReturn {
+	mount_path: String,
+	was_already_mounted: bool,
+}
+
+/// What is currently mounted at a disk's mount path, relative to what we want there.
+enum ExistingMount {
+	Nothing,
+	Expected,
+	/// A FUSE filesystem whose daemon has died, so any access fails with `ENOTCONN`.
+	DeadFuse(mountinfo::Entry),
+	Unexpected(mountinfo::Entry),
+}
+
+fn check_existing_mount(mount_path: &Path, dev_path: &Path) -> Result<ExistingMount> {
+	let Some(existing) = mountinfo::find_by_mount_point(mount_path)? else {
+		return Ok(ExistingMount::Nothing);
+	};
+
+	let expected_device = std::fs::canonicalize(dev_path).context("resolving device path")?;
+	if existing.is_from(&expected_device) {
+		return Ok(ExistingMount::Expected);
+	}
+
+	let is_dead_fuse = existing.fs_type.starts_with("fuse")
+		&& nix::sys::stat::stat(mount_path) == Err(nix::errno::Errno::ENOTCONN);
+	Ok(if is_dead_fuse {
+		ExistingMount::DeadFuse(existing)
+	} else {
+		ExistingMount::Unexpected(existing)
+	})
+}
+
+/// How many shadowed entries to list before summarizing the rest.
+const SHADOWED_ENTRIES_SHOWN: usize = 5;
+
+/// Refuse to mount over a non-empty directory unless forced, since the files in it would become invisible.
+fn check_shadowed_entries(mount_path: &str, force_shadow: bool) -> Result<()> {
+	let entries = std::fs::read_dir(mount_path)
+		.context("listing mount path")?
+		.map(|entry| entry.map(|entry| entry.file_name()))
+		.collect::<Result<Vec<_>, _>>()
+		.context("listing mount path")?;
+	if entries.is_empty() {
+		return Ok(());
+	}
+
+	output::warning(format_args!(
+		"mount path {mount_path:?} is not empty. mounting over it will hide these entries:"
+	));
+	for entry in entries.iter().take(SHADOWED_ENTRIES_SHOWN) {
+		eprintln!("\t{}", entry.to_string_lossy());
+	}
+	if entries.len() > SHADOWED_ENTRIES_SHOWN {
+		eprintln!("\t...and {} more", entries.len() - SHADOWED_ENTRIES_SHOWN);
+	}
+
+	ensure!(
+		force_shadow,
+		"refusing to mount over a non-empty directory. pass --force-shadow to do it anyway"
+	);
+	Ok(())
+}
+
+/// The mount option that skips replaying the journal, which would otherwise write to the disk even for a read-only mount.
+fn no_journal_replay_option(filesystem: &str) -> Result<&'static str> {
+	Ok(match filesystem {
+		"ext3" | "ext4" => "noload",
+		"xfs" => "norecovery",
+		"btrfs" => "rescue=nologreplay",
+		"ext2" | "vfat" | "exfat" => "",
+		_ => bail!("don't know how to mount {filesystem} without replaying its journal"),
+	})
+}
+
+/// The filesystem-specific data for the mount syscall.
+fn mount_data(disk: &Disk, filesystem: &str, read_only: bool) -> Result<Vec<String>> {
+	let mut data = if read_only {
+		vec![no_journal_replay_option(filesystem)?.to_owned()]
+	} else if filesystem == "ext4" {
+		vec!["discard".to_owned(), "delalloc".to_owned()]
+	} else {
+		vec!["discard".to_owned()]
+	};
+	// Quotas and tuning are set up for the configured filesystem, and would only get in the way of a fallback.
+	if !read_only && filesystem == disk.inner_filesystem() {
+		data.extend(quota::mount_options(disk)?.into_iter().map(str::to_owned));
+		if let Some(tuning) = &disk.ext4 {
+			if tuning.conflicts_with_delalloc() {
+				data.retain(|option| option != "delalloc");
+			}
+			data.extend(tuning.mount_options());
+		}
+		if let Some(tuning) = &disk.btrfs {
+			data.extend(tuning.mount_options());
+		}
+	}
+	data.extend(selinux::mount_options(disk));
+	data.retain(|option| !option.is_empty());
+	Ok(data)
+}
+
+/// Mount options that only help, so they can be dropped if the kernel or the filesystem doesn't support them.
+const OPTIONAL_MOUNT_OPTIONS: &[&str] = &["discard", "delalloc"];
+
+/// Which optional option to drop after the kernel rejected the mount with EINVAL: the one that it complained about. Otherwise, EINVAL means something else, such as a bad superblock, and no option is dropped.
+fn rejected_option(data: &[String], kernel_messages: &[String]) -> Option<String> {
+	data
+		.iter()
+		.filter(|option| OPTIONAL_MOUNT_OPTIONS.contains(&option.as_str()))
+		.find(|option| {
+			// Such as `Unrecognized mount option "delalloc"` or `unknown parameter 'discard'`.
+			let quoted = [format!("\"{option}\""), format!("'{option}'")];
+			kernel_messages.iter().any(|message| {
+				quoted
+					.iter()
+					.any(|quoted| message.contains(quoted.as_str()))
+			})
+		})
+		.cloned()
+}
+
+/// Makes the kernel refuse to follow symbolic links on the filesystem (Linux 5.10), which nix doesn't know about.
+// SAFETY: the kernel accepts flags that nix doesn't name.
+const MS_NOSYMFOLLOW: nix::mount::MsFlags =
+	unsafe { nix::mount::MsFlags::from_bits_unchecked(256) };
+
+/// Make the mount syscall, retrying without optional options that are rejected. On failure, returns the error and what the kernel logged about it.
+fn mount_as(
+	dev_path: &Path,
+	mount_path: &str,
+	filesystem: &str,
+	flags: nix::mount::MsFlags,
+	mut data: Vec<String>,
+	message: &str,
+) -> Result<(), (nix::errno::Errno, Vec<String>)> {
+	loop {
+		let joined = data.join(",");
+		let mut kernel_log = kmsg::Cursor::at_end();
+		let mount_res = progress::with_spinner(message, || {
+			nix::mount::mount(
+				Some(dev_path),
+				mount_path,
+				Some(filesystem),
+				flags,
+				Some(joined.as_str()),
+			)
+		});
+		let Err(error) = mount_res else {
+			return Ok(());
+		};
+		// The errno rarely says what's wrong, but the filesystem driver usually logs it.
+		let kernel_messages = kernel_log
+			.as_mut()
+			.map_or_else(Vec::new, kmsg::Cursor::read_new);
+		let dropped = if error == nix::errno::Errno::EINVAL {
+			rejected_option(&data, &kernel_messages)
+		} else {
+			None
+		};
+		let Some(dropped) = dropped else {
+			return Err((error, kernel_messages));
+		};
+		output::warning(format_args!(
+			"mounting as {filesystem} with the {dropped} option failed with EINVAL, trying without it."
+		));
+		data.retain(|option| *option != dropped);
+	}
+}
+
+fn mount_error(error: nix::errno::Errno, kernel_messages: &[String]) -> anyhow::Error {
+	let mut context = if kernel_messages.is_empty() {
+		"making mount syscall".to_owned()
+	} else {
+		format!(
+			"making mount syscall. the kernel said:\n{}",
+			kernel_messages.join("\n")
+		)
+	};
+	if error == nix::errno::Errno::EACCES {
+		if let Some(explanation) = selinux::explain_denial(kernel_messages) {
+			context = format!("{context}\n{explanation}");
+		}
+	}
+	anyhow::Error::new(error).context(context)
+}
+
+/// Mount as the configured filesystem, or else each of the fallbacks in turn if the kernel rejects it.
+fn mount_with_fallbacks(
+	disk: &Disk,
+	dev_path: &Path,
+	mount_path: &str,
+	flags: nix::mount::MsFlags,
+	read_only: bool,
+	degraded: bool,
+) -> Result<()> {
+	let mut candidates = std::iter::once(disk.inner_filesystem())
+		.chain(disk.fallback_filesystems.iter().map(String::as_str))
+		.peekable();
+	while let Some(filesystem) = candidates.next() {
+		let mut data = mount_data(disk, filesystem, read_only)?;
+		if degraded && filesystem == "btrfs" {
+			data.push("degraded".to_owned());
+		}
+		let message = if filesystem == disk.inner_filesystem() {
+			"mounting".to_owned()
+		} else {
+			format!("mounting as {filesystem}")
+		};
+		match mount_as(dev_path, mount_path, filesystem, flags, data, &message) {
+			Ok(()) => {
+				if filesystem != disk.inner_filesystem() {
+					output::warning(format_args!(
+						"mounted as {filesystem} instead of {}. update `filesystem` in the config if the disk was reformatted.",
+						disk.inner_filesystem()
+					));
+				}
+				break;
+			}
+			Err((nix::errno::Errno::EINVAL, _)) if candidates.peek().is_some() => {
+				eprintln!(
+					"mounting as {filesystem} failed with EINVAL, trying the next fallback filesystem."
+				);
+			}
+			Err((error, kernel_messages)) => return Err(mount_error(error, &kernel_messages)),
+		}
+	}
+	Ok(())
+}
+
+/// Returns the mount path, if successful.
+fn mount(
+	disk: &Disk,
+	dev_path: &Path,
+	mount_path: String,
+	options: MountOptions,
+) -> Result<MountReturn> {
+	use nix::mount::{umount2, MntFlags, MsFlags};
+
+	let existing_points = mount_points_of(dev_path)?;
+	if !existing_points
+		.iter()
+		.any(|point| *point == Path::new(&mount_path))
+	{
+		if let Some(elsewhere) = existing_points.first() {
+			eprintln!(
+				"expected device is already mounted at {} by something else, using that. `d u` will unmount it.",
+				elsewhere.display()
+			);
+			return Ok(MountReturn {
+				mount_path: elsewhere.to_string_lossy().into_owned(),
+				was_already_mounted: true,
+			});
+		}
+	}
+
+	match check_existing_mount(mount_path.as_ref(), dev_path)
+		.context("checking for an existing mount")?
+	{
+		ExistingMount::Nothing => {}
+		ExistingMount::Expected => {
+			eprintln!("expected device is already mounted at {mount_path:?}.");
+			return Ok(MountReturn {
+				mount_path,
+				was_already_mounted: true,
+			});
+		}
+		ExistingMount::DeadFuse(existing) => {
+			eprintln!(
+				"found dead FUSE mount ({} from {:?}) at {mount_path:?}, detaching it.",
+				existing.fs_type, existing.source,
+			);
+			umount2(mount_path.as_str(), MntFlags::MNT_DETACH).context("detaching dead FUSE mount")?;
+		}
+		ExistingMount::Unexpected(existing) => bail!(
+			"{mount_path:?} already has {:?} ({}) mounted on it, which is not the expected device {}",
+			existing.source,
+			existing.fs_type,
+			dev_path.display(),
+		),
+	}
+
+	if !Path::try_exists(mount_path.as_ref()).context("verifying that mount path exists")? {
+		eprintln!("mount path ({mount_path:?}) does not exist, trying to create it.");
+		std::fs::create_dir_all(&mount_path).context("creating mount path")?;
+	}
+	check_shadowed_entries(&mount_path, options.force_shadow)?;
+	let degraded = degraded::check(disk, dev_path, options.degraded)?;
+
+	let mut flags = MsFlags::MS_NOATIME | MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+	if disk.is_untrusted() && !options.allow_exec {
+		flags |= MsFlags::MS_NOEXEC | MS_NOSYMFOLLOW;
+	}
+	// A verity device can't be written to at all, so it is mounted like a forensic one.
+	let read_only = options.is_read_only() || disk.verity.is_some();
+	if read_only {
+		flags |= MsFlags::MS_RDONLY;
+	} else {
+		fsck::check_if_due(disk, dev_path, options.skip_check).context("checking filesystem")?;
+	}
+	// Free space can only be checked once mounted, so mount read-only until then, rather than writing to a disk that should be left alone.
+	let check_space_first = !read_only && disk.read_only_when_low && disk.min_free_percent > 0.0;
+	if check_space_first {
+		flags |= MsFlags::MS_RDONLY;
+	}
+
+	mount_with_fallbacks(disk, dev_path, &mount_path, flags, read_only, degraded)?;
+
+	if check_space_first {
+		if let Err(error) = remount_unless_low(disk, &mount_path, flags) {
+			if let Err(unmount_error) = umount2(mount_path.as_str(), MntFlags::empty()) {
+				output::warning(format_args!(
+					"failed to unmount after checking free space failed: {unmount_error}"
+				));
+			}
+			return Err(error);
+		}
+	}
+
+	Ok(MountReturn {
+		mount_path,
+		was_already_mounted: false,
+	})
+}
+
+/// For `read_only_when_low`, remount a disk that was mounted read-only read-write, unless it is low on free space.
+fn remount_unless_low(disk: &Disk, mount_path: &str, flags: nix::mount::MsFlags) -> Result<()> {
+	use nix::mount::MsFlags;
+
+	let usage = space::usage(mount_path).context("checking free space")?;
+	if usage.available_percent() < disk.min_free_percent {
+		eprintln!("keeping read-only since free space is low.");
+		return Ok(());
+	}
+	nix::mount::mount(
+		None::<&str>,
+		mount_path,
+		None::<&str>,
+		(flags - MsFlags::MS_RDONLY) | MsFlags::MS_REMOUNT,
+		None::<&str>,
+	)
+	.context("remounting read-write")
+}
+
+/// `cryptsetup` exits with this status if the passphrase is wrong.
+const CRYPTSETUP_WRONG_PASSPHRASE: i32 = 2;
+const UNLOCK_ATTEMPTS: usize = 3;
+
+fn cryptsetup_open(read_only: bool) -> std::process::Command {
+	let mut command = caps::tool("cryptsetup");
+	command.arg("open");
+	if read_only {
+		command.arg("--readonly");
+	}
+	command
+}
+
+fn cryptsetup_open_with_passphrase(
+	dev_path: &Path,
+	opened_name: &str,
+	passphrase: &str,
+	read_only: bool,
+) -> Result<std::process::ExitStatus> {
+	use std::io::Write as _;
+
+	let mut child = cryptsetup_open(read_only)
+		.arg(dev_path)
+		.arg(opened_name)
+		.stdin(std::process::Stdio::piped())
+		.spawn()?;
+	// When stdin is not a terminal, cryptsetup reads the passphrase up to the first newline.
+	let mut stdin = child.stdin.take().expect("stdin is piped");
+	writeln!(stdin, "{passphrase}").context("passing passphrase to cryptsetup")?;
+	drop(stdin);
+	Ok(child.wait()?)
+}
+
+fn check_cryptsetup_open(code: std::process::ExitStatus) -> Result<()> {
+	ensure!(
+		code.success(),
+		"cryptsetup exited with status {:?}",
+		code.code()
+	);
+	Ok(())
+}
+
+fn note_unlock_failure(disk_name: &str) {
+	if let Err(error) = stats::record_unlock_failure(disk_name) {
+		output::warning(format_args!("failed to record unlock failure: {error:#}"));
+	}
+}
+
+/// `askpass` is used to ask for the passphrase if there is no terminal to prompt on.
+fn open_encrypted(
+	dev_path: &Path,
+	luks_uuid: &str,
+	disk_name: &str,
+	key: &unlock::Key,
+	read_only: bool,
+	askpass: Option<&str>,
+) -> Result<()> {
+	let opened_name = opened_name_for_encrypted(luks_uuid, disk_name);
+	if caps::tool("cryptsetup")
+		.arg("status")
+		.arg(&opened_name)
+		.status()?
+		.success()
+	{
+		eprintln!("`cryptsetup status` reported OK, assuming encrypted device is already open.");
+		return Ok(());
+	}
+
+	match key {
+		unlock::Key::Prompt => {}
+		unlock::Key::Passphrase { passphrase, source } => {
+			let code = progress::with_spinner("unlocking", || {
+				cryptsetup_open_with_passphrase(dev_path, &opened_name, passphrase, read_only)
+			})?;
+			if code.code() != Some(CRYPTSETUP_WRONG_PASSPHRASE) {
+				return check_cryptsetup_open(code);
+			}
+			note_unlock_failure(disk_name);
+			output::warning(format_args!(
+				"the passphrase from {source} is wrong, falling back to prompting."
+			));
+		}
+		unlock::Key::File(keyfile) => {
+			let code = progress::with_spinner("unlocking", || {
+				cryptsetup_open(read_only)
+					.arg("--key-file")
+					.arg(keyfile)
+					.arg(dev_path)
+					.arg(&opened_name)
+					.status()
+			})?;
+			return check_cryptsetup_open(code);
+		}
+	}
+
+	for _ in 0..UNLOCK_ATTEMPTS {
+		let Some(passphrase) = passphrase::ask(&format!("passphrase for {disk_name}: "), askpass)?
+		else {
+			// cryptsetup would prompt on the terminal itself.
+			if prompt::is_non_interactive() && prompt::stdin_is_tty() {
+				return Err(
+					prompt::NeedsInteraction(format!(
+						"{disk_name} needs a passphrase, but d is non-interactive"
+					))
+					.into(),
+				);
+			}
+			// No terminal for us to prompt on, so let cryptsetup read the passphrase however it can.
+			let code = cryptsetup_open(read_only)
+				.arg(dev_path)
+				.arg(&opened_name)
+				.status()?;
+			return check_cryptsetup_open(code);
+		};
+
+		let code = progress::with_spinner("unlocking", || {
+			cryptsetup_open_with_passphrase(dev_path, &opened_name, &passphrase, read_only)
+		})?;
+		match code.code() {
+			_ if code.success() => return Ok(()),
+			Some(CRYPTSETUP_WRONG_PASSPHRASE) => {
+				note_unlock_failure(disk_name);
+				eprintln!("wrong passphrase, try again.");
+			}
+			other => bail!("cryptsetup exited with status {other:?}"),
+		}
+	}
+
+	bail!("no correct passphrase after {UNLOCK_ATTEMPTS} attempts")
+}
+
+fn close_encrypted(luks_uuid: &str, disk_name: &str) -> Result<()> {
+	close_mapping(&opened_name_for_encrypted(luks_uuid, disk_name))
+}
+
+/// Close the mapping that was just opened for a mount that then failed, so that the failure doesn't leave the disk unlocked.
+fn close_after_failed_mount(
+	luks_uuid: &str,
+	disk_name: &str,
+	error: anyhow::Error,
+) -> anyhow::Error {
+	match close_encrypted(luks_uuid, disk_name) {
+		Ok(()) => {
+			eprintln!("closed the encrypted device again since mounting failed.");
+			error
+		}
+		Err(close_error) => error.context(format!(
+			"closing the encrypted device after mounting failed also failed ({close_error:#})"
+		)),
+	}
+}
+
+fn close_mapping(mapping_name: &str) -> Result<()> {
+	let code = caps::tool("cryptsetup")
+		.arg("close")
+		.arg(mapping_name)
+		.status()?;
+
+	if code.success() {
+		Ok(())
+	} else {
+		Err(anyhow!("cryptsetup exited with status {:?}", code.code()))
+	}
+}
+
+/// Close the encryption of a disk, without touching any mounts.
+fn lock(disk: &Disk) -> Result<()> {
+	let Some(mapping_name) = open_mapping_name(disk)? else {
+		return Ok(());
+	};
+	close_mapping(&mapping_name).context("closing encrypted device")?;
+	state::clear_external_mapping(disk.as_repr())
+}
+
+fn do_lock(disk: &Disk) -> Result<()> {
+	let disk_name = disk.as_repr();
+	ensure!(disk.is_encrypted(), "{disk_name} is not encrypted");
+	match disk_state(disk)? {
+		DiskState::Absent => bail!("{disk_name} is not attached"),
+		DiskState::Unmounted => eprintln!("{disk_name} is already locked."),
+		DiskState::Mounted => {
+			bail!("{disk_name} is mounted; unmount it with `d u`, which also locks it")
+		}
+		DiskState::Open => {
+			lock(disk)?;
+			eprintln!("locked {disk_name}.");
+		}
+	}
+	Ok(())
+}
+
+fn do_mount(config: &Config, disk: &Disk, options: MountOptions) -> Result<MountReturn> {
+	match options.wait {
+		Wait::No => {}
+		Wait::Indefinitely => wait_for_device(disk, None)?,
+		Wait::AtMost(timeout) => wait_for_device(disk, Some(timeout))?,
+	}
+
+	if is_privileged() {
+		mount_with_key(config, disk, options, || unlock::key_for(config, disk))
+	} else {
+		helper::mount(config, disk, options)
+	}
+}
+
+fn check_forensic(disk: &Disk) -> Result<()> {
+	ensure!(
+		disk.is_encrypted(),
+		"forensic mode is only supported for encrypted disks, since it relies on a read-only LUKS mapping"
+	);
+	let state = disk_state(disk)?;
+	ensure!(
+		state == DiskState::Unmounted,
+		"{} is {}; unmount it first so that it can be opened read-only",
+		disk.as_repr(),
+		state.as_repr()
+	);
+	Ok(())
+}
+
+/// Mount the filesystem in an encrypted disk's LUKS mapping, closing the mapping again if it was just opened and mounting fails.
+fn mount_in_mapping(
+	disk: &Disk,
+	outer_uuid: &str,
+	inner_uuid: &str,
+	mount_path: String,
+	options: MountOptions,
+	was_open: bool,
+) -> Result<MountReturn> {
+	let disk_name = disk.as_repr();
+	let inner_dev_path = if was_open {
+		dev_path_for_uuid(inner_uuid)
+	} else {
+		let mapping_path =
+			Path::new("/dev/mapper").join(opened_name_for_encrypted(outer_uuid, disk_name));
+		devlink::resolve_in_mapping(&mapping_path, by_uuid_path(inner_uuid).as_ref())
+	};
+	let ret = inner_dev_path
+		.and_then(|dev_path| mount(disk, &dev_path, mount_path, options))
+		.context("mounting");
+	match ret {
+		Ok(ret) => {
+			if let Err(error) = state::set_external_mapping(disk_name, was_open) {
+				output::warning(format_args!(
+					"failed to record whether the encryption was already open: {error:#}"
+				));
+			}
+			Ok(ret)
+		}
+		Err(error) if was_open => Err(error),
+		Err(error) => Err(close_after_failed_mount(outer_uuid, disk_name, error)),
+	}
+}
+
+/// `get_key` is only called if the disk is encrypted and not open yet.
+fn mount_with_key(
+	config: &Config,
+	disk: &Disk,
+	options: MountOptions,
+	get_key: impl FnOnce() -> Result<unlock::Key>,
+) -> Result<MountReturn> {
+	let disk_name = disk.as_repr();
+	let mountable = disk.to_mountable();
+	let mount_path = config.mount_path(disk);
+
+	if options.forensic {
+		check_forensic(disk)?;
+	}
+
+	let ret = match mountable {
+		Mountable::Plain { uuid } => {
+			let dev_path = outer_dev_path(disk)?;
+			check_device(&dev_path, uuid, false)?;
+			mount(disk, &dev_path, mount_path, options).context("mounting")?
+		}
+		Mountable::Encrypted {
+			outer_uuid,
+			inner_uuid,
+		} => {
+			let was_open = device_present(inner_uuid)?;
+			if was_open {
+				eprintln!("the encrypted device is already open.");
+			} else {
+				let dev_path = outer_dev_path(disk)?;
+				check_device(&dev_path, outer_uuid, true)?;
+				let key = get_key()?;
+				open_encrypted(
+					&dev_path,
+					outer_uuid,
+					disk_name,
+					&key,
+					options.forensic,
+					config.askpass.as_deref(),
+				)
+				.context("opening encrypted device")?;
+			}
+			mount_in_mapping(disk, outer_uuid, inner_uuid, mount_path, options, was_open)?
+		}
+		Mountable::Verity {
+			hash_uuid,
+			root_hash,
+			..
+		} => {
+			if verity::is_open(disk_name) {
+				eprintln!("the verity device is already open.");
+			} else {
+				let data_dev_path = outer_dev_path(disk)?;
+				let hash_dev_path =
+					dev_path_for_uuid(hash_uuid).context("finding the verity hash device")?;
+				verity::open(&data_dev_path, &hash_dev_path, root_hash, disk_name)
+					.context("opening verity device")?;
+			}
+			mount(disk, &verity::mapping_path(disk_name), mount_path, options).context("mounting")?
+		}
+	};
+
+	if let Some(canary) = &disk.canary {
+		if let Err(error) = canary::verify(&ret.mount_path, canary) {
+			// Don't leave what may be the wrong disk mounted where the right one is expected.
+			if !ret.was_already_mounted {
+				if let Err(error) = do_unmount(config, disk, config.unmount) {
+					output::warning(format_args!(
+						"failed to unmount after the canary check failed: {error:#}"
+					));
+				}
+			}
+			return Err(error.context("verifying canary file"));
+		}
+	}
+	if !ret.was_already_mounted && !options.is_read_only() {
+		if let Err(error) = quota::enable(disk, &ret.mount_path) {
+			output::warning(format_args!("failed to turn on quotas: {error:#}"));
+		}
+	}
+	if let Some(snapshots) = &disk.snapshots {
+		if snapshots.on_mount && !ret.was_already_mounted && options.is_for_use() {
+			take_snapshot(disk, &ret.mount_path, snapshots);
+		}
+	}
+
+	if let Err(error) = stats::record_use(disk_name, ret.was_already_mounted) {
+		output::warning(format_args!("failed to record usage statistics: {error:#}"));
+	}
+	if !ret.was_already_mounted {
+		if let Err(error) = state::set_managed(disk_name, true) {
+			output::warning(format_args!("failed to record mount: {error:#}"));
+		}
+	}
+	if !ret.was_already_mounted && options.is_for_use() && !disk.units.is_empty() {
+		if let Err(error) = systemd::start(&disk.units) {
+			output::warning(format_args!("failed to start units: {error:#}"));
+		}
+	}
+	if let Some(minutes) = disk.standby_after_minutes {
+		if let Err(error) = power::set_standby_timer(&outer_dev_path(disk)?, minutes) {
+			output::warning(format_args!("failed to set standby timer: {error:#}"));
+		}
+	}
+
+	if disk.min_free_percent > 0.0 {
+		if let Err(error) = space::check(&ret.mount_path, disk_name, disk.min_free_percent) {
+			output::warning(format_args!("failed to check free space: {error:#}"));
+		}
+	}
+
+	Ok(ret)
+}
+
+/// The targets given on the command line, followed by the disks with any of `tags`.
+fn with_tagged(config: &Config, targets: &[String], tags: &[String]) -> Result<Vec<String>> {
+	let mut all = targets.to_vec();
+	for tag in tags {
+		all.extend(config.tagged(tag)?);
+	}
+	Ok(all)
+}
+
+/// `snapshot` is mounted alongside the disk, if there is exactly one target.
+///
+/// FUSE filesystems whose servers are unreachable are skipped rather than failing the rest, unless one was named by itself, and with `local_only`, those on servers are skipped without trying.
+fn do_mount_targets(
+	config: &Config,
+	targets: &[String],
+	options: MountOptions,
+	snapshot: Option<&str>,
+	local_only: bool,
+) -> Result<()> {
+	ensure!(!targets.is_empty(), "no disks given");
+	if snapshot.is_some() {
+		ensure!(
+			targets.len() == 1 && config.disk(&targets[0]).is_ok(),
+			"--snapshot can only be used with a single disk"
+		);
+		// Mounting the snapshot can't go through the helper.
+		ensure_root()?;
+	}
+	// Only a single FUSE filesystem, named by itself, fails if its server is unreachable.
+	let is_batch = !matches!(targets, [target] if config.fuse(target).is_some());
+	let (fuse_mounts, targets) = config.split_fuse_targets(targets);
+	let (swaps, targets) = config.split_swap_targets(&targets);
+	// FUSE filesystems need no privileges, so only become root if there is something else. Swap can't go through the helper.
+	if !swaps.is_empty() {
+		ensure_root()?;
+	}
+	if !targets.is_empty() {
+		ensure_root_or_helper()?;
+	}
+	for swap in swaps {
+		if swap::on(swap)? {
+			eprintln!("swap {} was already active.", swap.name);
+		} else {
+			eprintln!("activated swap {}.", swap.name);
+		}
+	}
+	for fuse in fuse_mounts {
+		if local_only && fuse.kind.is_remote() {
+			eprintln!("skipped {}, which is on a server.", fuse.name);
+			continue;
+		}
+		let mount_path = fuse::mount_path(fuse)?;
+		let was_already_mounted = match fuse::mount(fuse, &mount_path) {
+			Err(error) if is_batch && error.is::<fuse::Unreachable>() => {
+				output::warning(format_args!("skipped {}: {error:#}", fuse.name));
+				continue;
+			}
+			res => res?,
+		};
+		if was_already_mounted {
+			eprintln!(
+				"{} was already mounted at {}.",
+				fuse.name,
+				mount_path.display()
+			);
+		} else {
+			eprintln!("mounted {} at {}.", fuse.name, mount_path.display());
+		}
+	}
+	if targets.is_empty() {
+		return Ok(());
+	}
+
+	let disks = config.resolve_targets(&targets)?;
+	let mount_path = match disks.as_slice() {
+		[disk] => {
+			let MountReturn {
+				mount_path,
+				was_already_mounted,
+			} = do_mount(config, disk, options)?;
+			if was_already_mounted {
+				eprintln!("{} was already mounted at {mount_path:?}.", disk.as_repr());
+			} else {
+				eprintln!("mounted {} at {mount_path:?}.", disk.as_repr());
+			}
+			mount_path
+		}
+		disks => {
+			batch::mount_all(config, disks, options)?;
+			// The requested disk comes after its dependencies.
+			config.mount_path(disks.last().expect("there is at least one target"))
+		}
+	};
+	if let Some(name) = snapshot {
+		let disk = disks.last().expect("there is at least one target");
+		mount_snapshot(disk, &mount_path, name)?;
+	}
+	Ok(())
+}
+
+fn remount_read_only(mount_path: &str) -> Result<()> {
+	use nix::mount::{mount, MsFlags};
+
+	mount(
+		None::<&str>,
+		mount_path,
+		None::<&str>,
+		MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+		None::<&str>,
+	)
+	.context("remounting read-only")
+}
+
+/// Unmount one mount point of a disk, saying how if it took more than a plain unmount.
+fn unmount_point(mount_path: &Path, policy: unmount::Policy) -> Result<unmount::Step> {
+	let step = unmount::run(mount_path, policy).context("unmounting")?;
+	if step != unmount::Step::Plain {
+		eprintln!("{}: {step}.", mount_path.display());
+	}
+	Ok(step)
+}
+
+/// Unmount a disk that the user is done with, first taking a snapshot and backing it up if configured.
+///
+/// A failed backup doesn't stop the unmount, since the user asked for the disk to go away.
+fn unmount_when_done(config: &Config, disk: &Disk, policy: unmount::Policy) -> Result<()> {
+	// Without privileges, this goes through the helper, which does all of this itself.
+	if !is_privileged() || disk_state(disk)? != DiskState::Mounted {
+		return do_unmount(config, disk, policy);
+	}
+	if let Some(snapshots) = disk
+		.snapshots
+		.as_ref()
+		.filter(|snapshots| snapshots.on_unmount)
+	{
+		take_snapshot(disk, &config.mount_path(disk), snapshots);
+	}
+	if disk.backup_on_unmount {
+		let backup = disk.backup.as_ref().expect("validated in config");
+		match backup::run(disk.as_repr(), &config.mount_path(disk), backup) {
+			Ok(()) => eprintln!("backed up {}.", disk.as_repr()),
+			Err(error) => output::warning(format_args!(
+				"failed to back up {}: {error:#}",
+				disk.as_repr()
+			)),
+		}
+	}
+	do_unmount(config, disk, policy)
+}
+
+fn do_unmount(config: &Config, disk: &Disk, policy: unmount::Policy) -> Result<()> {
+	if !is_privileged() {
+		return helper::unmount(disk);
+	}
+
+	let disk_name = disk.as_repr();
+	let mountable = disk.to_mountable();
+	let mount_path = config.mount_path(disk);
+	let mount_path = Path::new(&mount_path);
+
+	// A mounted snapshot keeps the filesystem in use after its main mount is gone.
+	if snapshot::unmount(&snapshot::mount_path(disk_name))? {
+		eprintln!("unmounted {disk_name}'s snapshot.");
+	}
+
+	// Otherwise they would keep the filesystem busy.
+	if !disk.units.is_empty() {
+		if let Err(error) = systemd::stop(&disk.units) {
+			output::warning(format_args!("failed to stop units: {error:#}"));
+		}
+	}
+
+	let mut steps = Vec::new();
+	// Take responsibility for mounts made by something else, too.
+	for point in mount_points(disk)?.iter().rev() {
+		if point != mount_path {
+			eprintln!("unmounting external mount at {}.", point.display());
+			steps.push(unmount_point(point, policy)?);
+		}
+	}
+	steps.push(unmount_point(mount_path, policy)?);
+	// A lazily detached filesystem still uses the device until nothing uses the filesystem.
+	let is_detached = steps.contains(&unmount::Step::Lazy);
+
+	if let Mountable::Encrypted { outer_uuid, .. } = mountable {
+		if is_detached {
+			output::warning(format_args!(
+				"{disk_name} is still in use, so its encryption stays open. run `d cleanup` once nothing uses it."
+			));
+		} else if outer_device_present(disk)? {
+			if let Some(mapping_name) = open_mapping_name(disk)? {
+				if state::has_external_mapping(disk_name)? && !config.close_external_mappings {
+					eprintln!(
+						"leaving {disk_name}'s encryption open, since it was already open when it was mounted."
+					);
+				} else {
+					if mapping_name != opened_name_for_encrypted(outer_uuid, disk_name) {
+						eprintln!("closing external mapping {mapping_name}.");
+					}
+					close_mapping(&mapping_name).context("closing encrypted device")?;
+					if let Err(error) = state::clear_external_mapping(disk_name) {
+						output::warning(format_args!(
+							"failed to record closing the encryption: {error:#}"
+						));
+					}
+				}
+			}
+		}
+	}
+	if disk.verity.is_some() && !is_detached && verity::is_open(disk_name) {
+		verity::close(disk_name).context("closing verity device")?;
+	}
+
+	if let Err(error) = state::set_managed(disk_name, false) {
+		output::warning(format_args!("failed to record unmount: {error:#}"));
+	}
+
+	if disk.spin_down && !is_detached {
+		let spin_down_res = outer_dev_path(disk).and_then(|dev_path| power::standby_now(&dev_path));
+		if let Err(error) = spin_down_res {
+			output::warning(format_args!("failed to spin down: {error:#}"));
+		}
+	}
+
+	Ok(())
+}
+
+fn do_unmount_targets(config: &Config, targets: &[String], policy: unmount::Policy) -> Result<()> {
+	ensure!(!targets.is_empty(), "no disks given");
+	let (fuse_mounts, targets) = config.split_fuse_targets(targets);
+	let (swaps, targets) = config.split_swap_targets(&targets);
+	if !swaps.is_empty() {
+		ensure_root()?;
+	}
+	if !targets.is_empty() {
+		ensure_root_or_helper()?;
+		ensure!(
+			is_privileged() || policy == config.unmount,
+			"--terminate and --lazy can't be used through the helper"
+		);
+	}
+	for fuse in fuse_mounts {
+		if fuse::unmount(&fuse::mount_path(fuse)?)? {
+			eprintln!("unmounted {}.", fuse.name);
+		} else {
+			eprintln!("{} was not mounted.", fuse.name);
+		}
+	}
+	for swap in swaps {
+		if swap::off(swap)? {
+			eprintln!("deactivated swap {}.", swap.name);
+		} else {
+			eprintln!("swap {} was not active.", swap.name);
+		}
+	}
+
+	for disk in config.resolve_targets(&targets)?.into_iter().rev() {
+		let sessions = state::session_count(disk.as_repr())?;
+		ensure!(
+			sessions == 0,
+			"{} is in use by {sessions} `d c` session(s); exit them first",
+			disk.as_repr()
+		);
+		unmount_when_done(config, disk, policy)?;
+		eprintln!("unmounted {}.", disk.as_repr());
+	}
+	Ok(())
+}
+
+/// Only says that the disk is safe to unplug if every step succeeded.
+fn do_eject(config: &Config, args: &EjectArgs) -> Result<()> {
+	ensure_root()?;
+	let disk = config.disk(&args.disk)?;
+	let disk_name = disk.as_repr();
+	let sessions = state::session_count(disk_name)?;
+	ensure!(
+		sessions == 0,
+		"{disk_name} is in use by {sessions} `d c` session(s); exit them first"
+	);
+	// This also fails early if the drive isn't attached.
+	let dev_path = outer_dev_path(disk)?;
+	if disk_state(disk)? != DiskState::Unmounted {
+		unmount_when_done(config, disk, args.policy(config))?;
+		eprintln!("unmounted {disk_name}.");
+	}
+	let current = disk_state(disk)?;
+	ensure!(
+		current == DiskState::Unmounted,
+		"{disk_name} is still {}",
+		current.as_repr()
+	);
+	power::power_off(&dev_path).context("powering off")?;
+	eprintln!(
+		"{}",
+		output::paint_stderr(
+			format_args!("{disk_name} is safe to unplug."),
+			output::Color::Green
+		)
+	);
+	Ok(())
+}
+
+/// Unmount every disk that `d` mounted, in the reverse order, leaving disks mounted by something else alone.
+fn do_unmount_all(config: &Config, policy: unmount::Policy) -> Result<()> {
+	let disk_names = state::managed_disks()?;
+	if disk_names.is_empty() {
+		eprintln!("d has no disks mounted.");
+		return Ok(());
+	}
+
+	let mut table = output::Table::new(&["DISK", "RESULT"]);
+	let mut num_stuck = 0;
+	for disk_name in &disk_names {
+		let unmount_res = config.disk(disk_name).map_err(Into::into).and_then(|disk| {
+			let sessions = state::session_count(disk_name)?;
+			ensure!(sessions == 0, "in use by {sessions} `d c` session(s)");
+			unmount_when_done(config, disk, policy)
+		});
+		let result = match unmount_res {
+			Ok(()) => ("unmounted".to_owned(), Some(output::Color::Green)),
+			Err(error) => {
+				num_stuck += 1;
+				(
+					format!("still mounted: {error:#}"),
+					Some(output::Color::Red),
+				)
+			}
+		};
+		table.row(vec![(disk_name.clone(), None), result]);
+	}
+	table.print();
+
+	ensure!(
+		num_stuck == 0,
+		"{num_stuck} of {} disks are still mounted",
+		disk_names.len()
+	);
+	Ok(())
+}
+
+/// Set in `c` sessions to the names of the disks that the shell is in a session for, separated by colons.
+const SESSIONS_VAR: &str = "D_SESSIONS";
+
+fn sessions_in_environment() -> Vec<String> {
+	std::env::var(SESSIONS_VAR)
+		.unwrap_or_default()
+		.split(':')
+		.filter(|name| !name.is_empty())
+		.map(str::to_owned)
+		.collect()
+}
+
+/// A cgroup for the shell of a session on `disk`, so that whatever it leaves running can be found afterward, with its I/O on the disk limited to `io_limit` MiB/s.
+///
+/// Without root or cgroup v2, the session runs without one, which is only worth a warning if a limit was asked for.
+fn session_cgroup(disk: &Disk, mount_path: &str, io_limit: Option<u32>) -> Option<cgroup::Scope> {
+	let create = || {
+		ensure!(is_privileged(), "cgroups need root");
+		let scope = cgroup::Scope::create(disk.as_repr())?;
+		if let Some(mib_per_sec) = io_limit {
+			let devices = watchdog::device_stack(mount_path.as_ref());
+			let device = devices.first().context("the disk's device was not found")?;
+			scope.limit_io(device, u64::from(mib_per_sec) << 20)?;
+		}
+		Ok(scope)
+	};
+	match create() {
+		Ok(scope) => Some(scope),
+		Err(error) => {
+			if io_limit.is_some() {
+				output::warning(format_args!(
+					"not limiting I/O: {error:#}. the session will run without a limit."
+				));
+			}
+			None
+		}
+	}
+}
+
+/// Offer to terminate the processes that were started in a session and are still running, so that they don't keep the disk busy.
+fn stop_leftover_processes(config: &Config, cgroup: &cgroup::Scope) -> Result<()> {
+	let leftovers: Vec<unmount::Blocker> = cgroup
+		.processes()?
+		.into_iter()
+		.map(|pid| unmount::Blocker::new(pid, "was started in the session".to_owned()))
+		.collect();
+	if leftovers.is_empty() {
+		return Ok(());
+	}
+	eprintln!("d: processes from the session are still running:");
+	for leftover in &leftovers {
+		eprintln!("  {leftover}");
+	}
+	let terminate = config.unmount.terminate_blockers
+		|| (prompt::can_ask() && prompt::confirm("terminate them?", false)?);
+	if terminate {
+		unmount::terminate(&leftovers);
+	}
+	Ok(())
+}
+
+/// Run the shell of `account` in `mount_path`, watching free space and the kernel log while it runs.
+fn run_session_shell(
+	config: &Config,
+	disk: &Disk,
+	mount_path: &str,
+	account: &privilege::Account,
+	cgroup: Option<&cgroup::Scope>,
+) -> Result<()> {
+	let mut sessions = sessions_in_environment();
+	sessions.push(disk.as_repr().to_owned());
+	let private = disk
+		.has_private_sessions()
+		.then(|| shell::profile(config, account.shell()))
+		.flatten();
+	let devices = watchdog::device_stack(mount_path.as_ref());
+	let sandbox = disk
+		.is_untrusted()
+		.then(|| sandbox::Sandbox::for_mount_path(mount_path.as_ref()))
+		.transpose()
+		.context("sandboxing the shell of an untrusted disk")?;
+	let mut command = account.shell_command();
+	command
+		.current_dir(mount_path)
+		.env(SESSIONS_VAR, sessions.join(":"));
+	if let Some(private) = &private {
+		private.apply(&mut command);
+	}
+	if let Some(cgroup) = cgroup {
+		cgroup.attach(&mut command);
+	}
+	if let Some(sandbox) = &sandbox {
+		sandbox.apply(&mut command);
+	}
+	let mut shell = command.spawn().context("spawning sub-shell")?;
+	if let Some(cgroup) = cgroup {
+		if let Err(error) = cgroup.add(shell.id()) {
+			output::warning(format_args!(
+				"the session's processes won't be limited or tracked: {error:#}."
+			));
+		}
+	}
+	let stop_watchers = AtomicBool::new(false);
+	std::thread::scope(|scope| {
+		if !devices.is_empty() {
+			scope.spawn(|| {
+				watchdog::watch(
+					disk.as_repr(),
+					mount_path,
+					&devices,
+					disk.read_only_on_errors,
+					&stop_watchers,
+				);
+			});
+		}
+		if disk.min_free_percent > 0.0 {
+			scope.spawn(|| {
+				space::watch(
+					mount_path,
+					disk.as_repr(),
+					disk.min_free_percent,
+					&stop_watchers,
+				);
+			});
+		}
+		if let Some(minutes) = disk.sync_every_minutes {
+			let interval = Duration::from_secs(u64::from(minutes) * 60);
+			let stop_watchers = &stop_watchers;
+			scope.spawn(move || {
+				flush::watch(mount_path, disk.as_repr(), interval, stop_watchers);
+			});
+		}
+		let wait_res = shell.wait();
+		stop_watchers.store(true, Ordering::Relaxed);
+		wait_res
+	})
+	.context("waiting for sub-shell")?;
+	Ok(())
+}
+
+/// Register a `d c` session by this process, directly or through the helper.
+fn begin_own_session(disk: &Disk) -> Result<()> {
+	if is_privileged() {
+		state::begin_session(disk.as_repr(), state::own_pid())
+	} else {
+		helper::begin_session(disk.as_repr())
+	}
+	.context("registering session")
+}
+
+/// Returns how many other sessions are still using the disk.
+fn end_own_session(disk: &Disk) -> Result<usize> {
+	if is_privileged() {
+		state::end_session(disk.as_repr(), state::own_pid())
+	} else {
+		helper::end_session(disk.as_repr())
+	}
+	.context("unregistering session")
+}
+
+/// Returns whether this process should go on to end its session and unmount. After detaching, the foreground process hands its session to a background process, which waits for the tmux session to end, and leaves the rest to it.
+fn run_tmux_session(disk: &Disk, mount_path: &str, account: &privilege::Account) -> Result<bool> {
+	use nix::sys::signal::{signal, SigHandler, Signal};
+
+	// If the terminal is closed while attached, we must survive to hand off the session.
+	// SAFETY: ignoring a signal installs no handler.
+	unsafe { signal(Signal::SIGHUP, SigHandler::SigIgn) }.context("ignoring SIGHUP")?;
+	let mut sessions = sessions_in_environment();
+	sessions.push(disk.as_repr().to_owned());
+	let env = [(SESSIONS_VAR, sessions.join(":"))];
+	if !tmux::attach(disk.as_repr(), mount_path, account, &env)? {
+		return Ok(true);
+	}
+
+	eprintln!(
+		"d: detached; {} will be unmounted when the tmux session ends.",
+		disk.as_repr()
+	);
+	if !tmux::continue_in_background(|| begin_own_session(disk))? {
+		return Ok(false);
+	}
+	tmux::wait_for_end(disk.as_repr(), account)?;
+	Ok(true)
+}
+
+/// How long a `d c` session lasted and how much it read from and wrote to the disk.
+struct SessionAccounting {
+	start: Instant,
+	/// The kernel name of the device that the filesystem is on, and its counters when the session started.
+	device: Option<(String, iostat::Counters)>,
+}
+
+impl SessionAccounting {
+	fn start(mount_path: &str) -> Self {
+		let device = watchdog::device_stack(mount_path.as_ref())
+			.into_iter()
+			.next()
+			.and_then(|device| {
+				let counters = iostat::read().ok()?.get(&device).copied()?;
+				Some((device, counters))
+			});
+		Self {
+			start: Instant::now(),
+			device,
+		}
+	}
+
+	fn print_summary(&self) {
+		let duration = output::format_duration(self.start.elapsed());
+		let end_counters = self
+			.device
+			.as_ref()
+			.and_then(|(device, before)| Some((*before, iostat::read().ok()?.get(device).copied()?)));
+		match end_counters {
+			Some((before, after)) => eprintln!(
+				"d: session lasted {duration}, reading {} and writing {}.",
+				output::format_size(after.bytes_read().saturating_sub(before.bytes_read())),
+				output::format_size(after.bytes_written().saturating_sub(before.bytes_written())),
+			),
+			None => eprintln!("d: session lasted {duration}."),
+		}
+	}
+}
+
+fn do_cd(
+	config: &Config,
+	disk: &Disk,
+	options: MountOptions,
+	account: &privilege::Account,
+	session: SessionOptions,
+) -> Result<()> {
+	let is_nested = sessions_in_environment()
+		.iter()
+		.any(|name| name == disk.as_repr())
+		|| state::is_inside_session(disk.as_repr()).unwrap_or(false);
+	if is_nested {
+		// The outer session keeps the disk mounted, so there's nothing to set up or tear down.
+		eprintln!(
+			"d: already in a session for {}, entering it again.",
+			disk.as_repr()
+		);
+		let mount_path = config.mount_path(disk);
+		let cgroup = session_cgroup(disk, &mount_path, session.io_limit);
+		return run_session_shell(config, disk, &mount_path, account, cgroup.as_ref());
+	}
+
+	let MountReturn {
+		mount_path,
+		was_already_mounted: _,
+	} = do_mount(config, disk, options)?;
+	let accounting = SessionAccounting::start(&mount_path);
+	begin_own_session(disk)?;
+	set_up_session(disk, &mount_path, account)?;
+	let cgroup = if session.tmux {
+		None
+	} else {
+		session_cgroup(disk, &mount_path, session.io_limit)
+	};
+	let shell_res = if session.tmux {
+		run_tmux_session(disk, &mount_path, account)
+	} else {
+		let mut extras = Vec::new();
+		if disk.is_untrusted() {
+			extras.push("sandboxed to the disk".to_owned());
+		}
+		if let Some(minutes) = disk.sync_every_minutes {
+			let plural = if minutes == 1 { "" } else { "s" };
+			extras.push(format!("syncing every {minutes} minute{plural}"));
+		}
+		if let Some(limit) = session.io_limit {
+			extras.push(format!("limited to {limit} MiB/s"));
+		}
+		if extras.is_empty() {
+			eprintln!("d: entering subshell. stay safe, friend.");
+		} else {
+			eprintln!(
+				"d: entering subshell, {}. stay safe, friend.",
+				extras.join(", ")
+			);
+		}
+		run_session_shell(config, disk, &mount_path, account, cgroup.as_ref()).map(|()| true)
+	};
+	let remaining = end_own_session(disk)?;
+	let shell_res = match shell_res {
+		Ok(shell_res) => shell_res,
+		Err(error) => {
+			if remaining == 0 {
+				tear_down_session(disk, &mount_path, account);
+			}
+			return Err(error);
+		}
+	};
+	if !shell_res {
+		return Ok(());
+	}
+	accounting.print_summary();
+
+	if session.keep {
+		defer_unmount(disk);
+		return Ok(());
+	}
+	if remaining > 0 {
+		print_other_sessions(disk, remaining);
+		return Ok(());
+	}
+	let will_ask = !session.tmux && config.ask_before_unmount_seconds.is_some() && prompt::can_ask();
+	loop {
+		if will_ask && !ask_to_unmount(config, disk, &mount_path, account, cgroup.as_ref())? {
+			return Ok(());
+		}
+		tear_down_session(disk, &mount_path, account);
+		if let Some(cgroup) = &cgroup {
+			stop_leftover_processes(config, cgroup)?;
+		}
+		eprintln!("d: cleaning up; unmounting.");
+		if let Ok(()) = unmount_when_done(config, disk, config.unmount) {
+			eprintln!("d: unmounted, bye");
+			return Ok(());
+		}
+		output::warning("d: unmount failed. maybe still busy");
+		if !will_ask {
+			if !prompt::is_non_interactive() {
+				// Give the user some time to see the message.
+				std::thread::sleep(Duration::from_secs(1));
+			}
+			return Ok(());
+		}
+	}
+}
+
+/// Record whether a disk's `session_setup` commands are in effect, returning whether they were before.
+fn set_session_set_up(disk: &Disk, set_up: bool) -> Result<bool> {
+	if is_privileged() {
+		state::set_session_set_up(disk.as_repr(), set_up)
+	} else {
+		helper::set_session_set_up(disk.as_repr(), set_up)
+	}
+	.context("recording session setup")
+}
+
+/// Run the disk's `session_setup` commands, unless an earlier session already did and they haven't been torn down, such as when it was left mounted with `--keep`.
+fn set_up_session(disk: &Disk, mount_path: &str, account: &privilege::Account) -> Result<()> {
+	if disk.session_setup.is_empty() && disk.session_teardown.is_empty() {
+		return Ok(());
+	}
+	if !set_session_set_up(disk, true)? {
+		run_session_commands(&disk.session_setup, mount_path, account, "setup");
+	}
+	Ok(())
+}
+
+/// Run the disk's `session_teardown` commands, if its setup is in effect.
+fn tear_down_session(disk: &Disk, mount_path: &str, account: &privilege::Account) {
+	if disk.session_setup.is_empty() && disk.session_teardown.is_empty() {
+		return;
+	}
+	match set_session_set_up(disk, false) {
+		Ok(true) => run_session_commands(&disk.session_teardown, mount_path, account, "teardown"),
+		Ok(false) => {}
+		Err(error) => output::warning(format_args!("not running teardown commands: {error:#}")),
+	}
+}
+
+/// Run a disk's `session_setup` or `session_teardown` commands as `account` in the mount path, warning about any that fail.
+fn run_session_commands(
+	commands: &[String],
+	mount_path: &str,
+	account: &privilege::Account,
+	what: &str,
+) {
+	for command in commands {
+		eprintln!("d: running {what} command `{command}`.");
+		let status = account
+			.command("sh")
+			.arg("-c")
+			.arg(command)
+			.current_dir(mount_path)
+			.status();
+		match status {
+			Ok(status) if status.success() => {}
+			Ok(status) => output::warning(format_args!(
+				"{what} command `{command}` exited with status {:?}",
+				status.code()
+			)),
+			Err(error) => output::warning(format_args!(
+				"failed to run {what} command `{command}`: {error}"
+			)),
+		}
+	}
+}
+
+/// Leave the disk mounted after a session, remembering to unmount it with `d finish`.
+fn defer_unmount(disk: &Disk) {
+	let res = if is_privileged() {
+		state::defer_unmount(disk.as_repr())
+	} else {
+		helper::defer_unmount(disk.as_repr())
+	};
+	if let Err(error) = res {
+		output::warning(format_args!(
+			"failed to record the deferred unmount: {error:#}"
+		));
+	}
+	eprintln!(
+		"d: leaving {} mounted. unmount it with `d u {}`, or `d finish` to unmount everything left mounted like this.",
+		disk.as_repr(),
+		disk.shortcut()
+	);
+}
+
+fn do_pending(config: &Config) -> Result<()> {
+	let deferred = state::deferred_disks()?;
+	if deferred.is_empty() {
+		eprintln!("no unmounts are pending.");
+		return Ok(());
+	}
+	let mut table = output::Table::new(&["NAME", "STATE", "SESSIONS", "LEFT MOUNTED"]);
+	for (disk_name, deferred_at) in deferred {
+		let (state_repr, color) = match config.disk(&disk_name).ok().map(disk_state) {
+			Some(Ok(current)) => (current.as_repr().to_owned(), current.color()),
+			_ => ("unknown".to_owned(), Some(output::Color::Red)),
+		};
+		table.row(vec![
+			(disk_name.clone(), None),
+			(state_repr, color),
+			(state::session_count(&disk_name)?.to_string(), None),
+			(stats::format_ago(deferred_at), None),
+		]);
+	}
+	table.print();
+	Ok(())
+}
+
+fn do_finish(config: &Config) -> Result<()> {
+	let deferred = state::deferred_disks()?;
+	if deferred.is_empty() {
+		eprintln!("no unmounts are pending.");
+		return Ok(());
+	}
+	let mut failed = Vec::new();
+	for (disk_name, _) in deferred {
+		let Ok(disk) = config.disk(&disk_name) else {
+			output::warning(format_args!(
+				"{disk_name} is no longer in the config; forgetting it."
+			));
+			state::forget_deferred(&disk_name)?;
+			continue;
+		};
+		let sessions = state::session_count(&disk_name)?;
+		if sessions > 0 {
+			let plural = if sessions == 1 { "" } else { "s" };
+			eprintln!("{disk_name} is in use by {sessions} session{plural}; skipping it.");
+			continue;
+		}
+		if disk_state(disk)? == DiskState::Absent {
+			eprintln!("{disk_name} is no longer attached; forgetting it.");
+			state::forget_deferred(&disk_name)?;
+			continue;
+		}
+		match unmount_when_done(config, disk, config.unmount) {
+			Ok(()) => eprintln!("finished {disk_name}."),
+			Err(error) => {
+				output::warning(format_args!("failed to unmount {disk_name}: {error:#}"));
+				failed.push(disk_name);
+			}
+		}
+	}
+	ensure!(failed.is_empty(), "failed to unmount {}", failed.join(", "));
+	Ok(())
+}
+
+fn print_other_sessions(disk: &Disk, remaining: usize) {
+	let plural = if remaining == 1 { "" } else { "s" };
+	eprintln!(
+		"d: {remaining} other session{plural} still using {}; leaving it mounted.",
+		disk.as_repr()
+	);
+}
+
+/// After the shell exits, ask whether to unmount, so that an accidental `exit` doesn't take the disk away. "later" goes back into the shell and asks again when it exits. Returns whether to go on unmounting.
+fn ask_to_unmount(
+	config: &Config,
+	disk: &Disk,
+	mount_path: &str,
+	account: &privilege::Account,
+	cgroup: Option<&cgroup::Scope>,
+) -> Result<bool> {
+	let Some(seconds) = config.ask_before_unmount_seconds else {
+		return Ok(true);
+	};
+	let question = format!(
+		"d: unmount {}? [Y/n/later] (yes in {seconds} seconds)",
+		disk.as_repr()
+	);
+	loop {
+		let answer = prompt::ask_with_timeout(&question, Duration::from_secs(seconds.into()))?;
+		match answer.map(|answer| answer.to_lowercase()).as_deref() {
+			None | Some("" | "y" | "yes") => return Ok(true),
+			Some("n" | "no") => {
+				defer_unmount(disk);
+				return Ok(false);
+			}
+			Some("l" | "later") => {
+				eprintln!("d: back into the subshell.");
+				begin_own_session(disk)?;
+				let shell_res = run_session_shell(config, disk, mount_path, account, cgroup);
+				let remaining = end_own_session(disk)?;
+				shell_res?;
+				if remaining > 0 {
+					print_other_sessions(disk, remaining);
+					return Ok(false);
+				}
+			}
+			Some(_) => eprintln!("please answer y, n, or later."),
+		}
+	}
+}
+
+fn do_cd_target(config: &Config, args: &CdArgs) -> Result<()> {
+	let account = privilege::account(args.user.as_deref())?;
+	if let Some(fuse) = config.fuse(&args.disk) {
+		return do_cd_fuse(config, fuse, &account, args.keep);
+	}
+	ensure_root_or_helper()?;
+	let disk = config.disk(&args.disk)?;
+	ensure!(args.io_limit != Some(0), "--io-limit must be at least 1");
+	let io_limit = args.io_limit.or(disk.io_limit_mib_per_sec);
+	ensure!(
+		io_limit.is_none() || !args.tmux,
+		"I/O limits are not supported with --tmux"
+	);
+	ensure!(
+		!disk.is_untrusted() || !args.tmux,
+		"{} is untrusted, and its shell can't be sandboxed in tmux",
+		disk.as_repr()
+	);
+	let session = SessionOptions {
+		tmux: args.tmux,
+		io_limit,
+		keep: args.keep,
+	};
+	do_cd(config, disk, args.options()?, &account, session)
+}
+
+/// FUSE filesystems have no session tracking, since that needs root, so the filesystem is only unmounted afterward if this session mounted it.
+fn do_cd_fuse(
+	config: &Config,
+	fuse: &config::Fuse,
+	account: &privilege::Account,
+	keep: bool,
+) -> Result<()> {
+	let mount_path = fuse::mount_path(fuse)?;
+	let was_already_mounted = fuse::mount(fuse, &mount_path)?;
+	eprintln!("d: entering subshell. stay safe, friend.");
+	let private = fuse
+		.has_private_sessions()
+		.then(|| shell::profile(config, account.shell()))
+		.flatten();
+	let mut command = account.shell_command();
+	if let Some(private) = &private {
+		private.apply(&mut command);
+	}
+	command
+		.current_dir(&mount_path)
+		.status()
+		.context("running sub-shell")?;
+
+	if was_already_mounted {
+		eprintln!(
+			"d: {} was already mounted before this session; leaving it mounted.",
+			fuse.name
+		);
+		return Ok(());
+	}
+	if keep {
+		eprintln!("d: leaving {} mounted.", fuse.name);
+		return Ok(());
+	}
+	eprintln!("d: cleaning up; unmounting.");
+	if fuse::unmount(&mount_path).is_ok() {
+		eprintln!("d: unmounted, bye");
+	} else {
+		output::warning("d: unmount failed. maybe still busy");
+	}
+	Ok(())
+}
+
+fn do_list(
+	config: &Config,
+	ListArgs {
+		verbose,
+		tag,
+		sort,
+		format,
+	}: ListArgs,
+) -> Result<()> {
+	let all_stats = stats::load()?;
+	let stats_for = |disk: &Disk| all_stats.get(disk.as_repr()).copied().unwrap_or_default();
+
+	let mut disks: Vec<&Disk> = config
+		.disks
+		.iter()
+		.filter(|disk| tag.as_ref().is_none_or(|tag| disk.tags.contains(tag)))
+		.collect();
+	match sort {
+		ListSort::Config => {}
+		ListSort::Recent => disks.sort_by_key(|disk| std::cmp::Reverse(stats_for(disk).last_used)),
+	}
+
+	if let Some(format) = format {
+		let states = disks
+			.iter()
+			.map(|disk| Ok((disk.as_repr(), disk_state(disk)?)))
+			.collect::<Result<Vec<_>>>()?;
+		statusbar::print(format, &states);
+		return Ok(());
+	}
+
+	let mut header = vec!["SHORTCUT", "NAME", "KIND", "STATE", "MOUNT PATH"];
+	if verbose {
+		header.extend(["MOUNTS", "LAST USED"]);
+	}
+	let listed: Vec<String> = disks.iter().map(|disk| disk.as_repr().to_owned()).collect();
+	let any_described = disks.iter().any(|disk| disk.description.is_some());
+	if any_described {
+		header.push("DESCRIPTION");
+	}
+	let mut table = output::Table::new(&header);
+
+	for disk in disks {
+		let state = disk_state(disk).with_context(|| format!("getting state of {}", disk.as_repr()))?;
+		let kind = match disk.to_mountable() {
+			Mountable::Plain { .. } => "plain",
+			Mountable::Encrypted { .. } => "encrypted",
+			Mountable::Verity { .. } => "verified",
+		};
+		let mount_path = mount_points(disk)?
+			.iter()
+			.map(|point| point.display().to_string())
+			.collect::<Vec<_>>()
+			.join(", ");
+		let state_repr = if is_external(disk, state)? {
+			format!("{} (external)", state.as_repr())
+		} else {
+			state.as_repr().to_owned()
+		};
+		let mut row = vec![
+			(disk.shortcut().to_owned(), None),
+			(disk.as_repr().to_owned(), None),
+			(kind.to_owned(), None),
+			(state_repr, state.color()),
+			(mount_path, None),
+		];
+		if verbose {
+			let usage = stats_for(disk);
+			row.extend([
+				(usage.mount_count.to_string(), None),
+				(
+					usage
+						.last_used
+						.map_or_else(|| "never".to_owned(), stats::format_ago),
+					None,
+				),
+			]);
+		}
+		if any_described {
+			row.push((disk.description.clone().unwrap_or_default(), None));
+		}
+		table.row(row);
+	}
 
-	#[argh(positional)]
-	disk: Disk,
+	table.print();
+	print_sessions(&listed)?;
+	if !config.swaps.is_empty() && tag.is_none() {
+		print_swaps(config)?;
+	}
+	Ok(())
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Action {
-	Mount,
-	Unmount,
-	Cd,
+/// The `d c` sessions that are open on the disks called `disk_names`, and which terminals they are in.
+fn print_sessions(disk_names: &[String]) -> Result<()> {
+	let sessions: Vec<state::SessionRecord> = state::sessions()?
+		.into_iter()
+		.filter(|session| disk_names.contains(&session.disk))
+		.collect();
+	if sessions.is_empty() {
+		return Ok(());
+	}
+	println!();
+	let mut table = output::Table::new(&["SESSION", "PID", "TERMINAL", "STARTED"]);
+	for session in sessions {
+		table.row(vec![
+			(session.disk, None),
+			(session.pid.to_string(), None),
+			(session.tty.unwrap_or_else(|| "none".to_owned()), None),
+			(stats::format_ago(session.started), None),
+		]);
+	}
+	table.print();
+	Ok(())
 }
 
-#[derive(Debug, thiserror::Error)]
-#[error("unknown action {0:?}. valid actions are m (mount), u (unmount), c (cd).")]
-struct UnknownAction(String);
-
-impl FromStr for Action {
-	type Err = UnknownAction;
+fn print_swaps(config: &Config) -> Result<()> {
+	println!();
+	let mut table = output::Table::new(&["SHORTCUT", "SWAP", "KIND", "STATE", "USAGE"]);
+	for swap_config in &config.swaps {
+		let kind = if swap_config.encrypt {
+			"encrypted"
+		} else {
+			"plain"
+		};
+		let (state, usage) = match swap::status(swap_config)? {
+			Some(entry) => (
+				("active".to_owned(), Some(output::Color::Green)),
+				format!(
+					"{} of {}",
+					output::format_size(entry.used_bytes),
+					output::format_size(entry.size_bytes)
+				),
+			),
+			None => (("inactive".to_owned(), None), String::new()),
+		};
+		table.row(vec![
+			(swap_config.shortcut.clone(), None),
+			(swap_config.name.clone(), None),
+			(kind.to_owned(), None),
+			state,
+			(usage, None),
+		]);
+	}
+	table.print();
+	Ok(())
+}
 
-	fn from_str(s: &str) -> Result<Self, UnknownAction> {
-		Ok(match s {
-			"m" => Self::Mount,
-			"u" => Self::Unmount,
-			"c" => Self::Cd,
-			_ => return Err(UnknownAction(s.to_owned())),
-		})
+fn format_list(items: &[String]) -> String {
+	if items.is_empty() {
+		"none".to_owned()
+	} else {
+		items.join(", ")
 	}
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
-enum Disk {
-	Zdani,
-	Sivydatni,
-	Muhackiku,
-	Barda,
-	Sivbra,
+/// `link` is where to find the device, which is usually `by_uuid_path(uuid)`. See `outer_device_link`.
+fn print_device_info(label: &str, uuid: &str, link: Option<&str>) -> Result<Option<PathBuf>> {
+	println!("{label} UUID: {uuid}");
+	let Some(link) = link else {
+		println!("\tdevice: not present (the drive is not attached)");
+		return Ok(None);
+	};
+	if link != by_uuid_path(uuid) {
+		println!("\tfound at: {link}");
+	}
+	if !Path::try_exists(link.as_ref()).context("checking for device symlink")? {
+		println!("\tdevice: not present ({link} does not exist)");
+		return Ok(None);
+	}
+
+	let dev_path = std::fs::canonicalize(link).context("getting canonical device for symlink")?;
+	println!("\tdevice: {}", dev_path.display());
+	if let Some(kernel_name) = sysfs::kernel_name(&dev_path) {
+		println!("\tholders: {}", format_list(&sysfs::holders(&kernel_name)?));
+		println!("\tslaves: {}", format_list(&sysfs::slaves(&kernel_name)?));
+	}
+	let probe = blkid::probe(&dev_path)?;
+	println!(
+		"\tdetected content: {}",
+		probe.content_type.as_deref().unwrap_or("unknown")
+	);
+	Ok(Some(dev_path))
 }
 
-impl Disk {
-	fn as_repr(self) -> &'static str {
-		match self {
-			Self::Zdani => "zdani",
-			Self::Sivydatni => "sivydatni",
-			Self::Muhackiku => "muhackiku",
-			Self::Barda => "barda",
-			Self::Sivbra => "sivbra",
-		}
+fn do_info(config: &Config, disk: &Disk) -> Result<()> {
+	let disk_name = disk.as_repr();
+	let mount_path = config.mount_path(disk);
+
+	println!("name: {disk_name} (shortcut {})", disk.shortcut());
+	if let Some(description) = &disk.description {
+		println!("description: {description}");
+	}
+	if !disk.tags.is_empty() {
+		println!("tags: {}", disk.tags.join(", "));
+	}
+	let state = disk_state(disk)?;
+	match state.color() {
+		Some(color) => println!("state: {}", output::paint_stdout(state.as_repr(), color)),
+		None => println!("state: {}", state.as_repr()),
+	}
+	if is_external(disk, state)? {
+		println!("\tmounted or opened by something other than d. `d u` will still tear it down.");
+	}
+	println!("configured filesystem: {}", disk.inner_filesystem());
+	println!("mount path: {mount_path}");
+	if let Some((composite, _)) = config.composite_membership(disk) {
+		println!("part of composite: {}", composite.name);
 	}
 
-	fn inner_filesystem(self) -> &'static str {
-		match self {
-			Self::Zdani | Self::Sivydatni | Self::Muhackiku | Self::Barda | Self::Sivbra => "ext4",
+	let inner_dev_path = match disk.to_mountable() {
+		Mountable::Plain { uuid } => {
+			print_device_info("filesystem", uuid, outer_device_link(disk)?.as_deref())?
+		}
+		Mountable::Encrypted {
+			outer_uuid,
+			inner_uuid,
+		} => {
+			print_device_info(
+				"outer (LUKS)",
+				outer_uuid,
+				outer_device_link(disk)?.as_deref(),
+			)?;
+			let opened_name = opened_name_for_encrypted(outer_uuid, disk_name);
+			println!("mapping: {opened_name}");
+			let status = caps::tool("cryptsetup")
+				.arg("status")
+				.arg(&opened_name)
+				.output()
+				.context("running cryptsetup status")?;
+			for line in String::from_utf8_lossy(&status.stdout).lines() {
+				println!("\t{}", line.trim());
+			}
+			print_device_info("filesystem", inner_uuid, Some(&by_uuid_path(inner_uuid)))?
+		}
+		Mountable::Verity {
+			data_uuid,
+			hash_uuid,
+			root_hash,
+		} => {
+			print_device_info("data", data_uuid, outer_device_link(disk)?.as_deref())?;
+			print_device_info("verity hash", hash_uuid, Some(&by_uuid_path(hash_uuid)))?;
+			println!("verity root hash: {root_hash}");
+			let mapping_path = verity::mapping_path(disk_name);
+			println!("mapping: {}", mapping_path.display());
+			for line in verity::status(disk_name)?.lines() {
+				println!("\t{}", line.trim());
+			}
+			verity::is_open(disk_name).then_some(mapping_path)
+		}
+	};
+
+	match mountinfo::find_by_mount_point(mount_path.as_ref())? {
+		None => println!("mount: nothing mounted at {mount_path}"),
+		Some(entry) => {
+			println!(
+				"mount: {} ({}) at {mount_path}",
+				entry.source, entry.fs_type
+			);
+			let is_expected = match &inner_dev_path {
+				Some(dev_path) => entry.is_from(&std::fs::canonicalize(dev_path)?),
+				None => false,
+			};
+			if !is_expected {
+				println!(
+					"\t{}",
+					output::paint_stdout("this is not the expected device", output::Color::Red)
+				);
+			}
+			println!("\tmount options: {}", entry.mount_options);
+			println!("\tsuperblock options: {}", entry.super_options);
+			if is_expected && entry.fs_type == "btrfs" {
+				print_btrfs_info(disk, &mount_path)?;
+			}
 		}
 	}
 
-	fn to_mountable(self) -> Mountable {
-		match self {
-			Self::Zdani => Mountable::Plain {
-				uuid: "9972ca08-32d9-42da-9418-1afa4a7f6966",
-			},
-			Self::Sivydatni => Mountable::Encrypted {
-				outer_uuid: "a02adf15-769d-4b61-9122-ddb3b3d1e7c2",
-				inner_uuid: "ac80428f-f91d-4b99-9d40-c885d122be18",
-			},
-			Self::Muhackiku => Mountable::Encrypted {
-				outer_uuid: "809dbaf9-4c95-4baf-890c-e6866dd1a913",
-				inner_uuid: "e1258f59-cb99-4b6b-8bd7-513c66d64439",
-			},
-			Self::Barda => Mountable::Plain {
-				uuid: "8f8ccfd3-aeae-4515-b081-3706561c64d4",
-			},
-			Self::Sivbra => Mountable::Encrypted {
-				outer_uuid: "5bd18b6b-1fc7-42e8-b318-c0c6d32ec86c",
-				inner_uuid: "09edb833-774e-4480-b9fa-f9e81627b0d5",
-			},
+	Ok(())
+}
+
+fn print_btrfs_info(disk: &Disk, mount_path: &str) -> Result<()> {
+	let last_scrubbed = stats::load()?
+		.get(disk.as_repr())
+		.and_then(|usage| usage.last_scrubbed);
+	println!(
+		"last scrubbed: {}",
+		last_scrubbed.map_or_else(|| "never".to_owned(), stats::format_ago)
+	);
+
+	println!("device error counters:");
+	for stat in btrfs::device_stats(mount_path)? {
+		let line = format!("{} {}: {}", stat.device, stat.counter, stat.value);
+		if stat.value == 0 {
+			println!("\t{line}");
+		} else {
+			println!("\t{}", output::paint_stdout(line, output::Color::Red));
 		}
 	}
+	Ok(())
+}
+
+fn do_scrub(config: &Config, disk: &Disk) -> Result<()> {
+	ensure!(
+		disk.inner_filesystem() == "btrfs",
+		"{} is {}, but only btrfs can be scrubbed",
+		disk.as_repr(),
+		disk.inner_filesystem()
+	);
+	let state = disk_state(disk)?;
+	ensure!(
+		state == DiskState::Mounted,
+		"{} is {}; mount it first",
+		disk.as_repr(),
+		state.as_repr()
+	);
+	btrfs::scrub(disk.as_repr(), &config.mount_path(disk))
+}
 
-	fn is_encrypted(self) -> bool {
-		match self.to_mountable() {
-			Mountable::Plain { .. } => false,
-			Mountable::Encrypted { .. } => true,
+/// Take a snapshot of a mounted disk and prune old ones, warning instead of failing since this happens as part of something else.
+fn take_snapshot(disk: &Disk, mount_path: &str, snapshots: &config::Snapshots) {
+	match snapshot::take(mount_path) {
+		Ok(name) => eprintln!("took snapshot {name} of {}.", disk.as_repr()),
+		Err(error) => {
+			output::warning(format_args!("failed to take snapshot: {error:#}"));
+			return;
+		}
+	}
+	if snapshots.has_retention() {
+		match snapshot::prune(mount_path, snapshots, false) {
+			Ok(pruned) if pruned.is_empty() => {}
+			Ok(pruned) => eprintln!("pruned {} old snapshot(s).", pruned.len()),
+			Err(error) => output::warning(format_args!("failed to prune snapshots: {error:#}")),
 		}
 	}
 }
 
-#[derive(Debug, thiserror::Error)]
-#[error("unknown disk {0:?}. valid disks are z (zdani), s (sivydatni), m (muhackiku), b (barda).")]
-struct UnknownDisk(String);
+/// Mount the snapshot named `name`, or the most recent one if it is `latest`, of the disk mounted at `mount_path`.
+fn mount_snapshot(disk: &Disk, mount_path: &str, name: &str) -> Result<()> {
+	ensure!(
+		disk.inner_filesystem() == "btrfs",
+		"{} is {}, but only btrfs has snapshots",
+		disk.as_repr(),
+		disk.inner_filesystem()
+	);
+	let name = if name == "latest" {
+		let snapshots = snapshot::list(mount_path)?;
+		let latest = snapshots.last().context("there are no snapshots")?;
+		latest.name.clone()
+	} else {
+		name.to_owned()
+	};
+	let target = snapshot::mount_path(disk.as_repr());
+	snapshot::mount(mount_path, &name, &target)?;
+	eprintln!("mounted snapshot {name} read-only at {target:?}.");
+	Ok(())
+}
 
-impl FromStr for Disk {
-	type Err = UnknownDisk;
+fn do_snapshots(config: &Config, args: &SnapshotsArgs) -> Result<()> {
+	let disk = config.disk(&args.disk)?;
+	let settings = disk
+		.snapshots
+		.as_ref()
+		.with_context(|| format!("{} has no snapshots configured", disk.as_repr()))?;
+	ensure!(
+		usize::from(args.take) + usize::from(args.prune) + usize::from(args.mount.is_some()) <= 1,
+		"only one of --take, --prune, and --mount can be given"
+	);
+	ensure!(
+		!args.dry_run || args.prune,
+		"--dry-run only applies to --prune"
+	);
+	if args.take || args.mount.is_some() || (args.prune && !args.dry_run) {
+		ensure_root()?;
+	}
+	let state = disk_state(disk)?;
+	ensure!(
+		state == DiskState::Mounted,
+		"{} is {}; mount it first",
+		disk.as_repr(),
+		state.as_repr()
+	);
+	let mount_path = config.mount_path(disk);
 
-	fn from_str(s: &str) -> Result<Self, UnknownDisk> {
-		Ok(match s {
-			"z" => Self::Zdani,
-			"s" => Self::Sivydatni,
-			"m" => Self::Muhackiku,
-			"b" => Self::Barda,
-			"sb" => Self::Sivbra,
-			_ => return Err(UnknownDisk(s.to_owned())),
-		})
+	if args.take {
+		let name = snapshot::take(&mount_path)?;
+		eprintln!("took snapshot {name}.");
+	} else if args.prune {
+		let pruned = snapshot::prune(&mount_path, settings, args.dry_run)?;
+		let verb = if args.dry_run {
+			"would prune"
+		} else {
+			"pruned"
+		};
+		eprintln!("{verb} {} snapshot(s).", pruned.len());
+		for name in pruned {
+			eprintln!("\t{name}");
+		}
+	} else if let Some(name) = &args.mount {
+		mount_snapshot(disk, &mount_path, name)?;
+	} else {
+		let mut table = output::Table::new(&["NAME", "TAKEN"]);
+		for snapshot in snapshot::list(&mount_path)? {
+			table.row(vec![
+				(snapshot.name, None),
+				(stats::format_ago(snapshot.taken_at), None),
+			]);
+		}
+		table.print();
 	}
+	Ok(())
 }
 
-enum Mountable {
-	Plain {
-		uuid: &'static str,
-	},
-	Encrypted {
-		outer_uuid: &'static str,
-		inner_uuid: &'static str,
-	},
+fn do_quota(config: &Config, disk: &Disk) -> Result<()> {
+	// Reading other users' quotas requires root.
+	ensure_root()?;
+	let state = disk_state(disk)?;
+	ensure!(
+		state == DiskState::Mounted,
+		"{} is {}; mount it first",
+		disk.as_repr(),
+		state.as_repr()
+	);
+	quota::report(disk, &config.mount_path(disk))
 }
 
-fn dev_path_for_uuid(uuid: &str) -> Result<PathBuf> {
-	let by_uuid = format!("/dev/disk/by-uuid/{uuid}");
-	std::fs::canonicalize(by_uuid).context("getting canonical device for by-UUID symlink")
-}
+fn do_backup(config: &Config, disk: &Disk) -> Result<()> {
+	// The backup tool needs to read everything on the disk.
+	ensure_root()?;
+	let settings = disk
+		.backup
+		.as_ref()
+		.with_context(|| format!("{} has no backup configured", disk.as_repr()))?;
+	let options = MountOptions {
+		read_only: settings.read_only,
+		transient: true,
+		..MountOptions::default()
+	};
+	let MountReturn {
+		mount_path,
+		was_already_mounted,
+	} = do_mount(config, disk, options)?;
 
-fn mount_path_for_name(name: &str) -> String {
-	format!("/mnt/{name}")
+	let backup_res = backup::run(disk.as_repr(), &mount_path, settings);
+
+	if !was_already_mounted {
+		do_unmount(config, disk, config.unmount)?;
+	}
+	backup_res?;
+	eprintln!("backed up {}.", disk.as_repr());
+	Ok(())
 }
 
-fn opened_name_for_encrypted(uuid: &str, disk_name: &str) -> String {
-	format!("{uuid}-{disk_name}")
+fn do_sync(config: &Config, args: &SyncArgs) -> Result<()> {
+	ensure_root()?;
+	let disk = config.disk(&args.disk)?;
+	let jobs: Vec<_> = match &args.job {
+		Some(name) => vec![disk
+			.rsync
+			.iter()
+			.find(|job| &job.name == name)
+			.with_context(|| format!("{} has no rsync job named {name:?}", disk.as_repr()))?],
+		None => disk.rsync.iter().collect(),
+	};
+	ensure!(
+		!jobs.is_empty(),
+		"{} has no rsync jobs configured",
+		disk.as_repr()
+	);
+	let MountReturn {
+		mount_path,
+		was_already_mounted,
+	} = do_mount(config, disk, MountOptions::default())?;
+
+	let sync_res = jobs.iter().try_for_each(|job| {
+		rsync::run(&mount_path, job, args.dry_run)
+			.with_context(|| format!("running job {:?}", job.name))
+	});
+
+	if !was_already_mounted {
+		do_unmount(config, disk, config.unmount)?;
+	}
+	sync_res?;
+	eprintln!("synced {}.", disk.as_repr());
+	Ok(())
 }
 
-struct MountReturn {
-	mount_path: String,
-	was_already_mounted: bool,
+/// Run `f` on the disk's mount path, mounting it read-only first if it isn't mounted, and unmounting it again afterward.
+fn with_read_only_mount<T>(
+	config: &Config,
+	disk: &Disk,
+	f: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+	let options = MountOptions {
+		read_only: true,
+		transient: true,
+		..MountOptions::default()
+	};
+	let MountReturn {
+		mount_path,
+		was_already_mounted,
+	} = do_mount(config, disk, options)?;
+	let res = f(&mount_path);
+	if !was_already_mounted {
+		do_unmount(config, disk, config.unmount)?;
+	}
+	res
 }
 
-/// Returns the mount path, if successful.
-fn mount(uuid: &str, disk_name: &str, filesystem: &str) -> Result<MountReturn> {
-	use nix::mount::{mount, MsFlags};
+fn do_manifest(config: &Config, disk: &Disk) -> Result<()> {
+	// Every file has to be read, whoever owns it.
+	ensure_root()?;
+	let count = with_read_only_mount(config, disk, |mount_path| {
+		manifest::create(disk.as_repr(), mount_path)
+	})?;
+	eprintln!(
+		"recorded the checksums of {count} files on {}.",
+		disk.as_repr()
+	);
+	Ok(())
+}
 
-	let mount_path = mount_path_for_name(disk_name);
+fn do_verify(config: &Config, disk: &Disk) -> Result<()> {
+	ensure_root()?;
+	let report = with_read_only_mount(config, disk, |mount_path| {
+		manifest::verify(disk.as_repr(), mount_path)
+	})?;
+	report.print();
+	ensure!(
+		report.is_intact(),
+		"{} has corrupted or unreadable files",
+		disk.as_repr()
+	);
+	Ok(())
+}
 
-	if !std::path::Path::try_exists(mount_path.as_ref())
-		.context("verifying that mount path exists")?
-	{
-		eprintln!("mount path ({mount_path:?}) does not exist, trying to create it.");
-		std::fs::create_dir_all(&mount_path).context("creating mount path")?;
+/// Build the tree for a block device and everything stacked on top of it.
+fn device_tree(kernel_name: &str, mounts: &[mountinfo::Entry]) -> Result<output::TreeNode> {
+	let size = output::format_size(sysfs::size_bytes(kernel_name)?);
+	let mut label = match sysfs::dm_name(kernel_name) {
+		Some(dm_name) => format!("{kernel_name} [{dm_name}] {size}"),
+		None => format!("{kernel_name} {size}"),
+	};
+	if let Some(content) = blkid::probe(&Path::new("/dev").join(kernel_name))?.content_type {
+		label = format!("{label} {content}");
 	}
 
-	let mount_res = mount(
-		Some(&dev_path_for_uuid(uuid)?),
-		mount_path.as_str(),
-		Some(filesystem),
-		MsFlags::MS_NOATIME | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
-		Some("discard,delalloc"),
+	let mut children = sysfs::holders(kernel_name)?
+		.iter()
+		.map(|holder| device_tree(holder, mounts))
+		.collect::<Result<Vec<_>>>()?;
+
+	let device = sysfs::device_number(kernel_name)?;
+	children.extend(
+		mounts
+			.iter()
+			.filter(|entry| entry.device == device)
+			.map(|entry| {
+				output::TreeNode::leaf(format!(
+					"mounted at {}",
+					output::paint_stdout(entry.mount_point.display(), output::Color::Green)
+				))
+			}),
 	);
-	let was_already_mounted = match mount_res {
-		Err(nix::errno::Errno::EBUSY) => {
-			eprintln!("mount returned EBUSY, assuming already mounted.");
-			true
-		}
-		other => {
-			other.context("making mount syscall")?;
-			false
-		}
-	};
 
-	Ok(MountReturn {
-		mount_path,
-		was_already_mounted,
-	})
+	Ok(output::TreeNode { label, children })
 }
 
-fn unmount(disk_name: &str) -> Result<()> {
-	use nix::mount::umount;
+fn do_tree(config: &Config) -> Result<()> {
+	let mounts = mountinfo::read()?;
 
-	let mount_path = mount_path_for_name(disk_name);
+	for disk in &config.disks {
+		let state = disk_state(disk)?;
+		let state_repr = match state.color() {
+			Some(color) => output::paint_stdout(state.as_repr(), color).to_string(),
+			None => state.as_repr().to_owned(),
+		};
+		let mut root = output::TreeNode::leaf(format!(
+			"{} ({}) {state_repr}",
+			disk.as_repr(),
+			disk.shortcut()
+		));
 
-	if std::path::Path::try_exists(mount_path.as_ref()).context("verifying that mount path exists")? {
-		let umount_res = umount(mount_path.as_str());
-		match umount_res {
-			Err(nix::errno::Errno::EINVAL) => {
-				eprintln!("umount returned EINVAL, assuming already unmounted.");
-			}
-			other => other.context("making umount syscall")?,
+		if outer_device_present(disk)? {
+			let dev_path = outer_dev_path(disk)?;
+			let kernel_name = sysfs::kernel_name(&dev_path)
+				.with_context(|| format!("no kernel name for {}", dev_path.display()))?;
+			let device = device_tree(&kernel_name, &mounts)?;
+			root.children.push(match sysfs::parent_disk(&kernel_name)? {
+				Some(parent) => output::TreeNode {
+					label: format!(
+						"{parent} {}",
+						output::format_size(sysfs::size_bytes(&parent)?)
+					),
+					children: vec![device],
+				},
+				None => device,
+			});
 		}
+
+		root.print();
 	}
 
 	Ok(())
 }
 
-fn open_encrypted(luks_uuid: &str, disk_name: &str) -> Result<()> {
-	let opened_name = opened_name_for_encrypted(luks_uuid, disk_name);
-	if std::process::Command::new("cryptsetup")
-		.arg("status")
-		.arg(&opened_name)
-		.status()?
-		.success()
+fn ensure_unused(disk: &Disk) -> Result<()> {
+	let state = disk_state(disk)?;
+	ensure!(
+		matches!(state, DiskState::Absent | DiskState::Unmounted),
+		"{} is {}; unmount it first",
+		disk.as_repr(),
+		state.as_repr()
+	);
+	Ok(())
+}
+
+fn do_remove(disk: &Disk) -> Result<()> {
+	ensure_unused(disk)?;
+	let raw = config::load_raw()?;
+	config::save_raw(&config::remove_disk(&raw, &disk.name)?)?;
+	stats::remove(disk.as_repr()).context("removing usage statistics")?;
+	manifest::remove(disk.as_repr())?;
+	eprintln!("removed {} from the config.", disk.as_repr());
+	Ok(())
+}
+
+fn do_clone(config: &Config, args: &CloneArgs) -> Result<()> {
+	ensure_root()?;
+	let disk = config.disk(&args.disk)?;
+	// Encrypted disks are copied as ciphertext, so they must not be open either.
+	ensure_unused(disk)?;
+	clone::run(&outer_dev_path(disk)?, &args.target, args.verify)
+}
+
+fn do_wipe(disk: &Disk, method: wipe::Method, confirm: Option<&str>) -> Result<()> {
+	ensure_unused(disk)?;
+	wipe::run(disk, method, confirm)?;
+	eprintln!("wiped {}.", disk.as_repr());
+	do_remove(disk)
+}
+
+fn do_rename(config: &Config, disk: &Disk, new_name: &str) -> Result<()> {
+	config::validate_name(new_name).context("invalid name")?;
+	ensure_unused(disk)?;
+	let raw = config::load_raw()?;
+	config::save_raw(&config::rename_disk(&raw, &disk.name, new_name)?)?;
+	stats::rename(disk.as_repr(), new_name).context("moving usage statistics")?;
+	manifest::rename(disk.as_repr(), new_name)?;
+	if config.composite_membership(disk).is_some() {
+		eprintln!("renamed {} to {new_name}.", disk.as_repr());
+	} else {
+		eprintln!(
+			"renamed {} to {new_name}. it will now be mounted at {:?}.",
+			disk.as_repr(),
+			mount_path_for_name(new_name)
+		);
+	}
+	Ok(())
+}
+
+/// The device with the disk's filesystem on it, which for encrypted disks is only there while they are open.
+fn filesystem_dev_path(disk: &Disk) -> Result<PathBuf> {
+	if disk.is_encrypted() {
+		ensure!(
+			device_present(&disk.uuid)?,
+			"{} is locked; mount it first",
+			disk.as_repr()
+		);
+		return dev_path_for_uuid(&disk.uuid);
+	}
+	outer_dev_path(disk)
+}
+
+fn do_label(config: &Config, args: &LabelArgs) -> Result<()> {
+	let disk = config.disk(&args.disk)?;
+	let dev_path = filesystem_dev_path(disk)?;
+	let mount_point = mount_points(disk)?.into_iter().next();
+	// The ioctl works without probing the device, but not for every filesystem.
+	let old_label = match mount_point
+		.as_deref()
+		.map(label::get_mounted)
+		.and_then(Result::ok)
 	{
-		eprintln!("`cryptsetup status` reported OK, assuming encrypted device is already open.");
+		Some(label) => label,
+		None => blkid::probe(&dev_path)?.label.unwrap_or_default(),
+	};
+	let Some(new_label) = &args.new_label else {
+		if old_label.is_empty() {
+			eprintln!("{} has no label.", disk.as_repr());
+		} else {
+			println!("{old_label}");
+		}
 		return Ok(());
+	};
+
+	ensure_root()?;
+	ensure!(
+		disk.verity.is_none(),
+		"{} is verified with dm-verity, so its filesystem can't be changed",
+		disk.as_repr()
+	);
+	if let Some(mount_point) = &mount_point {
+		label::set_mounted(mount_point, new_label)?;
+	} else {
+		let filesystem = blkid::probe(&dev_path)?
+			.content_type
+			.context("no filesystem found on the device")?;
+		label::set_unmounted(&dev_path, &filesystem, new_label)?;
 	}
+	eprintln!("labeled {} {new_label:?}.", disk.as_repr());
 
-	let code = std::process::Command::new("cryptsetup")
-		.arg("open")
-		.arg(dev_path_for_uuid(luks_uuid)?)
-		.arg(&opened_name)
-		.status()?;
+	if let Some(kernel_name) = sysfs::kernel_name(&dev_path) {
+		if let Err(error) = sysfs::trigger_change(&kernel_name) {
+			output::warning(format_args!("failed to update device links: {error:#}"));
+		}
+	}
+	// For encrypted disks, the link is to the LUKS container, which has its own label.
+	if !disk.is_encrypted() && disk.device.as_deref() == Some(&label::by_label_path(&old_label)) {
+		let device = label::by_label_path(new_label);
+		let update_res = config::load_raw()
+			.and_then(|raw| config::set_disk_device(&raw, &disk.name, &device))
+			.and_then(|raw| config::save_raw(&raw));
+		match update_res {
+			Ok(()) => eprintln!("changed its device in the config to {device:?}."),
+			Err(error) => output::warning(format_args!(
+				"failed to change its device in the config to {device:?}: {error:#}"
+			)),
+		}
+	}
+	Ok(())
+}
 
-	if code.success() {
-		Ok(())
-	} else {
-		Err(anyhow!("cryptsetup exited with status {:?}", code.code()))
+fn is_root() -> bool {
+	nix::unistd::Uid::effective().is_root()
+}
+
+/// Whether we can mount and open encryption, either as root or with the capabilities from `caps`.
+fn is_privileged() -> bool {
+	is_root() || caps::has_required()
+}
+
+/// Like `ensure_root`, but mounting and unmounting can also go through the helper, if one is running.
+fn ensure_root_or_helper() -> Result<()> {
+	if is_privileged() || helper::available() {
+		return Ok(());
 	}
+	ensure_root()
 }
 
-fn close_encrypted(luks_uuid: &str, disk_name: &str) -> Result<()> {
-	let code = std::process::Command::new("cryptsetup")
-		.arg("close")
-		.arg(opened_name_for_encrypted(luks_uuid, disk_name))
-		.status()?;
+/// If not privileged, re-run as root with the configured tool, such as sudo.
+fn ensure_root() -> Result<()> {
+	if is_privileged() {
+		return Ok(());
+	}
+	// The config may not have been loaded yet, or may be invalid, so read the setting leniently.
+	let elevate_with = config::load_raw()
+		.ok()
+		.and_then(|raw| config::parse(&raw).ok())
+		.map(|config| config.elevate_with)
+		.unwrap_or_default();
+	privilege::reexec_as_root(elevate_with)
+}
 
-	if code.success() {
-		Ok(())
-	} else {
-		Err(anyhow!("cryptsetup exited with status {:?}", code.code()))
+/// Apply the options that affect every action.
+fn apply_global_options(args: &mut Args) {
+	if let Some(profile) = args.profile.take() {
+		config::set_profile(profile);
 	}
+	prompt::set_assume_yes(args.yes);
+	prompt::set_non_interactive(args.non_interactive || nix::unistd::isatty(2) != Ok(true));
+	prompt::set_askpass_allowed(!args.non_interactive);
 }
 
-fn do_mount(disk: Disk) -> Result<MountReturn> {
-	let disk_name = disk.as_repr();
-	let inner_filesystem = disk.inner_filesystem();
-	let mountable = disk.to_mountable();
+fn run() -> Result<()> {
+	let mut args: Args = argh::from_env();
 
-	match mountable {
-		Mountable::Plain { uuid } => mount(uuid, disk_name, inner_filesystem).context("mounting"),
-		Mountable::Encrypted {
-			outer_uuid,
-			inner_uuid,
-		} => {
-			open_encrypted(outer_uuid, disk_name).context("opening encrypted device")?;
-			mount(inner_uuid, disk_name, inner_filesystem).context("mounting")
+	if let Some(host) = &args.host {
+		return remote::exec(host);
+	}
+	apply_global_options(&mut args);
+
+	caps::prepare_for_tools()?;
+
+	// The config file may not exist yet or may be invalid, so don't try to load it.
+	match args.action {
+		Action::Add(AddArgs {}) => {
+			ensure_root()?;
+			return add::run();
+		}
+		Action::Helper(HelperArgs {}) => {
+			ensure!(
+				is_privileged(),
+				"the helper must be run as root or with the capabilities it needs"
+			);
+			return helper::serve();
+		}
+		Action::Format(FormatArgs { device }) => {
+			ensure_root()?;
+			return format::run(&device);
+		}
+		Action::Config(ConfigArgs {
+			action: ConfigAction::Edit(ConfigEditArgs {}),
+		}) => {
+			ensure_root()?;
+			return config::edit();
 		}
+		Action::Config(ConfigArgs {
+			action: ConfigAction::Check(ConfigCheckArgs { upgrade }),
+		}) => {
+			if upgrade {
+				ensure_root()?;
+			}
+			return config::check(upgrade);
+		}
+		_ => {}
 	}
-}
 
-fn do_unmount(disk: Disk) -> Result<()> {
-	let disk_name = disk.as_repr();
-	let mountable = disk.to_mountable();
+	let config = config::load()?;
+	devlink::set_timeout(
+		config
+			.resolution_timeout_seconds
+			.unwrap_or(devlink::DEFAULT_TIMEOUT_SECONDS),
+	);
+	run_action(&config, args.action)
+}
 
-	match mountable {
-		Mountable::Plain { .. } => {
-			unmount(disk_name).context("unmounting")?;
+/// Run one of the actions that need the config.
+fn run_action(config: &Config, action: Action) -> Result<()> {
+	match action {
+		Action::Mount(args) => {
+			let targets = with_tagged(config, &args.disks, &args.tag)?;
+			do_mount_targets(
+				config,
+				&targets,
+				args.options()?,
+				args.snapshot.as_deref(),
+				args.local_only,
+			)?;
 		}
-		Mountable::Encrypted {
-			outer_uuid,
-			inner_uuid: _,
-		} => {
-			unmount(disk_name).context("unmounting")?;
-			close_encrypted(outer_uuid, disk_name).context("closing encrypted device")?;
+		Action::Unmount(args) if !args.all => {
+			let targets = with_tagged(config, &args.disks, &args.tag)?;
+			do_unmount_targets(config, &targets, args.policy(config))?;
+		}
+		Action::Unmount(args) => {
+			ensure_root()?;
+			ensure!(
+				args.disks.is_empty() && args.tag.is_empty(),
+				"--all can't be combined with disks or tags"
+			);
+			do_unmount_all(config, args.policy(config))?;
+		}
+		Action::Cd(args) => do_cd_target(config, &args)?,
+		Action::Eject(args) => do_eject(config, &args)?,
+		Action::Lock(LockArgs { disk }) => {
+			ensure_root()?;
+			do_lock(config.disk(&disk)?)?;
+		}
+		Action::Pending(PendingArgs {}) => do_pending(config)?,
+		Action::Finish(FinishArgs {}) => {
+			ensure_root_or_helper()?;
+			do_finish(config)?;
+		}
+		Action::List(args) => do_list(config, args)?,
+		Action::Watch(WatchArgs { json }) => watch::run(config, json)?,
+		Action::Info(InfoArgs { disk }) => do_info(config, config.disk(&disk)?)?,
+		Action::Tree(TreeArgs {}) => do_tree(config)?,
+		Action::Dash(DashArgs {}) => dash::run(config)?,
+		Action::SleepHook(SleepHookArgs { phase, operation }) => {
+			ensure_root()?;
+			hibernate::run_hook(config, &phase, &operation)?;
+		}
+		Action::Add(..) | Action::Format(..) | Action::Helper(..) | Action::Config(..) => {
+			unreachable!("handled above")
+		}
+		Action::Remove(RemoveArgs { disk }) => {
+			ensure_root()?;
+			do_remove(config.disk(&disk)?)?;
+		}
+		Action::Wipe(WipeArgs {
+			disk,
+			method,
+			confirm,
+		}) => {
+			ensure_root()?;
+			do_wipe(config.disk(&disk)?, method, confirm.as_deref())?;
+		}
+		Action::Rename(RenameArgs { disk, new_name }) => {
+			ensure_root()?;
+			do_rename(config, config.disk(&disk)?, &new_name)?;
+		}
+		Action::Label(args) => do_label(config, &args)?,
+		Action::Clone(args) => do_clone(config, &args)?,
+		Action::Scrub(ScrubArgs { disk }) => {
+			ensure_root()?;
+			do_scrub(config, config.disk(&disk)?)?;
+		}
+		Action::Snapshots(args) => do_snapshots(config, &args)?,
+		Action::Quota(QuotaArgs { disk }) => do_quota(config, config.disk(&disk)?)?,
+		Action::Backup(BackupArgs { disk }) => do_backup(config, config.disk(&disk)?)?,
+		Action::Sync(args) => do_sync(config, &args)?,
+		Action::Manifest(ManifestArgs { disk }) => do_manifest(config, config.disk(&disk)?)?,
+		Action::Verify(VerifyArgs { disk }) => do_verify(config, config.disk(&disk)?)?,
+		Action::Export(ExportArgs { format, disks }) => export::run(config, &disks, format)?,
+		Action::Cleanup(CleanupArgs { dry_run }) => {
+			if !dry_run {
+				ensure_root()?;
+			}
+			cleanup::run(config, dry_run)?;
+		}
+		Action::Resize(ResizeArgs { disk, dry_run }) => {
+			if !dry_run {
+				ensure_root()?;
+			}
+			resize::run(config, config.disk(&disk)?, dry_run)?;
 		}
 	}
 
 	Ok(())
 }
 
-fn do_cd(disk: Disk) -> Result<()> {
-	use std::os::unix::process::CommandExt as _;
+/// The exit status when `d` needed to ask something but couldn't, so that scripts can tell this apart from other failures.
+const EXIT_NEEDS_INTERACTION: i32 = 3;
 
-	let MountReturn {
-		mount_path,
-		was_already_mounted: _,
-	} = do_mount(disk)?;
-	eprintln!("d: entering subshell. stay safe, friend.");
-	let mut shell = std::process::Command::new("fish")
-		.uid(nix::unistd::Uid::current().as_raw())
-		.gid(nix::unistd::Gid::current().as_raw())
-		.current_dir(mount_path)
-		.args(["--private"].into_iter().filter(|_| disk.is_encrypted()))
-		.spawn()
-		.context("spawning sub-shell")?;
-	shell.wait().context("waiting for sub-shell")?;
-	eprintln!("d: cleaning up; unmounting.");
-	if let Ok(()) = do_unmount(disk) {
-		eprintln!("d: unmounted, bye");
-	} else {
-		eprintln!("d: unmount failed. maybe still busy");
-		if nix::unistd::isatty(2) == Ok(true) {
-			// Give the user some time to see the message.
-			std::thread::sleep(std::time::Duration::from_secs(1));
+fn main() {
+	if let Err(error) = run() {
+		if prompt::is_non_interactive() {
+			output::error_line(&error);
+		} else {
+			output::error(&error);
 		}
+		let status = if error.is::<prompt::NeedsInteraction>() {
+			EXIT_NEEDS_INTERACTION
+		} else {
+			1
+		};
+		std::process::exit(status);
 	}
-	Ok(())
 }
 
-fn main() -> Result<()> {
-	ensure!(
-		nix::unistd::Uid::effective().is_root(),
-		"must be run as root to (un)mount disks and open/close encryption"
-	);
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn strings(strs: &[&str]) -> Vec<String> {
+		strs.iter().map(|&s| s.to_owned()).collect()
+	}
 
-	let args: Args = argh::from_env();
+	#[test]
+	fn rejected_option_named() {
+		let data = strings(&["discard", "delalloc", "noatime"]);
+		assert_eq!(
+			rejected_option(
+				&data,
+				&strings(&["EXT4-fs (dm-0): Unrecognized mount option \"delalloc\" or missing value"])
+			)
+			.as_deref(),
+			Some("delalloc")
+		);
+		assert_eq!(
+			rejected_option(&data, &strings(&["vfat: Unknown parameter 'discard'"])).as_deref(),
+			Some("discard")
+		);
+	}
 
-	match args.action {
-		Action::Mount => {
-			let MountReturn { mount_path, .. } = do_mount(args.disk)?;
-			eprintln!("mounted {} at {mount_path:?}.", args.disk.as_repr());
-		}
-		Action::Unmount => {
-			do_unmount(args.disk)?;
-			eprintln!("unmounted {}.", args.disk.as_repr());
-		}
-		Action::Cd => {
-			do_cd(args.disk)?;
-		}
+	#[test]
+	fn rejected_option_other_errors() {
+		let data = strings(&["discard", "delalloc"]);
+		assert_eq!(rejected_option(&data, &[]), None);
+		assert_eq!(
+			rejected_option(&[], &strings(&["Unknown parameter 'discard'"])),
+			None
+		);
+		assert_eq!(
+			rejected_option(
+				&data,
+				&strings(&["EXT4-fs (dm-0): VFS: Can't find ext4 filesystem"])
+			),
+			None
+		);
+		// Mentioned without quotes, such as in an unrelated message.
+		assert_eq!(
+			rejected_option(&data, &strings(&["mounted with discard"])),
+			None
+		);
+		// Part of another option's name.
+		assert_eq!(
+			rejected_option(&data, &strings(&["Unknown parameter 'nodiscard'"])),
+			None
+		);
 	}
 
-	Ok(())
+	#[test]
+	fn rejected_option_only_optional() {
+		let data = strings(&["discard", "noload"]);
+		assert_eq!(
+			rejected_option(&data, &strings(&["Unknown parameter 'noload'"])),
+			None
+		);
+	}
 }
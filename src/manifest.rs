@@ -0,0 +1,234 @@
+//! Checksum manifests of everything on a disk, for finding files that have silently changed, such as from bit rot.
+//!
+//! Manifests are kept in `/var/lib/d/manifests` in the format of `sha256sum -z`, so they can also be checked with `sha256sum --check -z` from inside the mount path.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context as _, Result};
+
+use crate::{output, progress};
+
+const MANIFEST_DIR: &str = "/var/lib/d/manifests";
+/// How many files to pass to each run of `sha256sum`.
+const BATCH_SIZE: usize = 256;
+/// Directories at the root of a disk that aren't part of its contents: btrfs snapshots, which are copies of the rest, and fsck's findings.
+const SKIPPED_DIRECTORIES: &[&str] = &[".snapshots", "lost+found"];
+
+fn manifest_path(disk_name: &str) -> PathBuf {
+	Path::new(MANIFEST_DIR).join(format!("{disk_name}.sha256"))
+}
+
+/// Checksums keyed by path relative to the root of the disk.
+type Checksums = BTreeMap<PathBuf, String>;
+
+/// The regular files under `root`, relative to it, with their sizes. Symbolic links are not followed.
+fn list_files(root: &Path) -> Result<Vec<(PathBuf, u64)>> {
+	let mut files = Vec::new();
+	let mut pending = vec![PathBuf::new()];
+	while let Some(dir) = pending.pop() {
+		let entries = std::fs::read_dir(root.join(&dir))
+			.with_context(|| format!("listing {}", root.join(&dir).display()))?;
+		for entry in entries {
+			let entry = entry.with_context(|| format!("listing {}", root.join(&dir).display()))?;
+			let path = dir.join(entry.file_name());
+			let file_type = entry.file_type().context("getting file type")?;
+			if file_type.is_dir() {
+				let is_skipped = dir.as_os_str().is_empty()
+					&& SKIPPED_DIRECTORIES
+						.iter()
+						.any(|&skipped| entry.file_name() == skipped);
+				if !is_skipped {
+					pending.push(path);
+				}
+			} else if file_type.is_file() {
+				let size = entry.metadata().context("getting file size")?.len();
+				files.push((path, size));
+			}
+		}
+	}
+	files.sort();
+	Ok(files)
+}
+
+/// Parse the output of `sha256sum -z`, which is `<checksum>  <path>` for each file, terminated by NUL bytes.
+fn parse(raw: &[u8]) -> Result<Checksums> {
+	raw
+		.split(|&byte| byte == 0)
+		.filter(|entry| !entry.is_empty())
+		.map(|entry| {
+			let parsed = entry
+				.get(..64)
+				.zip(entry.get(64..).and_then(|rest| rest.strip_prefix(b"  ")));
+			let (checksum, path) = parsed.with_context(|| {
+				format!(
+					"malformed manifest entry {:?}",
+					String::from_utf8_lossy(entry)
+				)
+			})?;
+			let path = Path::new(OsStr::from_bytes(path));
+			Ok((
+				path.to_owned(),
+				String::from_utf8_lossy(checksum).into_owned(),
+			))
+		})
+		.collect()
+}
+
+/// Checksum `files`, as listed by `list_files`, on the disk mounted at `mount_path`. Files that can't be read are left out.
+fn checksum_all(mount_path: &Path, files: &[(PathBuf, u64)], message: &str) -> Result<Checksums> {
+	let total = files.iter().map(|(_, size)| size).sum();
+	let mut progress = progress::Transfer::new(message, total);
+	let mut done = 0;
+	let mut checksums = Checksums::new();
+	for batch in files.chunks(BATCH_SIZE) {
+		// Errors for unreadable files go to stderr, and the rest are still checksummed.
+		let output = Command::new("sha256sum")
+			.args(["-z", "--"])
+			.args(batch.iter().map(|(path, _)| path))
+			.current_dir(mount_path)
+			.output()
+			.context("running sha256sum")?;
+		checksums.extend(parse(&output.stdout)?);
+		done += batch.iter().map(|(_, size)| size).sum::<u64>();
+		progress.update(done);
+	}
+	progress.finish();
+	Ok(checksums)
+}
+
+fn format(checksums: &Checksums) -> Vec<u8> {
+	let mut raw = Vec::new();
+	for (path, checksum) in checksums {
+		raw.extend_from_slice(checksum.as_bytes());
+		raw.extend_from_slice(b"  ");
+		raw.extend_from_slice(path.as_os_str().as_bytes());
+		raw.push(0);
+	}
+	raw
+}
+
+/// Create or replace the manifest of the disk mounted at `mount_path`. Returns how many files it lists.
+pub fn create(disk_name: &str, mount_path: &str) -> Result<usize> {
+	let mount_path = Path::new(mount_path);
+	let checksums = checksum_all(mount_path, &list_files(mount_path)?, "checksumming")?;
+	std::fs::create_dir_all(MANIFEST_DIR).context("creating manifest directory")?;
+	let path = manifest_path(disk_name);
+	let temp_path = path.with_extension("tmp");
+	std::fs::write(&temp_path, format(&checksums)).context("writing manifest")?;
+	std::fs::rename(temp_path, &path).context("replacing manifest")?;
+	Ok(checksums.len())
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+	pub added: Vec<PathBuf>,
+	pub removed: Vec<PathBuf>,
+	/// Files with different contents that were also modified after the manifest was made, so they were most likely changed on purpose.
+	pub modified: Vec<PathBuf>,
+	/// Files with different contents whose modification times are older than the manifest.
+	pub corrupted: Vec<PathBuf>,
+	pub unreadable: Vec<PathBuf>,
+	pub unchanged: usize,
+}
+
+impl Report {
+	pub fn is_intact(&self) -> bool {
+		self.corrupted.is_empty() && self.unreadable.is_empty()
+	}
+
+	pub fn print(&self) {
+		for (paths, marker, color) in [
+			(&self.added, "+", output::Color::Green),
+			(&self.removed, "-", output::Color::Yellow),
+			(&self.modified, "~", output::Color::Yellow),
+			(&self.corrupted, "!", output::Color::Red),
+			(&self.unreadable, "?", output::Color::Red),
+		] {
+			for path in paths {
+				println!("{} {}", output::paint_stdout(marker, color), path.display());
+			}
+		}
+		eprintln!(
+			"{} unchanged, {} added, {} removed, {} modified, {} corrupted, {} unreadable.",
+			self.unchanged,
+			self.added.len(),
+			self.removed.len(),
+			self.modified.len(),
+			self.corrupted.len(),
+			self.unreadable.len(),
+		);
+	}
+}
+
+/// Compare the disk mounted at `mount_path` against its manifest.
+pub fn verify(disk_name: &str, mount_path: &str) -> Result<Report> {
+	let path = manifest_path(disk_name);
+	let raw = match std::fs::read(&path) {
+		Ok(raw) => raw,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+			bail!("{disk_name} has no manifest. create one with `d manifest {disk_name}`")
+		}
+		Err(error) => return Err(error).context("reading manifest"),
+	};
+	let expected = parse(&raw).context("parsing manifest")?;
+	let created = std::fs::metadata(&path)
+		.and_then(|metadata| metadata.modified())
+		.context("getting manifest time")?;
+
+	let mount_path = Path::new(mount_path);
+	let files = list_files(mount_path)?;
+	let actual = checksum_all(mount_path, &files, "verifying")?;
+	let present: Vec<PathBuf> = files.into_iter().map(|(path, _)| path).collect();
+
+	let mut report = Report::default();
+	for path in &present {
+		let Some(expected_checksum) = expected.get(path) else {
+			report.added.push(path.clone());
+			continue;
+		};
+		match actual.get(path) {
+			None => report.unreadable.push(path.clone()),
+			Some(checksum) if checksum == expected_checksum => report.unchanged += 1,
+			Some(_) => {
+				let modified = std::fs::metadata(mount_path.join(path))
+					.and_then(|metadata| metadata.modified())
+					.unwrap_or(SystemTime::UNIX_EPOCH);
+				if modified > created {
+					report.modified.push(path.clone());
+				} else {
+					report.corrupted.push(path.clone());
+				}
+			}
+		}
+	}
+	report.removed = expected
+		.into_keys()
+		.filter(|path| present.binary_search(path).is_err())
+		.collect();
+	Ok(report)
+}
+
+/// Move a disk's manifest to a new name, after it has been renamed.
+pub fn rename(old_name: &str, new_name: &str) -> Result<()> {
+	match std::fs::rename(manifest_path(old_name), manifest_path(new_name)) {
+		Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+			Err(error).context("moving manifest")
+		}
+		_ => Ok(()),
+	}
+}
+
+/// Forget a disk's manifest, after it has been removed.
+pub fn remove(disk_name: &str) -> Result<()> {
+	match std::fs::remove_file(manifest_path(disk_name)) {
+		Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+			Err(error).context("removing manifest")
+		}
+		_ => Ok(()),
+	}
+}
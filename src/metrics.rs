@@ -0,0 +1,236 @@
+//! Prometheus metrics, served over HTTP by `d helper` so that disk state can be graphed alongside everything else.
+//!
+//! See <https://prometheus.io/docs/instrumenting/exposition_formats/> for the format.
+
+use std::fmt::Write as _;
+use std::io::{BufRead as _, BufReader, Read as _, Write as _};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+
+use crate::config::{self, Config, Disk};
+use crate::{caps, output, space, state, stats, sysfs};
+
+/// Requests are handled one at a time, so a client that stalls must not hold up the rest for long.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Far longer than any request line that Prometheus sends.
+const MAX_REQUEST_LINE_LEN: u64 = 8192;
+
+/// Write one metric family. `samples` are pairs of disk names and values.
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, f64)]) {
+	let _ = writeln!(out, "# HELP {name} {help}");
+	let _ = writeln!(out, "# TYPE {name} {kind}");
+	for (disk_name, value) in samples {
+		// Disk names are restricted to characters that don't need escaping.
+		let _ = writeln!(out, "{name}{{disk=\"{disk_name}\"}} {value}");
+	}
+}
+
+/// The temperature of a whole disk from `smartctl`, without waking it up if it is spun down.
+pub fn temperature(dev_path: &Path) -> Option<f64> {
+	let output = caps::tool("smartctl")
+		.args(["--attributes", "--nocheck=standby"])
+		.arg(dev_path)
+		.output()
+		.ok()?;
+	String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.find_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			match fields.as_slice() {
+				// An ATA attribute, whose raw value is the tenth column.
+				[_, "Temperature_Celsius" | "Airflow_Temperature_Cel", ..] => fields.get(9)?.parse().ok(),
+				// NVMe.
+				["Temperature:", value, "Celsius"] => value.parse().ok(),
+				_ => None,
+			}
+		})
+}
+
+pub fn whole_disk_path(disk: &Disk) -> Option<std::path::PathBuf> {
+	let dev_path = crate::outer_dev_path(disk).ok()?;
+	let kernel_name = sysfs::kernel_name(&dev_path)?;
+	let whole = sysfs::parent_disk(&kernel_name)
+		.ok()?
+		.unwrap_or(kernel_name);
+	Some(Path::new("/dev").join(whole))
+}
+
+/// The samples of each metric family, as pairs of disk names and values.
+#[derive(Default)]
+struct Samples<'a> {
+	mounted: Vec<(&'a str, f64)>,
+	mounted_seconds: Vec<(&'a str, f64)>,
+	mounts: Vec<(&'a str, f64)>,
+	unlock_failures: Vec<(&'a str, f64)>,
+	free_bytes: Vec<(&'a str, f64)>,
+	size_bytes: Vec<(&'a str, f64)>,
+	temperatures: Vec<(&'a str, f64)>,
+	errors: Vec<(&'a str, f64)>,
+}
+
+/// Whether a disk is attached and where it is mounted, or `None` if that can't be told, after warning about it. Such a disk only has an error metric, rather than failing the rest.
+fn disk_state(disk: &Disk) -> Option<(bool, Vec<std::path::PathBuf>)> {
+	crate::outer_device_present(disk)
+		.and_then(|is_present| Ok((is_present, crate::mount_points(disk)?)))
+		.map_err(|error| {
+			output::warning(format_args!(
+				"failed to get metrics of {}: {error:#}",
+				disk.as_repr()
+			));
+		})
+		.ok()
+}
+
+#[allow(clippy::cast_precision_loss)] // Metrics are floats.
+fn collect(config: &Config) -> Result<Samples<'_>> {
+	let all_stats = stats::load()?;
+	let run_state = state::load()?;
+	let now_nanos = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_nanos();
+
+	let mut samples = Samples::default();
+	for disk in &config.disks {
+		let name = disk.as_repr();
+		let stats = all_stats.get(name).copied().unwrap_or_default();
+		samples.mounts.push((name, stats.mount_count as f64));
+		samples
+			.unlock_failures
+			.push((name, stats.unlock_failures as f64));
+
+		let disk_state = disk_state(disk);
+		samples
+			.errors
+			.push((name, f64::from(u8::from(disk_state.is_none()))));
+		let Some((is_present, mount_points)) = disk_state else {
+			continue;
+		};
+		samples
+			.mounted
+			.push((name, f64::from(u8::from(!mount_points.is_empty()))));
+		if let Some(mounted_at) = run_state.get(name).and_then(|entry| entry.mounted_at) {
+			let seconds = now_nanos.saturating_sub(u128::from(mounted_at)) as f64 / 1e9;
+			samples.mounted_seconds.push((name, seconds));
+		}
+		if let Some(usage) = mount_points
+			.first()
+			.and_then(|point| space::usage(&point.to_string_lossy()).ok())
+		{
+			samples.free_bytes.push((name, usage.available as f64));
+			samples.size_bytes.push((name, usage.total as f64));
+		}
+		if is_present {
+			if let Some(temperature) = whole_disk_path(disk).and_then(|path| temperature(&path)) {
+				samples.temperatures.push((name, temperature));
+			}
+		}
+	}
+	Ok(samples)
+}
+
+fn render(config: &Config) -> Result<String> {
+	let samples = collect(config)?;
+	let mut out = String::new();
+	family(
+		&mut out,
+		"d_disk_mounted",
+		"gauge",
+		"Whether the disk is mounted.",
+		&samples.mounted,
+	);
+	family(
+		&mut out,
+		"d_disk_mounted_seconds",
+		"gauge",
+		"How long d has had the disk mounted.",
+		&samples.mounted_seconds,
+	);
+	family(
+		&mut out,
+		"d_disk_mounts_total",
+		"counter",
+		"Times d has mounted the disk.",
+		&samples.mounts,
+	);
+	family(
+		&mut out,
+		"d_disk_unlock_failures_total",
+		"counter",
+		"Wrong passphrases given when unlocking the disk.",
+		&samples.unlock_failures,
+	);
+	family(
+		&mut out,
+		"d_disk_free_bytes",
+		"gauge",
+		"Bytes available on the mounted filesystem.",
+		&samples.free_bytes,
+	);
+	family(
+		&mut out,
+		"d_disk_size_bytes",
+		"gauge",
+		"Total size of the mounted filesystem.",
+		&samples.size_bytes,
+	);
+	family(
+		&mut out,
+		"d_disk_temperature_celsius",
+		"gauge",
+		"Temperature reported by SMART, unless the disk is spun down.",
+		&samples.temperatures,
+	);
+	family(
+		&mut out,
+		"d_disk_scrape_error",
+		"gauge",
+		"Whether getting the state of the disk failed, in which case its other gauges are missing.",
+		&samples.errors,
+	);
+	Ok(out)
+}
+
+fn respond(mut stream: TcpStream) -> Result<()> {
+	stream
+		.set_read_timeout(Some(CONNECTION_TIMEOUT))
+		.and_then(|()| stream.set_write_timeout(Some(CONNECTION_TIMEOUT)))
+		.context("setting timeouts")?;
+	let mut request_line = String::new();
+	BufReader::new((&stream).take(MAX_REQUEST_LINE_LEN))
+		.read_line(&mut request_line)
+		.context("reading request")?;
+	let path = request_line.split(' ').nth(1).unwrap_or_default();
+
+	let (status, body) = if path == "/metrics" {
+		match config::load().and_then(|config| render(&config)) {
+			Ok(body) => ("200 OK", body),
+			Err(error) => ("500 Internal Server Error", format!("{error:#}\n")),
+		}
+	} else {
+		("404 Not Found", "metrics are at /metrics\n".to_owned())
+	};
+	write!(
+		stream,
+		"HTTP/1.0 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+		body.len()
+	)
+	.context("sending response")
+}
+
+/// Serve metrics at `/metrics` on `address`, one request at a time, forever.
+pub fn serve(address: &str) -> Result<()> {
+	let listener =
+		TcpListener::bind(address).with_context(|| format!("listening for metrics on {address}"))?;
+	eprintln!("serving metrics on http://{address}/metrics.");
+	for stream in listener.incoming() {
+		let result = stream.context("accepting connection").and_then(respond);
+		if let Err(error) = result {
+			output::warning(format_args!("failed to serve metrics: {error:#}"));
+		}
+	}
+	Ok(())
+}
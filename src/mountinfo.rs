@@ -0,0 +1,94 @@
+//! Parsing of `/proc/self/mountinfo`.
+//!
+//! See `proc(5)` for the format.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context as _, Result};
+use nix::sys::stat::{dev_t, makedev};
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+	/// The value of `st_dev` for files on this filesystem.
+	pub device: dev_t,
+	pub mount_point: PathBuf,
+	/// Per-mount options, such as `noatime`.
+	pub mount_options: String,
+	pub fs_type: String,
+	pub source: String,
+	/// Per-superblock options, which are specific to the filesystem.
+	pub super_options: String,
+}
+
+impl Entry {
+	/// Whether this filesystem was mounted from `device`, which must be canonical, going by the resolved path of its source.
+	///
+	/// Device numbers can't be used for this, since some filesystems, such as btrfs, report an anonymous one instead of that of their block device.
+	pub fn is_from(&self, device: &Path) -> bool {
+		self.source.starts_with('/')
+			&& std::fs::canonicalize(&self.source).is_ok_and(|source| source == device)
+	}
+}
+
+/// Undo the octal escaping the kernel applies to space, tab, newline, and backslash.
+fn unescape(raw: &str) -> String {
+	let mut unescaped = String::with_capacity(raw.len());
+	let mut rest = raw;
+	while let Some(idx) = rest.find('\\') {
+		unescaped.push_str(&rest[..idx]);
+		let escape = rest.get(idx + 1..idx + 4);
+		if let Some(byte) = escape.and_then(|escape| u8::from_str_radix(escape, 8).ok()) {
+			unescaped.push(char::from(byte));
+			rest = &rest[idx + 4..];
+		} else {
+			unescaped.push('\\');
+			rest = &rest[idx + 1..];
+		}
+	}
+	unescaped.push_str(rest);
+	unescaped
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+	let (before, after) = line.split_once(" - ")?;
+	let mut before = before.split(' ');
+	let mut after = after.split(' ');
+
+	let _mount_id = before.next()?;
+	let _parent_id = before.next()?;
+	let (major, minor) = before.next()?.split_once(':')?;
+	let _root = before.next()?;
+	let mount_point = before.next()?;
+	let mount_options = before.next()?;
+
+	let fs_type = after.next()?;
+	let source = after.next()?;
+	let super_options = after.next()?;
+
+	Some(Entry {
+		device: makedev(major.parse().ok()?, minor.parse().ok()?),
+		mount_point: unescape(mount_point).into(),
+		mount_options: mount_options.to_owned(),
+		fs_type: unescape(fs_type),
+		source: unescape(source),
+		super_options: super_options.to_owned(),
+	})
+}
+
+pub fn read() -> Result<Vec<Entry>> {
+	let raw = std::fs::read_to_string("/proc/self/mountinfo").context("reading mountinfo")?;
+	raw
+		.lines()
+		.map(|line| parse_line(line).ok_or_else(|| anyhow!("malformed mountinfo line {line:?}")))
+		.collect()
+}
+
+/// If there are several mounts stacked on the same mount point, the topmost (visible) one is returned.
+pub fn find_by_mount_point(mount_point: &Path) -> Result<Option<Entry>> {
+	Ok(
+		read()?
+			.into_iter()
+			.rev()
+			.find(|entry| entry.mount_point == mount_point),
+	)
+}
@@ -0,0 +1,215 @@
+//! Human-oriented output: colors and aligned tables.
+//!
+//! Colors are only used when the stream is a TTY and `NO_COLOR` is not set (see <https://no-color.org>).
+
+use std::fmt::{self, Display, Write as _};
+use std::os::unix::io::RawFd;
+
+const STDOUT: RawFd = 1;
+const STDERR: RawFd = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+	Green,
+	Yellow,
+	Red,
+}
+
+impl Color {
+	fn ansi_code(self) -> &'static str {
+		match self {
+			Self::Green => "32",
+			Self::Yellow => "33",
+			Self::Red => "31",
+		}
+	}
+}
+
+fn colors_enabled(fd: RawFd) -> bool {
+	let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+	!no_color && nix::unistd::isatty(fd) == Ok(true)
+}
+
+/// Text that is colored when displayed, if colors are enabled for the stream it is destined for.
+pub struct Painted<T> {
+	inner: T,
+	color: Option<Color>,
+}
+
+impl<T: Display> Display for Painted<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.color {
+			Some(color) => write!(f, "\x1b[{}m{}\x1b[0m", color.ansi_code(), self.inner),
+			None => self.inner.fmt(f),
+		}
+	}
+}
+
+fn paint<T: Display>(fd: RawFd, inner: T, color: Color) -> Painted<T> {
+	Painted {
+		inner,
+		color: colors_enabled(fd).then_some(color),
+	}
+}
+
+pub fn paint_stdout<T: Display>(inner: T, color: Color) -> Painted<T> {
+	paint(STDOUT, inner, color)
+}
+
+pub fn paint_stderr<T: Display>(inner: T, color: Color) -> Painted<T> {
+	paint(STDERR, inner, color)
+}
+
+pub fn warning(message: impl Display) {
+	eprintln!("{} {message}", paint_stderr("warning:", Color::Yellow));
+}
+
+pub fn error(error: &anyhow::Error) {
+	eprintln!("{} {error:#}", paint_stderr("error:", Color::Red));
+}
+
+/// The error on a single line, for logs and scripts: the causes are separated by `: ` as usual, and newlines within them are escaped.
+pub fn error_line(error: &anyhow::Error) {
+	eprintln!(
+		"error: {}",
+		format!("{error:#}")
+			.replace('\\', "\\\\")
+			.replace('\n', "\\n")
+	);
+}
+
+/// A table with columns aligned to the widest cell, printed to stdout.
+#[derive(Debug, Default)]
+pub struct Table {
+	rows: Vec<Vec<(String, Option<Color>)>>,
+}
+
+impl Table {
+	pub fn new(header: &[&str]) -> Self {
+		Self {
+			rows: vec![header.iter().map(|&cell| (cell.to_owned(), None)).collect()],
+		}
+	}
+
+	pub fn row(&mut self, cells: Vec<(String, Option<Color>)>) {
+		self.rows.push(cells);
+	}
+
+	pub fn print(&self) {
+		let num_columns = self.rows.iter().map(Vec::len).max().unwrap_or(0);
+		let widths: Vec<usize> = (0..num_columns)
+			.map(|column| {
+				self
+					.rows
+					.iter()
+					.filter_map(|row| row.get(column))
+					.map(|(text, _)| text.chars().count())
+					.max()
+					.unwrap_or(0)
+			})
+			.collect();
+
+		for row in &self.rows {
+			let mut line = String::new();
+			for (column, (text, color)) in row.iter().enumerate() {
+				let is_last = column + 1 == row.len();
+				let padding = if is_last {
+					0
+				} else {
+					widths[column] - text.chars().count() + 2
+				};
+				let cell = match color {
+					Some(color) => paint_stdout(text, *color).to_string(),
+					None => text.clone(),
+				};
+				line.push_str(&cell);
+				line.push_str(&" ".repeat(padding));
+			}
+			println!("{line}");
+		}
+	}
+}
+
+/// Format a duration with its two largest units, e.g. `1h 23m` or `42s`.
+pub fn format_duration(duration: std::time::Duration) -> String {
+	let secs = duration.as_secs();
+	let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+	if hours > 0 {
+		format!("{hours}h {minutes}m")
+	} else if minutes > 0 {
+		format!("{minutes}m {secs}s")
+	} else {
+		format!("{secs}s")
+	}
+}
+
+/// Quote `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+	let mut quoted = String::with_capacity(s.len() + 2);
+	quoted.push('"');
+	for ch in s.chars() {
+		match ch {
+			'"' => quoted.push_str("\\\""),
+			'\\' => quoted.push_str("\\\\"),
+			'\n' => quoted.push_str("\\n"),
+			ch if ch.is_control() => {
+				let _ = write!(quoted, "\\u{:04x}", u32::from(ch));
+			}
+			ch => quoted.push(ch),
+		}
+	}
+	quoted.push('"');
+	quoted
+}
+
+/// Format a size in bytes with binary units, e.g. `931.5G`.
+pub fn format_size(bytes: u64) -> String {
+	const UNITS: &[&str] = &["B", "K", "M", "G", "T", "P"];
+
+	#[allow(clippy::cast_precision_loss)] // Only used for display.
+	let mut size = bytes as f64;
+	let mut unit = 0;
+	while size >= 1024.0 && unit + 1 < UNITS.len() {
+		size /= 1024.0;
+		unit += 1;
+	}
+	if unit == 0 {
+		format!("{bytes}B")
+	} else {
+		format!("{size:.1}{}", UNITS[unit])
+	}
+}
+
+/// A node in a tree printed with box-drawing characters, like the output of `lsblk`.
+#[derive(Debug)]
+pub struct TreeNode {
+	pub label: String,
+	pub children: Vec<TreeNode>,
+}
+
+impl TreeNode {
+	pub fn leaf(label: String) -> Self {
+		Self {
+			label,
+			children: Vec::new(),
+		}
+	}
+
+	pub fn print(&self) {
+		println!("{}", self.label);
+		self.print_children("");
+	}
+
+	fn print_children(&self, prefix: &str) {
+		for (idx, child) in self.children.iter().enumerate() {
+			let is_last = idx + 1 == self.children.len();
+			let (branch, continuation) = if is_last {
+				("└─", "  ")
+			} else {
+				("├─", "│ ")
+			};
+			println!("{prefix}{branch}{}", child.label);
+			child.print_children(&format!("{prefix}{continuation}"));
+		}
+	}
+}
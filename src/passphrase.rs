@@ -0,0 +1,67 @@
+//! Reading passphrases from the controlling terminal.
+//!
+//! We read the passphrase ourselves rather than letting `cryptsetup` prompt for it so that we know when the user is done typing, and so that it can come from other sources.
+
+use std::io::{BufRead as _, BufReader, Write as _};
+use std::os::unix::io::AsRawFd as _;
+use std::sync::Mutex;
+
+use anyhow::{Context as _, Result};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+
+/// Disks may be unlocked in parallel, but only one prompt can use the terminal at a time.
+static PROMPT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Prompt for a passphrase on the controlling terminal, without echoing it.
+///
+/// Returns `None` if there is no controlling terminal, or if `d` is non-interactive.
+pub fn prompt(prompt: &str) -> Result<Option<String>> {
+	if crate::prompt::is_non_interactive() {
+		return Ok(None);
+	}
+	let _guard = PROMPT_LOCK
+		.lock()
+		.unwrap_or_else(std::sync::PoisonError::into_inner);
+	let Ok(tty) = std::fs::OpenOptions::new()
+		.read(true)
+		.write(true)
+		.open("/dev/tty")
+	else {
+		return Ok(None);
+	};
+	let fd = tty.as_raw_fd();
+
+	let original = tcgetattr(fd).context("getting terminal attributes")?;
+	let mut no_echo = original.clone();
+	no_echo.local_flags.remove(LocalFlags::ECHO);
+	no_echo.local_flags.insert(LocalFlags::ECHONL);
+
+	(&tty)
+		.write_all(prompt.as_bytes())
+		.context("writing prompt")?;
+	tcsetattr(fd, SetArg::TCSAFLUSH, &no_echo).context("disabling terminal echo")?;
+	let mut passphrase = String::new();
+	let read_res = BufReader::new(&tty).read_line(&mut passphrase);
+	tcsetattr(fd, SetArg::TCSAFLUSH, &original).context("restoring terminal attributes")?;
+	read_res.context("reading passphrase")?;
+
+	if passphrase.ends_with('\n') {
+		passphrase.pop();
+	}
+	Ok(Some(passphrase))
+}
+
+/// Prompt on the terminal, or else with the askpass program, if there is one and it may be used.
+///
+/// Returns `None` if there is no way to ask.
+pub fn ask(prompt: &str, askpass: Option<&str>) -> Result<Option<String>> {
+	if let Some(passphrase) = self::prompt(prompt)? {
+		return Ok(Some(passphrase));
+	}
+	match askpass {
+		Some(askpass) if crate::prompt::is_askpass_allowed() => {
+			crate::askpass::ask(askpass, prompt.trim_end_matches([' ', ':'])).map(Some)
+		}
+		_ => Ok(None),
+	}
+}
@@ -0,0 +1,143 @@
+//! Power management for rotational drives, so that they don't keep spinning after they're no longer needed.
+
+use std::os::unix::io::AsRawFd as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context as _, Result};
+use nix::errno::Errno;
+
+use crate::{mountinfo, sysfs};
+
+/// See `include/uapi/linux/hdreg.h`.
+const HDIO_DRIVE_CMD: u16 = 0x031f;
+const ATA_OP_STANDBY_NOW: u8 = 0xe0;
+const ATA_OP_SET_IDLE: u8 = 0xe3;
+
+// The argument is `[command, sector count, feature, sector number]`, as libata interprets it for non-SMART commands.
+nix::ioctl_readwrite_bad!(hdio_drive_cmd, HDIO_DRIVE_CMD, [u8; 4]);
+
+/// The longest standby timeout that the ATA standby timer can express.
+pub const MAX_STANDBY_MINUTES: u32 = 330;
+
+/// The whole drive that a device is on, since power management applies to drives rather than partitions.
+fn drive_kernel_name(dev_path: &Path) -> Result<String> {
+	let kernel_name = sysfs::kernel_name(dev_path)
+		.with_context(|| format!("no kernel name for {}", dev_path.display()))?;
+	Ok(sysfs::parent_disk(&kernel_name)?.unwrap_or(kernel_name))
+}
+
+fn drive_cmd(drive: &str, mut args: [u8; 4]) -> Result<()> {
+	let drive_path = PathBuf::from("/dev").join(drive);
+	let file = std::fs::File::open(&drive_path)
+		.with_context(|| format!("opening {}", drive_path.display()))?;
+	// SAFETY: HDIO_DRIVE_CMD reads and writes exactly four bytes through the pointer.
+	unsafe { hdio_drive_cmd(file.as_raw_fd(), std::ptr::addr_of_mut!(args)) }
+		.with_context(|| format!("sending ATA command to {}", drive_path.display()))?;
+	Ok(())
+}
+
+/// Whether anything on the drive, such as another partition, is still mounted or open.
+fn drive_in_use(drive: &str) -> Result<bool> {
+	let mounts = mountinfo::read()?;
+	let mut devices = vec![drive.to_owned()];
+	for device in sysfs::all_devices()? {
+		if sysfs::parent_disk(&device)?.as_deref() == Some(drive) {
+			devices.push(device);
+		}
+	}
+	for device in devices {
+		let number = sysfs::device_number(&device)?;
+		if !sysfs::holders(&device)?.is_empty() || mounts.iter().any(|entry| entry.device == number) {
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
+
+/// Spin down the drive that `dev_path` is on immediately, if it is rotational and nothing else on it is in use.
+pub fn standby_now(dev_path: &Path) -> Result<()> {
+	let drive = drive_kernel_name(dev_path)?;
+	if !sysfs::is_rotational(&drive)? {
+		return Ok(());
+	}
+	if drive_in_use(&drive)? {
+		eprintln!("not spinning down {drive}, since something else on it is still in use.");
+		return Ok(());
+	}
+	drive_cmd(&drive, [ATA_OP_STANDBY_NOW, 0, 0, 0])?;
+	eprintln!("spun down {drive}.");
+	Ok(())
+}
+
+/// Encode a timeout for the standby timer: units of 5 seconds up to 20 minutes, then units of 30 minutes.
+fn encode_standby_minutes(minutes: u32) -> Result<u8> {
+	ensure!(
+		(1..=MAX_STANDBY_MINUTES).contains(&minutes),
+		"standby timeout must be between 1 and {MAX_STANDBY_MINUTES} minutes"
+	);
+	let encoded = if minutes <= 20 {
+		minutes * 12
+	} else {
+		240 + minutes.div_ceil(30)
+	};
+	Ok(u8::try_from(encoded).expect("checked range"))
+}
+
+/// Make the drive that `dev_path` is on spin down by itself after being idle for `minutes`, if it is rotational.
+pub fn set_standby_timer(dev_path: &Path, minutes: u32) -> Result<()> {
+	let drive = drive_kernel_name(dev_path)?;
+	if !sysfs::is_rotational(&drive)? {
+		return Ok(());
+	}
+	drive_cmd(
+		&drive,
+		[ATA_OP_SET_IDLE, encode_standby_minutes(minutes)?, 0, 0],
+	)
+}
+
+/// Flush the drive that `dev_path` is on, spin it down if it is rotational, and detach it from the system so that it can be unplugged.
+///
+/// USB drives are disconnected from their port, which cuts their power on most hubs, and other SCSI and SATA drives are deleted from the SCSI layer.
+pub fn power_off(dev_path: &Path) -> Result<()> {
+	let drive = drive_kernel_name(dev_path)?;
+	ensure!(
+		!drive_in_use(&drive)?,
+		"something else on {drive} is still mounted or open"
+	);
+
+	let drive_path = PathBuf::from("/dev").join(&drive);
+	std::fs::File::open(&drive_path)
+		.and_then(|file| file.sync_all())
+		.with_context(|| format!("flushing {}", drive_path.display()))?;
+	eprintln!("flushed {drive}.");
+
+	if sysfs::is_rotational(&drive)? {
+		match drive_cmd(&drive, [ATA_OP_STANDBY_NOW, 0, 0, 0]) {
+			Ok(()) => eprintln!("spun down {drive}."),
+			// Many USB bridges don't pass ATA commands through; the drive spins down when it loses power anyway.
+			Err(error)
+				if matches!(
+					error.downcast_ref::<Errno>(),
+					Some(Errno::ENOTTY | Errno::EINVAL)
+				) => {}
+			Err(error) => return Err(error),
+		}
+	}
+
+	let remove_path = if let Some(usb_device) = sysfs::usb_device(&drive)? {
+		usb_device.join("remove")
+	} else {
+		let delete_path = Path::new("/sys/class/block")
+			.join(&drive)
+			.join("device/delete");
+		ensure!(
+			delete_path.exists(),
+			"{drive} can't be powered off, since it is neither a USB nor a SCSI or SATA drive"
+		);
+		delete_path
+	};
+	std::fs::write(&remove_path, "1")
+		.with_context(|| format!("writing to {}", remove_path.display()))?;
+	eprintln!("powered off {drive}.");
+	Ok(())
+}
@@ -0,0 +1,174 @@
+//! Running as root on behalf of the invoking user, either through the setuid bit, file capabilities (see `caps`), or by re-running with a tool like sudo.
+
+use std::ffi::{CString, OsStr};
+use std::os::unix::process::CommandExt as _;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context as _, Result};
+use nix::unistd::{Gid, Uid, User};
+
+/// How to become root when `d` is not installed setuid or with file capabilities.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Elevator {
+	#[default]
+	Sudo,
+	Doas,
+	Pkexec,
+	/// Fail instead.
+	None,
+}
+
+fn env_id(var: &str) -> Option<u32> {
+	std::env::var(var).ok()?.parse().ok()
+}
+
+/// The user who ran `d`, whose identity is used for editors, shells, and anything else that should not run as root.
+///
+/// With the setuid bit or file capabilities, this is the real user. sudo, doas, and pkexec make the real user root, but say who ran them in the environment.
+pub fn invoking_user() -> (Uid, Gid) {
+	let real = (Uid::current(), Gid::current());
+	if !real.0.is_root() {
+		return real;
+	}
+
+	if let (Some(uid), Some(gid)) = (env_id("SUDO_UID"), env_id("SUDO_GID")) {
+		return (Uid::from_raw(uid), Gid::from_raw(gid));
+	}
+	let user = if let Some(uid) = env_id("PKEXEC_UID") {
+		User::from_uid(Uid::from_raw(uid))
+	} else if let Ok(name) = std::env::var("DOAS_USER") {
+		User::from_name(&name)
+	} else {
+		return real;
+	};
+	match user {
+		Ok(Some(user)) => (user.uid, user.gid),
+		_ => real,
+	}
+}
+
+/// Variables that sudo, doas, and pkexec set for root, which would be wrong or misleading in a shell for the user.
+const ROOT_ONLY_VARS: &[&str] = &[
+	"SUDO_COMMAND",
+	"SUDO_USER",
+	"SUDO_UID",
+	"SUDO_GID",
+	"SUDO_PS1",
+	"DOAS_USER",
+	"PKEXEC_UID",
+	"MAIL",
+];
+
+/// A user to run an interactive shell as, with everything a login would set up for them.
+pub struct Account {
+	user: User,
+	/// Supplementary groups.
+	groups: Vec<Gid>,
+}
+
+/// The account of the invoking user, or of the user called `name`, which only root may ask for.
+pub fn account(name: Option<&str>) -> Result<Account> {
+	let (invoking_uid, _) = invoking_user();
+	let user = match name {
+		None => User::from_uid(invoking_uid)
+			.context("looking up the invoking user")?
+			.context("the invoking user does not exist")?,
+		Some(name) => {
+			let user = User::from_name(name)
+				.with_context(|| format!("looking up user {name:?}"))?
+				.with_context(|| format!("there is no user {name:?}"))?;
+			ensure!(
+				user.uid == invoking_uid || invoking_uid.is_root(),
+				"only root can run a shell as another user"
+			);
+			user
+		}
+	};
+	let c_name = CString::new(user.name.clone()).context("user name contains a nul byte")?;
+	let groups = nix::unistd::getgrouplist(&c_name, user.gid)
+		.with_context(|| format!("getting the groups of {}", user.name))?;
+	Ok(Account { user, groups })
+}
+
+impl Account {
+	/// The user's login shell.
+	pub fn shell(&self) -> &Path {
+		if self.user.shell.as_os_str().is_empty() {
+			Path::new("/bin/sh")
+		} else {
+			&self.user.shell
+		}
+	}
+
+	/// A command for the user's shell. See `command`.
+	pub fn shell_command(&self) -> Command {
+		self.command(self.shell())
+	}
+
+	/// A command that runs as the user with their groups, home directory, and so on, and without our capabilities.
+	pub fn command(&self, program: impl AsRef<OsStr>) -> Command {
+		let mut command = Command::new(program);
+		for var in ROOT_ONLY_VARS {
+			command.env_remove(var);
+		}
+		command
+			.env("HOME", &self.user.dir)
+			.env("SHELL", self.shell())
+			.env("USER", &self.user.name)
+			.env("LOGNAME", &self.user.name);
+
+		// Without root, we already are the user, and couldn't change our groups anyway.
+		let switch_user = Uid::effective().is_root();
+		let (uid, gid, groups) = (self.user.uid, self.user.gid, self.groups.clone());
+		let setup = move || {
+			crate::caps::clear_ambient()?;
+			if switch_user {
+				// `Command::uid` would reset the supplementary groups, so switch by hand, groups first while we still can.
+				nix::unistd::setgroups(&groups)?;
+				nix::unistd::setgid(gid)?;
+				nix::unistd::setuid(uid)?;
+			}
+			Ok(())
+		};
+		// SAFETY: `setup` only makes syscalls, and `groups` was allocated before forking.
+		unsafe { command.pre_exec(setup) };
+		command
+	}
+}
+
+pub trait CommandExt {
+	/// Run the command as the invoking user rather than root, and without our capabilities.
+	fn as_invoking_user(&mut self) -> &mut Self;
+}
+
+impl CommandExt for Command {
+	fn as_invoking_user(&mut self) -> &mut Self {
+		let (uid, gid) = invoking_user();
+		// Ambient capabilities are kept when the user doesn't change, as when running with file capabilities.
+		// SAFETY: `clear_ambient` only makes a syscall.
+		unsafe { self.pre_exec(crate::caps::clear_ambient) };
+		self.uid(uid.as_raw()).gid(gid.as_raw())
+	}
+}
+
+/// Replace this process with one running the same command as root through `elevator`.
+pub fn reexec_as_root(elevator: Elevator) -> Result<()> {
+	let (program, separator) = match elevator {
+		Elevator::Sudo => ("sudo", Some("--")),
+		Elevator::Doas => ("doas", Some("--")),
+		Elevator::Pkexec => ("pkexec", None),
+		Elevator::None => {
+			bail!("must be run as root to (un)mount disks and open/close encryption")
+		}
+	};
+
+	let exe = std::env::current_exe().context("finding own executable")?;
+	let error = Command::new(program)
+		.args(separator)
+		.arg(exe)
+		.args(std::env::args_os().skip(1))
+		.exec();
+	Err(error).with_context(|| format!("re-running as root with {program}"))
+}
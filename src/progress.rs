@@ -0,0 +1,150 @@
+//! Progress indication for operations that can take a while, such as unlocking, mounting, or copying a whole disk.
+
+use std::io::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const FRAMES: &[char] = &['|', '/', '-', '\\'];
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+/// Most operations finish almost instantly, in which case we don't want the spinner to flicker.
+const SHOW_AFTER: Duration = Duration::from_millis(300);
+
+/// Spinners are disabled when several operations run in parallel, since they would fight over the line.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn set_enabled(enabled: bool) {
+	ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+fn stderr_is_tty() -> bool {
+	nix::unistd::isatty(2) == Ok(true)
+}
+
+/// Run `f`, showing `message` along with a spinner and the elapsed time on stderr while it runs.
+///
+/// If stderr is not a TTY or spinners are disabled, `f` is run without any progress indication.
+pub fn with_spinner<T>(message: &str, f: impl FnOnce() -> T) -> T {
+	let message = message.to_owned();
+	with_status(move || message.clone(), f)
+}
+
+/// Like `with_spinner`, but the message is produced anew for each frame, such as to show how much is left to do.
+pub fn with_status<T>(
+	mut message: impl FnMut() -> String + Send + 'static,
+	f: impl FnOnce() -> T,
+) -> T {
+	if !ENABLED.load(Ordering::Relaxed) || !stderr_is_tty() {
+		return f();
+	}
+
+	let done = Arc::new(AtomicBool::new(false));
+	let thread = {
+		let done = Arc::clone(&done);
+		std::thread::spawn(move || spin(&mut message, &done))
+	};
+
+	let ret = f();
+
+	done.store(true, Ordering::Relaxed);
+	let _ = thread.join();
+
+	ret
+}
+
+fn spin(message: &mut impl FnMut() -> String, done: &AtomicBool) {
+	let start = Instant::now();
+	let mut stderr = std::io::stderr();
+	let mut shown = false;
+
+	for &frame in FRAMES.iter().cycle() {
+		if done.load(Ordering::Relaxed) {
+			break;
+		}
+		let elapsed = start.elapsed();
+		if elapsed >= SHOW_AFTER {
+			shown = true;
+			let _ = write!(
+				stderr,
+				"\r\x1b[K{frame} {} ({:.1}s)",
+				message(),
+				elapsed.as_secs_f32()
+			);
+			let _ = stderr.flush();
+		}
+		std::thread::sleep(FRAME_INTERVAL);
+	}
+
+	if shown {
+		let _ = write!(stderr, "\r\x1b[K");
+		let _ = stderr.flush();
+	}
+}
+
+/// How often to redraw the progress of a transfer.
+const TRANSFER_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Progress through a known number of bytes, shown on stderr with the percentage done and the rate.
+///
+/// Like spinners, this is only shown if stderr is a TTY and spinners are enabled.
+pub struct Transfer {
+	message: String,
+	total: u64,
+	start: Instant,
+	last_shown: Option<Instant>,
+	enabled: bool,
+}
+
+impl Transfer {
+	pub fn new(message: &str, total: u64) -> Self {
+		Self {
+			message: message.to_owned(),
+			total,
+			start: Instant::now(),
+			last_shown: None,
+			enabled: ENABLED.load(Ordering::Relaxed) && stderr_is_tty(),
+		}
+	}
+
+	pub fn update(&mut self, done: u64) {
+		if !self.enabled
+			|| self
+				.last_shown
+				.is_some_and(|last_shown| last_shown.elapsed() < TRANSFER_INTERVAL)
+		{
+			return;
+		}
+		self.last_shown = Some(Instant::now());
+		#[allow(clippy::cast_precision_loss)] // Only used for display.
+		let percent = if self.total == 0 {
+			100.0
+		} else {
+			done as f64 / self.total as f64 * 100.0
+		};
+		#[allow(
+			clippy::cast_possible_truncation,
+			clippy::cast_precision_loss,
+			clippy::cast_sign_loss
+		)] // Only used for display.
+		let rate = (done as f64 / self.start.elapsed().as_secs_f64().max(0.001)) as u64;
+		let mut stderr = std::io::stderr();
+		let _ = write!(
+			stderr,
+			"\r\x1b[K{} {} of {} ({percent:.1}%), {}/s",
+			self.message,
+			crate::output::format_size(done),
+			crate::output::format_size(self.total),
+			crate::output::format_size(rate),
+		);
+		let _ = stderr.flush();
+	}
+
+	/// Clear the progress line.
+	pub fn finish(self) {
+		if self.last_shown.is_some() {
+			let mut stderr = std::io::stderr();
+			let _ = write!(stderr, "\r\x1b[K");
+			let _ = stderr.flush();
+		}
+	}
+}
@@ -0,0 +1,179 @@
+//! Interactive questions on the terminal.
+
+use std::io::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{ensure, Context as _, Result};
+
+/// Set by `--yes`, to go ahead with risky operations without asking.
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--non-interactive`, or when stderr is not a TTY, so that `d` never waits for a person.
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Cleared by `--non-interactive`, and for the helper, which has nobody to ask.
+static ASKPASS_ALLOWED: AtomicBool = AtomicBool::new(true);
+
+/// `d` needed to ask something, but couldn't. `main` exits with a distinct status for this.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct NeedsInteraction(pub String);
+
+pub fn set_assume_yes(assume_yes: bool) {
+	ASSUME_YES.store(assume_yes, Ordering::Relaxed);
+}
+
+pub fn set_non_interactive(non_interactive: bool) {
+	NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+pub fn is_non_interactive() -> bool {
+	NON_INTERACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn set_askpass_allowed(allowed: bool) {
+	ASKPASS_ALLOWED.store(allowed, Ordering::Relaxed);
+}
+
+/// Whether the askpass program may be used. It works without a terminal, so it can still be used when `d` is non-interactive only because stderr is not a TTY.
+pub fn is_askpass_allowed() -> bool {
+	ASKPASS_ALLOWED.load(Ordering::Relaxed)
+}
+
+pub fn stdin_is_tty() -> bool {
+	nix::unistd::isatty(0) == Ok(true)
+}
+
+/// Whether there is a terminal to ask questions on, and asking is allowed.
+pub fn can_ask() -> bool {
+	!is_non_interactive() && stdin_is_tty()
+}
+
+/// Ask a question on stderr and read a line of response from stdin.
+pub fn ask(question: &str) -> Result<String> {
+	if is_non_interactive() {
+		return Err(
+			NeedsInteraction(format!(
+				"{question:?} needs an answer, but d is non-interactive"
+			))
+			.into(),
+		);
+	}
+	eprint!("{question} ");
+	std::io::stderr().flush().context("flushing prompt")?;
+
+	let mut response = String::new();
+	let read = std::io::stdin()
+		.read_line(&mut response)
+		.context("reading response")?;
+	ensure!(read > 0, "no response (end of input)");
+	Ok(response.trim().to_owned())
+}
+
+/// Like `ask`, but gives up and returns `None` if there is no response within `timeout`.
+pub fn ask_with_timeout(question: &str, timeout: Duration) -> Result<Option<String>> {
+	use nix::poll::{poll, PollFd, PollFlags};
+
+	if is_non_interactive() {
+		return Ok(None);
+	}
+	eprint!("{question} ");
+	std::io::stderr().flush().context("flushing prompt")?;
+
+	let mut fds = [PollFd::new(0, PollFlags::POLLIN)];
+	let timeout_ms = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+	if poll(&mut fds, timeout_ms).context("waiting for response")? == 0 {
+		eprintln!();
+		return Ok(None);
+	}
+	let mut response = String::new();
+	let read = std::io::stdin()
+		.read_line(&mut response)
+		.context("reading response")?;
+	ensure!(read > 0, "no response (end of input)");
+	Ok(Some(response.trim().to_owned()))
+}
+
+/// Like `ask`, but an empty response selects `default`, if any.
+pub fn ask_with_default(question: &str, default: Option<&str>) -> Result<String> {
+	match default {
+		Some(default) => {
+			let response = ask(&format!("{question} [{default}]"))?;
+			Ok(if response.is_empty() {
+				default.to_owned()
+			} else {
+				response
+			})
+		}
+		None => ask(question),
+	}
+}
+
+/// Ask a yes/no question. An empty response selects `default`.
+pub fn confirm(question: &str, default: bool) -> Result<bool> {
+	let hint = if default { "[Y/n]" } else { "[y/N]" };
+	loop {
+		match ask(&format!("{question} {hint}"))?.to_lowercase().as_str() {
+			"" => return Ok(default),
+			"y" | "yes" => return Ok(true),
+			"n" | "no" => return Ok(false),
+			_ => eprintln!("please answer y or n."),
+		}
+	}
+}
+
+/// Make sure that the user wants to `action`, something risky: by `--yes`, or by asking if there is a terminal to ask on.
+pub fn confirm_risky(action: &str) -> Result<()> {
+	if ASSUME_YES.load(Ordering::Relaxed) {
+		return Ok(());
+	}
+	if !can_ask() {
+		return Err(NeedsInteraction(format!(
+			"not confirmed to {action}, and d can't ask without a terminal or when non-interactive. pass --yes to confirm"
+		))
+		.into());
+	}
+	ensure!(
+		confirm(&format!("{action}?"), false)?,
+		"not confirmed to {action}"
+	);
+	Ok(())
+}
+
+/// Require the user to type `expected` exactly before doing something destructive, unless `--yes` was passed.
+pub fn confirm_typed(expected: &str) -> Result<()> {
+	if ASSUME_YES.load(Ordering::Relaxed) {
+		return Ok(());
+	}
+	if !can_ask() {
+		return Err(NeedsInteraction(
+			"this needs confirmation, and d can't ask without a terminal or when non-interactive. pass --yes to confirm"
+				.to_owned(),
+		)
+		.into());
+	}
+	let response = ask(&format!("type {expected:?} to continue:"))?;
+	ensure!(response == expected, "aborted; nothing was changed");
+	Ok(())
+}
+
+/// Like `confirm_typed`, but for something irreversible, so `--yes` isn't enough. `given` is the same text passed in advance, such as with `--confirm`.
+pub fn confirm_typed_always(expected: &str, given: Option<&str>) -> Result<()> {
+	if let Some(given) = given {
+		ensure!(
+			given == expected,
+			"{given:?} was given to confirm, which is not {expected:?}; nothing was changed"
+		);
+		return Ok(());
+	}
+	if !can_ask() {
+		return Err(NeedsInteraction(format!(
+			"typing {expected:?} is needed to confirm, and d can't ask without a terminal or when non-interactive. pass --confirm with the name of the disk to confirm"
+		))
+		.into());
+	}
+	let response = ask(&format!("type {expected:?} to continue:"))?;
+	ensure!(response == expected, "aborted; nothing was changed");
+	Ok(())
+}
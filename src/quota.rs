@@ -0,0 +1,143 @@
+//! Disk quotas on ext4 and xfs, for disks shared between several users.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::caps;
+use crate::config::Disk;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+	User,
+	Group,
+	Project,
+}
+
+impl Kind {
+	fn mount_option(self, filesystem: &str) -> Result<&'static str> {
+		Ok(match (filesystem, self) {
+			("ext4", Self::User) => "usrquota",
+			("ext4", Self::Group) => "grpquota",
+			("ext4", Self::Project) => "prjquota",
+			("xfs", Self::User) => "uquota",
+			("xfs", Self::Group) => "gquota",
+			("xfs", Self::Project) => "pquota",
+			_ => bail!("quotas are only supported on ext4 and xfs"),
+		})
+	}
+
+	/// The quota file that `quotacheck` creates for ext4. Project quotas need the `project` feature instead, which keeps them in hidden inodes.
+	fn ext4_file(self) -> Option<&'static str> {
+		match self {
+			Self::User => Some("aquota.user"),
+			Self::Group => Some("aquota.group"),
+			Self::Project => None,
+		}
+	}
+
+	/// The flag that selects this kind for `quotacheck`, `quotaon`, and `repquota`.
+	fn flag(self) -> &'static str {
+		match self {
+			Self::User => "-u",
+			Self::Group => "-g",
+			Self::Project => "-P",
+		}
+	}
+}
+
+/// The mount options that enable the disk's quotas, to add to the others.
+pub fn mount_options(disk: &Disk) -> Result<Vec<&'static str>> {
+	disk
+		.quota
+		.iter()
+		.map(|kind| kind.mount_option(disk.inner_filesystem()))
+		.collect()
+}
+
+fn run(command: &mut Command) -> Result<()> {
+	let program = command.get_program().to_string_lossy().into_owned();
+	let status = command
+		.status()
+		.with_context(|| format!("running {program}"))?;
+	ensure!(
+		status.success(),
+		"{program} exited with status {:?}",
+		status.code()
+	);
+	Ok(())
+}
+
+/// Turn on quota enforcement after mounting, creating the quota files first if needed.
+///
+/// xfs enforces quotas as soon as it is mounted with the options, so this only does anything for ext4.
+pub fn enable(disk: &Disk, mount_path: &str) -> Result<()> {
+	if disk.inner_filesystem() != "ext4" || disk.quota.is_empty() {
+		return Ok(());
+	}
+
+	let missing: Vec<Kind> = disk
+		.quota
+		.iter()
+		.copied()
+		.filter(|kind| {
+			kind
+				.ext4_file()
+				.is_some_and(|file| !Path::new(mount_path).join(file).exists())
+		})
+		.collect();
+	if !missing.is_empty() {
+		eprintln!("creating quota files. this scans the whole filesystem, so it can take a while.");
+		// -c creates new files, and -m doesn't try to remount read-only, which would fail for a busy disk.
+		run(
+			caps::tool("quotacheck")
+				.arg("-cm")
+				.args(missing.iter().map(|kind| kind.flag()))
+				.arg(mount_path),
+		)
+		.context("creating quota files")?;
+	}
+
+	run(
+		caps::tool("quotaon")
+			.args(disk.quota.iter().map(|kind| kind.flag()))
+			.arg(mount_path),
+	)
+	.context("turning on quotas")
+}
+
+/// Print the usage and limits of each user, group, or project with quotas on the disk mounted at `mount_path`.
+pub fn report(disk: &Disk, mount_path: &str) -> Result<()> {
+	ensure!(
+		!disk.quota.is_empty(),
+		"{} has no quotas configured",
+		disk.as_repr()
+	);
+	match disk.inner_filesystem() {
+		"ext4" => run(
+			caps::tool("repquota")
+				.arg("-s")
+				.args(disk.quota.iter().map(|kind| kind.flag()))
+				.arg(mount_path),
+		),
+		"xfs" => {
+			let kinds: String = disk
+				.quota
+				.iter()
+				.map(|kind| match kind {
+					Kind::User => " -u",
+					Kind::Group => " -g",
+					Kind::Project => " -p",
+				})
+				.collect();
+			run(
+				caps::tool("xfs_quota")
+					.args(["-x", "-c", &format!("report -h{kinds}")])
+					.arg(mount_path),
+			)
+		}
+		other => bail!("quotas are not supported on {other}"),
+	}
+}
@@ -0,0 +1,83 @@
+//! Running `d` on another machine over SSH.
+
+use std::os::unix::process::CommandExt as _;
+
+use anyhow::{Context as _, Result};
+
+use crate::privilege::CommandExt as _;
+
+/// Quote an argument for a POSIX shell, since SSH passes the remote command through the remote user's shell.
+fn shell_quote(arg: &str) -> String {
+	format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// The command-line arguments to forward, which are all of ours except `--host` and its value.
+fn forwarded_args() -> Vec<String> {
+	let mut forwarded = Vec::new();
+	let mut args = std::env::args().skip(1);
+	while let Some(arg) = args.next() {
+		if arg == "--host" {
+			let _ = args.next();
+		} else if !arg.starts_with("--host=") {
+			forwarded.push(arg);
+		}
+	}
+	forwarded
+}
+
+/// Replace this process with `ssh`, running `d` on `host` with the same arguments.
+///
+/// SSH runs as the real user so that their keys and config are used. A TTY is requested so that prompts and `c` subshells work.
+pub fn exec(host: &str) -> Result<()> {
+	let remote_command = std::iter::once("d".to_owned())
+		.chain(forwarded_args().iter().map(|arg| shell_quote(arg)))
+		.collect::<Vec<_>>()
+		.join(" ");
+
+	let error = std::process::Command::new("ssh")
+		.arg("-t")
+		.arg(host)
+		.arg("--")
+		.arg(remote_command)
+		.as_invoking_user()
+		.exec();
+	Err(error).context("running ssh")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn quote_plain() {
+		assert_eq!(shell_quote("sivydatni"), "'sivydatni'");
+	}
+
+	#[test]
+	fn quote_empty() {
+		assert_eq!(shell_quote(""), "''");
+	}
+
+	#[test]
+	fn quote_special() {
+		assert_eq!(shell_quote("a b$c;\"d\"\\"), r#"'a b$c;"d"\'"#);
+	}
+
+	#[test]
+	fn quote_single_quotes() {
+		assert_eq!(shell_quote("it's"), r"'it'\''s'");
+		assert_eq!(shell_quote("''"), r"''\'''\'''");
+	}
+
+	#[test]
+	fn shell_reads_back() {
+		for arg in ["", "it's", "a b", "$HOME", "`id`", "'\\n'", "\\"] {
+			let output = std::process::Command::new("sh")
+				.arg("-c")
+				.arg(format!("printf %s {}", shell_quote(arg)))
+				.output()
+				.unwrap();
+			assert_eq!(String::from_utf8(output.stdout).unwrap(), arg);
+		}
+	}
+}
@@ -0,0 +1,147 @@
+//! Growing a disk's LUKS mapping and filesystem to fill its partition, after the partition has been enlarged.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::config::{Config, Disk, Mountable};
+use crate::{caps, output, space, sysfs, DiskState};
+
+struct Step {
+	description: &'static str,
+	command: Command,
+}
+
+impl Step {
+	fn new(description: &'static str, program: &str, args: &[&str]) -> Self {
+		let mut command = caps::tool(program);
+		command.args(args);
+		Self {
+			description,
+			command,
+		}
+	}
+
+	fn command_line(&self) -> String {
+		std::iter::once(self.command.get_program())
+			.chain(self.command.get_args())
+			.map(|part| part.to_string_lossy())
+			.collect::<Vec<_>>()
+			.join(" ")
+	}
+}
+
+fn print_device_size(label: &str, dev_path: &Path) -> Result<()> {
+	let kernel_name = sysfs::kernel_name(dev_path)
+		.with_context(|| format!("no kernel name for {}", dev_path.display()))?;
+	println!(
+		"{label} ({}): {}",
+		dev_path.display(),
+		output::format_size(sysfs::size_bytes(&kernel_name)?)
+	);
+	Ok(())
+}
+
+fn print_sizes(disk: &Disk, mount_path: &str) -> Result<()> {
+	match disk.to_mountable() {
+		Mountable::Plain { .. } => {
+			print_device_size("partition", &crate::outer_dev_path(disk)?)?;
+		}
+		Mountable::Encrypted { inner_uuid, .. } => {
+			print_device_size("partition", &crate::outer_dev_path(disk)?)?;
+			print_device_size("mapping", &crate::dev_path_for_uuid(inner_uuid)?)?;
+		}
+		Mountable::Verity { .. } => unreachable!("verity disks can't be resized"),
+	}
+	println!(
+		"filesystem: {}",
+		output::format_size(space::usage(mount_path)?.total)
+	);
+	Ok(())
+}
+
+fn filesystem_step(disk: &Disk, mount_path: &str) -> Result<Step> {
+	let dev_path = if disk.is_encrypted() {
+		crate::dev_path_for_uuid(&disk.uuid)?
+	} else {
+		crate::outer_dev_path(disk)?
+	};
+	let dev_path = dev_path.to_string_lossy();
+	Ok(match disk.inner_filesystem() {
+		"ext2" | "ext3" | "ext4" => Step::new("grow the filesystem", "resize2fs", &[&dev_path]),
+		"btrfs" => Step::new(
+			"grow the filesystem",
+			"btrfs",
+			&["filesystem", "resize", "max", mount_path],
+		),
+		"xfs" => Step::new("grow the filesystem", "xfs_growfs", &[mount_path]),
+		other => bail!("don't know how to grow {other} filesystems online"),
+	})
+}
+
+/// Grow the LUKS mapping, if any, and then the filesystem, while the disk is mounted.
+///
+/// With `dry_run`, only print the planned steps.
+pub fn run(config: &Config, disk: &Disk, dry_run: bool) -> Result<()> {
+	ensure!(
+		disk.verity.is_none(),
+		"{} is verified with dm-verity, so it is read-only and can't be resized",
+		disk.as_repr()
+	);
+	let state = crate::disk_state(disk)?;
+	ensure!(
+		state == DiskState::Mounted,
+		"{} is {}; mount it first, since filesystems are grown online",
+		disk.as_repr(),
+		state.as_repr()
+	);
+	let mount_path = config.mount_path(disk);
+
+	let mut steps = Vec::new();
+	if let Mountable::Encrypted { outer_uuid, .. } = disk.to_mountable() {
+		let opened_name = crate::opened_name_for_encrypted(outer_uuid, disk.as_repr());
+		steps.push(Step::new(
+			"grow the LUKS mapping to fill the partition",
+			"cryptsetup",
+			&["resize", &opened_name],
+		));
+	}
+	steps.push(filesystem_step(disk, &mount_path)?);
+
+	print_sizes(disk, &mount_path)?;
+	println!(
+		"if the partition size is not the new size, the kernel has not noticed the change yet. run `partprobe` first."
+	);
+
+	if dry_run {
+		println!("planned steps:");
+		for (index, step) in steps.iter().enumerate() {
+			println!(
+				"\t{}. {}: {}",
+				index + 1,
+				step.description,
+				step.command_line()
+			);
+		}
+		return Ok(());
+	}
+
+	for mut step in steps {
+		eprintln!("{}: running `{}`.", step.description, step.command_line());
+		// Not in a spinner, since `cryptsetup resize` may prompt for a passphrase.
+		let status = step
+			.command
+			.status()
+			.with_context(|| format!("running {}", step.command_line()))?;
+		ensure!(
+			status.success(),
+			"`{}` exited with status {:?}",
+			step.command_line(),
+			status.code()
+		);
+	}
+
+	println!("after resizing:");
+	print_sizes(disk, &mount_path)
+}
@@ -0,0 +1,67 @@
+//! Copying directories to or from a disk with rsync, as configured per disk.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context as _, Result};
+
+use crate::config::RsyncJob;
+use crate::output;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+	/// From the local directory to the disk.
+	#[default]
+	ToDisk,
+	/// From the disk to the local directory.
+	FromDisk,
+}
+
+/// What rsync's exit statuses mean, for the ones that people are likely to run into.
+fn describe_status(code: i32) -> Option<&'static str> {
+	Some(match code {
+		1 => "syntax or usage error; check the job's options",
+		11 => "error in file I/O; the destination may be full",
+		20 => "interrupted",
+		23 => "some files could not be transferred",
+		_ => return None,
+	})
+}
+
+/// Run the job for the disk mounted at `mount_path`. With `dry_run`, rsync only lists what it would transfer.
+pub fn run(mount_path: &str, job: &RsyncJob, dry_run: bool) -> Result<()> {
+	let on_disk = match &job.path {
+		Some(path) => Path::new(mount_path).join(path),
+		None => Path::new(mount_path).to_owned(),
+	};
+	// Trailing slashes make rsync copy the contents of the source rather than the directory itself.
+	let on_disk = format!("{}/", on_disk.display());
+	let local = format!("{}/", job.local.trim_end_matches('/'));
+	let (source, destination) = match job.direction {
+		Direction::ToDisk => (local, on_disk),
+		Direction::FromDisk => (on_disk, local),
+	};
+
+	let mut command = Command::new("rsync");
+	command
+		.args(["--archive", "--info=progress2"])
+		.args(dry_run.then_some("--dry-run"))
+		.args(&job.options)
+		.arg("--")
+		.args([&source, &destination]);
+
+	eprintln!("syncing {source} to {destination}.");
+	let status = command.status().context("running rsync")?;
+	match status.code() {
+		Some(0) => {}
+		// Such as temporary files that were deleted while rsync ran, which doesn't make the copy any less complete.
+		Some(24) => output::warning("some files vanished before rsync could copy them."),
+		Some(code) => match describe_status(code) {
+			Some(description) => bail!("rsync exited with status {code}: {description}"),
+			None => bail!("rsync exited with status {code}"),
+		},
+		None => bail!("rsync was killed"),
+	}
+	Ok(())
+}
@@ -0,0 +1,133 @@
+//! Landlock sandboxes for the shells of `c` sessions on untrusted disks, so that whatever is run from the disk can only change the disk itself.
+//!
+//! See `Documentation/userspace-api/landlock.rst` in the kernel.
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd as _, FromRawFd as _, RawFd};
+use std::os::unix::process::CommandExt as _;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+use nix::errno::Errno;
+use nix::libc;
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+/// Everything that the first version of Landlock can restrict, which also includes removing and making files of each type.
+const ACCESS_ALL: u64 = (1 << 13) - 1;
+const ACCESS_READ: u64 = ACCESS_READ_FILE | ACCESS_READ_DIR;
+
+/// What the shell may do outside of the disk: run programs and read the system's configuration, and use terminals and temporary files.
+const SYSTEM_PATHS: &[(&str, u64)] = &[
+	("/usr", ACCESS_READ | ACCESS_EXECUTE),
+	("/bin", ACCESS_READ | ACCESS_EXECUTE),
+	("/sbin", ACCESS_READ | ACCESS_EXECUTE),
+	("/lib", ACCESS_READ | ACCESS_EXECUTE),
+	("/lib64", ACCESS_READ | ACCESS_EXECUTE),
+	("/opt", ACCESS_READ | ACCESS_EXECUTE),
+	("/nix/store", ACCESS_READ | ACCESS_EXECUTE),
+	("/etc", ACCESS_READ),
+	("/proc", ACCESS_READ),
+	("/sys", ACCESS_READ),
+	("/dev", ACCESS_READ | ACCESS_WRITE_FILE),
+	("/tmp", ACCESS_ALL),
+];
+
+#[repr(C)]
+struct RulesetAttr {
+	handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+	allowed_access: u64,
+	parent_fd: RawFd,
+}
+
+/// A Landlock ruleset that only allows full access to the mount path.
+pub struct Sandbox {
+	ruleset: File,
+}
+
+impl Sandbox {
+	pub fn for_mount_path(mount_path: &Path) -> Result<Self> {
+		// SAFETY: asking for the version takes no attributes.
+		let version = unsafe {
+			libc::syscall(
+				libc::SYS_landlock_create_ruleset,
+				std::ptr::null::<RulesetAttr>(),
+				0,
+				LANDLOCK_CREATE_RULESET_VERSION,
+			)
+		};
+		Errno::result(version)
+			.context("Landlock is not available; it needs Linux 5.13 or later, with Landlock enabled")?;
+
+		let attr = RulesetAttr {
+			handled_access_fs: ACCESS_ALL,
+		};
+		// SAFETY: `attr` is valid for the duration of the call, and its size is passed along with it.
+		let fd = unsafe {
+			libc::syscall(
+				libc::SYS_landlock_create_ruleset,
+				std::ptr::addr_of!(attr),
+				size_of::<RulesetAttr>(),
+				0,
+			)
+		};
+		let fd = Errno::result(fd).context("creating Landlock ruleset")?;
+		// SAFETY: the syscall returned a new file descriptor, which is now owned by the `File`.
+		let ruleset =
+			unsafe { File::from_raw_fd(RawFd::try_from(fd).expect("file descriptors fit in an int")) };
+		let sandbox = Self { ruleset };
+
+		sandbox.allow(mount_path, ACCESS_ALL)?;
+		for &(path, access) in SYSTEM_PATHS {
+			if Path::new(path).exists() {
+				sandbox.allow(Path::new(path), access)?;
+			}
+		}
+		Ok(sandbox)
+	}
+
+	fn allow(&self, path: &Path, access: u64) -> Result<()> {
+		let dir = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+		let attr = PathBeneathAttr {
+			allowed_access: access,
+			parent_fd: dir.as_raw_fd(),
+		};
+		// SAFETY: `attr` and the file descriptor in it are valid for the duration of the call.
+		let ret = unsafe {
+			libc::syscall(
+				libc::SYS_landlock_add_rule,
+				self.ruleset.as_raw_fd(),
+				LANDLOCK_RULE_PATH_BENEATH,
+				std::ptr::addr_of!(attr),
+				0,
+			)
+		};
+		Errno::result(ret).with_context(|| format!("allowing access to {}", path.display()))?;
+		Ok(())
+	}
+
+	/// Make `command` run in the sandbox, failing to spawn if it can't be applied.
+	pub fn apply(&self, command: &mut Command) {
+		let ruleset = self.ruleset.as_raw_fd();
+		let restrict = move || {
+			// Without this, an unprivileged process can't restrict itself.
+			// SAFETY: setting no_new_privs takes no pointers.
+			Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+			// SAFETY: `ruleset` stays open until the sandbox is dropped, which is after spawning.
+			Errno::result(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) })?;
+			Ok(())
+		};
+		// SAFETY: `restrict` only makes syscalls.
+		unsafe { command.pre_exec(restrict) };
+	}
+}
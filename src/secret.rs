@@ -0,0 +1,84 @@
+//! Secrets in the config file, which may be stored encrypted so that the config can be shared or published safely.
+
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::privilege::CommandExt as _;
+
+/// Global settings for decrypting secrets.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+	/// The identity file passed to `age`. Identities for hardware tokens (such as from `age-plugin-yubikey`) work too.
+	pub age_identity: Option<PathBuf>,
+}
+
+/// A secret value, either in plain text or encrypted.
+///
+/// ```toml
+/// keyfile = "/etc/d/keys/sivydatni"
+/// passphrase = { age = "-----BEGIN AGE ENCRYPTED FILE-----\n..." }
+/// passphrase = { gpg = "-----BEGIN PGP MESSAGE-----\n..." }
+/// ```
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+	Plain(String),
+	Encrypted(Encrypted),
+}
+
+/// ASCII-armored ciphertext.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Encrypted {
+	Age(String),
+	Gpg(String),
+}
+
+/// Run a decryption command as the real user, so that their keys and agents are used rather than root's.
+fn decrypt_with(mut command: Command, ciphertext: &str) -> Result<String> {
+	let mut child = command
+		.as_invoking_user()
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.spawn()?;
+	let mut stdin = child.stdin.take().expect("stdin is piped");
+	stdin
+		.write_all(ciphertext.as_bytes())
+		.context("passing ciphertext")?;
+	drop(stdin);
+
+	let output = child.wait_with_output()?;
+	ensure!(
+		output.status.success(),
+		"exited with status {:?}",
+		output.status.code()
+	);
+	let plaintext = String::from_utf8(output.stdout).context("decrypted secret is not UTF-8")?;
+	Ok(plaintext.trim_end_matches('\n').to_owned())
+}
+
+impl Secret {
+	/// Get the plain text of the secret, decrypting it if necessary.
+	pub fn reveal(&self, settings: &Settings) -> Result<String> {
+		match self {
+			Self::Plain(plain) => Ok(plain.clone()),
+			Self::Encrypted(Encrypted::Age(ciphertext)) => {
+				let Some(identity) = &settings.age_identity else {
+					bail!("an age-encrypted secret requires `age_identity` in the [secrets] section");
+				};
+				let mut command = Command::new("age");
+				command.arg("--decrypt").arg("--identity").arg(identity);
+				decrypt_with(command, ciphertext).context("decrypting secret with age")
+			}
+			Self::Encrypted(Encrypted::Gpg(ciphertext)) => {
+				let mut command = Command::new("gpg");
+				command.args(["--decrypt", "--quiet"]);
+				decrypt_with(command, ciphertext).context("decrypting secret with gpg")
+			}
+		}
+	}
+}
@@ -0,0 +1,55 @@
+//! SELinux labeling of mounted disks, and recognizing when SELinux is why a mount failed.
+
+use std::path::Path;
+
+use crate::config::Disk;
+
+/// Filesystems that can't store labels, so that everything on them gets one label from the mount options or the policy.
+const UNLABELED_FILESYSTEMS: &[&str] = &["vfat", "exfat", "ntfs", "ntfs3"];
+
+/// What removable media is labeled by default, so that confined user programs can use it like a USB stick.
+const REMOVABLE_CONTEXT: &str = "system_u:object_r:removable_t:s0";
+
+pub fn is_enabled() -> bool {
+	Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+fn is_enforcing() -> bool {
+	std::fs::read_to_string("/sys/fs/selinux/enforce").is_ok_and(|enforce| enforce.trim() == "1")
+}
+
+/// The context mount options for the disk, if SELinux is enabled.
+///
+/// Contexts are quoted, since MLS categories such as `s0:c1,c2` contain commas.
+pub fn mount_options(disk: &Disk) -> Vec<String> {
+	if !is_enabled() {
+		return Vec::new();
+	}
+	let mut options = Vec::new();
+	if let Some(context) = &disk.context {
+		options.push(format!("context=\"{context}\""));
+	}
+	if let Some(fscontext) = &disk.fscontext {
+		options.push(format!("fscontext=\"{fscontext}\""));
+	}
+	if options.is_empty() && UNLABELED_FILESYSTEMS.contains(&disk.inner_filesystem()) {
+		options.push(format!("context=\"{REMOVABLE_CONTEXT}\""));
+	}
+	options
+}
+
+/// An explanation for EACCES from the mount syscall, if SELinux is likely to be the cause.
+pub fn explain_denial(kernel_messages: &[String]) -> Option<String> {
+	let denials: Vec<&str> = kernel_messages
+		.iter()
+		.map(String::as_str)
+		.filter(|message| message.contains("avc:") && message.contains("denied"))
+		.collect();
+	if !denials.is_empty() {
+		return Some(format!("SELinux denied the mount:\n{}", denials.join("\n")));
+	}
+	// With auditd running, denials go to the audit log instead of the kernel log.
+	is_enforcing().then(|| {
+		"SELinux is enforcing, so this may be a denial. check `ausearch -m avc -ts recent`, and set `context` or `fscontext` for the disk if needed".to_owned()
+	})
+}
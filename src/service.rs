@@ -0,0 +1,219 @@
+//! The `org.mattfbacon.d` service on the system bus, so that status bars and GUIs can drive `d` without parsing its output. Offered by `d helper` when `dbus_service` is set.
+//!
+//! Anyone can list disks and get their status, but only `helper_users` can mount and unmount them. `dbus/org.mattfbacon.d.conf` allows root to own the name.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::io::AsRawFd as _;
+
+use anyhow::{anyhow, Context as _, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::config::{self, Config};
+use crate::dbus::{self, Message};
+use crate::{helper, output, MountOptions, MountReturn};
+
+const NAME: &str = "org.mattfbacon.d";
+const PATH: &str = "/org/mattfbacon/d";
+const INTERFACE: &str = "org.mattfbacon.d";
+const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+
+const ERROR_FAILED: &str = "org.mattfbacon.d.Error.Failed";
+const ERROR_ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+const ERROR_UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+const ERROR_UNKNOWN_OBJECT: &str = "org.freedesktop.DBus.Error.UnknownObject";
+
+/// Disk states are also checked this often, since opening encryption and attaching devices don't change the mount table.
+const POLL_TIMEOUT_MS: i32 = 5000;
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.mattfbacon.d">
+    <method name="ListDisks">
+      <arg name="disks" type="as" direction="out"/>
+    </method>
+    <method name="GetStatus">
+      <arg name="disk" type="s" direction="in"/>
+      <arg name="state" type="s" direction="out"/>
+    </method>
+    <method name="Mount">
+      <arg name="disk" type="s" direction="in"/>
+      <!-- Empty to use the key file from the config. -->
+      <arg name="passphrase" type="s" direction="in"/>
+      <arg name="mount_path" type="s" direction="out"/>
+    </method>
+    <method name="Unmount">
+      <arg name="disk" type="s" direction="in"/>
+    </method>
+    <signal name="StateChanged">
+      <arg name="disk" type="s"/>
+      <arg name="state" type="s"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+/// The arguments of a call that takes `N` strings.
+fn args<const N: usize>(call: &Message) -> Result<[String; N]> {
+	call
+		.string_args()?
+		.try_into()
+		.map_err(|args: Vec<String>| anyhow!("expected {N} arguments, got {}", args.len()))
+}
+
+/// The disks' states, as in `d list`, keyed by name. Disks whose state can't be told are left out after warning about them, rather than hiding the others.
+fn states(config: &Config) -> BTreeMap<String, &'static str> {
+	config
+		.disks
+		.iter()
+		.filter_map(|disk| match crate::disk_state(disk) {
+			Ok(state) => Some((disk.name.clone(), state.as_repr())),
+			Err(error) => {
+				output::warning(format_args!(
+					"failed to get the state of {}: {error:#}",
+					disk.as_repr()
+				));
+				None
+			}
+		})
+		.collect()
+}
+
+/// Mounting and unmounting is only for `helper_users`.
+fn is_authorized(bus: &mut dbus::Connection, call: &Message, config: &Config) -> Result<bool> {
+	let sender = call
+		.sender
+		.as_deref()
+		.context("method call has no sender")?;
+	helper::is_allowed(config, bus.unix_user(sender)?)
+}
+
+fn reply_to(bus: &mut dbus::Connection, call: &Message) -> Result<Message> {
+	if call.path.as_deref() != Some(PATH) {
+		return Ok(call.error(ERROR_UNKNOWN_OBJECT, "no such object"));
+	}
+	let member = call.member.as_deref().unwrap_or_default();
+	let mut reply = call.method_return();
+	match (call.interface.as_deref(), member) {
+		(Some(INTROSPECTABLE) | None, "Introspect") => {
+			reply.set_body("s", |writer| writer.string(INTROSPECTION));
+			return Ok(reply);
+		}
+		(Some(INTERFACE) | None, _) => {}
+		_ => return Ok(call.error(ERROR_UNKNOWN_METHOD, "no such method")),
+	}
+
+	// Load the config for every call so that edits take effect without restarting the helper.
+	let config = config::load()?;
+	match member {
+		"ListDisks" => {
+			let names: Vec<&str> = config.disks.iter().map(config::Disk::as_repr).collect();
+			reply.set_body("as", |writer| writer.strings(&names));
+		}
+		"GetStatus" => {
+			let [disk] = args(call)?;
+			let state = crate::disk_state(config.disk(&disk)?)?.as_repr();
+			reply.set_body("s", |writer| writer.string(state));
+		}
+		"Mount" | "Unmount" if !is_authorized(bus, call, &config)? => {
+			return Ok(call.error(
+				ERROR_ACCESS_DENIED,
+				"only helper_users can mount and unmount disks",
+			));
+		}
+		"Mount" => {
+			let [disk, passphrase] = args(call)?;
+			let passphrase = (!passphrase.is_empty()).then_some(passphrase);
+			let MountReturn { mount_path, .. } = helper::mount_for_client(
+				&config,
+				&disk,
+				passphrase,
+				"the D-Bus caller",
+				MountOptions::default(),
+			)?;
+			reply.set_body("s", |writer| writer.string(&mount_path));
+		}
+		"Unmount" => {
+			let [disk] = args(call)?;
+			helper::unmount_for_client(&config, &disk)?;
+		}
+		_ => return Ok(call.error(ERROR_UNKNOWN_METHOD, "no such method")),
+	}
+	Ok(reply)
+}
+
+fn handle_call(bus: &mut dbus::Connection, call: &Message) -> Result<()> {
+	let reply =
+		reply_to(bus, call).unwrap_or_else(|error| call.error(ERROR_FAILED, &format!("{error:#}")));
+	if call.flags & dbus::NO_REPLY_EXPECTED == 0 {
+		bus.send(&reply)?;
+	}
+	Ok(())
+}
+
+/// Emit `StateChanged` for every disk whose state differs from `previous`, and update it.
+///
+/// Only failing to send is an error. If the config can't be loaded, such as while it is being edited, the states are checked again next time.
+fn emit_changes(
+	bus: &mut dbus::Connection,
+	previous: &mut BTreeMap<String, &'static str>,
+) -> Result<()> {
+	let config = match config::load() {
+		Ok(config) => config,
+		Err(error) => {
+			output::warning(format_args!("not checking for state changes: {error:#}"));
+			return Ok(());
+		}
+	};
+	let current = states(&config);
+	for (disk, &state) in &current {
+		if previous.get(disk) != Some(&state) {
+			let mut signal = Message::signal(PATH, INTERFACE, "StateChanged");
+			signal.set_body("ss", |writer| {
+				writer.string(disk);
+				writer.string(state);
+			});
+			bus.send(&signal)?;
+		}
+	}
+	*previous = current;
+	Ok(())
+}
+
+/// Serve calls and emit signals until the connection to the bus fails.
+///
+/// Calls that fail get error replies, and disks or configs that fail are warned about, so nothing else ends the service.
+pub fn run() -> Result<()> {
+	let mut bus = dbus::Connection::system()?;
+	bus.request_name(NAME)?;
+	eprintln!("offering {NAME} on the system bus.");
+
+	// Polling the mount table reports a priority event whenever something is mounted or unmounted.
+	let mount_table = File::open("/proc/self/mountinfo").context("opening the mount table")?;
+	let mut states = config::load()
+		.map(|config| states(&config))
+		.unwrap_or_default();
+	loop {
+		while let Some(message) = bus.buffered_message() {
+			if message.kind == Some(dbus::Kind::MethodCall) {
+				handle_call(&mut bus, &message)?;
+			}
+		}
+
+		let mut fds = [
+			PollFd::new(bus.as_raw_fd(), PollFlags::POLLIN),
+			PollFd::new(mount_table.as_raw_fd(), PollFlags::POLLPRI),
+		];
+		poll(&mut fds, POLL_TIMEOUT_MS).context("waiting for events")?;
+		emit_changes(&mut bus, &mut states)?;
+		if fds[0].revents().is_some_and(|events| !events.is_empty()) {
+			bus.receive()?;
+		}
+	}
+}
@@ -0,0 +1,61 @@
+//! How to keep each kind of shell from saving history in `c` sessions on encrypted disks, where even the names of files shouldn't leak onto the unencrypted system.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use crate::config::Config;
+
+/// What to change when starting a shell so that it doesn't save history.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+	/// Arguments for the shell, such as `--private` for fish.
+	#[serde(default)]
+	pub private_args: Vec<String>,
+	/// Environment variables for the shell. An empty value sets the variable to be empty.
+	#[serde(default)]
+	pub private_env: BTreeMap<String, String>,
+}
+
+impl Profile {
+	pub fn apply(&self, command: &mut Command) {
+		command.args(&self.private_args).envs(&self.private_env);
+	}
+}
+
+/// The shells that are known without any configuration.
+fn builtin(shell_name: &str) -> Option<Profile> {
+	let env = |vars: &[(&str, &str)]| {
+		vars
+			.iter()
+			.map(|&(name, value)| (name.to_owned(), value.to_owned()))
+			.collect()
+	};
+	Some(match shell_name {
+		"fish" => Profile {
+			private_args: vec!["--private".to_owned()],
+			private_env: BTreeMap::new(),
+		},
+		"zsh" => Profile {
+			private_args: Vec::new(),
+			private_env: env(&[("HISTFILE", "/dev/null"), ("SAVEHIST", "0")]),
+		},
+		// bash doesn't save history when `HISTFILE` is empty, as it is when unset.
+		"bash" => Profile {
+			private_args: Vec::new(),
+			private_env: env(&[("HISTFILE", "")]),
+		},
+		_ => return None,
+	})
+}
+
+/// How to keep `shell` from saving history, from the `shell` table in the config or else the built-in profiles, by the file name of the shell's program.
+pub fn profile(config: &Config, shell: &Path) -> Option<Profile> {
+	let shell_name = shell.file_name()?.to_str()?;
+	config
+		.shells
+		.get(shell_name)
+		.cloned()
+		.or_else(|| builtin(shell_name))
+}
@@ -0,0 +1,388 @@
+//! Read-only btrfs snapshots of a disk, taken on mount or unmount and pruned by a retention policy.
+//!
+//! Snapshots are kept in `.snapshots` at the root of the disk, named after the UTC time they were taken.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Context as _, Result};
+
+use crate::btrfs::btrfs_output;
+use crate::config::Snapshots;
+use crate::mountinfo;
+
+const DIRECTORY: &str = ".snapshots";
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+	pub name: String,
+	/// When it was taken, in seconds since the Unix epoch.
+	pub taken_at: u64,
+}
+
+/// The date in the proleptic Gregorian calendar of a number of days since 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+	// Howard Hinnant's algorithm, restricted to dates after the epoch.
+	let z = days + 719_468;
+	let era = z / 146_097;
+	let day_of_era = z % 146_097;
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 {
+		month_index + 3
+	} else {
+		month_index - 9
+	};
+	let year = year_of_era + era * 400 + u64::from(month <= 2);
+	(year, month, day)
+}
+
+/// The inverse of `civil_from_days`.
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+	if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+		return None;
+	}
+	let year = year - u64::from(month <= 2);
+	let era = year / 400;
+	let year_of_era = year % 400;
+	let month_index = if month > 2 { month - 3 } else { month + 9 };
+	let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	(era * 146_097 + day_of_era).checked_sub(719_468)
+}
+
+/// Such as `2024-05-01T12:34:56Z`.
+fn format_timestamp(timestamp: u64) -> String {
+	let (year, month, day) = civil_from_days(timestamp / SECONDS_PER_DAY);
+	let seconds = timestamp % SECONDS_PER_DAY;
+	format!(
+		"{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+		seconds / 3600,
+		seconds / 60 % 60,
+		seconds % 60
+	)
+}
+
+fn parse_timestamp(name: &str) -> Option<u64> {
+	let (date, time) = name.strip_suffix('Z')?.split_once('T')?;
+	let mut date = date.splitn(3, '-').map(str::parse::<u64>);
+	let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+	let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+	let (hours, minutes, seconds) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+	if hours >= 24 || minutes >= 60 || seconds >= 60 {
+		return None;
+	}
+	Some(days_from_civil(year, month, day)? * SECONDS_PER_DAY + hours * 3600 + minutes * 60 + seconds)
+}
+
+fn directory(mount_path: &str) -> PathBuf {
+	Path::new(mount_path).join(DIRECTORY)
+}
+
+pub fn path(mount_path: &str, name: &str) -> PathBuf {
+	directory(mount_path).join(name)
+}
+
+/// The snapshots of the disk mounted at `mount_path`, oldest first. Other entries in the snapshot directory are ignored.
+pub fn list(mount_path: &str) -> Result<Vec<Snapshot>> {
+	let entries = match std::fs::read_dir(directory(mount_path)) {
+		Ok(entries) => entries,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(error) => return Err(error).context("reading snapshot directory"),
+	};
+	let mut snapshots = Vec::new();
+	for entry in entries {
+		let name = entry
+			.context("reading snapshot directory")?
+			.file_name()
+			.to_string_lossy()
+			.into_owned();
+		if let Some(taken_at) = parse_timestamp(&name) {
+			snapshots.push(Snapshot { name, taken_at });
+		}
+	}
+	snapshots.sort_by_key(|snapshot| snapshot.taken_at);
+	Ok(snapshots)
+}
+
+/// Take a read-only snapshot of the disk mounted at `mount_path`, returning its name.
+pub fn take(mount_path: &str) -> Result<String> {
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.expect("system time is before the epoch")
+		.as_secs();
+	let name = format_timestamp(now);
+	std::fs::create_dir_all(directory(mount_path)).context("creating snapshot directory")?;
+	let destination = path(mount_path, &name);
+	ensure!(
+		!destination.exists(),
+		"a snapshot named {name} already exists"
+	);
+	btrfs_output(&[
+		"subvolume",
+		"snapshot",
+		"-r",
+		mount_path,
+		&destination.to_string_lossy(),
+	])
+	.context("taking snapshot")?;
+	Ok(name)
+}
+
+/// The newest snapshot in each of the `count` most recent periods that have any, where `period` maps a time to its period.
+fn newest_per_period<'a>(
+	newest_first: &[&'a Snapshot],
+	count: Option<u32>,
+	period: impl Fn(u64) -> u64,
+) -> Vec<&'a str> {
+	let Some(count) = count else {
+		return Vec::new();
+	};
+	let mut seen = HashSet::new();
+	newest_first
+		.iter()
+		.filter(|snapshot| seen.insert(period(snapshot.taken_at)))
+		.take(count as usize)
+		.map(|snapshot| snapshot.name.as_str())
+		.collect()
+}
+
+/// The snapshots that the retention policy keeps, by name.
+fn to_keep<'a>(snapshots: &'a [Snapshot], retention: &Snapshots) -> HashSet<&'a str> {
+	let newest_first: Vec<&Snapshot> = snapshots.iter().rev().collect();
+	let mut keep: HashSet<&str> = newest_first
+		.iter()
+		.take(retention.keep_last.map_or(0, |count| count as usize))
+		.map(|snapshot| snapshot.name.as_str())
+		.collect();
+	keep.extend(newest_per_period(
+		&newest_first,
+		retention.keep_daily,
+		|time| time / SECONDS_PER_DAY,
+	));
+	// The epoch was a Thursday, so this makes weeks start on Monday.
+	keep.extend(newest_per_period(
+		&newest_first,
+		retention.keep_weekly,
+		|time| (time / SECONDS_PER_DAY + 3) / 7,
+	));
+	keep
+}
+
+/// Delete the snapshots that the retention policy doesn't keep, returning their names. With `dry_run`, nothing is deleted.
+pub fn prune(mount_path: &str, retention: &Snapshots, dry_run: bool) -> Result<Vec<String>> {
+	ensure!(
+		retention.has_retention(),
+		"no retention is configured (keep_last, keep_daily, or keep_weekly), so every snapshot is kept"
+	);
+	let snapshots = list(mount_path)?;
+	let keep = to_keep(&snapshots, retention);
+	let mut pruned = Vec::new();
+	for snapshot in &snapshots {
+		if keep.contains(snapshot.name.as_str()) {
+			continue;
+		}
+		if !dry_run {
+			btrfs_output(&[
+				"subvolume",
+				"delete",
+				&path(mount_path, &snapshot.name).to_string_lossy(),
+			])
+			.with_context(|| format!("deleting snapshot {}", snapshot.name))?;
+		}
+		pruned.push(snapshot.name.clone());
+	}
+	Ok(pruned)
+}
+
+/// Where a disk's snapshot is mounted, next to the disk's own mount path.
+pub fn mount_path(disk_name: &str) -> String {
+	format!("{}-snap", crate::mount_path_for_name(disk_name))
+}
+
+/// Mount a snapshot read-only at `target`, replacing a snapshot that is already mounted there.
+pub fn mount(mount_path: &str, name: &str, target: &str) -> Result<()> {
+	use nix::mount::{mount, MsFlags};
+
+	let source = path(mount_path, name);
+	ensure!(source.is_dir(), "there is no snapshot named {name}");
+	unmount(target)?;
+	std::fs::create_dir_all(target).context("creating snapshot mount path")?;
+	mount(
+		Some(&source),
+		target,
+		None::<&str>,
+		MsFlags::MS_BIND,
+		None::<&str>,
+	)
+	.context("bind-mounting snapshot")?;
+	// Bind mounts ignore MS_RDONLY initially, so it has to be added by remounting.
+	mount(
+		None::<&str>,
+		target,
+		None::<&str>,
+		MsFlags::MS_REMOUNT
+			| MsFlags::MS_BIND
+			| MsFlags::MS_RDONLY
+			| MsFlags::MS_NOSUID
+			| MsFlags::MS_NODEV,
+		None::<&str>,
+	)
+	.context("making snapshot mount read-only")
+}
+
+/// Unmount a snapshot mounted at `target`, returning whether one was.
+pub fn unmount(target: &str) -> Result<bool> {
+	if mountinfo::find_by_mount_point(Path::new(target))?.is_none() {
+		return Ok(false);
+	}
+	nix::mount::umount(target).context("unmounting snapshot")?;
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// 2024-05-06, a Monday.
+	const MONDAY: u64 = 19_849 * SECONDS_PER_DAY;
+	const HOUR: u64 = 3600;
+
+	fn snapshots(times: &[u64]) -> Vec<Snapshot> {
+		times
+			.iter()
+			.map(|&taken_at| Snapshot {
+				name: format_timestamp(taken_at),
+				taken_at,
+			})
+			.collect()
+	}
+
+	fn retention(
+		keep_last: Option<u32>,
+		keep_daily: Option<u32>,
+		keep_weekly: Option<u32>,
+	) -> Snapshots {
+		Snapshots {
+			on_mount: false,
+			on_unmount: false,
+			keep_last,
+			keep_daily,
+			keep_weekly,
+		}
+	}
+
+	fn kept(times: &[u64], retention: &Snapshots) -> Vec<u64> {
+		let snapshots = snapshots(times);
+		let keep = to_keep(&snapshots, retention);
+		let mut kept: Vec<u64> = snapshots
+			.iter()
+			.filter(|snapshot| keep.contains(snapshot.name.as_str()))
+			.map(|snapshot| snapshot.taken_at)
+			.collect();
+		kept.sort_unstable();
+		kept
+	}
+
+	#[test]
+	fn timestamps() {
+		assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+		assert_eq!(format_timestamp(MONDAY + HOUR + 61), "2024-05-06T01:01:01Z");
+		for time in [0, MONDAY, MONDAY + 86_399, 951_782_400, 4_107_542_399] {
+			assert_eq!(parse_timestamp(&format_timestamp(time)), Some(time));
+		}
+		for name in [
+			"",
+			"2024-05-06",
+			"2024-05-06T01:01:01",
+			"2024-13-01T00:00:00Z",
+			"2024-05-06T24:00:00Z",
+			"1969-12-31T23:59:59Z",
+			"notes.txt",
+		] {
+			assert_eq!(parse_timestamp(name), None, "{name}");
+		}
+	}
+
+	#[test]
+	fn nothing_to_keep() {
+		assert!(kept(&[], &retention(Some(3), Some(3), Some(3))).is_empty());
+		assert!(kept(&[MONDAY], &retention(None, None, None)).is_empty());
+		assert!(kept(&[MONDAY], &retention(Some(0), Some(0), Some(0))).is_empty());
+	}
+
+	#[test]
+	fn keep_last() {
+		let times = [MONDAY, MONDAY + HOUR, MONDAY + 2 * HOUR];
+		assert_eq!(
+			kept(&times, &retention(Some(2), None, None)),
+			[MONDAY + HOUR, MONDAY + 2 * HOUR]
+		);
+		assert_eq!(kept(&times, &retention(Some(3), None, None)), times);
+		assert_eq!(kept(&times, &retention(Some(10), None, None)), times);
+	}
+
+	#[test]
+	fn keep_daily() {
+		let day = SECONDS_PER_DAY;
+		// Two on the first day, none on the second, and one on each of the third and fourth.
+		let times = [
+			MONDAY + HOUR,
+			MONDAY + 2 * HOUR,
+			MONDAY + 2 * day,
+			MONDAY + 3 * day + HOUR,
+		];
+		// Days without snapshots don't count.
+		assert_eq!(
+			kept(&times, &retention(None, Some(3), None)),
+			[MONDAY + 2 * HOUR, MONDAY + 2 * day, MONDAY + 3 * day + HOUR]
+		);
+		assert_eq!(
+			kept(&times, &retention(None, Some(1), None)),
+			[MONDAY + 3 * day + HOUR]
+		);
+	}
+
+	#[test]
+	fn keep_daily_boundary() {
+		// The last second of a day and the first of the next are different days.
+		let times = [MONDAY - 1, MONDAY];
+		assert_eq!(kept(&times, &retention(None, Some(2), None)), times);
+	}
+
+	#[test]
+	fn keep_weekly() {
+		let day = SECONDS_PER_DAY;
+		// Sunday before, then Monday and Sunday of the same week.
+		let times = [MONDAY - day, MONDAY, MONDAY + 6 * day];
+		assert_eq!(
+			kept(&times, &retention(None, None, Some(2))),
+			[MONDAY - day, MONDAY + 6 * day]
+		);
+		assert_eq!(
+			kept(&times, &retention(None, None, Some(1))),
+			[MONDAY + 6 * day]
+		);
+	}
+
+	#[test]
+	fn policies_combine() {
+		let day = SECONDS_PER_DAY;
+		let times = [
+			MONDAY - 7 * day,
+			MONDAY,
+			MONDAY + HOUR,
+			MONDAY + day,
+			MONDAY + day + HOUR,
+		];
+		assert_eq!(
+			kept(&times, &retention(Some(1), Some(2), Some(2))),
+			[MONDAY - 7 * day, MONDAY + HOUR, MONDAY + day + HOUR]
+		);
+	}
+}
@@ -0,0 +1,82 @@
+//! Free space checks, so that disks don't get filled up without anyone noticing.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+
+use crate::output;
+
+/// How often to check free space during a `c` session.
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+/// How often to check whether the session is over.
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+	/// Bytes available to unprivileged users.
+	pub available: u64,
+	pub total: u64,
+}
+
+impl Usage {
+	pub fn available_percent(self) -> f64 {
+		if self.total == 0 {
+			return 100.0;
+		}
+		#[allow(clippy::cast_precision_loss)] // Only used for display and thresholds.
+		let ratio = self.available as f64 / self.total as f64;
+		ratio * 100.0
+	}
+}
+
+pub fn usage(mount_path: &str) -> Result<Usage> {
+	let stats = nix::sys::statvfs::statvfs(mount_path).context("getting filesystem statistics")?;
+	let fragment_size = stats.fragment_size();
+	Ok(Usage {
+		available: stats.blocks_available() * fragment_size,
+		total: stats.blocks() * fragment_size,
+	})
+}
+
+/// Warn if the filesystem has less than `min_free_percent` free. Returns whether it does.
+pub fn check(mount_path: &str, disk_name: &str, min_free_percent: f64) -> Result<bool> {
+	let usage = usage(mount_path)?;
+	let is_low = usage.available_percent() < min_free_percent;
+	if is_low {
+		output::warning(format_args!(
+			"{disk_name} is almost full! only {} ({:.1}%) free.",
+			output::paint_stderr(output::format_size(usage.available), output::Color::Red),
+			usage.available_percent(),
+		));
+	}
+	Ok(is_low)
+}
+
+/// Periodically check free space until `stop` is set, warning when it becomes low.
+pub fn watch(mount_path: &str, disk_name: &str, min_free_percent: f64, stop: &AtomicBool) {
+	let mut last_check = Instant::now();
+	let mut was_low = false;
+	while !stop.load(Ordering::Relaxed) {
+		std::thread::sleep(STOP_POLL_INTERVAL);
+		if last_check.elapsed() < WATCH_INTERVAL {
+			continue;
+		}
+		last_check = Instant::now();
+
+		// Only warn when crossing the threshold, to avoid spamming the terminal.
+		match usage(mount_path) {
+			Ok(usage) => {
+				let is_low = usage.available_percent() < min_free_percent;
+				if is_low && !was_low {
+					let _ = check(mount_path, disk_name, min_free_percent);
+				}
+				was_low = is_low;
+			}
+			Err(error) => {
+				output::warning(format_args!("failed to check free space: {error:#}"));
+				return;
+			}
+		}
+	}
+}
@@ -0,0 +1,329 @@
+//! Runtime state in `/run/d`, which is cleared on reboot along with the mounts that it describes.
+
+use std::collections::BTreeMap;
+use std::os::unix::io::AsRawFd as _;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, Result};
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::Pid;
+
+const STATE_DIR: &str = "/run/d";
+const STATE_PATH: &str = "/run/d/state.toml";
+/// Held while reading and writing the state, since several `d` processes can run at once.
+const LOCK_PATH: &str = "/run/d/lock";
+/// Each session also has a record here, named after its PID, saying where and when it started.
+const SESSIONS_DIR: &str = "/run/d/sessions";
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+	/// The PIDs of the `d c` processes with sessions in this disk.
+	#[serde(default)]
+	pub sessions: Vec<i32>,
+	/// Whether `d` mounted the disk itself, as opposed to finding it already mounted or open.
+	#[serde(default)]
+	pub managed: bool,
+	/// When `d` mounted the disk, in nanoseconds since the Unix epoch, to unmount disks in the reverse order.
+	#[serde(default)]
+	pub mounted_at: Option<u64>,
+	/// When a `d c` session left the disk mounted to be unmounted later by `d finish`, in seconds since the Unix epoch.
+	#[serde(default)]
+	pub deferred_at: Option<u64>,
+	/// Whether the disk's LUKS mapping was already open when `d` mounted it, so that unmounting leaves it open for whatever opened it, or `None` if the mapping isn't known to be open.
+	#[serde(default)]
+	pub external_mapping: Option<bool>,
+	/// Whether the disk's `session_setup` commands have run without `session_teardown` running since, so that each runs once however sessions come and go.
+	#[serde(default)]
+	pub session_set_up: bool,
+}
+
+impl Entry {
+	fn is_empty(&self) -> bool {
+		self.sessions.is_empty()
+			&& !self.managed
+			&& self.deferred_at.is_none()
+			&& self.external_mapping.is_none()
+			&& !self.session_set_up
+	}
+}
+
+/// Keyed by disk name.
+pub type State = BTreeMap<String, Entry>;
+
+fn is_alive(pid: i32) -> bool {
+	// Signal 0 only checks whether the process exists.
+	nix::sys::signal::kill(Pid::from_raw(pid), None).is_ok()
+}
+
+/// Forget sessions whose processes are gone, such as after `kill -9`.
+fn prune(state: &mut State) {
+	for entry in state.values_mut() {
+		entry.sessions.retain(|&pid| {
+			let alive = is_alive(pid);
+			if !alive {
+				let _ = std::fs::remove_file(record_path(pid));
+			}
+			alive
+		});
+	}
+	state.retain(|_, entry| !entry.is_empty());
+}
+
+fn load_unlocked() -> Result<State> {
+	let mut state = match std::fs::read_to_string(STATE_PATH) {
+		Ok(raw) => toml::from_str(&raw).context("parsing state file")?,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => State::new(),
+		Err(error) => return Err(error).context("reading state file"),
+	};
+	prune(&mut state);
+	Ok(state)
+}
+
+fn lock() -> Result<std::fs::File> {
+	std::fs::create_dir_all(STATE_DIR).context("creating state directory")?;
+	let file = std::fs::File::create(LOCK_PATH).context("opening state lock")?;
+	flock(file.as_raw_fd(), FlockArg::LockExclusive).context("locking state")?;
+	Ok(file)
+}
+
+/// Doesn't take the lock, since the state file is replaced atomically, so that this works without root.
+pub fn load() -> Result<State> {
+	load_unlocked()
+}
+
+/// Modify the state while holding the lock, so that concurrent updates aren't lost.
+pub fn update<T>(f: impl FnOnce(&mut State) -> T) -> Result<T> {
+	let _lock = lock()?;
+	let mut state = load_unlocked()?;
+	let ret = f(&mut state);
+	state.retain(|_, entry| !entry.is_empty());
+	let raw = toml::to_string(&state).context("serializing state")?;
+	let temp_path = format!("{STATE_PATH}.tmp");
+	std::fs::write(&temp_path, raw).context("writing state file")?;
+	std::fs::rename(temp_path, STATE_PATH).context("replacing state file")?;
+	Ok(ret)
+}
+
+pub fn own_pid() -> i32 {
+	std::process::id().try_into().expect("PIDs fit in i32")
+}
+
+/// Where a `d c` session is, so that the terminal that is keeping a disk mounted can be found.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SessionRecord {
+	pub disk: String,
+	pub pid: i32,
+	/// In seconds since the Unix epoch.
+	pub started: u64,
+	/// The terminal that the session is in, if it is in one.
+	pub tty: Option<String>,
+}
+
+fn record_path(pid: i32) -> PathBuf {
+	PathBuf::from(SESSIONS_DIR).join(format!("{pid}.toml"))
+}
+
+/// The terminal on the standard input of `pid`.
+fn terminal_of(pid: i32) -> Option<String> {
+	let stdin = std::fs::read_link(format!("/proc/{pid}/fd/0")).ok()?;
+	let stdin = stdin.to_str()?;
+	(stdin.starts_with("/dev/pts/") || stdin.starts_with("/dev/tty")).then(|| stdin.to_owned())
+}
+
+fn write_record(disk_name: &str, pid: i32) -> Result<()> {
+	let record = SessionRecord {
+		disk: disk_name.to_owned(),
+		pid,
+		started: crate::stats::now(),
+		tty: terminal_of(pid),
+	};
+	std::fs::create_dir_all(SESSIONS_DIR).context("creating sessions directory")?;
+	let raw = toml::to_string(&record).context("serializing session record")?;
+	std::fs::write(record_path(pid), raw).context("writing session record")
+}
+
+/// Register a `d c` session by the process `pid` in a disk.
+pub fn begin_session(disk_name: &str, pid: i32) -> Result<()> {
+	update(|state| {
+		state
+			.entry(disk_name.to_owned())
+			.or_default()
+			.sessions
+			.push(pid);
+	})?;
+	write_record(disk_name, pid)
+}
+
+/// The records of the sessions that are still running, oldest first.
+pub fn sessions() -> Result<Vec<SessionRecord>> {
+	let entries = match std::fs::read_dir(SESSIONS_DIR) {
+		Ok(entries) => entries,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(error) => return Err(error).context("listing session records"),
+	};
+	let mut records: Vec<SessionRecord> = entries
+		.flatten()
+		.filter_map(|entry| toml::from_str(&std::fs::read_to_string(entry.path()).ok()?).ok())
+		.filter(|record: &SessionRecord| is_alive(record.pid))
+		.collect();
+	records.sort_by_key(|record| record.started);
+	Ok(records)
+}
+
+/// End the session of the process `pid` in a disk, returning how many other sessions are still using it.
+pub fn end_session(disk_name: &str, pid: i32) -> Result<usize> {
+	let remaining = update(|state| {
+		let Some(entry) = state.get_mut(disk_name) else {
+			return 0;
+		};
+		entry.sessions.retain(|&session_pid| session_pid != pid);
+		entry.sessions.len()
+	})?;
+	match std::fs::remove_file(record_path(pid)) {
+		Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+			Err(error).context("removing session record")
+		}
+		_ => Ok(remaining),
+	}
+}
+
+/// The parent of `pid`, which is the fourth field of `/proc/<pid>/stat`.
+fn parent_of(pid: i32) -> Option<i32> {
+	let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+	// The second field is the command name in parentheses, which may itself contain spaces and parentheses.
+	let (_, rest) = stat.rsplit_once(')')?;
+	rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Whether this process was started from inside a `d c` session in a disk, found by its ancestors rather than its environment, which tools like sudo clear.
+pub fn is_inside_session(disk_name: &str) -> Result<bool> {
+	let state = load()?;
+	let Some(entry) = state.get(disk_name) else {
+		return Ok(false);
+	};
+	let mut pid = own_pid();
+	while let Some(parent) = parent_of(pid).filter(|&parent| parent > 1) {
+		if entry.sessions.contains(&parent) {
+			return Ok(true);
+		}
+		pid = parent;
+	}
+	Ok(false)
+}
+
+/// How many `d c` sessions are using a disk.
+pub fn session_count(disk_name: &str) -> Result<usize> {
+	Ok(
+		load()?
+			.get(disk_name)
+			.map_or(0, |entry| entry.sessions.len()),
+	)
+}
+
+/// Record whether `d` is responsible for a disk being mounted.
+pub fn set_managed(disk_name: &str, managed: bool) -> Result<()> {
+	let mounted_at = managed.then(|| {
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |since_epoch| {
+				u64::try_from(since_epoch.as_nanos()).unwrap_or(u64::MAX)
+			})
+	});
+	update(|state| {
+		let entry = state.entry(disk_name.to_owned()).or_default();
+		entry.managed = managed;
+		entry.mounted_at = mounted_at;
+		// Once the disk is unmounted, there is nothing left to finish, and the next session starts afresh.
+		if !managed {
+			entry.deferred_at = None;
+			entry.session_set_up = false;
+		}
+	})
+}
+
+/// Record whether a disk's LUKS mapping was already open when `d` mounted it.
+///
+/// A mapping that `d` opened for an earlier mount is found already open by the next one, so it stays recorded as opened by `d`.
+pub fn set_external_mapping(disk_name: &str, external: bool) -> Result<()> {
+	update(|state| {
+		let entry = &mut state
+			.entry(disk_name.to_owned())
+			.or_default()
+			.external_mapping;
+		if !external || entry.is_none() {
+			*entry = Some(external);
+		}
+	})
+}
+
+/// Forget who opened a disk's LUKS mapping, once it is closed.
+pub fn clear_external_mapping(disk_name: &str) -> Result<()> {
+	update(|state| {
+		if let Some(entry) = state.get_mut(disk_name) {
+			entry.external_mapping = None;
+		}
+	})
+}
+
+pub fn has_external_mapping(disk_name: &str) -> Result<bool> {
+	Ok(
+		load()?
+			.get(disk_name)
+			.is_some_and(|entry| entry.external_mapping == Some(true)),
+	)
+}
+
+/// Record whether a disk's `session_setup` commands are in effect, returning whether they were before.
+pub fn set_session_set_up(disk_name: &str, set_up: bool) -> Result<bool> {
+	update(|state| {
+		let entry = state.entry(disk_name.to_owned()).or_default();
+		std::mem::replace(&mut entry.session_set_up, set_up)
+	})
+}
+
+/// Record that a disk was left mounted after a `d c` session, to be unmounted later by `d finish`.
+pub fn defer_unmount(disk_name: &str) -> Result<()> {
+	update(|state| {
+		let entry = state.entry(disk_name.to_owned()).or_default();
+		entry.deferred_at.get_or_insert_with(crate::stats::now);
+	})
+}
+
+/// Forget a deferred unmount without unmounting, such as for a disk that was unmounted by something else.
+pub fn forget_deferred(disk_name: &str) -> Result<()> {
+	update(|state| {
+		if let Some(entry) = state.get_mut(disk_name) {
+			entry.deferred_at = None;
+		}
+	})
+}
+
+/// The disks whose unmounts were deferred, and when, oldest first.
+pub fn deferred_disks() -> Result<Vec<(String, u64)>> {
+	let mut deferred: Vec<(String, u64)> = load()?
+		.into_iter()
+		.filter_map(|(disk_name, entry)| Some((disk_name, entry.deferred_at?)))
+		.collect();
+	deferred.sort_by_key(|&(_, deferred_at)| deferred_at);
+	Ok(deferred)
+}
+
+/// The disks that `d` mounted, most recently mounted first.
+pub fn managed_disks() -> Result<Vec<String>> {
+	let mut managed: Vec<(String, Entry)> = load()?
+		.into_iter()
+		.filter(|(_, entry)| entry.managed)
+		.collect();
+	managed.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.mounted_at));
+	Ok(
+		managed
+			.into_iter()
+			.map(|(disk_name, _)| disk_name)
+			.collect(),
+	)
+}
+
+pub fn is_managed(disk_name: &str) -> Result<bool> {
+	Ok(load()?.get(disk_name).is_some_and(|entry| entry.managed))
+}
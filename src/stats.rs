@@ -0,0 +1,146 @@
+//! Persistent per-disk usage statistics.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, Result};
+
+const STATS_DIR: &str = "/var/lib/d";
+const STATS_PATH: &str = "/var/lib/d/stats.toml";
+
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+pub struct DiskStats {
+	pub mount_count: u64,
+	/// Seconds since the Unix epoch.
+	pub last_used: Option<u64>,
+	#[serde(default)]
+	pub mounts_since_check: u64,
+	/// When `d` last ran a filesystem check, in seconds since the Unix epoch.
+	#[serde(default)]
+	pub last_checked: Option<u64>,
+	/// When `d scrub` last finished scrubbing the disk, in seconds since the Unix epoch.
+	#[serde(default)]
+	pub last_scrubbed: Option<u64>,
+	/// How many wrong passphrases have been given when unlocking the disk.
+	#[serde(default)]
+	pub unlock_failures: u64,
+}
+
+/// Keyed by disk name.
+pub type Stats = BTreeMap<String, DiskStats>;
+
+/// Held across each load-modify-save so that disks mounted in parallel don't lose each other's updates.
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+
+fn update(f: impl FnOnce(&mut Stats) -> bool) -> Result<()> {
+	let _guard = UPDATE_LOCK
+		.lock()
+		.unwrap_or_else(std::sync::PoisonError::into_inner);
+	let mut stats = load()?;
+	if f(&mut stats) {
+		save(&stats)?;
+	}
+	Ok(())
+}
+
+pub fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+pub fn load() -> Result<Stats> {
+	match std::fs::read_to_string(STATS_PATH) {
+		Ok(raw) => toml::from_str(&raw).context("parsing stats file"),
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Stats::new()),
+		Err(error) => Err(error).context("reading stats file"),
+	}
+}
+
+fn save(stats: &Stats) -> Result<()> {
+	std::fs::create_dir_all(STATS_DIR).context("creating stats directory")?;
+	let raw = toml::to_string(stats).context("serializing stats")?;
+	let temp_path = format!("{STATS_PATH}.tmp");
+	std::fs::write(&temp_path, raw).context("writing stats file")?;
+	std::fs::rename(temp_path, STATS_PATH).context("replacing stats file")
+}
+
+/// Record that a disk was used, counting it as a new mount if it was not already mounted.
+pub fn record_use(disk_name: &str, was_already_mounted: bool) -> Result<()> {
+	update(|stats| {
+		let entry = stats.entry(disk_name.to_owned()).or_default();
+		if !was_already_mounted {
+			entry.mount_count += 1;
+			entry.mounts_since_check += 1;
+		}
+		entry.last_used = Some(now());
+		true
+	})
+}
+
+/// Record that a disk's filesystem was just checked.
+pub fn record_check(disk_name: &str) -> Result<()> {
+	update(|stats| {
+		let entry = stats.entry(disk_name.to_owned()).or_default();
+		entry.mounts_since_check = 0;
+		entry.last_checked = Some(now());
+		true
+	})
+}
+
+/// Record that a disk was just scrubbed.
+pub fn record_scrub(disk_name: &str) -> Result<()> {
+	update(|stats| {
+		stats.entry(disk_name.to_owned()).or_default().last_scrubbed = Some(now());
+		true
+	})
+}
+
+/// Record that a wrong passphrase was given for a disk.
+pub fn record_unlock_failure(disk_name: &str) -> Result<()> {
+	update(|stats| {
+		stats
+			.entry(disk_name.to_owned())
+			.or_default()
+			.unlock_failures += 1;
+		true
+	})
+}
+
+/// Move a disk's statistics to a new name, after it has been renamed.
+pub fn rename(old_name: &str, new_name: &str) -> Result<()> {
+	update(|stats| {
+		let Some(entry) = stats.remove(old_name) else {
+			return false;
+		};
+		stats.insert(new_name.to_owned(), entry);
+		true
+	})
+}
+
+/// Forget a disk's statistics, after it has been removed.
+pub fn remove(disk_name: &str) -> Result<()> {
+	update(|stats| stats.remove(disk_name).is_some())
+}
+
+/// Format a timestamp as a coarse relative time such as "3 days ago".
+pub fn format_ago(timestamp: u64) -> String {
+	const UNITS: &[(u64, &str)] = &[
+		(60 * 60 * 24 * 365, "year"),
+		(60 * 60 * 24 * 30, "month"),
+		(60 * 60 * 24, "day"),
+		(60 * 60, "hour"),
+		(60, "minute"),
+	];
+
+	let elapsed = now().saturating_sub(timestamp);
+	for &(unit_secs, unit_name) in UNITS {
+		let amount = elapsed / unit_secs;
+		if amount > 0 {
+			let plural = if amount == 1 { "" } else { "s" };
+			return format!("{amount} {unit_name}{plural} ago");
+		}
+	}
+	"just now".to_owned()
+}
@@ -0,0 +1,82 @@
+//! One-line summaries of disk state for status bars, printed by `d list --format`.
+//!
+//! See waybar-custom(5) and the JSON format section of i3blocks(1).
+
+use std::str::FromStr;
+
+use crate::output::json_string;
+use crate::DiskState;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+	/// A Waybar custom module with `return-type` set to `json`.
+	Waybar,
+	/// An i3blocks block with `format` set to `json`.
+	I3blocks,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown format {0:?}. valid formats are waybar, i3blocks.")]
+pub struct UnknownFormat(String);
+
+impl FromStr for Format {
+	type Err = UnknownFormat;
+
+	fn from_str(s: &str) -> Result<Self, UnknownFormat> {
+		Ok(match s {
+			"waybar" => Self::Waybar,
+			"i3blocks" => Self::I3blocks,
+			_ => return Err(UnknownFormat(s.to_owned())),
+		})
+	}
+}
+
+/// Print the state of `disks`, given as pairs of names and states.
+///
+/// The text lists the disks that are in use, so that the module is hidden when there are none, and the tooltip lists every disk.
+pub fn print(format: Format, disks: &[(&str, DiskState)]) {
+	let in_use: Vec<&str> = disks
+		.iter()
+		.filter(|(_, state)| matches!(state, DiskState::Open | DiskState::Mounted))
+		.map(|&(name, _)| name)
+		.collect();
+	let text = in_use.join(" ");
+	let any_in = |wanted: DiskState| disks.iter().any(|&(_, state)| state == wanted);
+	let class = if any_in(DiskState::Mounted) {
+		"mounted"
+	} else if any_in(DiskState::Open) {
+		"open"
+	} else {
+		"idle"
+	};
+
+	let line = match format {
+		Format::Waybar => {
+			let tooltip = disks
+				.iter()
+				.map(|(name, state)| format!("{name}: {}", state.as_repr()))
+				.collect::<Vec<_>>()
+				.join("\n");
+			format!(
+				"{{\"text\":{},\"tooltip\":{},\"class\":{}}}",
+				json_string(&text),
+				json_string(&tooltip),
+				json_string(class),
+			)
+		}
+		Format::I3blocks => {
+			// i3blocks has no classes, so the state is shown by color instead, matching `d list`.
+			let color = match class {
+				"mounted" => ",\"color\":\"#00cc00\"",
+				"open" => ",\"color\":\"#cccc00\"",
+				_ => "",
+			};
+			format!(
+				"{{\"full_text\":{},\"short_text\":{}{color}}}",
+				json_string(&text),
+				json_string(&in_use.len().to_string()),
+			)
+		}
+	};
+	println!("{line}");
+}
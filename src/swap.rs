@@ -0,0 +1,158 @@
+//! Swap devices, optionally encrypted with a random key each time they are activated.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::config::Swap;
+use crate::{blkid, caps, format, output, prompt, sysfs};
+
+/// A line of `/proc/swaps`.
+#[derive(Debug, Clone)]
+pub struct Entry {
+	pub device: PathBuf,
+	pub size_bytes: u64,
+	pub used_bytes: u64,
+}
+
+fn run(command: &mut Command) -> Result<()> {
+	let program = command.get_program().to_string_lossy().into_owned();
+	let status = command
+		.status()
+		.with_context(|| format!("running {program}"))?;
+	ensure!(
+		status.success(),
+		"{program} exited with status {:?}",
+		status.code()
+	);
+	Ok(())
+}
+
+fn mapping_name(swap: &Swap) -> String {
+	format!("swap-{}", swap.name)
+}
+
+fn mapping_path(swap: &Swap) -> PathBuf {
+	Path::new("/dev/mapper").join(mapping_name(swap))
+}
+
+/// The device that swapon is run on: the mapping for encrypted swap, or the device itself.
+fn swap_device(swap: &Swap) -> PathBuf {
+	if swap.encrypt {
+		mapping_path(swap)
+	} else {
+		PathBuf::from(&swap.device)
+	}
+}
+
+/// The active swap areas, from `/proc/swaps`.
+pub fn active() -> Result<Vec<Entry>> {
+	let raw = std::fs::read_to_string("/proc/swaps").context("reading /proc/swaps")?;
+	let mut entries = Vec::new();
+	// The first line is a header. Sizes are in KiB.
+	for line in raw.lines().skip(1) {
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		let [device, _kind, size, used, ..] = fields.as_slice() else {
+			continue;
+		};
+		let kib = |field: &str| field.parse::<u64>().map(|kib| kib * 1024);
+		entries.push(Entry {
+			// Spaces and other special characters are escaped as octal, like in mountinfo.
+			device: PathBuf::from(device.replace("\\040", " ")),
+			size_bytes: kib(size).context("parsing size in /proc/swaps")?,
+			used_bytes: kib(used).context("parsing usage in /proc/swaps")?,
+		});
+	}
+	Ok(entries)
+}
+
+/// The active swap area for this swap device, if it is active.
+pub fn status(swap: &Swap) -> Result<Option<Entry>> {
+	let Ok(device) = std::fs::canonicalize(swap_device(swap)) else {
+		return Ok(None);
+	};
+	Ok(
+		active()?.into_iter().find(|entry| {
+			std::fs::canonicalize(&entry.device).is_ok_and(|candidate| candidate == device)
+		}),
+	)
+}
+
+/// Formatting with a random key destroys whatever is on the device, so make sure it isn't anything else.
+fn check_overwritable(swap: &Swap) -> Result<()> {
+	let device = std::fs::canonicalize(&swap.device)
+		.with_context(|| format!("resolving swap device {}", swap.device))?;
+	let kernel_name = sysfs::kernel_name(&device)
+		.with_context(|| format!("no kernel name for {}", device.display()))?;
+	format::ensure_not_in_use(&kernel_name)?;
+
+	let probe = blkid::probe(&device).context("probing swap device")?;
+	if let Some(table) = probe.partition_table {
+		bail!(
+			"{} has a {table} partition table. refusing to overwrite it with encrypted swap",
+			swap.device
+		);
+	}
+	match probe.content_type.as_deref() {
+		Some("swap") => Ok(()),
+		Some(content) => bail!(
+			"{} contains {content}, not swap or random data. refusing to overwrite it with encrypted swap",
+			swap.device
+		),
+		// Random data looks the same as a device that blkid can't recognize, so ask.
+		None => {
+			output::warning(format_args!(
+				"{} contains nothing recognizable, which is expected for encrypted swap. it will be overwritten.",
+				swap.device
+			));
+			prompt::confirm_typed(&swap.device)
+		}
+	}
+}
+
+/// Activate the swap device, returning whether it was already active.
+pub fn on(swap: &Swap) -> Result<bool> {
+	if status(swap)?.is_some() {
+		return Ok(true);
+	}
+
+	if swap.encrypt && !mapping_path(swap).exists() {
+		check_overwritable(swap)?;
+		run(
+			caps::tool("cryptsetup")
+				.args(["open", "--type", "plain", "--key-file", "/dev/urandom"])
+				.args(["--cipher", "aes-xts-plain64", "--key-size", "512"])
+				.arg(&swap.device)
+				.arg(mapping_name(swap)),
+		)
+		.context("opening encrypted swap with a random key")?;
+		run(caps::tool("mkswap").arg(mapping_path(swap))).context("formatting encrypted swap")?;
+	}
+
+	let mut command = caps::tool("swapon");
+	if let Some(priority) = swap.priority {
+		command.arg("--priority").arg(priority.to_string());
+	}
+	run(command.arg(swap_device(swap))).context("activating swap")?;
+	Ok(false)
+}
+
+/// Deactivate the swap device, returning whether it was active.
+///
+/// The random key of encrypted swap is forgotten when its mapping is closed, making what was swapped out unreadable.
+pub fn off(swap: &Swap) -> Result<bool> {
+	let was_active = status(swap)?.is_some();
+	if was_active {
+		run(caps::tool("swapoff").arg(swap_device(swap))).context("deactivating swap")?;
+	}
+	if swap.encrypt && mapping_path(swap).exists() {
+		run(
+			caps::tool("cryptsetup")
+				.arg("close")
+				.arg(mapping_name(swap)),
+		)
+		.context("closing encrypted swap")?;
+	}
+	Ok(was_active)
+}
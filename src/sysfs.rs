@@ -0,0 +1,163 @@
+//! Block device relationships from `/sys/class/block`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use nix::sys::stat::{dev_t, makedev};
+
+fn block_dir(kernel_name: &str) -> PathBuf {
+	Path::new("/sys/class/block").join(kernel_name)
+}
+
+/// The kernel's name for a device node, e.g. `sda1` for `/dev/sda1` or `dm-0` for `/dev/dm-0`.
+pub fn kernel_name(dev_path: &Path) -> Option<String> {
+	dev_path
+		.file_name()
+		.map(|name| name.to_string_lossy().into_owned())
+}
+
+fn list_dir(kernel_name: &str, subdir: &str) -> Result<Vec<String>> {
+	let dir = block_dir(kernel_name).join(subdir);
+	let mut names = std::fs::read_dir(&dir)
+		.with_context(|| format!("listing {}", dir.display()))?
+		.map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+		.collect::<Result<Vec<_>, _>>()
+		.with_context(|| format!("listing {}", dir.display()))?;
+	names.sort();
+	Ok(names)
+}
+
+/// Devices stacked on top of this one, such as dm-crypt mappings.
+pub fn holders(kernel_name: &str) -> Result<Vec<String>> {
+	list_dir(kernel_name, "holders")
+}
+
+/// Devices this one is stacked on top of.
+pub fn slaves(kernel_name: &str) -> Result<Vec<String>> {
+	list_dir(kernel_name, "slaves")
+}
+
+fn read_attribute(kernel_name: &str, attribute: &str) -> Result<String> {
+	let path = block_dir(kernel_name).join(attribute);
+	let raw =
+		std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+	Ok(raw.trim().to_owned())
+}
+
+pub fn size_bytes(kernel_name: &str) -> Result<u64> {
+	// Always in 512-byte sectors, regardless of the device's actual sector size.
+	let sectors: u64 = read_attribute(kernel_name, "size")?
+		.parse()
+		.context("parsing device size")?;
+	Ok(sectors * 512)
+}
+
+/// Whether a whole disk has spinning platters. Partitions don't have this attribute.
+pub fn is_rotational(kernel_name: &str) -> Result<bool> {
+	Ok(read_attribute(kernel_name, "queue/rotational")? == "1")
+}
+
+pub fn device_number(kernel_name: &str) -> Result<dev_t> {
+	let raw = read_attribute(kernel_name, "dev")?;
+	let parsed = raw
+		.split_once(':')
+		.and_then(|(major, minor)| Some(makedev(major.parse().ok()?, minor.parse().ok()?)));
+	parsed.with_context(|| format!("malformed device number {raw:?}"))
+}
+
+/// The whole disk that a partition belongs to, or `None` if the device is not a partition.
+pub fn parent_disk(kernel_name: &str) -> Result<Option<String>> {
+	let dir = block_dir(kernel_name);
+	if !dir.join("partition").exists() {
+		return Ok(None);
+	}
+	// The canonical path of a partition is nested inside that of its disk.
+	let canonical = std::fs::canonicalize(&dir).context("resolving sysfs path of partition")?;
+	Ok(canonical.parent().and_then(self::kernel_name))
+}
+
+/// The device-mapper name of a `dm-*` device, such as the name given to `cryptsetup open`.
+pub fn dm_name(kernel_name: &str) -> Option<String> {
+	read_attribute(kernel_name, "dm/name").ok()
+}
+
+/// The serial number of a whole disk, as reported by NVMe drives directly or by SCSI and SATA drives in VPD page 0x80.
+pub fn serial(kernel_name: &str) -> Option<String> {
+	if let Ok(serial) = read_attribute(kernel_name, "device/serial") {
+		return Some(serial);
+	}
+	// The page has a 4-byte header before the serial number, which is padded with spaces.
+	let page = std::fs::read(block_dir(kernel_name).join("device/vpd_pg80")).ok()?;
+	let serial = String::from_utf8_lossy(page.get(4..)?).trim().to_owned();
+	(!serial.is_empty()).then_some(serial)
+}
+
+/// The World Wide Name of a whole disk, such as `naa.5000c500a1b2c3d4` or `eui.0025388b91b2c3d4`.
+pub fn wwn(kernel_name: &str) -> Option<String> {
+	read_attribute(kernel_name, "wwid")
+		.or_else(|_| read_attribute(kernel_name, "device/wwid"))
+		.ok()
+}
+
+/// The kernel name of partition `number` of a whole disk.
+pub fn partition(disk_kernel_name: &str, number: u32) -> Result<Option<String>> {
+	let number = number.to_string();
+	for name in list_dir(disk_kernel_name, "")? {
+		if read_attribute(&name, "partition").is_ok_and(|partition| partition == number) {
+			return Ok(Some(name));
+		}
+	}
+	Ok(None)
+}
+
+/// For a degraded md array, how many devices it is missing and the kernel names of those that are present.
+///
+/// `None` for arrays with all of their devices, and for devices that are not md arrays at all.
+pub fn md_degraded(kernel_name: &str) -> Result<Option<(u32, Vec<String>)>> {
+	let Ok(degraded) = read_attribute(kernel_name, "md/degraded") else {
+		return Ok(None);
+	};
+	let missing: u32 = degraded.parse().context("parsing md degraded count")?;
+	if missing == 0 {
+		return Ok(None);
+	}
+	let present = list_dir(kernel_name, "md")?
+		.into_iter()
+		.filter_map(|entry| entry.strip_prefix("dev-").map(str::to_owned))
+		.collect();
+	Ok(Some((missing, present)))
+}
+
+/// The sysfs directory of the USB device that a whole disk is attached through, if any.
+pub fn usb_device(kernel_name: &str) -> Result<Option<PathBuf>> {
+	let canonical =
+		std::fs::canonicalize(block_dir(kernel_name)).context("resolving sysfs path of disk")?;
+	// Interfaces and the disk's own SCSI devices are below the USB device, and only devices can be removed from their port.
+	let is_usb_device = |dir: &Path| {
+		dir.join("remove").exists()
+			&& std::fs::read_link(dir.join("subsystem")).is_ok_and(|subsystem| subsystem.ends_with("usb"))
+	};
+	Ok(
+		canonical
+			.ancestors()
+			.find(|dir| is_usb_device(dir))
+			.map(Path::to_owned),
+	)
+}
+
+/// Ask udev to probe the device again, such as to update its `/dev/disk` symlinks after changing its label.
+pub fn trigger_change(kernel_name: &str) -> Result<()> {
+	let path = block_dir(kernel_name).join("uevent");
+	std::fs::write(&path, "change").with_context(|| format!("writing to {}", path.display()))
+}
+
+/// The kernel names of all block devices, sorted.
+pub fn all_devices() -> Result<Vec<String>> {
+	let mut names = std::fs::read_dir("/sys/class/block")
+		.context("listing block devices")?
+		.map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+		.collect::<Result<Vec<_>, _>>()
+		.context("listing block devices")?;
+	names.sort();
+	Ok(names)
+}
@@ -0,0 +1,100 @@
+//! Starting and stopping the systemd units that serve files from a disk, such as a Samba share or a media server, through the manager's D-Bus API.
+//!
+//! See org.freedesktop.systemd1(5).
+
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context as _, Result};
+
+use crate::dbus::{self, Message, Value};
+
+const DESTINATION: &str = "org.freedesktop.systemd1";
+const PATH: &str = "/org/freedesktop/systemd1";
+const MANAGER: &str = "org.freedesktop.systemd1.Manager";
+
+/// How long to wait for a unit to start or stop, which is longer than systemd's own default timeout of 90 seconds, so that it usually reports the failure first. Units with longer or no timeouts are reported as failed after this.
+const JOB_TIMEOUT: Duration = Duration::from_mins(2);
+
+/// Wait for systemd to finish `job`, returning its result, which is `done` if it succeeded, or `None` if it took too long.
+fn wait_for_job(bus: &mut dbus::Connection, job: &str) -> Result<Option<String>> {
+	let deadline = Instant::now() + JOB_TIMEOUT;
+	loop {
+		let Some(message) = bus.next_message_before(deadline)? else {
+			return Ok(None);
+		};
+		if message.kind != Some(dbus::Kind::Signal) || message.member.as_deref() != Some("JobRemoved") {
+			continue;
+		}
+		// The arguments are the job's ID and path, the unit, and the result.
+		if let [_, Value::String(path), _, Value::String(result)] = message.args()?.as_slice() {
+			if path == job {
+				return Ok(Some(result.clone()));
+			}
+		}
+	}
+}
+
+fn run_job(bus: &mut dbus::Connection, method: &str, unit: &str) -> Result<()> {
+	let mut call = Message::method_call(DESTINATION, PATH, MANAGER, method);
+	call.set_body("ss", |writer| {
+		writer.string(unit);
+		// Like `systemctl`, replacing conflicting jobs that are queued.
+		writer.string("replace");
+	});
+	let job = bus
+		.call(&call)?
+		.string_args()?
+		.into_iter()
+		.next()
+		.context("systemd returned no job")?;
+	let result = wait_for_job(bus, &job)?.with_context(|| {
+		format!(
+			"still not finished after {}s; see `systemctl status {unit}`",
+			JOB_TIMEOUT.as_secs()
+		)
+	})?;
+	ensure!(
+		result == "done",
+		"job {result}; see `systemctl status {unit}`"
+	);
+	Ok(())
+}
+
+/// Run a job for each of `units` in order, each after the previous one finishes, so that units can depend on each other. Units whose jobs fail don't stop the rest.
+fn run_jobs(method: &str, units: &[String]) -> Result<()> {
+	let mut bus = dbus::Connection::system()?;
+	// Subscribed before starting any jobs, so that no results are missed.
+	bus.add_match(&format!(
+		"type='signal',sender='{DESTINATION}',interface='{MANAGER}',member='JobRemoved'"
+	))?;
+	bus
+		.call(&Message::method_call(
+			DESTINATION,
+			PATH,
+			MANAGER,
+			"Subscribe",
+		))
+		.context("subscribing to systemd")?;
+
+	let failures: Vec<String> = units
+		.iter()
+		.filter_map(|unit| {
+			run_job(&mut bus, method, unit)
+				.err()
+				.map(|error| format!("{unit}: {error:#}"))
+		})
+		.collect();
+	ensure!(failures.is_empty(), "{}", failures.join(", "));
+	Ok(())
+}
+
+/// Start `units` once their disk is mounted.
+pub fn start(units: &[String]) -> Result<()> {
+	run_jobs("StartUnit", units)
+}
+
+/// Stop `units` before their disk is unmounted, in reverse order.
+pub fn stop(units: &[String]) -> Result<()> {
+	let reversed: Vec<String> = units.iter().rev().cloned().collect();
+	run_jobs("StopUnit", &reversed)
+}
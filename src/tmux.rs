@@ -0,0 +1,92 @@
+//! Running `d c` sessions in tmux, so that work on a disk survives closing the terminal.
+
+use std::time::Duration;
+
+use anyhow::{ensure, Context as _, Result};
+use nix::fcntl::OFlag;
+use nix::unistd::ForkResult;
+
+use crate::privilege::Account;
+
+/// How often to check whether a detached session has ended.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+fn session_name(disk_name: &str) -> String {
+	format!("d-{disk_name}")
+}
+
+fn session_exists(disk_name: &str, account: &Account) -> Result<bool> {
+	let status = account
+		.command("tmux")
+		.args(["has-session", "-t"])
+		// `=` requires an exact match rather than a prefix.
+		.arg(format!("={}", session_name(disk_name)))
+		.stderr(std::process::Stdio::null())
+		.status()
+		.context("running tmux")?;
+	Ok(status.success())
+}
+
+/// Attach to the disk's tmux session, creating it in `mount_path` if it doesn't exist.
+///
+/// Returns whether the session still exists afterward, which means that the client detached or its terminal was closed.
+pub fn attach(
+	disk_name: &str,
+	mount_path: &str,
+	account: &Account,
+	env: &[(&str, String)],
+) -> Result<bool> {
+	let mut command = account.command("tmux");
+	command.args([
+		"new-session",
+		"-A",
+		"-s",
+		&session_name(disk_name),
+		"-c",
+		mount_path,
+	]);
+	for (name, value) in env {
+		command.arg("-e").arg(format!("{name}={value}"));
+	}
+	command.status().context("running tmux")?;
+	session_exists(disk_name, account)
+}
+
+/// Wait until the disk's tmux session has ended.
+pub fn wait_for_end(disk_name: &str, account: &Account) -> Result<()> {
+	while session_exists(disk_name, account)? {
+		std::thread::sleep(POLL_INTERVAL);
+	}
+	Ok(())
+}
+
+/// Continue in a background process, detached from the terminal, so that the shell gets the terminal back.
+///
+/// `take_over` runs in the background process before the foreground one carries on, so that it can take over anything the foreground process holds. Returns whether this is the background process.
+pub fn continue_in_background(take_over: impl FnOnce() -> Result<()>) -> Result<bool> {
+	let (read_end, write_end) = nix::unistd::pipe().context("creating pipe")?;
+	// SAFETY: there are no other threads at this point.
+	match unsafe { nix::unistd::fork() }.context("forking")? {
+		ForkResult::Parent { .. } => {
+			let _ = nix::unistd::close(write_end);
+			let mut buf = [0];
+			let num_read = nix::unistd::read(read_end, &mut buf);
+			let _ = nix::unistd::close(read_end);
+			ensure!(num_read == Ok(1), "the background process failed to start");
+			Ok(false)
+		}
+		ForkResult::Child => {
+			let _ = nix::unistd::close(read_end);
+			nix::unistd::setsid().context("starting new session")?;
+			let null = nix::fcntl::open("/dev/null", OFlag::O_RDWR, nix::sys::stat::Mode::empty())
+				.context("opening /dev/null")?;
+			for fd in 0..=2 {
+				nix::unistd::dup2(null, fd).context("redirecting standard streams")?;
+			}
+			take_over()?;
+			nix::unistd::write(write_end, &[1]).context("notifying the foreground process")?;
+			let _ = nix::unistd::close(write_end);
+			Ok(true)
+		}
+	}
+}
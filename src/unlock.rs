@@ -0,0 +1,119 @@
+//! Deciding how to get the key for an encrypted disk.
+
+use std::path::PathBuf;
+
+use anyhow::{ensure, Context as _, Result};
+
+use crate::config::{Config, Disk};
+
+pub enum Key {
+	/// Prompt for a passphrase on the terminal.
+	Prompt,
+	/// If the passphrase is wrong, we fall back to prompting.
+	Passphrase {
+		passphrase: String,
+		/// Where the passphrase came from, for messages.
+		source: &'static str,
+	},
+	File(PathBuf),
+}
+
+pub fn key_for(config: &Config, disk: &Disk) -> Result<Key> {
+	if let Some(passphrase) = &disk.passphrase {
+		let passphrase = passphrase
+			.reveal(&config.secrets)
+			.context("getting configured passphrase")?;
+		return Ok(Key::Passphrase {
+			passphrase,
+			source: "the config",
+		});
+	}
+
+	if let Some(keyfile) = &disk.keyfile {
+		let keyfile = keyfile
+			.reveal(&config.secrets)
+			.context("getting configured keyfile path")?;
+		return Ok(Key::File(keyfile.into()));
+	}
+
+	if let Some(command) = &disk.passphrase_command {
+		let passphrase = run_passphrase_command(command)
+			.with_context(|| format!("running passphrase command {command:?}"))?;
+		return Ok(Key::Passphrase {
+			passphrase,
+			source: "the passphrase command",
+		});
+	}
+
+	if disk.keyring {
+		match keyring_lookup(disk.as_repr()) {
+			Ok(Some(passphrase)) => {
+				return Ok(Key::Passphrase {
+					passphrase,
+					source: "the keyring",
+				})
+			}
+			Ok(None) => eprintln!(
+				"no passphrase for {} in the keyring. store one with `secret-tool store --label='d: {}' {KEYRING_SERVICE_ATTRIBUTE} {KEYRING_SERVICE} {KEYRING_DISK_ATTRIBUTE} {}`.",
+				disk.as_repr(),
+				disk.as_repr(),
+				disk.as_repr(),
+			),
+			Err(error) => crate::output::warning(format_args!(
+				"failed to look up passphrase in the keyring: {error:#}"
+			)),
+		}
+	}
+
+	Ok(Key::Prompt)
+}
+
+const KEYRING_SERVICE_ATTRIBUTE: &str = "service";
+const KEYRING_SERVICE: &str = "d";
+const KEYRING_DISK_ATTRIBUTE: &str = "disk";
+
+/// Look up a disk's passphrase in the freedesktop Secret Service (such as GNOME Keyring) using `secret-tool`.
+///
+/// This runs as the real user, since the keyring belongs to their session.
+fn keyring_lookup(disk_name: &str) -> Result<Option<String>> {
+	use crate::privilege::CommandExt as _;
+
+	let output = std::process::Command::new("secret-tool")
+		.arg("lookup")
+		.args([KEYRING_SERVICE_ATTRIBUTE, KEYRING_SERVICE])
+		.args([KEYRING_DISK_ATTRIBUTE, disk_name])
+		.as_invoking_user()
+		.stderr(std::process::Stdio::null())
+		.output()
+		.context("running secret-tool")?;
+
+	// secret-tool exits unsuccessfully if there is no matching secret.
+	if !output.status.success() || output.stdout.is_empty() {
+		return Ok(None);
+	}
+	let passphrase = String::from_utf8(output.stdout).context("passphrase is not UTF-8")?;
+	Ok(Some(passphrase))
+}
+
+/// Run a shell command as the real user and take the first line of its output as the passphrase, as is conventional for tools like `pass`.
+fn run_passphrase_command(command: &str) -> Result<String> {
+	use crate::privilege::CommandExt as _;
+
+	let output = std::process::Command::new("sh")
+		.arg("-c")
+		.arg(command)
+		.as_invoking_user()
+		.stdin(std::process::Stdio::inherit())
+		.stderr(std::process::Stdio::inherit())
+		.output()?;
+	ensure!(
+		output.status.success(),
+		"exited with status {:?}",
+		output.status.code()
+	);
+
+	let stdout = String::from_utf8(output.stdout).context("output is not UTF-8")?;
+	let passphrase = stdout.lines().next().unwrap_or_default();
+	ensure!(!passphrase.is_empty(), "output no passphrase");
+	Ok(passphrase.to_owned())
+}
@@ -0,0 +1,283 @@
+//! Unmounting busy filesystems, escalating through more forceful steps as far as the policy allows.
+
+use std::fmt::{self, Display};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context as _, Result};
+use nix::errno::Errno;
+use nix::mount::{umount2, MntFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+use crate::{iostat, kmsg, output, progress, prompt, watchdog};
+
+/// How long to wait after syncing before trying again.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How long to give terminated processes to exit.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+const TERMINATE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Below this, flushing is quick enough not to mention before unmounting.
+const SHOW_UNWRITTEN_BYTES: u64 = 64 << 20;
+
+/// Which steps to take when a filesystem is busy. Each step is only taken if the previous ones didn't work.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)] // Independent steps.
+pub struct Policy {
+	/// Sync and try again after a moment, for filesystems that are only busy briefly.
+	#[serde(default)]
+	pub sync_and_retry: bool,
+	/// List the processes that are using the filesystem.
+	#[serde(default)]
+	pub report_blockers: bool,
+	/// Send SIGTERM to the processes that are using the filesystem, and try again once they exit.
+	#[serde(default)]
+	pub terminate_blockers: bool,
+	/// Detach the filesystem, so that it is unmounted once nothing uses it anymore.
+	#[serde(default)]
+	pub lazy: bool,
+}
+
+/// The step that unmounted the filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+	Plain,
+	SyncAndRetry,
+	TerminateBlockers,
+	Lazy,
+}
+
+impl Display for Step {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Plain => "unmounted",
+			Self::SyncAndRetry => "unmounted after syncing and trying again",
+			Self::TerminateBlockers => "unmounted after terminating the processes using it",
+			Self::Lazy => "detached lazily; it will be unmounted once nothing uses it",
+		})
+	}
+}
+
+/// A process that keeps a filesystem busy.
+#[derive(Debug)]
+pub struct Blocker {
+	pub pid: i32,
+	pub command: String,
+	/// How it uses the filesystem, such as `has /mnt/foo/bar open`.
+	pub usage: String,
+}
+
+impl Blocker {
+	pub fn new(pid: i32, usage: String) -> Self {
+		let command = std::fs::read_to_string(format!("/proc/{pid}/comm"))
+			.map(|comm| comm.trim().to_owned())
+			.unwrap_or_default();
+		Self {
+			pid,
+			command,
+			usage,
+		}
+	}
+}
+
+impl Display for Blocker {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} (pid {}) {}", self.command, self.pid, self.usage)
+	}
+}
+
+fn process_usage(proc_dir: &Path, mount_path: &Path) -> Option<String> {
+	let is_inside = |link: &str| {
+		std::fs::read_link(proc_dir.join(link)).is_ok_and(|target| target.starts_with(mount_path))
+	};
+	if is_inside("cwd") {
+		return Some("has its working directory there".to_owned());
+	}
+	if is_inside("root") {
+		return Some("is chrooted there".to_owned());
+	}
+	if is_inside("exe") {
+		return Some("is running a program from there".to_owned());
+	}
+	std::fs::read_dir(proc_dir.join("fd"))
+		.ok()?
+		.flatten()
+		.find_map(|fd| {
+			let target = std::fs::read_link(fd.path()).ok()?;
+			target
+				.starts_with(mount_path)
+				.then(|| format!("has {} open", target.display()))
+		})
+}
+
+/// The processes that are using anything under `mount_path`. Processes that can't be inspected are skipped.
+pub fn blockers(mount_path: &Path) -> Vec<Blocker> {
+	let Ok(entries) = std::fs::read_dir("/proc") else {
+		return Vec::new();
+	};
+	let mut blockers: Vec<Blocker> = entries
+		.flatten()
+		.filter_map(|entry| {
+			let pid = entry.file_name().to_str()?.parse().ok()?;
+			let usage = process_usage(&entry.path(), mount_path)?;
+			Some(Blocker::new(pid, usage))
+		})
+		.collect();
+	blockers.sort_by_key(|blocker| blocker.pid);
+	blockers
+}
+
+/// How many of the kernel's recent messages about the filesystem's devices to include when unmounting fails.
+const KERNEL_MESSAGES_SHOWN: usize = 10;
+
+/// The error from the umount syscall, with what the kernel has recently said about `devices`, since an errno like EIO alone doesn't say which drive is failing or how.
+fn umount_error(error: Errno, devices: &[String]) -> anyhow::Error {
+	let messages: Vec<String> = kmsg::Cursor::at_start()
+		.map_or_else(Vec::new, |mut log| log.read_new())
+		.into_iter()
+		.filter(|message| {
+			devices
+				.iter()
+				.any(|device| watchdog::mentions(message, device))
+		})
+		.collect();
+	let recent = &messages[messages.len().saturating_sub(KERNEL_MESSAGES_SHOWN)..];
+	let context = if recent.is_empty() {
+		"making umount syscall".to_owned()
+	} else {
+		format!(
+			"making umount syscall. the kernel recently said about {}:\n{}",
+			devices.join(", "),
+			recent.join("\n")
+		)
+	};
+	anyhow::Error::new(error).context(context)
+}
+
+/// Returns false if the filesystem is busy. `devices` are those under the filesystem, to explain other errors with.
+fn try_unmount(mount_path: &Path, flags: MntFlags, devices: &[String]) -> Result<bool> {
+	match umount2(mount_path, flags) {
+		Ok(()) => Ok(true),
+		Err(Errno::EBUSY) => Ok(false),
+		Err(Errno::EINVAL) => {
+			eprintln!("umount returned EINVAL, assuming already unmounted.");
+			Ok(true)
+		}
+		Err(error) => Err(umount_error(error, devices)),
+	}
+}
+
+/// A spinner message that shows how much is left to write to the top of `devices` and how fast it is being written.
+fn flush_status(
+	message: &'static str,
+	devices: &[String],
+) -> impl FnMut() -> String + Send + 'static {
+	let mut flush_progress = devices.first().cloned().map(iostat::FlushProgress::new);
+	move || match flush_progress
+		.as_mut()
+		.and_then(iostat::FlushProgress::describe)
+	{
+		Some(description) => format!("{message} ({description})"),
+		None => message.to_owned(),
+	}
+}
+
+/// Send SIGTERM to the blockers and wait a little for them to exit.
+pub fn terminate(blockers: &[Blocker]) {
+	let own_pid = std::process::id();
+	let pids: Vec<Pid> = blockers
+		.iter()
+		.filter(|blocker| u32::try_from(blocker.pid).ok() != Some(own_pid))
+		.map(|blocker| Pid::from_raw(blocker.pid))
+		.collect();
+	for &pid in &pids {
+		eprintln!("sending SIGTERM to pid {pid}.");
+		let _ = kill(pid, Signal::SIGTERM);
+	}
+
+	let start = Instant::now();
+	// A signal of `None` only checks whether the process exists.
+	while start.elapsed() < TERMINATE_TIMEOUT && pids.iter().any(|&pid| kill(pid, None).is_ok()) {
+		std::thread::sleep(TERMINATE_POLL_INTERVAL);
+	}
+}
+
+/// Unmount the filesystem at `mount_path`, escalating as far as `policy` allows, and return the step that worked.
+pub fn run(mount_path: &Path, policy: Policy) -> Result<Step> {
+	if !mount_path
+		.try_exists()
+		.context("verifying that mount path exists")?
+	{
+		return Ok(Step::Plain);
+	}
+
+	// Found before unmounting, since the filesystem's devices can't be found afterwards.
+	let devices = watchdog::device_stack(mount_path);
+	if let Some((bytes, is_per_device)) = devices.first().and_then(|top| iostat::unwritten_bytes(top))
+	{
+		if bytes >= SHOW_UNWRITTEN_BYTES {
+			let scope = if is_per_device { "" } else { " (system-wide)" };
+			eprintln!(
+				"{} of writes are waiting to be flushed{scope}.",
+				output::format_size(bytes)
+			);
+		}
+	}
+	let is_unmounted = progress::with_status(
+		flush_status("unmounting and flushing writes", &devices),
+		|| try_unmount(mount_path, MntFlags::empty(), &devices),
+	)?;
+	if is_unmounted {
+		return Ok(Step::Plain);
+	}
+
+	if policy.sync_and_retry {
+		eprintln!(
+			"{} is busy; syncing and trying again.",
+			mount_path.display()
+		);
+		progress::with_status(flush_status("syncing", &devices), nix::unistd::sync);
+		std::thread::sleep(RETRY_DELAY);
+		if try_unmount(mount_path, MntFlags::empty(), &devices)? {
+			return Ok(Step::SyncAndRetry);
+		}
+	}
+
+	let blockers = blockers(mount_path);
+	if policy.report_blockers || policy.terminate_blockers {
+		if blockers.is_empty() {
+			eprintln!(
+				"{} is busy, but no process seems to be using it.",
+				mount_path.display()
+			);
+		} else {
+			eprintln!("{} is in use by:", mount_path.display());
+			for blocker in &blockers {
+				eprintln!("  {blocker}");
+			}
+		}
+	}
+
+	if policy.terminate_blockers && !blockers.is_empty() {
+		match prompt::confirm_risky("send SIGTERM to these processes") {
+			Ok(()) => {
+				terminate(&blockers);
+				if try_unmount(mount_path, MntFlags::empty(), &devices)? {
+					return Ok(Step::TerminateBlockers);
+				}
+			}
+			Err(error) => eprintln!("{error:#}."),
+		}
+	}
+
+	if policy.lazy && try_unmount(mount_path, MntFlags::MNT_DETACH, &devices)? {
+		return Ok(Step::Lazy);
+	}
+
+	let num_remaining = self::blockers(mount_path).len();
+	bail!(
+		"{} is still busy, in use by {num_remaining} process(es)",
+		mount_path.display()
+	);
+}
@@ -0,0 +1,65 @@
+//! dm-verity, which verifies every block of a read-only disk against a hash tree as it is read.
+//!
+//! Tampering or corruption then surfaces as I/O errors instead of silently wrong data.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context as _, Result};
+
+use crate::caps;
+
+fn mapping_name(disk_name: &str) -> String {
+	format!("{disk_name}-verity")
+}
+
+/// The verified device that the filesystem is mounted from.
+pub fn mapping_path(disk_name: &str) -> PathBuf {
+	Path::new("/dev/mapper").join(mapping_name(disk_name))
+}
+
+pub fn is_open(disk_name: &str) -> bool {
+	mapping_path(disk_name).exists()
+}
+
+fn veritysetup(args: &[&std::ffi::OsStr]) -> Result<()> {
+	let status = caps::tool("veritysetup")
+		.args(args)
+		.status()
+		.context("running veritysetup")?;
+	ensure!(
+		status.success(),
+		"veritysetup exited with status {:?}",
+		status.code()
+	);
+	Ok(())
+}
+
+/// Open the data device, verified against the hash device and root hash.
+pub fn open(
+	data_dev_path: &Path,
+	hash_dev_path: &Path,
+	root_hash: &str,
+	disk_name: &str,
+) -> Result<()> {
+	veritysetup(&[
+		"open".as_ref(),
+		data_dev_path.as_os_str(),
+		mapping_name(disk_name).as_ref(),
+		hash_dev_path.as_os_str(),
+		root_hash.as_ref(),
+	])
+}
+
+pub fn close(disk_name: &str) -> Result<()> {
+	veritysetup(&["close".as_ref(), mapping_name(disk_name).as_ref()])
+}
+
+/// The output of `veritysetup status`, such as whether corruption has been detected.
+pub fn status(disk_name: &str) -> Result<String> {
+	let output = caps::tool("veritysetup")
+		.arg("status")
+		.arg(mapping_name(disk_name))
+		.output()
+		.context("running veritysetup status")?;
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,97 @@
+//! Streaming changes in the state of disks as they happen, for status bars and notification scripts.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read as _, Write as _};
+use std::os::unix::io::{AsRawFd as _, FromRawFd as _};
+
+use anyhow::{Context as _, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{
+	bind, socket, AddressFamily, NetlinkAddr, SockFlag, SockProtocol, SockType,
+};
+
+use crate::config::Config;
+use crate::output::json_string;
+use crate::DiskState;
+
+/// The multicast group of uevents that udev has finished processing, after which the `/dev/disk` symlinks exist.
+const UDEV_GROUP: u32 = 2;
+/// Disk states are also checked this often, in case an event was missed.
+const POLL_TIMEOUT_MS: i32 = 5000;
+
+/// Listen for devices being added, changed, or removed, including LUKS and verity mappings.
+fn uevent_socket() -> Result<File> {
+	let fd = socket(
+		AddressFamily::Netlink,
+		SockType::Datagram,
+		SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+		SockProtocol::NetlinkKObjectUEvent,
+	)
+	.context("creating uevent socket")?;
+	// SAFETY: the socket was just created, and is now owned by the `File`.
+	let socket = unsafe { File::from_raw_fd(fd) };
+	bind(fd, &NetlinkAddr::new(0, UDEV_GROUP)).context("listening for udev events")?;
+	Ok(socket)
+}
+
+/// Discard pending events, which are only used to know when to check the disks again.
+fn drain(socket: &File) -> Result<()> {
+	let mut buf = [0; 8192];
+	loop {
+		match (&*socket).read(&mut buf) {
+			Ok(_) => {}
+			Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+			Err(error) => return Err(error).context("reading udev events"),
+		}
+	}
+}
+
+/// Print a line for every disk whose state differs from `previous`, and update it.
+fn report_changes(
+	config: &Config,
+	previous: &mut BTreeMap<String, DiskState>,
+	json: bool,
+) -> Result<()> {
+	let mut stdout = std::io::stdout().lock();
+	for disk in &config.disks {
+		let name = disk.as_repr();
+		let state = crate::disk_state(disk).with_context(|| format!("getting state of {name}"))?;
+		let old = previous.insert(name.to_owned(), state);
+		if old == Some(state) {
+			continue;
+		}
+		let line = if json {
+			let old = old.map_or_else(|| "null".to_owned(), |old| json_string(old.as_repr()));
+			format!(
+				"{{\"disk\":{},\"state\":{},\"previous\":{old}}}",
+				json_string(name),
+				json_string(state.as_repr()),
+			)
+		} else if let Some(old) = old {
+			format!("{name}: {} -> {}", old.as_repr(), state.as_repr())
+		} else {
+			format!("{name}: {}", state.as_repr())
+		};
+		// Stop once whatever is reading the output goes away.
+		writeln!(stdout, "{line}").context("writing output")?;
+	}
+	Ok(())
+}
+
+/// Print the state of every disk, and then each change as it happens, forever.
+pub fn run(config: &Config, json: bool) -> Result<()> {
+	// Polling the mount table reports a priority event whenever something is mounted or unmounted.
+	let mount_table = File::open("/proc/self/mountinfo").context("opening the mount table")?;
+	let uevents = uevent_socket()?;
+	let mut states = BTreeMap::new();
+	loop {
+		report_changes(config, &mut states, json)?;
+		let mut fds = [
+			PollFd::new(mount_table.as_raw_fd(), PollFlags::POLLPRI),
+			PollFd::new(uevents.as_raw_fd(), PollFlags::POLLIN),
+		];
+		poll(&mut fds, POLL_TIMEOUT_MS).context("waiting for events")?;
+		drain(&uevents)?;
+	}
+}
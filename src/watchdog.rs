@@ -0,0 +1,157 @@
+//! Watching the kernel log for errors from a disk during `c` sessions, so that a failing disk is noticed before more is written to it.
+//!
+//! The filesystem is also watched for becoming read-only by itself, which filesystems like ext4 do after errors.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use nix::sys::statvfs::{statvfs, FsFlags};
+
+use crate::output::{self, Color};
+use crate::{kmsg, mountinfo, sysfs};
+
+/// How often to check the kernel log, which is also how often to check whether the session is over.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Words that mark a kernel message as an error, such as `I/O error, dev sdb` or `EXT4-fs error (device dm-3)`.
+const ERROR_WORDS: &[&str] = &["error", "corrupt", "failed", "abort", "read-only"];
+
+/// The kernel names of the device mounted at `mount_path` and everything below it, such as `dm-3`, `sdb1`, and `sdb`.
+pub fn device_stack(mount_path: &Path) -> Vec<String> {
+	let Ok(Some(entry)) = mountinfo::find_by_mount_point(mount_path) else {
+		return Vec::new();
+	};
+	// Resolve links like `/dev/mapper/<name>` to the kernel's name.
+	let Some(top) = std::fs::canonicalize(&entry.source)
+		.ok()
+		.and_then(|path| sysfs::kernel_name(&path))
+	else {
+		return Vec::new();
+	};
+
+	let mut stack = Vec::new();
+	let mut pending = vec![top];
+	while let Some(device) = pending.pop() {
+		pending.extend(sysfs::slaves(&device).unwrap_or_default());
+		pending.extend(sysfs::parent_disk(&device).ok().flatten());
+		if !stack.contains(&device) {
+			stack.push(device);
+		}
+	}
+	stack
+}
+
+/// Whether `message` mentions `device` as a whole word, so that `sdb` doesn't match `sdb1`.
+pub fn mentions(message: &str, device: &str) -> bool {
+	message.match_indices(device).any(|(idx, _)| {
+		let before = message[..idx].chars().next_back();
+		let after = message[idx + device.len()..].chars().next();
+		let is_boundary =
+			|ch: Option<char>| !ch.is_some_and(|ch| ch.is_ascii_alphanumeric() || ch == '-');
+		is_boundary(before) && is_boundary(after)
+	})
+}
+
+/// Failed writes mean that data is being lost, such as `lost async page write` or `op 0x1:(WRITE)`.
+fn is_write_error(message: &str) -> bool {
+	message.to_lowercase().contains("write")
+}
+
+pub fn is_read_only(mount_path: &str) -> bool {
+	statvfs(mount_path).is_ok_and(|stats| stats.flags().contains(FsFlags::ST_RDONLY))
+}
+
+/// Get the user's attention with the terminal bell and a desktop notification, since the messages may scroll by in a busy session.
+fn alert(disk_name: &str, message: &str) {
+	use crate::privilege::CommandExt as _;
+
+	eprint!("\x07");
+	// Not everyone has a notification daemon, so failing to notify is fine.
+	let _ = Command::new("notify-send")
+		.args([
+			"--urgency=critical",
+			&format!("d: {disk_name} has errors"),
+			message,
+		])
+		.as_invoking_user()
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.status();
+}
+
+fn is_error_about(message: &str, devices: &[String]) -> bool {
+	let lowercase = message.to_lowercase();
+	ERROR_WORDS.iter().any(|word| lowercase.contains(word))
+		&& devices.iter().any(|device| mentions(message, device))
+}
+
+/// Report errors that the kernel logs about `devices` until `stop` is set. With `read_only`, the disk is also remounted read-only at the first error.
+pub fn watch(
+	disk_name: &str,
+	mount_path: &str,
+	devices: &[String],
+	read_only: bool,
+	stop: &AtomicBool,
+) {
+	// Without permission to read the kernel log, there's nothing to watch.
+	let Some(mut log) = kmsg::Cursor::at_end() else {
+		return;
+	};
+	let mut remounted = false;
+	let mut was_read_only = is_read_only(mount_path);
+	while !stop.load(Ordering::Relaxed) {
+		std::thread::sleep(POLL_INTERVAL);
+		if !was_read_only && is_read_only(mount_path) {
+			was_read_only = true;
+			let message = format!("the kernel made {disk_name} read-only, probably because of errors. back it up while you still can.");
+			eprintln!(
+				"\n{} {message}",
+				output::paint_stderr("DISK ERRORS:", Color::Red)
+			);
+			alert(disk_name, &message);
+		}
+
+		let errors: Vec<String> = log
+			.read_new()
+			.into_iter()
+			.filter(|message| is_error_about(message, devices))
+			.collect();
+		if errors.is_empty() {
+			continue;
+		}
+
+		eprintln!(
+			"\n{} the kernel reported errors on {disk_name}:",
+			output::paint_stderr("DISK ERRORS:", Color::Red)
+		);
+		for error in &errors {
+			eprintln!("  {error}");
+		}
+		let writes_failed = errors.iter().any(|error| is_write_error(error));
+		if read_only && !remounted {
+			match crate::remount_read_only(mount_path) {
+				Ok(()) => {
+					let message = format!(
+						"remounted {disk_name} read-only to protect it. back it up while you still can."
+					);
+					eprintln!("{message}");
+					alert(disk_name, &message);
+					remounted = true;
+					was_read_only = true;
+				}
+				Err(error) => output::warning(format_args!("failed to remount read-only: {error:#}")),
+			}
+		} else if !remounted && !was_read_only && writes_failed {
+			let message = format!("writes to {disk_name} are failing. to stop further damage, remount it read-only with `mount -o remount,ro {mount_path}`.");
+			eprintln!("{message} set `read_only_on_errors = true` to do this automatically.");
+			alert(disk_name, &message);
+		} else if !remounted {
+			eprintln!(
+				"the disk may be failing. avoid writing to it, and back it up while you still can."
+			);
+		}
+	}
+}
@@ -0,0 +1,145 @@
+//! Erasing disks that are being decommissioned.
+
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context as _, Result};
+
+use crate::config::Disk;
+use crate::{caps, format, output, progress, prompt, sysfs};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Method {
+	/// Discard every block, which makes SSDs return zeros (or garbage) for them.
+	Discard,
+	/// Ask the drive's firmware to erase itself with ATA Secure Erase.
+	Ata,
+	/// Destroy the LUKS keyslots, leaving only ciphertext that can never be decrypted.
+	Crypto,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown wipe method {0:?}. valid methods are discard, ata, crypto.")]
+pub struct UnknownMethod(String);
+
+impl FromStr for Method {
+	type Err = UnknownMethod;
+
+	fn from_str(s: &str) -> Result<Self, UnknownMethod> {
+		Ok(match s {
+			"discard" => Self::Discard,
+			"ata" => Self::Ata,
+			"crypto" => Self::Crypto,
+			_ => return Err(UnknownMethod(s.to_owned())),
+		})
+	}
+}
+
+/// hdparm requires a password to be set before erasing, which the erase then clears.
+const ATA_PASSWORD: &str = "d-wipe";
+
+fn run_command(description: &str, command: &mut Command) -> Result<()> {
+	let status = progress::with_spinner(description, || command.status())
+		.with_context(|| format!("running {}", command.get_program().to_string_lossy()))?;
+	ensure!(
+		status.success(),
+		"{} exited with status {:?}",
+		command.get_program().to_string_lossy(),
+		status.code()
+	);
+	Ok(())
+}
+
+/// `confirmed` is whether the wipe was confirmed with `--confirm`, which also covers erasing the whole drive.
+fn ata_secure_erase(dev_path: &Path, confirmed: bool) -> Result<()> {
+	let kernel_name = sysfs::kernel_name(dev_path)
+		.with_context(|| format!("no kernel name for {}", dev_path.display()))?;
+	// Secure Erase works on whole drives, not partitions, so nothing else on the drive can be in use either.
+	let drive_name = sysfs::parent_disk(&kernel_name)?;
+	format::ensure_not_in_use(drive_name.as_deref().unwrap_or(&kernel_name))
+		.context("the whole drive would be erased, but part of it is in use")?;
+	let drive = match drive_name {
+		Some(parent) => {
+			output::warning(format_args!(
+				"ATA Secure Erase erases the whole drive /dev/{parent}, including any other partitions on it."
+			));
+			if !confirmed {
+				prompt::confirm_typed_always(&format!("/dev/{parent}"), None)?;
+			}
+			Path::new("/dev").join(parent)
+		}
+		None => dev_path.to_owned(),
+	};
+
+	let identify = caps::tool("hdparm")
+		.arg("-I")
+		.arg(&drive)
+		.output()
+		.context("running hdparm")?;
+	let identify = String::from_utf8_lossy(&identify.stdout);
+	ensure!(
+		identify.contains("Security:"),
+		"{} does not support ATA security commands",
+		drive.display()
+	);
+	ensure!(
+		identify.contains("not\tfrozen"),
+		"the security state of {} is frozen. suspending and resuming the system usually unfreezes it",
+		drive.display()
+	);
+
+	run_command(
+		"setting ATA security password",
+		caps::tool("hdparm")
+			.args(["--user-master", "u", "--security-set-pass", ATA_PASSWORD])
+			.arg(&drive),
+	)?;
+	run_command(
+		"erasing (this can take hours)",
+		caps::tool("hdparm")
+			.args(["--user-master", "u", "--security-erase", ATA_PASSWORD])
+			.arg(&drive),
+	)
+}
+
+/// Irreversibly erase `disk`. The caller must make sure that it is not in use.
+///
+/// The disk's name has to be typed to confirm, or given in advance as `confirm`.
+pub fn run(disk: &Disk, method: Method, confirm: Option<&str>) -> Result<()> {
+	ensure!(
+		crate::outer_device_present(disk)?,
+		"{} is not attached",
+		disk.as_repr()
+	);
+	let dev_path = crate::outer_dev_path(disk)?;
+
+	output::warning(format_args!(
+		"this will irreversibly destroy everything on {} ({}).",
+		disk.as_repr(),
+		dev_path.display()
+	));
+	prompt::confirm_typed_always(disk.as_repr(), confirm)?;
+
+	match method {
+		Method::Discard => run_command(
+			"discarding",
+			caps::tool("blkdiscard").arg("--force").arg(&dev_path),
+		),
+		Method::Ata => ata_secure_erase(&dev_path, confirm.is_some()),
+		Method::Crypto => {
+			if !disk.is_encrypted() {
+				bail!(
+					"{} is not encrypted, so it can't be crypto-erased",
+					disk.as_repr()
+				);
+			}
+			run_command(
+				"destroying keyslots",
+				caps::tool("cryptsetup")
+					.args(["luksErase", "--batch-mode"])
+					.arg(&dev_path),
+			)
+		}
+	}
+}